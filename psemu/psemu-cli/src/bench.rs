@@ -0,0 +1,99 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use clap::Args;
+use psemu_core::{Cpu, Speed, VideoTiming};
+use tracing::{subscriber::NoSubscriber, warn};
+
+use crate::{test_rom, Engine, Video};
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// PS-X EXE to sideload. Boots the BIOS from ./data otherwise.
+    exe: Option<PathBuf>,
+    /// How many frames to emulate, as fast as possible
+    #[arg(long, default_value_t = 600)]
+    frames: u64,
+    /// Run this many instructions instead of a number of frames
+    #[arg(long, value_name = "N", conflicts_with = "frames")]
+    cycles: Option<u64>,
+    #[arg(long, value_enum, default_value_t = Engine::Interpreter)]
+    engine: Engine,
+    /// Video timing, which decides how long a frame is
+    #[arg(long, value_enum, default_value_t = Video::Ntsc)]
+    video: Video,
+}
+
+pub fn run(args: &BenchArgs) -> Result<(), String> {
+    let mut cpu = match &args.exe {
+        Some(path) => test_rom::load(path)?,
+        None => {
+            let mut cpu = Cpu::new();
+            cpu.instruction_history.set_capacity(0);
+            cpu
+        }
+    };
+    let mut backend = args.engine.backend();
+    let mut video = VideoTiming::new(args.video.standard());
+    let mut frames = 0;
+
+    // Logging would be most of what's measured
+    let (cpu_time, res) = tracing::subscriber::with_default(NoSubscriber::default(), || {
+        let mut cpu_time = Duration::ZERO;
+        let mut res = Ok(());
+        // A frame at a time, which is where the other subsystems will run
+        while res.is_ok() {
+            let mut frame_end = cpu.cycles + video.cycles_to_vblank();
+            match args.cycles {
+                Some(total) if cpu.cycles >= total => break,
+                Some(total) => frame_end = frame_end.min(total),
+                None if frames >= args.frames => break,
+                None => (),
+            }
+            let start = Instant::now();
+            let before = cpu.cycles;
+            res = backend.run_until(&mut cpu, frame_end).map(|_| ());
+            cpu_time += start.elapsed();
+            frames += video.advance(cpu.cycles - before) as u64;
+        }
+        (cpu_time, res)
+    });
+    if let Err(e) = &res {
+        warn!("CPU stopped early: {e}");
+    }
+
+    let speed = Speed {
+        instructions: cpu.cycles,
+        frames,
+        elapsed: cpu_time,
+    };
+    println!(
+        "Ran {} instructions ({frames} frames) in {:.3}s with the {} backend",
+        cpu.cycles,
+        cpu_time.as_secs_f64(),
+        backend.name()
+    );
+    println!("  {speed}\n");
+    // Only the CPU exists so far; the rest are listed so the report keeps
+    // its shape as they're added
+    let times = [
+        ("CPU", Some(cpu_time)),
+        ("GPU", None),
+        ("SPU", None),
+        ("CD", None),
+    ];
+    let measured: Duration = times.iter().filter_map(|(_, time)| *time).sum();
+    for (name, time) in times {
+        match time {
+            Some(time) => println!(
+                "  {name:<4} {:>8.3}s {:>6.1}%",
+                time.as_secs_f64(),
+                100.0 * time.as_secs_f64() / measured.as_secs_f64().max(f64::MIN_POSITIVE)
+            ),
+            None => println!("  {name:<4} not emulated yet"),
+        }
+    }
+    res.map_err(|e| format!("CPU stopped: {e}"))
+}
@@ -0,0 +1,441 @@
+use std::{
+    collections::BTreeSet,
+    fmt::Write as _,
+    io::{self, Write as _},
+    path::PathBuf,
+};
+
+use clap::{Args, ValueEnum};
+use psemu_core::{
+    disassemble, disassemble_exact, kernel, DecodedInstruction, Flow, PsxExe, SymbolTable,
+};
+
+const BIOS_BASE: u32 = 0xbfc00000;
+const BIOS_SIZE: usize = 512 * 1024;
+// Column the trailing comments line up on
+const COMMENT_COLUMN: usize = 56;
+
+#[derive(Args, Debug)]
+pub struct DisasmArgs {
+    /// BIOS image or PS-X EXE
+    file: PathBuf,
+    /// How to tell code from data
+    #[arg(long, value_enum, default_value_t = Mode::Recursive)]
+    mode: Mode,
+    /// Output format
+    #[arg(long, value_enum, default_value_t = Syntax::Plain)]
+    syntax: Syntax,
+    /// Extra address to start recursive descent from, e.g. a function only
+    /// reached through a pointer. Can be repeated.
+    #[arg(long = "entry", value_name = "ADDR", value_parser = parse_addr)]
+    entries: Vec<u32>,
+    /// ELF, PsyQ .SYM or .map file to name functions and labels from. Can be
+    /// repeated.
+    #[arg(long = "symbols", value_name = "FILE")]
+    symbols: Vec<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Mode {
+    /// Decode every word as an instruction
+    Linear,
+    /// Follow control flow from the entry points, everything else is data
+    Recursive,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Syntax {
+    /// Addresses, raw words and instructions
+    Plain,
+    /// Source that GNU as assembles back into the same image
+    Gnu,
+}
+
+pub fn parse_addr(s: &str) -> Result<u32, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| format!("Invalid address `{s}`: {e}"))
+}
+
+struct Image {
+    base: u32,
+    data: Vec<u8>,
+    description: String,
+    entry_points: Vec<u32>,
+}
+
+impl Image {
+    fn load(path: &PathBuf) -> Result<Self, String> {
+        let bytes =
+            std::fs::read(path).map_err(|e| format!("Unable to read {}: {e}", path.display()))?;
+        if PsxExe::is_exe(&bytes) {
+            let exe = PsxExe::parse(&bytes).map_err(|e| e.to_string())?;
+            Ok(Image {
+                base: exe.load_addr,
+                description: format!(
+                    "PS-X EXE, load address {:#010x}, entry {:#010x}",
+                    exe.load_addr, exe.pc
+                ),
+                entry_points: vec![exe.pc],
+                data: exe.data,
+            })
+        } else if bytes.len() == BIOS_SIZE {
+            Ok(Image {
+                base: BIOS_BASE,
+                description: format!("BIOS image, load address {BIOS_BASE:#010x}"),
+                entry_points: kernel::BIOS_ENTRY_POINTS
+                    .iter()
+                    .map(|(addr, _)| *addr)
+                    .filter(|addr| (BIOS_BASE..BIOS_BASE + BIOS_SIZE as u32).contains(addr))
+                    .collect(),
+                data: bytes,
+            })
+        } else {
+            Err(format!(
+                "{} is neither a PS-X EXE nor a {}KB BIOS image",
+                path.display(),
+                BIOS_SIZE / 1024
+            ))
+        }
+    }
+
+    fn word(&self, addr: u32) -> Option<u32> {
+        if !addr.is_multiple_of(4) {
+            return None;
+        }
+        let offset = addr.checked_sub(self.base)? as usize;
+        let bytes = self.data.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    // Trailing bytes that don't make up a whole word are left out
+    fn addrs(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.data.len() as u32 / 4).map(|i| self.base.wrapping_add(i * 4))
+    }
+}
+
+#[derive(Default)]
+struct Analysis {
+    // Words that were decoded as instructions
+    code: BTreeSet<u32>,
+    functions: BTreeSet<u32>,
+    // Branch and jump targets that aren't function starts
+    labels: BTreeSet<u32>,
+}
+
+impl Analysis {
+    fn add_target(&mut self, instr: &DecodedInstruction, image: &Image) {
+        let Some(target) = instr.target else {
+            return;
+        };
+        if image.word(target).is_none() {
+            return;
+        }
+        if instr.flow == Flow::Call {
+            self.functions.insert(target);
+        } else {
+            self.labels.insert(target);
+        }
+    }
+
+    fn linear(image: &Image, entries: &[u32]) -> Self {
+        let mut analysis = Analysis::default();
+        analysis.functions.extend(entries);
+        for addr in image.addrs() {
+            let instr = disassemble(image.word(addr).unwrap(), addr);
+            analysis.code.insert(addr);
+            analysis.add_target(&instr, image);
+        }
+        analysis.finish()
+    }
+
+    // Doesn't follow `jr` through jump tables, pass their targets with
+    // `--entry` if they matter
+    fn recursive(image: &Image, entries: &[u32]) -> Self {
+        let mut analysis = Analysis::default();
+        analysis.functions.extend(entries);
+        let mut pending: Vec<u32> = entries.to_vec();
+        while let Some(start) = pending.pop() {
+            let mut addr = start;
+            // Set once a jump has been seen; its delay slot is the last
+            // instruction of the block
+            let mut ends_after_delay_slot = false;
+            loop {
+                if analysis.code.contains(&addr) {
+                    break;
+                }
+                let Some(word) = image.word(addr) else {
+                    break;
+                };
+                let instr = disassemble(word, addr);
+                if instr.mnemonic == ".word" {
+                    break;
+                }
+                analysis.code.insert(addr);
+                analysis.add_target(&instr, image);
+                if let Some(target) = instr.target {
+                    pending.push(target);
+                }
+                if ends_after_delay_slot {
+                    break;
+                }
+                ends_after_delay_slot = matches!(instr.flow, Flow::Jump | Flow::IndirectJump);
+                addr = addr.wrapping_add(4);
+            }
+        }
+        analysis.finish()
+    }
+
+    fn finish(mut self) -> Self {
+        self.labels.retain(|addr| !self.functions.contains(addr));
+        self
+    }
+
+    fn label(&self, addr: u32, symbols: &SymbolTable) -> Option<String> {
+        // Skip names that are shared with another address, the label has to
+        // be unique for the listing to assemble
+        let symbol = symbols
+            .name(addr)
+            .filter(|name| symbols.resolve(name) == Some(addr));
+        if let Some(name) = symbol {
+            Some(name.to_string())
+        } else if let Some(name) = kernel::entry_point_name(addr) {
+            Some(name.to_string())
+        } else if self.functions.contains(&addr) {
+            Some(format!("func_{addr:08x}"))
+        } else if self.labels.contains(&addr) {
+            Some(format!("loc_{addr:08x}"))
+        } else {
+            None
+        }
+    }
+}
+
+// `li $<reg>, <value>` in either of its encodings
+fn li_value(word: u32, reg: u32) -> Option<u32> {
+    let op = word >> 26;
+    let rs = (word >> 21) & 0x1f;
+    let rt = (word >> 16) & 0x1f;
+    if rs != 0 || rt != reg {
+        return None;
+    }
+    match op {
+        0x09 => Some(word as i16 as u32),
+        0x0d => Some(word & 0xffff),
+        _ => None,
+    }
+}
+
+// Kernel calls look like `li $t2, 0xa0; jr $t2; li $t1, <function>`
+fn kernel_call(image: &Image, addr: u32) -> Option<String> {
+    const JR_T2: u32 = 0x01400008;
+    const T1: u32 = 9;
+    const T2: u32 = 10;
+    if image.word(addr)? != JR_T2 {
+        return None;
+    }
+    let table = li_value(image.word(addr.wrapping_sub(4))?, T2)?;
+    let function = li_value(image.word(addr.wrapping_add(4))?, T1)?;
+    let name = kernel::kernel_call_name(table, function).unwrap_or("unknown");
+    Some(format!("{table:X}:{function:02X} {name}"))
+}
+
+pub fn run(args: &DisasmArgs) -> Result<(), String> {
+    let image = Image::load(&args.file)?;
+    let mut symbols = SymbolTable::default();
+    for path in &args.symbols {
+        symbols.load(path).map_err(|e| e.to_string())?;
+    }
+    let out = listing(args, &image, &symbols);
+    match std::io::stdout().write_all(out.as_bytes()) {
+        // Piped into `head` or similar
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        res => res.map_err(|e| format!("Unable to write listing: {e}")),
+    }
+}
+
+fn listing(args: &DisasmArgs, image: &Image, symbols: &SymbolTable) -> String {
+    let mut entries = image.entry_points.clone();
+    entries.extend(&args.entries);
+    let analysis = match args.mode {
+        Mode::Linear => Analysis::linear(image, &entries),
+        Mode::Recursive => Analysis::recursive(image, &entries),
+    };
+
+    let mut out = String::new();
+    writeln!(out, "# {}: {}", args.file.display(), image.description).unwrap();
+    writeln!(
+        out,
+        "# {} instructions, {} functions, {} labels",
+        analysis.code.len(),
+        analysis.functions.len(),
+        analysis.labels.len()
+    )
+    .unwrap();
+    if args.syntax == Syntax::Gnu {
+        writeln!(out, "# Link with .text at {:#010x}", image.base).unwrap();
+        writeln!(out, "\t.set noreorder\n\t.set noat\n\t.text").unwrap();
+        for entry in &entries {
+            if let Some(label) = analysis.label(*entry, symbols) {
+                writeln!(out, "\t.globl {label}").unwrap();
+            }
+        }
+    }
+
+    for addr in image.addrs() {
+        let word = image.word(addr).unwrap();
+        if let Some(label) = analysis.label(addr, symbols) {
+            out.push('\n');
+            let mut line = format!("{label}:");
+            if kernel::entry_point_name(addr).is_some() {
+                pad_to_comment(&mut line);
+                line.push_str("# BIOS entry point");
+            } else if entries.contains(&addr) {
+                pad_to_comment(&mut line);
+                line.push_str("# entry point");
+            }
+            writeln!(out, "{line}").unwrap();
+        }
+
+        let is_code = analysis.code.contains(&addr);
+        let instr = match (is_code, args.syntax) {
+            (true, Syntax::Plain) => disassemble(word, addr),
+            (true, Syntax::Gnu) => disassemble_exact(word, addr),
+            (false, _) => DecodedInstruction {
+                mnemonic: ".word",
+                operands: format!("{word:#010x}"),
+                target: None,
+                ..disassemble(word, addr)
+            },
+        };
+        let mut text = instr.to_string();
+        let mut comment = kernel_call(image, addr);
+        if let Some(target) = instr.target {
+            let hex = format!("{target:#010x}");
+            match analysis.label(target, symbols) {
+                // Only labels inside the image are defined
+                Some(label) if image.word(target).is_some() => {
+                    text = text.replace(&hex, &label);
+                }
+                Some(label) => comment = Some(label),
+                None => (),
+            }
+            // There's no label to branch to, and the assembler can't encode
+            // an absolute address for a relative branch
+            if args.syntax == Syntax::Gnu && image.word(target).is_none() {
+                comment = Some(text);
+                text = format!("{:<7} {word:#010x}", ".word");
+            }
+        }
+
+        let mut line = match args.syntax {
+            Syntax::Plain => format!("  {addr:08x}  {word:08x}  {text}"),
+            Syntax::Gnu => format!("\t{text}"),
+        };
+        let comment = match (args.syntax, comment) {
+            (Syntax::Plain, comment) => comment,
+            (Syntax::Gnu, Some(comment)) => Some(format!("{addr:08x} {comment}")),
+            (Syntax::Gnu, None) => Some(format!("{addr:08x}")),
+        };
+        if let Some(comment) = comment {
+            pad_to_comment(&mut line);
+            write!(line, "# {comment}").unwrap();
+        }
+        writeln!(out, "{line}").unwrap();
+    }
+    out
+}
+
+fn pad_to_comment(line: &mut String) {
+    // Tabs count as a full indent
+    let len = line.replace('\t', "        ").len();
+    let padding = COMMENT_COLUMN.saturating_sub(len).max(1);
+    line.push_str(&" ".repeat(padding));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u32 = 0x80010000;
+
+    fn image(program: &[u32]) -> Image {
+        Image {
+            base: BASE,
+            data: program.iter().flat_map(|w| w.to_le_bytes()).collect(),
+            description: "test".to_string(),
+            entry_points: vec![BASE],
+        }
+    }
+
+    fn args(mode: Mode, syntax: Syntax) -> DisasmArgs {
+        DisasmArgs {
+            file: PathBuf::from("test.exe"),
+            mode,
+            syntax,
+            entries: vec![],
+            symbols: vec![],
+        }
+    }
+
+    // Calls a function, then spins. The last word is never reached.
+    const PROGRAM: [u32; 7] = [
+        0x0c004004, // jal 0x80010010
+        0x00000000, // nop
+        0x08004002, // j 0x80010008
+        0x00000000, // nop
+        0x03e00008, // jr $ra
+        0x00000000, // nop
+        0x24020001, // addiu $v0, $zero, 1
+    ];
+
+    fn lines(mode: Mode, syntax: Syntax, image: &Image) -> Vec<String> {
+        listing(&args(mode, syntax), image, &SymbolTable::default())
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn linear_decodes_every_word() {
+        let lines = lines(Mode::Linear, Syntax::Plain, &image(&PROGRAM));
+        assert_eq!(lines[1], "# 7 instructions, 2 functions, 1 labels");
+        assert!(lines.contains(&"  80010000  0c004004  jal     func_80010010".to_string()));
+        assert!(lines.contains(&"loc_80010008:".to_string()));
+        assert_eq!(
+            lines.last().unwrap(),
+            "  80010018  24020001  li      $v0, 1"
+        );
+    }
+
+    #[test]
+    fn recursive_leaves_unreached_words_as_data() {
+        let lines = lines(Mode::Recursive, Syntax::Plain, &image(&PROGRAM));
+        assert_eq!(lines[1], "# 6 instructions, 2 functions, 1 labels");
+        assert!(lines.contains(&"func_80010010:".to_string()));
+        assert!(lines.contains(&"  80010010  03e00008  jr      $ra".to_string()));
+        assert_eq!(
+            lines.last().unwrap(),
+            "  80010018  24020001  .word   0x24020001"
+        );
+    }
+
+    #[test]
+    fn gnu_syntax_labels_targets_and_comments_addresses() {
+        let lines = lines(Mode::Recursive, Syntax::Gnu, &image(&PROGRAM));
+        assert_eq!(lines[2], "# Link with .text at 0x80010000");
+        assert!(lines.contains(&"\t.globl func_80010000".to_string()));
+        assert!(lines.contains(&format!("\t{:<47} # 80010008", "j       loc_80010008")));
+        assert!(lines.contains(&format!("\t{:<47} # 80010018", ".word   0x24020001")));
+    }
+
+    #[test]
+    fn images_at_the_top_of_the_address_space() {
+        let mut image = image(&[0x00000000, 0x00000000]);
+        image.base = 0xfffffff8;
+        let lines = lines(Mode::Linear, Syntax::Plain, &image);
+        assert_eq!(lines.last().unwrap(), "  fffffffc  00000000  nop");
+    }
+}
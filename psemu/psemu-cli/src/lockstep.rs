@@ -0,0 +1,590 @@
+use std::{
+    collections::BTreeSet,
+    panic::{self, AssertUnwindSafe},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::Args;
+use psemu_core::{disassemble, Cpu, PsemuCoreError, RegisterIndex, REGISTER_NAMES};
+use tracing::{info, subscriber::NoSubscriber};
+
+use crate::{panic_message, reference::Reference, Engine};
+
+// Where the generated code and the memory it loads from and stores to live
+const PROGRAM_BASE: u32 = 0x80010000;
+const DATA_BASE: u32 = 0x80100000;
+const DATA_WORDS: u32 = 0x400;
+// $s0-$s3 always point into the data area, so loads and stores land there.
+// Nothing generated writes to them.
+const BASE_REGS: [u32; 4] = [16, 17, 18, 19];
+
+#[derive(Args, Debug)]
+pub struct LockstepArgs {
+    /// How many random programs to run
+    #[arg(long, default_value_t = 1000)]
+    runs: u64,
+    /// Instructions per program
+    #[arg(long, default_value_t = 64)]
+    length: u32,
+    /// Most instructions to execute per program; branches can loop
+    #[arg(long, default_value_t = 1000)]
+    max_steps: u64,
+    /// Seed for the first program, each one after it uses the next number.
+    /// Random by default.
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Only generate these instructions, e.g. `addiu,sw,lw`
+    #[arg(long, value_delimiter = ',')]
+    ops: Vec<String>,
+    /// Also generate loads and stores from unaligned addresses, which raise
+    /// address errors
+    #[arg(long)]
+    misaligned: bool,
+    /// Which psemu backend to check. Block-based ones are compared after
+    /// each block rather than each instruction.
+    #[arg(long, value_enum, default_value_t = Engine::Interpreter)]
+    engine: Engine,
+}
+
+// xorshift64*, seeded through splitmix64 so that nearby seeds diverge
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        Rng((z ^ (z >> 31)) | 1)
+    }
+
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545f4914f6cdd1d) >> 32) as u32
+    }
+
+    fn below(&mut self, n: u32) -> u32 {
+        self.next() % n
+    }
+}
+
+// Everything needed to build one instruction
+struct Gen<'a> {
+    rng: &'a mut Rng,
+    // Index of the instruction being generated, and the program length
+    index: u32,
+    length: u32,
+    misaligned: bool,
+}
+
+impl Gen<'_> {
+    fn src(&mut self) -> u32 {
+        self.rng.below(32)
+    }
+
+    fn dst(&mut self) -> u32 {
+        loop {
+            let reg = self.rng.below(32);
+            if !BASE_REGS.contains(&reg) {
+                return reg;
+            }
+        }
+    }
+
+    fn imm(&mut self) -> u32 {
+        self.rng.next() & 0xffff
+    }
+
+    // Somewhere inside the program, relative to the delay slot
+    fn branch_offset(&mut self) -> u32 {
+        let target = self.rng.below(self.length);
+        target.wrapping_sub(self.index + 1) & 0xffff
+    }
+
+    fn jump_index(&mut self) -> u32 {
+        let target = PROGRAM_BASE + self.rng.below(self.length) * 4;
+        (target >> 2) & 0x03ffffff
+    }
+
+    // Base register and offset for an access of `size` bytes
+    fn address(&mut self, size: u32) -> (u32, u32) {
+        let base = BASE_REGS[self.rng.below(4) as usize];
+        let mut offset = self.rng.below(0x400) as i32 - 0x200;
+        if !(self.misaligned && self.rng.below(16) == 0) {
+            offset &= !(size as i32 - 1);
+        }
+        (base, offset as u32 & 0xffff)
+    }
+}
+
+fn r(funct: u32, rs: u32, rt: u32, rd: u32, sa: u32) -> u32 {
+    (rs << 21) | (rt << 16) | (rd << 11) | (sa << 6) | funct
+}
+
+fn i(op: u32, rs: u32, rt: u32, imm: u32) -> u32 {
+    (op << 26) | (rs << 21) | (rt << 16) | imm
+}
+
+struct Op {
+    mnemonic: &'static str,
+    generate: fn(&mut Gen) -> u32,
+}
+
+macro_rules! ops {
+    ($($mnemonic:literal => |$g:ident| $generate:expr),* $(,)?) => {
+        &[$(Op { mnemonic: $mnemonic, generate: |$g| $generate }),*]
+    };
+}
+
+// Every R3000A instruction the reference knows, except MTC0, which can
+// isolate the cache and turn every later store into a no-op
+const OPS: &[Op] = ops! {
+    "sll" => |g| r(0x00, 0, g.src(), g.dst(), g.rng.below(32)),
+    "srl" => |g| r(0x02, 0, g.src(), g.dst(), g.rng.below(32)),
+    "sra" => |g| r(0x03, 0, g.src(), g.dst(), g.rng.below(32)),
+    "sllv" => |g| r(0x04, g.src(), g.src(), g.dst(), 0),
+    "srlv" => |g| r(0x06, g.src(), g.src(), g.dst(), 0),
+    "srav" => |g| r(0x07, g.src(), g.src(), g.dst(), 0),
+    "jr" => |g| r(0x08, g.src(), 0, 0, 0),
+    "jalr" => |g| r(0x09, g.src(), 0, g.dst(), 0),
+    "syscall" => |g| (g.rng.next() & 0xfffff) << 6 | 0x0c,
+    "break" => |g| (g.rng.next() & 0xfffff) << 6 | 0x0d,
+    "mfhi" => |g| r(0x10, 0, 0, g.dst(), 0),
+    "mthi" => |g| r(0x11, g.src(), 0, 0, 0),
+    "mflo" => |g| r(0x12, 0, 0, g.dst(), 0),
+    "mtlo" => |g| r(0x13, g.src(), 0, 0, 0),
+    "mult" => |g| r(0x18, g.src(), g.src(), 0, 0),
+    "multu" => |g| r(0x19, g.src(), g.src(), 0, 0),
+    "div" => |g| r(0x1a, g.src(), g.src(), 0, 0),
+    "divu" => |g| r(0x1b, g.src(), g.src(), 0, 0),
+    "add" => |g| r(0x20, g.src(), g.src(), g.dst(), 0),
+    "addu" => |g| r(0x21, g.src(), g.src(), g.dst(), 0),
+    "sub" => |g| r(0x22, g.src(), g.src(), g.dst(), 0),
+    "subu" => |g| r(0x23, g.src(), g.src(), g.dst(), 0),
+    "and" => |g| r(0x24, g.src(), g.src(), g.dst(), 0),
+    "or" => |g| r(0x25, g.src(), g.src(), g.dst(), 0),
+    "xor" => |g| r(0x26, g.src(), g.src(), g.dst(), 0),
+    "nor" => |g| r(0x27, g.src(), g.src(), g.dst(), 0),
+    "slt" => |g| r(0x2a, g.src(), g.src(), g.dst(), 0),
+    "sltu" => |g| r(0x2b, g.src(), g.src(), g.dst(), 0),
+    "bltz" => |g| i(0x01, g.src(), 0x00, g.branch_offset()),
+    "bgez" => |g| i(0x01, g.src(), 0x01, g.branch_offset()),
+    "bltzal" => |g| i(0x01, g.src(), 0x10, g.branch_offset()),
+    "bgezal" => |g| i(0x01, g.src(), 0x11, g.branch_offset()),
+    "j" => |g| (0x02 << 26) | g.jump_index(),
+    "jal" => |g| (0x03 << 26) | g.jump_index(),
+    "beq" => |g| i(0x04, g.src(), g.src(), g.branch_offset()),
+    "bne" => |g| i(0x05, g.src(), g.src(), g.branch_offset()),
+    "blez" => |g| i(0x06, g.src(), 0, g.branch_offset()),
+    "bgtz" => |g| i(0x07, g.src(), 0, g.branch_offset()),
+    "addi" => |g| i(0x08, g.src(), g.dst(), g.imm()),
+    "addiu" => |g| i(0x09, g.src(), g.dst(), g.imm()),
+    "slti" => |g| i(0x0a, g.src(), g.dst(), g.imm()),
+    "sltiu" => |g| i(0x0b, g.src(), g.dst(), g.imm()),
+    "andi" => |g| i(0x0c, g.src(), g.dst(), g.imm()),
+    "ori" => |g| i(0x0d, g.src(), g.dst(), g.imm()),
+    "xori" => |g| i(0x0e, g.src(), g.dst(), g.imm()),
+    "lui" => |g| i(0x0f, 0, g.dst(), g.imm()),
+    // BadVaddr, SR, Cause and EPC
+    "mfc0" => |g| i(0x10, 0x00, g.dst(), [8, 12, 13, 14][g.rng.below(4) as usize] << 11),
+    "rfe" => |_g| 0x42000010,
+    "lb" => |g| { let (base, offset) = g.address(1); i(0x20, base, g.dst(), offset) },
+    "lh" => |g| { let (base, offset) = g.address(2); i(0x21, base, g.dst(), offset) },
+    "lwl" => |g| { let (base, offset) = g.address(1); i(0x22, base, g.dst(), offset) },
+    "lw" => |g| { let (base, offset) = g.address(4); i(0x23, base, g.dst(), offset) },
+    "lbu" => |g| { let (base, offset) = g.address(1); i(0x24, base, g.dst(), offset) },
+    "lhu" => |g| { let (base, offset) = g.address(2); i(0x25, base, g.dst(), offset) },
+    "lwr" => |g| { let (base, offset) = g.address(1); i(0x26, base, g.dst(), offset) },
+    "sb" => |g| { let (base, offset) = g.address(1); i(0x28, base, g.src(), offset) },
+    "sh" => |g| { let (base, offset) = g.address(2); i(0x29, base, g.src(), offset) },
+    "swl" => |g| { let (base, offset) = g.address(1); i(0x2a, base, g.src(), offset) },
+    "sw" => |g| { let (base, offset) = g.address(4); i(0x2b, base, g.src(), offset) },
+    "swr" => |g| { let (base, offset) = g.address(1); i(0x2e, base, g.src(), offset) },
+};
+
+/// A random program and the state it starts from
+#[derive(Clone)]
+struct Case {
+    program: Vec<u32>,
+    registers: [u32; 32],
+    data: Vec<u32>,
+}
+
+impl Case {
+    fn generate(rng: &mut Rng, ops: &[&Op], length: u32, misaligned: bool) -> Self {
+        let mut registers = [0; 32];
+        for reg in registers.iter_mut().skip(1) {
+            *reg = rng.next();
+        }
+        for (k, reg) in BASE_REGS.iter().enumerate() {
+            registers[*reg as usize] = DATA_BASE + 0x200 + k as u32 * 0x400;
+        }
+        let data = (0..DATA_WORDS).map(|_| rng.next()).collect();
+        let program = (0..length)
+            .map(|index| {
+                let op = ops[rng.below(ops.len() as u32) as usize];
+                let mut gen = Gen {
+                    rng,
+                    index,
+                    length,
+                    misaligned,
+                };
+                (op.generate)(&mut gen)
+            })
+            .collect();
+        Case {
+            program,
+            registers,
+            data,
+        }
+    }
+
+    fn contains(&self, addr: u32) -> bool {
+        (PROGRAM_BASE..PROGRAM_BASE + self.program.len() as u32 * 4).contains(&addr)
+    }
+}
+
+struct Divergence {
+    step: u64,
+    pc: u32,
+    // What didn't match, one line each
+    differences: Vec<String>,
+}
+
+enum Outcome {
+    Match,
+    Diverged(Divergence),
+    // psemu doesn't know this instruction yet
+    Unsupported,
+}
+
+fn run_case(case: &Case, max_steps: u64, engine: Engine) -> Outcome {
+    let words = |words: &[u32]| -> Vec<u8> { words.iter().flat_map(|w| w.to_le_bytes()).collect() };
+    let mut cpu = Cpu::builder()
+        .ram(PROGRAM_BASE, &words(&case.program))
+        .ram(DATA_BASE, &words(&case.data))
+        .entry(PROGRAM_BASE)
+        .build()
+        .unwrap();
+    cpu.instruction_history.set_capacity(0);
+    let mut reference = Reference::new();
+    for (i, word) in case.program.iter().enumerate() {
+        reference.store32(PROGRAM_BASE + i as u32 * 4, *word);
+    }
+    for (i, word) in case.data.iter().enumerate() {
+        reference.store32(DATA_BASE + i as u32 * 4, *word);
+    }
+    for (i, val) in case.registers.iter().enumerate() {
+        cpu.set_register(RegisterIndex(i as u32), *val);
+        reference.set_register(i, *val);
+    }
+    reference.jump_to(PROGRAM_BASE);
+    let mut backend = engine.backend();
+
+    let mut step = 0;
+    while step < max_steps {
+        // Stop once execution leaves the program. psemu fetches one
+        // instruction ahead, so that one has to be fetchable too.
+        if !case.contains(reference.pc) || reference.load32(reference.next_pc).is_none() {
+            break;
+        }
+        let pc = reference.pc;
+        let res = panic::catch_unwind(AssertUnwindSafe(|| backend.step(&mut cpu)));
+        // Catch the reference up with however many psemu ran
+        let ran = match res {
+            Ok(Ok(ran)) => ran,
+            _ => 1,
+        };
+        let (mut stores, mut exception) = (vec![], None);
+        for _ in 0..ran {
+            let expected = reference.step();
+            stores.extend(expected.stores);
+            exception = exception.or(expected.exception);
+        }
+
+        let mut differences = vec![];
+        match res {
+            Ok(Err(
+                PsemuCoreError::UnknownInstruction(_)
+                | PsemuCoreError::UnknownSecondaryOpInstruction(_),
+            )) => return Outcome::Unsupported,
+            Ok(Err(e)) => differences.push(format!("psemu stopped: {e}")),
+            Ok(Ok(_)) => (),
+            Err(payload) => {
+                differences.push(format!("psemu panicked: {}", panic_message(&*payload)))
+            }
+        }
+        if differences.is_empty() {
+            compare(&cpu, &reference, &stores, &mut differences);
+            if let (Some(exception), false) = (exception, differences.is_empty()) {
+                differences.push(format!("reference raised a {exception} exception"));
+            }
+        }
+        if !differences.is_empty() {
+            return Outcome::Diverged(Divergence {
+                step,
+                pc,
+                differences,
+            });
+        }
+        step += ran;
+    }
+    Outcome::Match
+}
+
+// HI and LO can't be compared directly since psemu-core doesn't have them
+// yet; MFHI and MFLO bring them into the registers
+fn compare(cpu: &Cpu, reference: &Reference, stores: &[u32], differences: &mut Vec<String>) {
+    let pc = cpu.next_instruction_pc();
+    if pc != reference.pc {
+        differences.push(format!(
+            "pc: psemu {pc:08x}, reference {:08x}",
+            reference.pc
+        ));
+    } else if cpu.pc != reference.next_pc {
+        differences.push(format!(
+            "next pc: psemu {:08x}, reference {:08x}",
+            cpu.pc, reference.next_pc
+        ));
+    }
+    for (i, (val, expected)) in cpu.get_registers().iter().zip(reference.regs).enumerate() {
+        if *val != expected {
+            differences.push(format!(
+                "{}: psemu {val:08x}, reference {expected:08x}",
+                REGISTER_NAMES[i]
+            ));
+        }
+    }
+    // Memory either side wrote to
+    let written: BTreeSet<u32> = cpu
+        .last_memory_accesses()
+        .iter()
+        .map(|access| access.addr & !3)
+        .chain(stores.iter().copied())
+        .collect();
+    for addr in written {
+        let (val, expected) = (cpu.load32(addr).ok(), reference.load32(addr));
+        if val != expected {
+            let show =
+                |val: Option<u32>| val.map_or("unmapped".to_string(), |v| format!("{v:08x}"));
+            differences.push(format!(
+                "[{addr:08x}]: psemu {}, reference {}",
+                show(val),
+                show(expected)
+            ));
+        }
+    }
+}
+
+// Replaces instructions with NOPs for as long as `run` still diverges, which
+// leaves the addresses and branch targets of the rest alone
+fn minimize(
+    case: &Case,
+    divergence: Divergence,
+    mut run: impl FnMut(&Case) -> Outcome,
+) -> (Case, Divergence) {
+    let mut case = case.clone();
+    let mut divergence = divergence;
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in 0..case.program.len() {
+            if case.program[i] == 0 {
+                continue;
+            }
+            let mut candidate = case.clone();
+            candidate.program[i] = 0;
+            if let Outcome::Diverged(d) = run(&candidate) {
+                (case, divergence) = (candidate, d);
+                changed = true;
+            }
+        }
+    }
+    (case, divergence)
+}
+
+fn report(seed: u64, case: &Case, divergence: &Divergence) {
+    let instructions = case.program.iter().filter(|w| **w != 0).count();
+    println!(
+        "Seed {seed} diverged at step {}, minimized to {instructions} instruction(s):\n",
+        divergence.step
+    );
+    // Only the registers the remaining instructions use
+    let mut used = BTreeSet::new();
+    for word in case.program.iter().filter(|w| **w != 0) {
+        used.extend([(word >> 21) & 0x1f, (word >> 16) & 0x1f]);
+    }
+    for reg in used.into_iter().filter(|reg| *reg != 0) {
+        println!(
+            "  {:<5} = {:08x}",
+            REGISTER_NAMES[reg as usize], case.registers[reg as usize]
+        );
+    }
+    println!();
+    for (i, word) in case.program.iter().enumerate() {
+        let addr = PROGRAM_BASE + i as u32 * 4;
+        if *word == 0 && addr != divergence.pc {
+            continue;
+        }
+        let marker = if addr == divergence.pc { '>' } else { ' ' };
+        println!(
+            "{marker} {addr:08x}  {word:08x}  {}",
+            disassemble(*word, addr)
+        );
+    }
+    println!();
+    for difference in &divergence.differences {
+        println!("  {difference}");
+    }
+    println!("\nRerun with --seed {seed} --runs 1");
+}
+
+pub fn run(args: &LockstepArgs) -> Result<(), String> {
+    let mut ops: Vec<&Op> = OPS.iter().collect();
+    if !args.ops.is_empty() {
+        for name in &args.ops {
+            if !OPS.iter().any(|op| op.mnemonic == name) {
+                return Err(format!("Unknown instruction `{name}`"));
+            }
+        }
+        ops.retain(|op| args.ops.iter().any(|name| name == op.mnemonic));
+    }
+    if args.length == 0 {
+        return Err("Programs need at least one instruction".to_string());
+    }
+    let seed = args.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64)
+    });
+    // A panic in psemu is reported as a divergence; don't also print it for
+    // every program tried while minimizing. psemu's own error logs would
+    // only repeat what the report says.
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let res = tracing::subscriber::with_default(NoSubscriber::default(), || {
+        let unsupported = drop_unsupported(&mut ops);
+        // Reported below; there's nothing left to generate programs from
+        if ops.is_empty() {
+            return (unsupported, None);
+        }
+        let failure = (0..args.runs).find_map(|i| {
+            let case_seed = seed.wrapping_add(i);
+            let case = Case::generate(&mut Rng::new(case_seed), &ops, args.length, args.misaligned);
+            match run_case(&case, args.max_steps, args.engine) {
+                Outcome::Diverged(divergence) => Some((case_seed, case, divergence)),
+                _ => None,
+            }
+        });
+        let failure = failure.map(|(case_seed, case, divergence)| {
+            let (case, divergence) = minimize(&case, divergence, |case| {
+                run_case(case, args.max_steps, args.engine)
+            });
+            (case_seed, case, divergence)
+        });
+        (unsupported, failure)
+    });
+    panic::set_hook(hook);
+    let (unsupported, failure) = res;
+
+    if !unsupported.is_empty() {
+        info!(
+            "Not implemented by psemu, skipped: {}",
+            unsupported.join(", ")
+        );
+    }
+    if let Some((case_seed, case, divergence)) = failure {
+        report(case_seed, &case, &divergence);
+        return Err("psemu diverged from the reference".to_string());
+    }
+    if ops.is_empty() {
+        return Err("psemu implements none of the instructions to test".to_string());
+    }
+    println!(
+        "{} programs matched the reference (seeds {seed}..={})",
+        args.runs,
+        seed.wrapping_add(args.runs.saturating_sub(1))
+    );
+    Ok(())
+}
+
+// Tries one of each instruction so that the ones psemu doesn't have yet are
+// left out up front, rather than whenever a program happens to use them.
+// Which ones those are changes as psemu grows, and with them the program a
+// seed generates, so seeds are only reproducible with the same build.
+fn drop_unsupported(ops: &mut Vec<&Op>) -> Vec<&'static str> {
+    let mut unsupported = vec![];
+    ops.retain(|op| {
+        let case = Case::generate(&mut Rng::new(0), &[op], 1, false);
+        match run_case(&case, 1, Engine::Interpreter) {
+            Outcome::Unsupported => {
+                unsupported.push(op.mnemonic);
+                false
+            }
+            _ => true,
+        }
+    });
+    unsupported
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(words: &[u32]) -> Case {
+        Case {
+            program: words.to_vec(),
+            registers: [0; 32],
+            data: vec![0; DATA_WORDS as usize],
+        }
+    }
+
+    #[test]
+    fn implemented_instructions_match_the_reference() {
+        let case = words(&[
+            0x3c088010, // lui $t0, 0x8010
+            0x35090004, // ori $t1, $t0, 4
+            0xad090008, // sw $t1, 8($t0)
+            0x00095100, // sll $t2, $t1, 4
+        ]);
+        assert!(matches!(
+            run_case(&case, 100, Engine::Interpreter),
+            Outcome::Match
+        ));
+    }
+
+    #[test]
+    fn minimizing_keeps_only_what_diverges() {
+        // Stands in for a psemu bug that needs both of these to show up
+        const SET: u32 = 0x24080001; // addiu $t0, $zero, 1
+        const USE: u32 = 0x01084821; // addu $t1, $t0, $t0
+        let run = |case: &Case| {
+            let Some(i) = case.program.iter().position(|w| *w == USE) else {
+                return Outcome::Match;
+            };
+            if !case.program.contains(&SET) {
+                return Outcome::Match;
+            }
+            Outcome::Diverged(Divergence {
+                step: i as u64,
+                pc: PROGRAM_BASE + i as u32 * 4,
+                differences: vec!["injected".to_string()],
+            })
+        };
+        // addiu $zero, $zero, <n> everywhere else
+        let mut case = words(&(1..=16).map(|n| 0x24000000 | n).collect::<Vec<_>>());
+        case.program[3] = SET;
+        case.program[9] = USE;
+        let Outcome::Diverged(divergence) = run(&case) else {
+            panic!("the injected divergence didn't show up");
+        };
+
+        let (minimized, divergence) = minimize(&case, divergence, run);
+        let mut expected = vec![0; 16];
+        expected[3] = SET;
+        expected[9] = USE;
+        assert_eq!(minimized.program, expected);
+        assert_eq!(divergence.pc, PROGRAM_BASE + 36);
+    }
+}
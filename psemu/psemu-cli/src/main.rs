@@ -1,30 +1,169 @@
 use std::{
+    any::Any,
     io::Write,
+    path::PathBuf,
     sync::{
+        atomic::{AtomicBool, Ordering},
         mpsc::{self, Sender},
         Arc, Mutex,
     },
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use tracing::{error, info, warn};
 
-use psemu_core::Cpu;
+use psemu_core::{
+    kernel::KernelCall, CachedInterpreter, Cpu, ExecutionBackend, FrameLimiter, Interpreter, Movie,
+    MoviePlayer, Profiler, SymbolTable, VideoStandard, VideoTiming, Watchpoint,
+    DEFAULT_HISTORY_CAPACITY, SPEED_RANGE,
+};
 use psemudb::Debugger;
 
+mod bench;
+mod disasm;
+mod lockstep;
+mod profile;
+mod reference;
+mod test_rom;
+mod trace;
+mod trace_diff;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Start in TUI debug mode
     #[arg(long)]
     debug_mode: bool,
     /// Step through instructions automatically
     #[arg(long, default_value_t = false)]
     auto: bool,
+    /// Load the machine state from this save slot before starting
+    #[arg(long, value_name = "SLOT")]
+    load_state: Option<u8>,
+    /// Save the machine state to this slot when the headless run stops
+    /// (Ctrl-C, CPU error or end of the GDB session)
+    #[arg(long, value_name = "SLOT")]
+    save_state: Option<u8>,
+    /// Let GDB drive the CPU through a remote stub listening on ADDR
+    /// (`host:port` or `unix:/path/to/socket`)
+    #[arg(long, value_name = "ADDR", conflicts_with = "debug_mode")]
+    gdb: Option<String>,
+    /// Log every write to a memory range, given as `<addr>[,<len>][:w]`.
+    /// Can be repeated.
+    #[arg(long, value_name = "SPEC", conflicts_with = "gdb")]
+    watch: Vec<Watchpoint>,
+    /// Name addresses in the debugger and in kernel call traces using an ELF,
+    /// PsyQ .SYM or .map file. Can be repeated.
+    #[arg(long, value_name = "FILE")]
+    symbols: Vec<PathBuf>,
+    /// How many executed instructions to remember, 0 to not record any
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_HISTORY_CAPACITY)]
+    history: usize,
+    /// Write the remembered instructions to FILE when the headless run stops.
+    /// In the debugger, press `h` instead.
+    #[arg(long, value_name = "FILE", conflicts_with = "debug_mode")]
+    export_history: Option<PathBuf>,
+    #[command(flatten)]
+    trace: trace::TraceArgs,
+    #[command(flatten)]
+    profile: profile::ProfileArgs,
+    /// How the headless run executes instructions
+    #[arg(long, value_enum, default_value_t = Engine::Interpreter, conflicts_with = "debug_mode")]
+    engine: Engine,
+    /// Video timing of the emulated console
+    #[arg(long, value_enum, default_value_t = Video::Ntsc, conflicts_with = "gdb")]
+    video: Video,
+    /// Run the headless loop at the console's real speed instead of as
+    /// fast as possible
+    #[arg(long, conflicts_with_all = ["debug_mode", "gdb"])]
+    realtime: bool,
+    /// Record a hash of the machine at every frame of the headless run to
+    /// FILE, starting from power-on or --load-state. No pad input is
+    /// recorded, since nothing emulates the controller yet.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["debug_mode", "gdb", "play_movie"])]
+    record_movie: Option<PathBuf>,
+    /// Play back a movie made with --record-movie. Stops at its last frame,
+    /// or fails at the first one that doesn't end the way it was recorded.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["debug_mode", "gdb", "load_state", "video"])]
+    play_movie: Option<PathBuf>,
+    /// Fast-forward by this much with --realtime, e.g. 2 for double speed
+    #[arg(long, value_name = "MULTIPLIER", default_value_t = 1.0, requires = "realtime", value_parser = parse_speed)]
+    speed: f64,
     //    /// Number of times to greet
     //    #[arg(short, long, default_value_t = 1)]
     //    count: u8,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Engine {
+    /// Fetch and decode every instruction as it runs
+    Interpreter,
+    /// Decode basic blocks once and run them from a cache. Tracing and
+    /// profiling still go one instruction at a time.
+    Cached,
+    /// Translate basic blocks to native x86-64 code. Tracing, profiling and
+    /// watchpoints go through the interpreter.
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    Recompiler,
+}
+
+impl Engine {
+    fn backend(self) -> Box<dyn ExecutionBackend> {
+        match self {
+            Engine::Interpreter => Box::new(Interpreter),
+            Engine::Cached => Box::new(CachedInterpreter),
+            #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+            Engine::Recompiler => Box::new(psemu_core::Recompiler),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Video {
+    /// 263 scanlines at 59.82Hz, 59.94Hz interlaced
+    Ntsc,
+    /// 314 scanlines at 49.76Hz, 49.83Hz interlaced
+    Pal,
+}
+
+impl Video {
+    fn standard(self) -> VideoStandard {
+        match self {
+            Video::Ntsc => VideoStandard::Ntsc,
+            Video::Pal => VideoStandard::Pal,
+        }
+    }
+}
+
+fn parse_speed(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(speed) if SPEED_RANGE.contains(&speed) => Ok(speed),
+        _ => Err(format!(
+            "Expected a multiplier from {} to {}, found `{s}`",
+            SPEED_RANGE.start(),
+            SPEED_RANGE.end()
+        )),
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Disassemble a BIOS image or PS-X EXE
+    Disasm(disasm::DisasmArgs),
+    /// Compare two execution traces and report where they first diverge
+    TraceDiff(trace_diff::TraceDiffArgs),
+    /// Run random programs on the CPU and on a simple reference model side
+    /// by side, and report the smallest one where they disagree
+    Lockstep(lockstep::LockstepArgs),
+    /// Run a BIOS boot or an EXE as fast as possible and report how fast
+    /// each part of the emulator went
+    Bench(bench::BenchArgs),
+    /// Run test programs headless and report which pass
+    Test(test_rom::TestArgs),
+}
+
 struct ChannelLogger {
     tx: &'static mut Sender<String>,
 }
@@ -65,14 +204,216 @@ impl Write for ChannelLogger {
     }
 }
 
+fn load_symbols(paths: &[PathBuf]) -> SymbolTable {
+    let mut symbols = SymbolTable::default();
+    for path in paths {
+        match symbols.load(path) {
+            Ok(count) => info!("Loaded {count} symbols from {}", path.display()),
+            Err(e) => {
+                error!("{e}");
+                std::process::exit(1);
+            }
+        }
+    }
+    symbols
+}
+
+/// The message a panic was raised with, when it has one
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
+    if let Some(command) = &args.command {
+        tracing_subscriber::fmt::init();
+        let res = match command {
+            Command::Disasm(args) => disasm::run(args),
+            Command::TraceDiff(args) => trace_diff::run(args),
+            Command::Lockstep(args) => lockstep::run(args),
+            Command::Test(args) => test_rom::run(args),
+            Command::Bench(args) => bench::run(args),
+        };
+        if let Err(e) = res {
+            error!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
     if !args.debug_mode {
         tracing_subscriber::fmt::init();
+        let symbols = load_symbols(&args.symbols);
         let mut cpu = Cpu::new();
-        loop {
-            cpu.run_single_cycle();
+        // Blocks only help when there's no history to record
+        let history = match (args.engine, &args.export_history) {
+            (Engine::Interpreter, _) | (_, Some(_)) => args.history,
+            _ => 0,
+        };
+        cpu.instruction_history.set_capacity(history);
+        if let Some(slot) = args.load_state {
+            if let Err(e) = cpu.load_state_from_slot(slot) {
+                error!(slot, "Unable to load state: {e}");
+                std::process::exit(1);
+            }
+        }
+
+        let mut desynced = false;
+        if let Some(addr) = &args.gdb {
+            if let Err(e) = psemu_gdb::serve(&mut cpu, addr) {
+                error!("GDB stub failed: {e}");
+            }
+        } else {
+            let stop = Arc::new(AtomicBool::new(false));
+            let stop_clone = stop.clone();
+            tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    stop_clone.store(true, Ordering::Relaxed);
+                }
+            });
+
+            let mut tracer = match trace::Tracer::new(&args.trace) {
+                Ok(tracer) => tracer,
+                Err(e) => {
+                    error!("{e}");
+                    std::process::exit(1);
+                }
+            };
+            for watchpoint in args.watch {
+                cpu.add_watchpoint(watchpoint);
+            }
+            let mut profiler = args.profile.enabled().then(|| Profiler::new(&cpu));
+            let mut player = args.play_movie.as_ref().map(|path| {
+                let movie = Movie::load(path).and_then(|movie| {
+                    movie.load_start(&mut cpu)?;
+                    Ok(movie)
+                });
+                match movie {
+                    Ok(movie) => MoviePlayer::new(movie),
+                    Err(e) => {
+                        error!(path = %path.display(), "Unable to play movie: {e}");
+                        std::process::exit(1);
+                    }
+                }
+            });
+            let standard = match &player {
+                Some(player) => player.movie().standard,
+                None => args.video.standard(),
+            };
+            let mut recording = args.record_movie.as_ref().map(|_| {
+                let start = args.load_state.map(|_| cpu.save_state());
+                Movie::new(standard, start)
+            });
+            // Tracing and profiling need every instruction, and movie frames
+            // have to end on the same one whatever the engine
+            let single_step =
+                tracer.is_some() || profiler.is_some() || recording.is_some() || player.is_some();
+            let mut backend = args.engine.backend();
+            let mut video = VideoTiming::new(standard);
+            let mut limiter = args
+                .realtime
+                .then(|| FrameLimiter::new(video.refresh_rate(), args.speed));
+            while !stop.load(Ordering::Relaxed) {
+                if let Some(player) = &player {
+                    if player.is_finished() {
+                        info!("Played back {} frames without desyncing", player.frame());
+                        break;
+                    }
+                }
+                if let Some(tracer) = &mut tracer {
+                    tracer.before(&cpu);
+                }
+                if let Some(profiler) = &mut profiler {
+                    profiler.before(&cpu);
+                }
+                let res = match single_step {
+                    true => Interpreter.step(&mut cpu),
+                    false => backend.step(&mut cpu),
+                };
+                if let Some(profiler) = &mut profiler {
+                    profiler.after(&cpu);
+                }
+                if let Some(tracer) = &mut tracer {
+                    if let Err(e) = tracer.after(&cpu) {
+                        error!("Unable to write trace: {e}");
+                        break;
+                    }
+                }
+                let ran = match res {
+                    Ok(ran) => ran,
+                    Err(e) => {
+                        error!("CPU stopped: {e}");
+                        break;
+                    }
+                };
+                let vblanks = video.advance(ran);
+                if vblanks > 0 {
+                    if let Some(movie) = &mut recording {
+                        movie.record_frame(&cpu);
+                    }
+                    if let Some(player) = &mut player {
+                        if let Err(e) = player.end_frame(&cpu) {
+                            error!("{e}");
+                            desynced = true;
+                            break;
+                        }
+                    }
+                }
+                if let Some(limiter) = &mut limiter {
+                    for _ in 0..vblanks {
+                        limiter.wait();
+                    }
+                }
+                if let Some(hit) = cpu.take_watchpoint_hit() {
+                    warn!("Watchpoint {hit}");
+                }
+                if let Some(call) = KernelCall::at(&cpu) {
+                    info!(
+                        "Kernel call {call} from {}",
+                        symbols.symbolize(call.return_addr.wrapping_sub(8))
+                    );
+                }
+            }
+            if let (Some(path), Some(movie)) = (&args.record_movie, &recording) {
+                match movie.save(path) {
+                    Ok(()) => {
+                        info!(path = %path.display(), "Recorded {} frames", movie.frames.len())
+                    }
+                    Err(e) => error!(path = %path.display(), "Unable to save movie: {e}"),
+                }
+            }
+            if let Some(Err(e)) = tracer.map(|tracer| tracer.finish()) {
+                error!("Unable to write trace: {e}");
+            }
+            if let Some(profiler) = &profiler {
+                if let Err(e) = args.profile.write(profiler, &symbols) {
+                    error!("{e}");
+                }
+            }
+        }
+
+        if let Some(path) = &args.export_history {
+            let res = std::fs::File::create(path).and_then(|file| {
+                let mut w = std::io::BufWriter::new(file);
+                cpu.instruction_history.export(&mut w)?;
+                w.flush()
+            });
+            if let Err(e) = res {
+                error!(path = %path.display(), "Unable to export history: {e}");
+            }
+        }
+        if let Some(slot) = args.save_state {
+            if let Err(e) = cpu.save_state_to_slot(slot) {
+                error!(slot, "Unable to save state: {e}");
+                std::process::exit(1);
+            }
+        }
+        if desynced {
+            std::process::exit(1);
         }
     } else {
         let logs = Arc::new(Mutex::new(vec![]));
@@ -83,7 +424,15 @@ async fn main() {
             .finish();
         let _default = tracing::subscriber::set_default(subscriber);
 
-        let mut debugger = Debugger::new(logs, args.auto);
+        let mut debugger = Debugger::new(logs, args.auto, args.video.standard());
+        debugger.set_symbols(load_symbols(&args.symbols));
+        debugger.set_history_capacity(args.history);
+        if let Some(slot) = args.load_state {
+            debugger.load_state_slot(slot);
+        }
+        for watchpoint in args.watch {
+            debugger.add_watchpoint(watchpoint);
+        }
         debugger.run();
     }
 }
@@ -0,0 +1,49 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::Args;
+use psemu_core::{Profiler, SymbolTable};
+
+#[derive(Args, Debug)]
+pub struct ProfileArgs {
+    /// Count where the guest spends its time and write a flat profile, its
+    /// hot spots and a call graph to FILE when the headless run stops
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["debug_mode", "gdb"])]
+    profile: Option<PathBuf>,
+    /// Write the profiled call stacks to FILE in the folded format that
+    /// flamegraph.pl, inferno and speedscope read
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["debug_mode", "gdb"])]
+    profile_folded: Option<PathBuf>,
+}
+
+impl ProfileArgs {
+    pub fn enabled(&self) -> bool {
+        self.profile.is_some() || self.profile_folded.is_some()
+    }
+
+    pub fn write(&self, profiler: &Profiler, symbols: &SymbolTable) -> Result<(), String> {
+        if let Some(path) = &self.profile {
+            write_to(path, |w| profiler.write_report(symbols, w))?;
+        }
+        if let Some(path) = &self.profile_folded {
+            write_to(path, |w| profiler.write_folded(symbols, w))?;
+        }
+        Ok(())
+    }
+}
+
+fn write_to(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> std::io::Result<()>,
+) -> Result<(), String> {
+    File::create(path)
+        .and_then(|file| {
+            let mut w = BufWriter::new(file);
+            write(&mut w)?;
+            w.flush()
+        })
+        .map_err(|e| format!("Unable to write profile {}: {e}", path.display()))
+}
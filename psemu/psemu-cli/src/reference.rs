@@ -0,0 +1,599 @@
+use std::fmt;
+
+// A deliberately plain model of the R3000A, written from the MIPS I manual
+// rather than from psemu-core so that the two don't share mistakes. Only RAM
+// is modelled; there's no BIOS, I/O, cache, GTE or interrupts.
+
+const RAM_SIZE: usize = 2 * 1024 * 1024;
+// Status register bits
+const SR_ISOLATE_CACHE: u32 = 1 << 16;
+const SR_BEV: u32 = 1 << 22;
+const SR_CU2: u32 = 1 << 30;
+// COP0 register numbers
+const BADVADDR: u32 = 8;
+const SR: u32 = 12;
+const CAUSE: u32 = 13;
+const EPC: u32 = 14;
+
+/// Exceptions the reference can raise, numbered like Cause.ExcCode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exception {
+    AddressErrorLoad = 4,
+    AddressErrorStore = 5,
+    BusErrorData = 7,
+    Syscall = 8,
+    Break = 9,
+    ReservedInstruction = 10,
+    CoprocessorUnusable = 11,
+    Overflow = 12,
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Exception::AddressErrorLoad => "address error (load)",
+            Exception::AddressErrorStore => "address error (store)",
+            Exception::BusErrorData => "bus error (data)",
+            Exception::Syscall => "syscall",
+            Exception::Break => "break",
+            Exception::ReservedInstruction => "reserved instruction",
+            Exception::CoprocessorUnusable => "coprocessor unusable",
+            Exception::Overflow => "overflow",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// What one instruction did besides changing registers
+#[derive(Default)]
+pub struct Step {
+    pub exception: Option<Exception>,
+    /// Word-aligned addresses of the stores it made
+    pub stores: Vec<u32>,
+}
+
+pub struct Reference {
+    pub regs: [u32; 32],
+    pub hi: u32,
+    pub lo: u32,
+    /// The instruction that runs next
+    pub pc: u32,
+    /// The one after it; differs from `pc + 4` in a branch-delay slot
+    pub next_pc: u32,
+    pub sr: u32,
+    pub cause: u32,
+    pub epc: u32,
+    pub badvaddr: u32,
+    ram: Vec<u8>,
+    // Register writes made by the current instruction, committed once it's
+    // done so that it reads the values from before any load delay resolves
+    out_regs: [u32; 32],
+    // A load whose value doesn't reach the register until after the next
+    // instruction
+    load: Option<(usize, u32)>,
+    // Whether the current instruction is a branch, i.e. the next one sits in
+    // a delay slot
+    branch: bool,
+    delay_slot: bool,
+    current_pc: u32,
+}
+
+impl Default for Reference {
+    fn default() -> Self {
+        Reference::new()
+    }
+}
+
+impl Reference {
+    /// Registers zeroed and the CPU about to run the reset vector, with BEV set
+    pub fn new() -> Self {
+        Reference {
+            regs: [0; 32],
+            hi: 0,
+            lo: 0,
+            pc: 0xbfc00000,
+            next_pc: 0xbfc00004,
+            sr: SR_BEV,
+            cause: 0,
+            epc: 0,
+            badvaddr: 0,
+            ram: vec![0; RAM_SIZE],
+            out_regs: [0; 32],
+            load: None,
+            branch: false,
+            delay_slot: false,
+            current_pc: 0,
+        }
+    }
+
+    pub fn jump_to(&mut self, addr: u32) {
+        self.pc = addr;
+        self.next_pc = addr.wrapping_add(4);
+        self.branch = false;
+        self.delay_slot = false;
+        self.load = None;
+    }
+
+    pub fn set_register(&mut self, index: usize, val: u32) {
+        if index != 0 {
+            self.regs[index] = val;
+        }
+    }
+
+    /// None outside of RAM or when misaligned
+    pub fn load32(&self, addr: u32) -> Option<u32> {
+        let offset = ram_offset(addr).filter(|_| addr.is_multiple_of(4))?;
+        Some(u32::from_le_bytes(
+            self.ram[offset..offset + 4].try_into().unwrap(),
+        ))
+    }
+
+    /// Setup write, no side effects. False outside of RAM or when misaligned.
+    pub fn store32(&mut self, addr: u32, val: u32) -> bool {
+        let Some(offset) = ram_offset(addr).filter(|_| addr.is_multiple_of(4)) else {
+            return false;
+        };
+        self.ram[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+        true
+    }
+
+    /// Runs the instruction at `pc`. The caller makes sure it's in RAM.
+    pub fn step(&mut self) -> Step {
+        let mut step = Step::default();
+        self.current_pc = self.pc;
+        self.delay_slot = self.branch;
+        self.branch = false;
+        self.out_regs = self.regs;
+        if let Some((reg, val)) = self.load.take() {
+            self.out_regs[reg] = val;
+        }
+        if !self.pc.is_multiple_of(4) {
+            self.badvaddr = self.pc;
+            self.exception(&mut step, Exception::AddressErrorLoad);
+        } else {
+            let word = self.load32(self.pc).unwrap_or(0);
+            self.pc = self.next_pc;
+            self.next_pc = self.next_pc.wrapping_add(4);
+            if let Err(e) = self.execute(word, &mut step) {
+                self.exception(&mut step, e);
+            }
+        }
+        self.out_regs[0] = 0;
+        self.regs = self.out_regs;
+        step
+    }
+
+    fn exception(&mut self, step: &mut Step, exception: Exception) {
+        // EPC points at the branch when the delay slot faults, so the branch
+        // runs again on return
+        let (epc, bd) = if self.delay_slot {
+            (self.current_pc.wrapping_sub(4), 1 << 31)
+        } else {
+            (self.current_pc, 0)
+        };
+        self.epc = epc;
+        self.cause = (self.cause & !0x8000_007c) | bd | ((exception as u32) << 2);
+        // Push a zero onto the KU/IE stack: kernel mode, interrupts off
+        self.sr = (self.sr & !0x3f) | ((self.sr << 2) & 0x3f);
+        let vector = if self.sr & SR_BEV != 0 {
+            0xbfc00180
+        } else {
+            0x80000080
+        };
+        self.pc = vector;
+        self.next_pc = vector + 4;
+        self.branch = false;
+        step.exception = Some(exception);
+    }
+
+    fn reg(&self, index: u32) -> u32 {
+        self.regs[index as usize]
+    }
+
+    fn set_reg(&mut self, index: u32, val: u32) {
+        self.out_regs[index as usize] = val;
+    }
+
+    fn delayed_load(&mut self, index: u32, val: u32) {
+        if index != 0 {
+            self.load = Some((index as usize, val));
+        }
+    }
+
+    fn jump(&mut self, target: u32) {
+        self.next_pc = target;
+        self.branch = true;
+    }
+
+    fn branch_if(&mut self, taken: bool, offset: u32) {
+        // `pc` already points at the delay slot, which branches are relative to
+        if taken {
+            self.jump(self.pc.wrapping_add(offset << 2));
+        }
+        self.branch = true;
+    }
+
+    fn execute(&mut self, word: u32, step: &mut Step) -> Result<(), Exception> {
+        let rs = (word >> 21) & 0x1f;
+        let rt = (word >> 16) & 0x1f;
+        let rd = (word >> 11) & 0x1f;
+        let sa = (word >> 6) & 0x1f;
+        let imm = word & 0xffff;
+        let simm = word as i16 as u32;
+        let (s, t) = (self.reg(rs), self.reg(rt));
+
+        match word >> 26 {
+            0x00 => match word & 0x3f {
+                0x00 => self.set_reg(rd, t << sa),
+                0x02 => self.set_reg(rd, t >> sa),
+                0x03 => self.set_reg(rd, ((t as i32) >> sa) as u32),
+                0x04 => self.set_reg(rd, t << (s & 0x1f)),
+                0x06 => self.set_reg(rd, t >> (s & 0x1f)),
+                0x07 => self.set_reg(rd, ((t as i32) >> (s & 0x1f)) as u32),
+                0x08 => self.jump(s),
+                0x09 => {
+                    self.set_reg(rd, self.current_pc.wrapping_add(8));
+                    self.jump(s);
+                }
+                0x0c => return Err(Exception::Syscall),
+                0x0d => return Err(Exception::Break),
+                0x10 => self.set_reg(rd, self.hi),
+                0x11 => self.hi = s,
+                0x12 => self.set_reg(rd, self.lo),
+                0x13 => self.lo = s,
+                0x18 => {
+                    let product = (s as i32 as i64) * (t as i32 as i64);
+                    (self.hi, self.lo) = ((product >> 32) as u32, product as u32);
+                }
+                0x19 => {
+                    let product = (s as u64) * (t as u64);
+                    (self.hi, self.lo) = ((product >> 32) as u32, product as u32);
+                }
+                0x1a => {
+                    let (n, d) = (s as i32, t as i32);
+                    (self.hi, self.lo) = match (n, d) {
+                        // The hardware doesn't trap, it produces these
+                        (n, 0) if n >= 0 => (n as u32, 0xffffffff),
+                        (n, 0) => (n as u32, 1),
+                        (i32::MIN, -1) => (0, 0x80000000),
+                        (n, d) => ((n % d) as u32, (n / d) as u32),
+                    };
+                }
+                0x1b => {
+                    (self.hi, self.lo) = match t {
+                        0 => (s, 0xffffffff),
+                        t => (s % t, s / t),
+                    };
+                }
+                0x20 => {
+                    let sum = (s as i32)
+                        .checked_add(t as i32)
+                        .ok_or(Exception::Overflow)?;
+                    self.set_reg(rd, sum as u32);
+                }
+                0x21 => self.set_reg(rd, s.wrapping_add(t)),
+                0x22 => {
+                    let difference = (s as i32)
+                        .checked_sub(t as i32)
+                        .ok_or(Exception::Overflow)?;
+                    self.set_reg(rd, difference as u32);
+                }
+                0x23 => self.set_reg(rd, s.wrapping_sub(t)),
+                0x24 => self.set_reg(rd, s & t),
+                0x25 => self.set_reg(rd, s | t),
+                0x26 => self.set_reg(rd, s ^ t),
+                0x27 => self.set_reg(rd, !(s | t)),
+                0x2a => self.set_reg(rd, ((s as i32) < (t as i32)) as u32),
+                0x2b => self.set_reg(rd, (s < t) as u32),
+                _ => return Err(Exception::ReservedInstruction),
+            },
+            // BcondZ: bit 16 picks >= 0 over < 0, and rt 0x10/0x11 also link.
+            // The other rt values decode the same way on the real chip.
+            0x01 => {
+                let taken = if rt & 1 != 0 {
+                    (s as i32) >= 0
+                } else {
+                    (s as i32) < 0
+                };
+                if rt & 0x1e == 0x10 {
+                    self.set_reg(31, self.current_pc.wrapping_add(8));
+                }
+                self.branch_if(taken, simm);
+            }
+            0x02 => self.jump((self.pc & 0xf0000000) | ((word & 0x03ffffff) << 2)),
+            0x03 => {
+                self.set_reg(31, self.current_pc.wrapping_add(8));
+                self.jump((self.pc & 0xf0000000) | ((word & 0x03ffffff) << 2));
+            }
+            0x04 => self.branch_if(s == t, simm),
+            0x05 => self.branch_if(s != t, simm),
+            0x06 => self.branch_if((s as i32) <= 0, simm),
+            0x07 => self.branch_if((s as i32) > 0, simm),
+            0x08 => {
+                let sum = (s as i32)
+                    .checked_add(simm as i32)
+                    .ok_or(Exception::Overflow)?;
+                self.set_reg(rt, sum as u32);
+            }
+            0x09 => self.set_reg(rt, s.wrapping_add(simm)),
+            0x0a => self.set_reg(rt, ((s as i32) < (simm as i32)) as u32),
+            0x0b => self.set_reg(rt, (s < simm) as u32),
+            0x0c => self.set_reg(rt, s & imm),
+            0x0d => self.set_reg(rt, s | imm),
+            0x0e => self.set_reg(rt, s ^ imm),
+            0x0f => self.set_reg(rt, imm << 16),
+            0x10 => self.cop0(word, rs, rt, rd)?,
+            0x12 if self.sr & SR_CU2 != 0 => {
+                // The GTE isn't modelled
+            }
+            0x11..=0x13 => return Err(Exception::CoprocessorUnusable),
+            0x20..=0x26 => self.load(word >> 26, s.wrapping_add(simm), rt)?,
+            0x28..=0x2b | 0x2e => self.store(word >> 26, s.wrapping_add(simm), t, step)?,
+            // LWCz/SWCz: only the GTE has data registers
+            0x30..=0x33 | 0x38..=0x3b => return Err(Exception::CoprocessorUnusable),
+            _ => return Err(Exception::ReservedInstruction),
+        }
+        Ok(())
+    }
+
+    fn cop0(&mut self, word: u32, rs: u32, rt: u32, rd: u32) -> Result<(), Exception> {
+        match rs {
+            // MFC0, delayed like a load
+            0x00 => {
+                let val = match rd {
+                    BADVADDR => self.badvaddr,
+                    SR => self.sr,
+                    CAUSE => self.cause,
+                    EPC => self.epc,
+                    _ => 0,
+                };
+                self.delayed_load(rt, val);
+            }
+            // MTC0, only SR and the software interrupt bits of Cause are
+            // writable
+            0x04 => {
+                let val = self.reg(rt);
+                match rd {
+                    SR => self.sr = val,
+                    CAUSE => self.cause = (self.cause & !0x300) | (val & 0x300),
+                    _ => (),
+                }
+            }
+            // RFE pops the KU/IE stack
+            0x10 if word & 0x3f == 0x10 => {
+                self.sr = (self.sr & !0xf) | ((self.sr >> 2) & 0xf);
+            }
+            _ => return Err(Exception::ReservedInstruction),
+        }
+        Ok(())
+    }
+
+    fn read(&self, addr: u32, size: u32) -> Result<u32, Exception> {
+        let offset = ram_offset(addr).ok_or(Exception::BusErrorData)?;
+        let bytes = &self.ram[offset..offset + size as usize];
+        Ok(bytes.iter().rev().fold(0, |val, b| (val << 8) | *b as u32))
+    }
+
+    fn write(&mut self, addr: u32, size: u32, val: u32) -> Result<(), Exception> {
+        let offset = ram_offset(addr).ok_or(Exception::BusErrorData)?;
+        // With the cache isolated, stores only reach the cache
+        if self.sr & SR_ISOLATE_CACHE == 0 {
+            let bytes = &val.to_le_bytes()[..size as usize];
+            self.ram[offset..offset + size as usize].copy_from_slice(bytes);
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, op: u32, addr: u32, rt: u32) -> Result<(), Exception> {
+        let size = match op {
+            0x21 | 0x25 => 2,
+            0x23 => 4,
+            _ => 1,
+        };
+        if !addr.is_multiple_of(size) {
+            self.badvaddr = addr;
+            return Err(Exception::AddressErrorLoad);
+        }
+        // LWL and LWR merge with a load still in flight to the same register
+        let current = self.out_regs[rt as usize];
+        let aligned = addr & !3;
+        let val = match op {
+            0x20 => self.read(addr, 1)? as i8 as u32,
+            0x21 => self.read(addr, 2)? as i16 as u32,
+            0x23 => self.read(addr, 4)?,
+            0x24 => self.read(addr, 1)?,
+            0x25 => self.read(addr, 2)?,
+            0x22 => {
+                let word = self.read(aligned, 4)?;
+                match addr & 3 {
+                    0 => (current & 0x00ffffff) | (word << 24),
+                    1 => (current & 0x0000ffff) | (word << 16),
+                    2 => (current & 0x000000ff) | (word << 8),
+                    _ => word,
+                }
+            }
+            _ => {
+                let word = self.read(aligned, 4)?;
+                match addr & 3 {
+                    0 => word,
+                    1 => (current & 0xff000000) | (word >> 8),
+                    2 => (current & 0xffff0000) | (word >> 16),
+                    _ => (current & 0xffffff00) | (word >> 24),
+                }
+            }
+        };
+        self.delayed_load(rt, val);
+        Ok(())
+    }
+
+    fn store(&mut self, op: u32, addr: u32, val: u32, step: &mut Step) -> Result<(), Exception> {
+        let size = match op {
+            0x29 => 2,
+            0x2b => 4,
+            _ => 1,
+        };
+        if !addr.is_multiple_of(size) {
+            self.badvaddr = addr;
+            return Err(Exception::AddressErrorStore);
+        }
+        let aligned = addr & !3;
+        match op {
+            0x28 | 0x29 | 0x2b => self.write(addr, size, val)?,
+            0x2a => {
+                let word = self.read(aligned, 4)?;
+                let merged = match addr & 3 {
+                    0 => (word & 0xffffff00) | (val >> 24),
+                    1 => (word & 0xffff0000) | (val >> 16),
+                    2 => (word & 0xff000000) | (val >> 8),
+                    _ => val,
+                };
+                self.write(aligned, 4, merged)?;
+            }
+            _ => {
+                let word = self.read(aligned, 4)?;
+                let merged = match addr & 3 {
+                    0 => val,
+                    1 => (word & 0x000000ff) | (val << 8),
+                    2 => (word & 0x0000ffff) | (val << 16),
+                    _ => (word & 0x00ffffff) | (val << 24),
+                };
+                self.write(aligned, 4, merged)?;
+            }
+        }
+        step.stores.push(aligned);
+        Ok(())
+    }
+}
+
+// KSEG0 and KSEG1 mirror KUSEG; KSEG2 has no RAM
+fn ram_offset(addr: u32) -> Option<usize> {
+    let physical = match addr >> 29 {
+        4 | 5 => addr & 0x1fffffff,
+        6 | 7 => return None,
+        _ => addr,
+    };
+    let offset = physical as usize;
+    (offset + 4 <= RAM_SIZE).then_some(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: u32 = 0x80010000;
+    const DATA: u32 = 0x80100000;
+    const T0: usize = 8;
+    const T1: usize = 9;
+    const T2: usize = 10;
+    const S0: usize = 16;
+
+    fn reference(program: &[u32], registers: &[(usize, u32)]) -> Reference {
+        let mut reference = Reference::new();
+        for (i, word) in program.iter().enumerate() {
+            reference.store32(PROGRAM + i as u32 * 4, *word);
+        }
+        for &(index, val) in registers {
+            reference.set_register(index, val);
+        }
+        reference.jump_to(PROGRAM);
+        reference
+    }
+
+    #[test]
+    fn loads_land_after_the_next_instruction() {
+        let mut r = reference(
+            &[
+                0x8e080000, // lw $t0, 0($s0)
+                0x01004821, // addu $t1, $t0, $zero
+                0x01005021, // addu $t2, $t0, $zero
+            ],
+            &[(S0, DATA), (T0, 0x11111111)],
+        );
+        r.store32(DATA, 0xdeadbeef);
+        r.step();
+        assert_eq!(r.regs[T0], 0x11111111);
+        r.step();
+        assert_eq!(r.regs[T1], 0x11111111);
+        assert_eq!(r.regs[T0], 0xdeadbeef);
+        r.step();
+        assert_eq!(r.regs[T2], 0xdeadbeef);
+    }
+
+    #[test]
+    fn lwl_merges_with_an_lwr_in_flight() {
+        // The usual unaligned load of the word at DATA + 1
+        let mut r = reference(
+            &[
+                0x9a080000, // lwr $t0, 0($s0)
+                0x8a080003, // lwl $t0, 3($s0)
+                0,
+            ],
+            &[(S0, DATA + 1), (T0, 0xaaaaaaaa)],
+        );
+        r.store32(DATA, 0x44332211);
+        r.store32(DATA + 4, 0x88776655);
+        r.step();
+        r.step();
+        // lwr's 0xaa443322 has landed, lwl's merge with it hasn't yet
+        assert_eq!(r.regs[T0], 0xaa443322);
+        r.step();
+        assert_eq!(r.regs[T0], 0x55443322);
+    }
+
+    #[test]
+    fn division_edge_cases_dont_trap() {
+        // div $t0, $t1 and divu $t0, $t1
+        let divide = |word, n, d| {
+            let mut r = reference(&[word], &[(T0, n), (T1, d)]);
+            let step = r.step();
+            assert_eq!(step.exception, None);
+            (r.hi, r.lo)
+        };
+        let (div, divu) = (0x0109001a, 0x0109001b);
+        assert_eq!(divide(div, 7, 0), (7, 0xffffffff));
+        assert_eq!(divide(div, -7i32 as u32, 0), (-7i32 as u32, 1));
+        assert_eq!(divide(div, 0x80000000, u32::MAX), (0, 0x80000000));
+        assert_eq!(divide(div, -7i32 as u32, 2), (-1i32 as u32, -3i32 as u32));
+        assert_eq!(divide(divu, 7, 0), (7, 0xffffffff));
+        assert_eq!(divide(divu, 0x80000000, u32::MAX), (0x80000000, 0));
+    }
+
+    #[test]
+    fn overflow_leaves_the_destination_alone() {
+        for word in [
+            0x01095020, // add $t2, $t0, $t1
+            0x210a0001, // addi $t2, $t0, 1
+        ] {
+            let mut r = reference(&[word], &[(T0, 0x7fffffff), (T1, 1), (T2, 0x12345678)]);
+            let step = r.step();
+            assert_eq!(step.exception, Some(Exception::Overflow));
+            assert_eq!(r.regs[T2], 0x12345678);
+            assert_eq!(r.epc, PROGRAM);
+            assert_eq!(r.cause, 12 << 2);
+            // BEV is set after reset
+            assert_eq!(r.pc, 0xbfc00180);
+        }
+    }
+
+    #[test]
+    fn exceptions_in_delay_slots_point_epc_at_the_branch() {
+        let mut r = reference(
+            &[
+                0x10000002, // b +2
+                0x01095020, // add $t2, $t0, $t1
+            ],
+            &[(T0, 0x7fffffff), (T1, 1)],
+        );
+        r.step();
+        assert_eq!(r.step().exception, Some(Exception::Overflow));
+        assert_eq!(r.epc, PROGRAM);
+        assert_eq!(r.cause, 1 << 31 | 12 << 2);
+
+        // Outside a delay slot, BD is cleared again
+        r.store32(PROGRAM + 8, 0x0000000d); // break
+        r.jump_to(PROGRAM + 8);
+        assert_eq!(r.step().exception, Some(Exception::Break));
+        assert_eq!(r.epc, PROGRAM + 8);
+        assert_eq!(r.cause, 9 << 2);
+    }
+}
@@ -0,0 +1,508 @@
+use std::{
+    fmt::Write as _,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use clap::Args;
+use psemu_core::{kernel::KernelCall, Coverage, Cpu, PsxExe, RegisterIndex, SymbolTable};
+
+use crate::{disasm::parse_addr, load_symbols, panic_message};
+
+const V0: RegisterIndex = RegisterIndex(2);
+const A0: RegisterIndex = RegisterIndex(4);
+const GP: RegisterIndex = RegisterIndex(28);
+const SP: RegisterIndex = RegisterIndex(29);
+const FP: RegisterIndex = RegisterIndex(30);
+// Where the BIOS would have put the stack
+const DEFAULT_SP: u32 = 0x801ffff0;
+// Longest string read out of guest memory for the TTY
+const MAX_STRING: u32 = 4096;
+
+// There's no GPU yet, so there's no framebuffer to hash; GPU tests can only
+// report through the TTY or the exit register for now.
+#[derive(Args, Debug)]
+pub struct TestArgs {
+    /// PS-X EXEs to run, one test case each
+    #[arg(required = true)]
+    exes: Vec<PathBuf>,
+    /// The test passed once the TTY output contains TEXT. Can be repeated.
+    #[arg(long = "pass", value_name = "TEXT")]
+    pass: Vec<String>,
+    /// The test failed once the TTY output contains TEXT. Can be repeated.
+    #[arg(long = "fail", value_name = "TEXT")]
+    fail: Vec<String>,
+    /// Writing here ends the test: 0 passes, anything else is the failure's
+    /// exit code. Only a store whose address equals this one counts, through
+    /// any of KUSEG, KSEG0 and KSEG1; one merely overlapping it doesn't. The
+    /// default is the register PCSX-Redux uses for this.
+    #[arg(long, value_name = "ADDR", value_parser = parse_addr, default_value = "0x1f802082")]
+    exit_addr: u32,
+    /// Fail tests that run longer than this
+    #[arg(long, value_name = "N", default_value_t = 10_000_000)]
+    max_instructions: u64,
+    /// Also write the results as JUnit XML
+    #[arg(long, value_name = "FILE")]
+    junit: Option<PathBuf>,
+    /// Record which instructions and branch outcomes each test ran, and
+    /// write `<test>.coverage` (per symbol) and `<test>.ranges` (executed
+    /// address ranges, for addr2line) to DIR
+    #[arg(long, value_name = "DIR")]
+    coverage: Option<PathBuf>,
+    /// Name the code in coverage reports using an ELF, PsyQ .SYM or .map
+    /// file. Can be repeated.
+    #[arg(long, value_name = "FILE")]
+    symbols: Vec<PathBuf>,
+}
+
+enum Verdict {
+    Passed(String),
+    Failed(String),
+    /// The test couldn't run to completion, e.g. the CPU hit an instruction
+    /// it doesn't know
+    Error(String),
+}
+
+struct TestResult {
+    name: String,
+    verdict: Verdict,
+    tty: String,
+    instructions: u64,
+    time: Duration,
+}
+
+pub fn run(args: &TestArgs) -> Result<(), String> {
+    if let Some(dir) = &args.coverage {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Unable to create {}: {e}", dir.display()))?;
+    }
+    let symbols = load_symbols(&args.symbols);
+    // Panics in the core become errors in the report
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let results: Vec<_> = args
+        .exes
+        .iter()
+        .map(|exe| run_test(args, exe, &symbols))
+        .collect();
+    panic::set_hook(hook);
+
+    let mut failed = 0;
+    for result in &results {
+        let (status, reason) = match &result.verdict {
+            Verdict::Passed(reason) => ("PASS", reason),
+            Verdict::Failed(reason) => ("FAIL", reason),
+            Verdict::Error(reason) => ("ERROR", reason),
+        };
+        if !matches!(result.verdict, Verdict::Passed(_)) {
+            failed += 1;
+        }
+        println!(
+            "{status:<5} {} ({reason}, {} instructions in {:.2}s)",
+            result.name,
+            result.instructions,
+            result.time.as_secs_f64()
+        );
+    }
+    println!("\n{} passed, {failed} failed", results.len() - failed);
+
+    if let Some(path) = &args.junit {
+        std::fs::write(path, junit_xml(&results))
+            .map_err(|e| format!("Unable to write {}: {e}", path.display()))?;
+    }
+    if failed > 0 {
+        return Err(format!("{failed} test(s) failed"));
+    }
+    Ok(())
+}
+
+fn run_test(args: &TestArgs, path: &Path, symbols: &SymbolTable) -> TestResult {
+    let start = Instant::now();
+    let mut result = TestResult {
+        name: path.file_stem().map_or_else(
+            || path.display().to_string(),
+            |s| s.to_string_lossy().into(),
+        ),
+        verdict: Verdict::Error(String::new()),
+        tty: String::new(),
+        instructions: 0,
+        time: Duration::ZERO,
+    };
+    let mut coverage = args.coverage.as_ref().map(|_| Coverage::default());
+    result.verdict = match load(path) {
+        Ok(mut cpu) => {
+            let verdict = execute(args, &mut cpu, &mut result, coverage.as_mut());
+            match (&args.coverage, &coverage) {
+                (Some(dir), Some(coverage)) => {
+                    match write_coverage(dir, &result.name, &cpu, coverage, symbols) {
+                        Ok(()) => verdict,
+                        Err(e) => Verdict::Error(e),
+                    }
+                }
+                _ => verdict,
+            }
+        }
+        Err(e) => Verdict::Error(e),
+    };
+    result.time = start.elapsed();
+    result
+}
+
+// Sideloaded the way the BIOS's shell would, without running the BIOS
+pub(crate) fn load(path: &Path) -> Result<Cpu, String> {
+    let bytes =
+        std::fs::read(path).map_err(|e| format!("Unable to read {}: {e}", path.display()))?;
+    let exe = PsxExe::parse(&bytes).map_err(|e| e.to_string())?;
+    let mut cpu = Cpu::builder()
+        .ram(exe.load_addr, &exe.data)
+        .entry(exe.pc)
+        .build()
+        .map_err(|e| e.to_string())?;
+    cpu.instruction_history.set_capacity(0);
+    let sp = if exe.sp != 0 { exe.sp } else { DEFAULT_SP };
+    cpu.set_register(GP, exe.gp);
+    cpu.set_register(SP, sp);
+    cpu.set_register(FP, sp);
+    Ok(cpu)
+}
+
+fn execute(
+    args: &TestArgs,
+    cpu: &mut Cpu,
+    result: &mut TestResult,
+    mut coverage: Option<&mut Coverage>,
+) -> Verdict {
+    while result.instructions < args.max_instructions {
+        if let Some(code) = exit_write(cpu, args.exit_addr) {
+            return match code {
+                0 => Verdict::Passed("exit code 0".to_string()),
+                code => Verdict::Failed(format!("exit code {code}")),
+            };
+        }
+        if let Some(call) = KernelCall::at(cpu) {
+            if let Err(e) = kernel_call(cpu, &call, &mut result.tty) {
+                return Verdict::Error(e);
+            }
+            if let Some(text) = args.fail.iter().find(|t| result.tty.contains(*t)) {
+                return Verdict::Failed(format!("printed `{text}`"));
+            }
+            if let Some(text) = args.pass.iter().find(|t| result.tty.contains(*t)) {
+                return Verdict::Passed(format!("printed `{text}`"));
+            }
+            continue;
+        }
+        if let Some(coverage) = &mut coverage {
+            coverage.record(cpu);
+        }
+        match panic::catch_unwind(AssertUnwindSafe(|| cpu.run_single_cycle())) {
+            Ok(Ok(())) => result.instructions += 1,
+            Ok(Err(e)) => return Verdict::Error(format!("CPU stopped: {e}")),
+            Err(payload) => {
+                return Verdict::Error(format!("CPU panicked: {}", panic_message(&*payload)))
+            }
+        }
+    }
+    Verdict::Failed(format!(
+        "timed out after {} instructions",
+        args.max_instructions
+    ))
+}
+
+fn write_coverage(
+    dir: &Path,
+    name: &str,
+    cpu: &Cpu,
+    coverage: &Coverage,
+    symbols: &SymbolTable,
+) -> Result<(), String> {
+    let write = |extension: &str, contents: &dyn Fn(&mut Vec<u8>) -> std::io::Result<()>| {
+        let path = dir.join(format!("{name}.{extension}"));
+        let mut out = vec![];
+        contents(&mut out)
+            .and_then(|()| std::fs::write(&path, out))
+            .map_err(|e| format!("Unable to write {}: {e}", path.display()))
+    };
+    write("coverage", &|w| coverage.write_report(cpu, symbols, w))?;
+    write("ranges", &|w| coverage.write_ranges(w))
+}
+
+// The value, if the instruction about to run is a store to `exit_addr`.
+// Checked before it runs since the register isn't mapped in the core.
+fn exit_write(cpu: &Cpu, exit_addr: u32) -> Option<u32> {
+    let word = cpu.next_instruction();
+    let mask = match word >> 26 {
+        0x28 => 0xff,
+        0x29 => 0xffff,
+        0x2b => 0xffffffff,
+        _ => return None,
+    };
+    let base = cpu.get_register(RegisterIndex((word >> 21) & 0x1f));
+    let addr = base.wrapping_add(word as i16 as u32);
+    // Any of KUSEG, KSEG0 and KSEG1
+    if addr & 0x1fffffff != exit_addr & 0x1fffffff {
+        return None;
+    }
+    Some(cpu.get_register(RegisterIndex((word >> 16) & 0x1f)) & mask)
+}
+
+// Stands in for the few kernel functions test programs report through,
+// then returns to the caller
+fn kernel_call(cpu: &mut Cpu, call: &KernelCall, tty: &mut String) -> Result<(), String> {
+    let a0 = cpu.get_register(A0);
+    match (call.table, call.function) {
+        (0xa0, 0x3c) | (0xb0, 0x3d) => tty.push(a0 as u8 as char),
+        (0xa0, 0x3e) | (0xb0, 0x3f) => tty.push_str(&read_string(cpu, a0)),
+        (0xa0, 0x3f) => tty.push_str(&printf(cpu)),
+        _ => return Err(format!("Kernel call {call} isn't emulated")),
+    }
+    cpu.set_register(V0, 0);
+    cpu.jump_to(call.return_addr)
+}
+
+fn read_byte(cpu: &Cpu, addr: u32) -> Option<u8> {
+    let word = cpu.load32(addr & !3).ok()?;
+    Some((word >> ((addr & 3) * 8)) as u8)
+}
+
+fn read_string(cpu: &Cpu, addr: u32) -> String {
+    let bytes: Vec<u8> = (0..MAX_STRING)
+        .map_while(|i| read_byte(cpu, addr.wrapping_add(i)).filter(|b| *b != 0))
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// Enough of printf for test output: flags, width and the common
+// conversions. Arguments after the fourth come from the caller's stack.
+fn printf(cpu: &Cpu) -> String {
+    let format = read_string(cpu, cpu.get_register(A0));
+    let mut next_arg = 1;
+    let mut arg = || {
+        let val = if next_arg < 4 {
+            cpu.get_register(RegisterIndex(4 + next_arg))
+        } else {
+            let sp = cpu.get_register(SP);
+            cpu.load32(sp.wrapping_add(next_arg * 4)).unwrap_or(0)
+        };
+        next_arg += 1;
+        val
+    };
+
+    let mut out = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let mut zero = false;
+        let mut left = false;
+        while let Some(flag) = chars.next_if(|c| "-0+ #".contains(*c)) {
+            zero |= flag == '0';
+            left |= flag == '-';
+        }
+        let mut width = 0;
+        while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+            width = width * 10 + digit.to_digit(10).unwrap() as usize;
+        }
+        // Sizes don't matter, everything is 32 bits
+        while chars.next_if(|c| matches!(c, 'l' | 'h')).is_some() {}
+        let text = match chars.next() {
+            Some('d' | 'i') => (arg() as i32).to_string(),
+            Some('u') => arg().to_string(),
+            Some('x') => format!("{:x}", arg()),
+            Some('X') => format!("{:X}", arg()),
+            Some('p') => format!("{:08x}", arg()),
+            Some('c') => (arg() as u8 as char).to_string(),
+            Some('s') => read_string(cpu, arg()),
+            Some('%') => "%".to_string(),
+            Some(other) => format!("%{other}"),
+            None => "%".to_string(),
+        };
+        let padding = width.saturating_sub(text.chars().count());
+        if left {
+            out.push_str(&text);
+            out.extend(std::iter::repeat_n(' ', padding));
+        } else {
+            let fill = if zero { '0' } else { ' ' };
+            out.extend(std::iter::repeat_n(fill, padding));
+            out.push_str(&text);
+        }
+    }
+    out
+}
+
+fn junit_xml(results: &[TestResult]) -> String {
+    let failures = results
+        .iter()
+        .filter(|r| matches!(r.verdict, Verdict::Failed(_)))
+        .count();
+    let errors = results
+        .iter()
+        .filter(|r| matches!(r.verdict, Verdict::Error(_)))
+        .count();
+    let time: f64 = results.iter().map(|r| r.time.as_secs_f64()).sum();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    writeln!(
+        xml,
+        "<testsuite name=\"psemu\" tests=\"{}\" failures=\"{failures}\" errors=\"{errors}\" time=\"{time:.3}\">",
+        results.len()
+    )
+    .unwrap();
+    for result in results {
+        writeln!(
+            xml,
+            "  <testcase classname=\"psemu\" name=\"{}\" time=\"{:.3}\">",
+            escape(&result.name),
+            result.time.as_secs_f64()
+        )
+        .unwrap();
+        match &result.verdict {
+            Verdict::Passed(_) => (),
+            Verdict::Failed(reason) => {
+                writeln!(xml, "    <failure message=\"{}\"/>", escape(reason)).unwrap()
+            }
+            Verdict::Error(reason) => {
+                writeln!(xml, "    <error message=\"{}\"/>", escape(reason)).unwrap()
+            }
+        }
+        if !result.tty.is_empty() {
+            writeln!(xml, "    <system-out>{}</system-out>", escape(&result.tty)).unwrap();
+        }
+        xml.push_str("  </testcase>\n");
+    }
+    xml.push_str("</testsuite>\n");
+    xml
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            // Not allowed in XML 1.0 at all
+            c if c.is_control() && !matches!(c, '\n' | '\r' | '\t') => (),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STRINGS: u32 = 0x80010000;
+    const STACK: u32 = 0x80020000;
+
+    // `strings` are laid out one after another from STRINGS, NUL-terminated;
+    // returns their addresses too
+    fn cpu_with(strings: &[&str]) -> (Cpu, Vec<u32>) {
+        let mut bytes = vec![];
+        let mut addrs = vec![];
+        for s in strings {
+            addrs.push(STRINGS + bytes.len() as u32);
+            bytes.extend(s.as_bytes());
+            bytes.push(0);
+        }
+        let cpu = Cpu::builder()
+            .ram(STRINGS, &bytes)
+            .ram(STACK, &[0; 64])
+            .entry(STRINGS)
+            .build()
+            .unwrap();
+        (cpu, addrs)
+    }
+
+    #[test]
+    fn printf_conversions() {
+        let (mut cpu, addrs) = cpu_with(&["%d %5u|%-4x|%04X %c %s %% %q", "str"]);
+        cpu.set_register(A0, addrs[0]);
+        cpu.set_register(RegisterIndex(5), -12i32 as u32);
+        cpu.set_register(RegisterIndex(6), 42);
+        cpu.set_register(RegisterIndex(7), 0xab);
+        // The rest are on the stack, after the space for $a0-$a3
+        cpu.set_register(SP, STACK);
+        for (i, val) in [0xbeef, 'z' as u32, addrs[1]].iter().enumerate() {
+            cpu.poke32(STACK + 16 + i as u32 * 4, *val).unwrap();
+        }
+        assert_eq!(printf(&cpu), "-12    42|ab  |BEEF z str % %q");
+    }
+
+    #[test]
+    fn printf_reads_strings_up_to_the_nul() {
+        let (mut cpu, addrs) = cpu_with(&["%s!", "hello", "unused"]);
+        cpu.set_register(A0, addrs[0]);
+        cpu.set_register(RegisterIndex(5), addrs[1]);
+        assert_eq!(printf(&cpu), "hello!");
+        assert_eq!(read_string(&cpu, addrs[1] + 1), "ello");
+        // Unmapped memory ends the string
+        assert_eq!(read_string(&cpu, 0x1f000000), "");
+    }
+
+    #[test]
+    fn exit_writes() {
+        const EXIT: u32 = 0x1f802082;
+        let (mut cpu, _) = cpu_with(&[]);
+        cpu.set_register(RegisterIndex(8), 0xbf802080); // $t0
+        cpu.set_register(RegisterIndex(9), 0x1234); // $t1
+                                                    // sb $t1, 2($t0), through KSEG1
+        cpu.set_next_instruction(STRINGS, 0xa1090002);
+        assert_eq!(exit_write(&cpu, EXIT), Some(0x34));
+        // sh $t1, 2($t0)
+        cpu.set_next_instruction(STRINGS, 0xa5090002);
+        assert_eq!(exit_write(&cpu, EXIT), Some(0x1234));
+        // sw $t1, -2($t0) is somewhere else
+        cpu.set_next_instruction(STRINGS, 0xad09fffe);
+        assert_eq!(exit_write(&cpu, EXIT), None);
+        // ori $t1, $t0, 2 isn't a store
+        cpu.set_next_instruction(STRINGS, 0x35090002);
+        assert_eq!(exit_write(&cpu, EXIT), None);
+    }
+
+    fn result(name: &str, verdict: Verdict, tty: &str) -> TestResult {
+        TestResult {
+            name: name.to_string(),
+            verdict,
+            tty: tty.to_string(),
+            instructions: 0,
+            time: Duration::from_millis(1500),
+        }
+    }
+
+    #[test]
+    fn escapes_xml() {
+        assert_eq!(
+            escape("<a href=\"x\">&</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;"
+        );
+        assert_eq!(escape("bell\x07\ttab\nline"), "bell\ttab\nline");
+    }
+
+    #[test]
+    fn junit_report() {
+        let results = [
+            result("ok", Verdict::Passed("exit code 0".to_string()), ""),
+            result("bad", Verdict::Failed("exit code 1".to_string()), "a < b\n"),
+            result("broken", Verdict::Error("CPU stopped".to_string()), ""),
+        ];
+        assert_eq!(
+            junit_xml(&results),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <testsuite name=\"psemu\" tests=\"3\" failures=\"1\" errors=\"1\" time=\"4.500\">\n\
+             \x20 <testcase classname=\"psemu\" name=\"ok\" time=\"1.500\">\n\
+             \x20 </testcase>\n\
+             \x20 <testcase classname=\"psemu\" name=\"bad\" time=\"1.500\">\n\
+             \x20   <failure message=\"exit code 1\"/>\n\
+             \x20   <system-out>a &lt; b\n</system-out>\n\
+             \x20 </testcase>\n\
+             \x20 <testcase classname=\"psemu\" name=\"broken\" time=\"1.500\">\n\
+             \x20   <error message=\"CPU stopped\"/>\n\
+             \x20 </testcase>\n\
+             </testsuite>\n"
+        );
+    }
+}
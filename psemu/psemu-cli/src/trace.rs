@@ -0,0 +1,288 @@
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{BufWriter, Write},
+    ops::RangeInclusive,
+    path::PathBuf,
+};
+
+use clap::Args;
+use psemu_core::{disassemble, Cpu, REGISTER_NAMES};
+
+use crate::disasm::parse_addr;
+
+const DEFAULT_FORMAT: &str = "{pc} {raw} {asm} {regs}";
+const PLACEHOLDERS: [&str; 6] = ["cycle", "pc", "raw", "asm", "regs", "allregs"];
+
+#[derive(Args, Debug)]
+pub struct TraceArgs {
+    /// Write one line per executed instruction to FILE
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["debug_mode", "gdb"])]
+    trace: Option<PathBuf>,
+    /// Layout of each trace line. Placeholders: {cycle}, {pc}, {raw}, {asm},
+    /// {regs} (registers the instruction changed, as `name=value`) and
+    /// {allregs} (all 32 registers)
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        default_value = DEFAULT_FORMAT,
+        value_parser = parse_format,
+        requires = "trace"
+    )]
+    trace_format: String,
+    /// Only trace instructions in `<start>-<end>` (inclusive). Can be repeated.
+    #[arg(long, value_name = "RANGE", value_parser = parse_range, requires = "trace")]
+    trace_range: Vec<RangeInclusive<u32>>,
+    /// Don't trace anything until the instruction at ADDR runs
+    #[arg(long, value_name = "ADDR", value_parser = parse_addr, requires = "trace")]
+    trace_start: Option<u32>,
+    /// Stop tracing after the instruction at ADDR runs
+    #[arg(long, value_name = "ADDR", value_parser = parse_addr, requires = "trace")]
+    trace_stop: Option<u32>,
+}
+
+fn parse_range(s: &str) -> Result<RangeInclusive<u32>, String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or(format!("Expected `<start>-<end>`, found `{s}`"))?;
+    let (start, end) = (parse_addr(start.trim())?, parse_addr(end.trim())?);
+    if start > end {
+        return Err(format!("Range `{s}` ends before it starts"));
+    }
+    Ok(start..=end)
+}
+
+// Catches typos in placeholders up front instead of copying them into
+// every line of the trace
+fn parse_format(s: &str) -> Result<String, String> {
+    let mut rest = s;
+    while let Some(open) = rest.find('{') {
+        let close = rest[open..]
+            .find('}')
+            .ok_or(format!("Unclosed `{{` in `{s}`"))?;
+        let name = &rest[open + 1..open + close];
+        if !PLACEHOLDERS.contains(&name) {
+            return Err(format!(
+                "Unknown placeholder `{{{name}}}`, expected one of {{{}}}",
+                PLACEHOLDERS.join("}, {")
+            ));
+        }
+        rest = &rest[open + close + 1..];
+    }
+    Ok(s.to_string())
+}
+
+/// Writes instructions to the trace file as they execute
+pub struct Tracer<W: Write = BufWriter<File>> {
+    out: W,
+    format: String,
+    ranges: Vec<RangeInclusive<u32>>,
+    start: Option<u32>,
+    stop: Option<u32>,
+    // Waiting for the start trigger
+    armed: bool,
+    // Already past the stop trigger
+    done: bool,
+    // State from before the current instruction
+    pc: u32,
+    raw: u32,
+    cycle: u64,
+    registers: [u32; 32],
+    line: String,
+}
+
+impl Tracer {
+    /// None if tracing wasn't asked for
+    pub fn new(args: &TraceArgs) -> Result<Option<Self>, String> {
+        let Some(path) = &args.trace else {
+            return Ok(None);
+        };
+        let file = File::create(path)
+            .map_err(|e| format!("Unable to create trace {}: {e}", path.display()))?;
+        Ok(Some(Tracer::with_writer(BufWriter::new(file), args)))
+    }
+}
+
+impl<W: Write> Tracer<W> {
+    fn with_writer(out: W, args: &TraceArgs) -> Self {
+        Tracer {
+            out,
+            format: args.trace_format.clone(),
+            ranges: args.trace_range.clone(),
+            start: args.trace_start,
+            stop: args.trace_stop,
+            armed: args.trace_start.is_none(),
+            done: false,
+            pc: 0,
+            raw: 0,
+            cycle: 0,
+            registers: [0; 32],
+            line: String::new(),
+        }
+    }
+
+    /// Called with the instruction that's about to run
+    pub fn before(&mut self, cpu: &Cpu) {
+        self.pc = cpu.next_instruction_pc();
+        self.raw = cpu.next_instruction();
+        self.cycle = cpu.cycles;
+        self.registers = cpu.get_registers().try_into().unwrap();
+        if Some(self.pc) == self.start {
+            self.armed = true;
+        }
+    }
+
+    /// Called once the instruction passed to `before` has run
+    pub fn after(&mut self, cpu: &Cpu) -> std::io::Result<()> {
+        if !self.armed || self.done {
+            return Ok(());
+        }
+        if Some(self.pc) == self.stop {
+            self.done = true;
+        }
+        if !self.ranges.is_empty() && !self.ranges.iter().any(|r| r.contains(&self.pc)) {
+            return Ok(());
+        }
+
+        let registers = cpu.get_registers();
+        let mut changed = String::new();
+        for (i, (old, new)) in self.registers.iter().zip(registers).enumerate() {
+            if old != new {
+                write!(changed, "{}={new:08x} ", REGISTER_NAMES[i]).unwrap();
+            }
+        }
+        let mut all = String::new();
+        if self.format.contains("{allregs}") {
+            for (i, val) in registers.iter().enumerate() {
+                write!(all, "{}={val:08x} ", REGISTER_NAMES[i]).unwrap();
+            }
+        }
+        let asm = disassemble(self.raw, self.pc).to_string();
+
+        self.line.clear();
+        let mut rest = self.format.as_str();
+        while let Some(open) = rest.find('{') {
+            self.line.push_str(&rest[..open]);
+            rest = &rest[open..];
+            // `parse_format` made sure every placeholder is closed and known
+            let close = rest.find('}').unwrap();
+            match &rest[1..close] {
+                "cycle" => write!(self.line, "{}", self.cycle).unwrap(),
+                "pc" => write!(self.line, "{:08x}", self.pc).unwrap(),
+                "raw" => write!(self.line, "{:08x}", self.raw).unwrap(),
+                "asm" => write!(self.line, "{asm:<30}").unwrap(),
+                "regs" => self.line.push_str(changed.trim_end()),
+                "allregs" => self.line.push_str(all.trim_end()),
+                placeholder => unreachable!("unknown placeholder {placeholder}"),
+            }
+            rest = &rest[close + 1..];
+        }
+        self.line.push_str(rest);
+        writeln!(self.out, "{}", self.line.trim_end())
+    }
+
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: u32 = 0x80010000;
+
+    fn args(format: &str) -> TraceArgs {
+        TraceArgs {
+            trace: None,
+            trace_format: parse_format(format).unwrap(),
+            trace_range: vec![],
+            trace_start: None,
+            trace_stop: None,
+        }
+    }
+
+    // Runs `program` with a tracer and returns the lines it wrote
+    fn trace(args: &TraceArgs, program: &[u32]) -> Vec<String> {
+        let bytes: Vec<u8> = program.iter().flat_map(|w| w.to_le_bytes()).collect();
+        let mut cpu = Cpu::builder()
+            .ram(PROGRAM, &bytes)
+            .entry(PROGRAM)
+            .build()
+            .unwrap();
+        let mut tracer = Tracer::with_writer(vec![], args);
+        for _ in program {
+            tracer.before(&cpu);
+            cpu.run_single_cycle().unwrap();
+            tracer.after(&cpu).unwrap();
+        }
+        let out = String::from_utf8(tracer.out).unwrap();
+        out.lines().map(str::to_string).collect()
+    }
+
+    const PROGRAM_WORDS: [u32; 3] = [
+        0x3c088001, // lui $t0, 0x8001
+        0x24090001, // li $t1, 1
+        0x00000000, // nop
+    ];
+
+    #[test]
+    fn expands_placeholders() {
+        let lines = trace(&args("{cycle}: {pc} {raw} [{regs}]"), &PROGRAM_WORDS);
+        assert_eq!(
+            lines,
+            [
+                "0: 80010000 3c088001 [$t0=80010000]",
+                "1: 80010004 24090001 [$t1=00000001]",
+                "2: 80010008 00000000 []",
+            ]
+        );
+        let lines = trace(&args("{allregs}"), &PROGRAM_WORDS[..1]);
+        assert_eq!(lines[0].split(' ').count(), 32);
+        assert!(lines[0].starts_with("$zero=00000000 $at="));
+        assert!(lines[0].contains(" $t0=80010000 "));
+    }
+
+    #[test]
+    fn disassembly_is_padded_so_registers_line_up() {
+        let lines = trace(&args(DEFAULT_FORMAT), &PROGRAM_WORDS);
+        let column = |line: &str, reg: &str| line.find(reg).unwrap();
+        assert_eq!(column(&lines[0], "$t0="), column(&lines[1], "$t1="));
+        assert!(lines[0].starts_with("80010000 3c088001 lui"));
+        // Nothing trails the last placeholder
+        assert_eq!(lines[2], "80010008 00000000 nop");
+    }
+
+    #[test]
+    fn ranges_and_triggers() {
+        let mut args = args("{pc}");
+        args.trace_range = vec![parse_range("0x80010004-0x80010008").unwrap()];
+        assert_eq!(trace(&args, &PROGRAM_WORDS), ["80010004", "80010008"]);
+
+        args.trace_range.clear();
+        args.trace_start = Some(PROGRAM + 4);
+        args.trace_stop = Some(PROGRAM + 4);
+        assert_eq!(trace(&args, &PROGRAM_WORDS), ["80010004"]);
+
+        assert!(parse_range("0x80010008-0x80010004").is_err());
+        assert!(parse_range("0x80010008").is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        assert_eq!(parse_format(DEFAULT_FORMAT).as_deref(), Ok(DEFAULT_FORMAT));
+        assert_eq!(parse_format("pc={pc}").as_deref(), Ok("pc={pc}"));
+        assert_eq!(
+            parse_format("{pc} {reg}"),
+            Err(
+                "Unknown placeholder `{reg}`, expected one of {cycle}, {pc}, {raw}, {asm}, \
+                 {regs}, {allregs}"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            parse_format("{pc"),
+            Err("Unclosed `{` in `{pc`".to_string())
+        );
+    }
+}
@@ -0,0 +1,370 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::File,
+    io::{BufRead, BufReader, Lines},
+    path::{Path, PathBuf},
+};
+
+use clap::Args;
+use psemu_core::REGISTER_NAMES;
+
+// How many instructions in a row have to match for two traces to count as
+// lined up; a single PC is ambiguous inside loops
+const SYNC_STEPS: usize = 16;
+
+#[derive(Args, Debug)]
+pub struct TraceDiffArgs {
+    /// Trace written by psemu (or any other emulator)
+    a: PathBuf,
+    /// Trace to compare it with
+    b: PathBuf,
+    /// Lines to show before the divergence
+    #[arg(long, default_value_t = 5)]
+    context: usize,
+    /// How far into either trace to look for the start of the other one, for
+    /// traces that started at different points
+    #[arg(long, value_name = "LINES", default_value_t = 100_000)]
+    sync_window: usize,
+    /// Which whitespace-separated field holds the PC, counting from 0. By
+    /// default it's the first 8-digit hex number.
+    #[arg(long, value_name = "N")]
+    pc_field: Option<usize>,
+}
+
+/// The parts of a trace line that can be compared across emulators: a
+/// `pc=value` pair or else the first 32-bit hex number is taken to be the
+/// PC, and other `name=value` or `name:value` pairs are register values
+struct Step {
+    line_number: usize,
+    text: String,
+    pc: u32,
+    registers: BTreeMap<&'static str, u32>,
+}
+
+impl Step {
+    fn parse(line_number: usize, text: String, pc_field: Option<usize>) -> Option<Self> {
+        let mut pc = pc_field
+            .and_then(|i| text.split_whitespace().nth(i))
+            .and_then(|field| parse_hex(field.trim_end_matches(':')));
+        let mut labelled_pc = None;
+        let mut registers = BTreeMap::new();
+        let tokens = text
+            .split(|c: char| c.is_whitespace() || c == ',' || c == ';')
+            .filter(|t| !t.is_empty());
+        for token in tokens {
+            match token.split_once(['=', ':']) {
+                Some((name, value)) if !value.is_empty() => {
+                    if name.trim_start_matches('$').eq_ignore_ascii_case("pc") {
+                        labelled_pc = labelled_pc.or(parse_hex(value));
+                    } else if let (Some(name), Some(value)) =
+                        (register_name(name), parse_hex(value))
+                    {
+                        registers.insert(name, value);
+                    }
+                }
+                // Addresses are often followed by a colon
+                _ if pc.is_none() && pc_field.is_none() => {
+                    let token = token.trim_end_matches(':');
+                    pc = parse_hex(token).filter(|_| is_word(token));
+                }
+                _ => (),
+            }
+        }
+        if pc_field.is_none() {
+            pc = labelled_pc.or(pc);
+        }
+        Some(Step {
+            line_number,
+            pc: pc?,
+            registers,
+            text,
+        })
+    }
+}
+
+// Eight hex digits, so counters and short immediates aren't taken for PCs
+fn is_word(token: &str) -> bool {
+    let digits = token.strip_prefix("0x").unwrap_or(token);
+    digits.len() == 8
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    let s = s.trim_matches(|c| c == '[' || c == ']');
+    u32::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16).ok()
+}
+
+// Accepts `$a0`, `a0`, `r4` and `$4`
+fn register_name(name: &str) -> Option<&'static str> {
+    let name = name.trim_start_matches('$').to_ascii_lowercase();
+    if let Some(i) = REGISTER_NAMES.iter().position(|r| r[1..] == name) {
+        return Some(REGISTER_NAMES[i]);
+    }
+    let index: usize = name.strip_prefix('r').unwrap_or(&name).parse().ok()?;
+    REGISTER_NAMES.get(index).copied()
+}
+
+struct Trace {
+    path: PathBuf,
+    lines: Lines<Box<dyn BufRead>>,
+    line_number: usize,
+    pc_field: Option<usize>,
+    // Steps read ahead while lining the traces up
+    ahead: VecDeque<Step>,
+    // Recent steps, for context
+    recent: VecDeque<Step>,
+}
+
+impl Trace {
+    fn open(path: &Path, pc_field: Option<usize>) -> Result<Self, String> {
+        let file =
+            File::open(path).map_err(|e| format!("Unable to open {}: {e}", path.display()))?;
+        Ok(Trace::from_reader(
+            path,
+            Box::new(BufReader::new(file)),
+            pc_field,
+        ))
+    }
+
+    fn from_reader(path: &Path, reader: Box<dyn BufRead>, pc_field: Option<usize>) -> Self {
+        Trace {
+            path: path.to_path_buf(),
+            lines: reader.lines(),
+            line_number: 0,
+            pc_field,
+            ahead: VecDeque::new(),
+            recent: VecDeque::new(),
+        }
+    }
+
+    // Lines without a PC (headers, log messages) are skipped
+    fn read(&mut self) -> Result<Option<Step>, String> {
+        for line in self.lines.by_ref() {
+            let line = line.map_err(|e| format!("Unable to read {}: {e}", self.path.display()))?;
+            self.line_number += 1;
+            if let Some(step) = Step::parse(self.line_number, line, self.pc_field) {
+                return Ok(Some(step));
+            }
+        }
+        Ok(None)
+    }
+
+    fn next(&mut self) -> Result<Option<Step>, String> {
+        match self.ahead.pop_front() {
+            Some(step) => Ok(Some(step)),
+            None => self.read(),
+        }
+    }
+
+    fn peek(&mut self, i: usize) -> Result<Option<&Step>, String> {
+        while self.ahead.len() <= i {
+            match self.read()? {
+                Some(step) => self.ahead.push_back(step),
+                None => return Ok(None),
+            }
+        }
+        Ok(self.ahead.get(i))
+    }
+
+    fn remember(&mut self, step: Step, context: usize) {
+        if self.recent.len() == context {
+            self.recent.pop_front();
+        }
+        if context > 0 {
+            self.recent.push_back(step);
+        }
+    }
+}
+
+// Why `a` and `b` don't describe the same instruction
+fn differences(a: &Step, b: &Step) -> Vec<String> {
+    let mut differences = vec![];
+    if a.pc != b.pc {
+        differences.push(format!("pc: {:08x} vs {:08x}", a.pc, b.pc));
+    }
+    // Only registers both traces report can be compared
+    for (name, value_a) in &a.registers {
+        if let Some(value_b) = b.registers.get(name) {
+            if value_a != value_b {
+                differences.push(format!("{name}: {value_a:08x} vs {value_b:08x}"));
+            }
+        }
+    }
+    differences
+}
+
+// Whether `later`, with its first `skip` steps dropped, runs the same
+// instructions as the start of `start`
+fn lines_up(start: &mut Trace, later: &mut Trace, skip: usize) -> Result<bool, String> {
+    for i in 0..SYNC_STEPS {
+        // Make sure both are read before borrowing either
+        let (has_a, has_b) = (start.peek(i)?.is_some(), later.peek(skip + i)?.is_some());
+        if !has_a || !has_b {
+            return Ok(i > 0);
+        }
+        if !differences(&start.ahead[i], &later.ahead[skip + i]).is_empty() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// Drops steps from the start of either trace until both run the same
+// instructions. False if no alignment was found within `window` steps.
+fn align(a: &mut Trace, b: &mut Trace, window: usize) -> Result<bool, String> {
+    for skip in 0..window {
+        if b.peek(skip)?.is_some() && lines_up(a, b, skip)? {
+            b.ahead.drain(..skip);
+            return Ok(true);
+        }
+        if a.peek(skip)?.is_some() && lines_up(b, a, skip)? {
+            a.ahead.drain(..skip);
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+pub fn run(args: &TraceDiffArgs) -> Result<(), String> {
+    let mut a = Trace::open(&args.a, args.pc_field)?;
+    let mut b = Trace::open(&args.b, args.pc_field)?;
+    if !align(&mut a, &mut b, args.sync_window)? {
+        return Err(format!(
+            "Unable to line up the traces within their first {} instructions",
+            args.sync_window
+        ));
+    }
+    let (Some(mut step_a), Some(mut step_b)) = (a.next()?, b.next()?) else {
+        return Err("Both traces need at least one line with a PC".to_string());
+    };
+    println!(
+        "Aligned {}:{} with {}:{} at {:08x}",
+        args.a.display(),
+        step_a.line_number,
+        args.b.display(),
+        step_b.line_number,
+        step_a.pc
+    );
+
+    let mut compared = 0u64;
+    loop {
+        let differences = differences(&step_a, &step_b);
+        if !differences.is_empty() {
+            println!("Diverged after {compared} matching instructions\n");
+            for (trace, step) in [(&a, &step_a), (&b, &step_b)] {
+                println!("{}:", trace.path.display());
+                for recent in &trace.recent {
+                    println!("  {:>8}  {}", recent.line_number, recent.text);
+                }
+                println!("> {:>8}  {}\n", step.line_number, step.text);
+            }
+            for difference in &differences {
+                println!("  {difference}");
+            }
+            return Err("Traces diverge".to_string());
+        }
+        compared += 1;
+
+        a.remember(step_a, args.context);
+        b.remember(step_b, args.context);
+        match (a.next()?, b.next()?) {
+            (Some(next_a), Some(next_b)) => (step_a, step_b) = (next_a, next_b),
+            (None, None) => break,
+            (Some(_), None) => {
+                println!(
+                    "Traces match for {compared} instructions, then {} ends",
+                    args.b.display()
+                );
+                return Ok(());
+            }
+            (None, Some(_)) => {
+                println!(
+                    "Traces match for {compared} instructions, then {} ends",
+                    args.a.display()
+                );
+                return Ok(());
+            }
+        }
+    }
+    println!("Traces match ({compared} instructions)");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn step(text: &str) -> Option<Step> {
+        Step::parse(1, text.to_string(), None)
+    }
+
+    fn trace(text: &str) -> Trace {
+        let reader = Box::new(Cursor::new(text.as_bytes().to_vec()));
+        Trace::from_reader(Path::new("trace"), reader, None)
+    }
+
+    // One line per PC, with $t0 set to the PC's low byte
+    fn lines(pcs: impl IntoIterator<Item = u32>) -> String {
+        pcs.into_iter()
+            .map(|pc| format!("{pc:08x} t0={:08x}\n", pc & 0xff))
+            .collect()
+    }
+
+    #[test]
+    fn finds_the_pc_and_registers() {
+        let parsed = step("80010000: 3c088001 lui $t0, 0x8001  t0=80010000 $a0:00000004").unwrap();
+        assert_eq!(parsed.pc, 0x80010000);
+        assert_eq!(parsed.registers["$t0"], 0x80010000);
+        assert_eq!(parsed.registers["$a0"], 4);
+        // Counters and short immediates aren't PCs
+        assert!(step("12 ori 0x1234").is_none());
+    }
+
+    #[test]
+    fn a_labelled_pc_wins_over_the_first_word() {
+        let parsed = step("[00001234] op=3c088001 PC=80010000 r8=00000001").unwrap();
+        assert_eq!(parsed.pc, 0x80010000);
+        assert_eq!(parsed.registers["$t0"], 1);
+        assert!(!parsed.registers.contains_key("pc"));
+        assert_eq!(step("pc=bfc00000").unwrap().pc, 0xbfc00000);
+        // An explicit field still takes priority
+        let parsed = Step::parse(1, "bfc00004 pc=bfc00000".to_string(), Some(0)).unwrap();
+        assert_eq!(parsed.pc, 0xbfc00004);
+    }
+
+    #[test]
+    fn register_names() {
+        assert_eq!(register_name("$a0"), Some("$a0"));
+        assert_eq!(register_name("A0"), Some("$a0"));
+        assert_eq!(register_name("r31"), Some("$ra"));
+        assert_eq!(register_name("$4"), Some("$a0"));
+        assert_eq!(register_name("r32"), None);
+        assert_eq!(register_name("hi"), None);
+    }
+
+    #[test]
+    fn aligns_traces_that_start_at_different_points() {
+        let pcs = (0..40).map(|i| 0x80010000 + i * 4);
+        let mut a = trace(&format!("header\n{}", lines(pcs.clone())));
+        let mut b = trace(&lines(pcs.skip(5)));
+        assert!(align(&mut a, &mut b, 100).unwrap());
+        assert_eq!(a.next().unwrap().unwrap().pc, 0x80010014);
+        assert_eq!(b.next().unwrap().unwrap().pc, 0x80010014);
+
+        // Same instructions, but the registers never agree
+        let mut a = trace(&lines((0..40).map(|i| 0x80010000 + i * 4)));
+        let mut b = trace(&lines((0..40).map(|i| 0x80010000 + i * 4)).replace("t0=0", "t0=1"));
+        assert!(!align(&mut a, &mut b, 100).unwrap());
+    }
+
+    #[test]
+    fn reports_which_values_differ() {
+        let a = step("80010000 t0=00000001 t1=00000002").unwrap();
+        let b = step("80010004 t0=00000001 t1=00000003 t2=00000004").unwrap();
+        assert_eq!(
+            differences(&a, &b),
+            ["pc: 80010000 vs 80010004", "$t1: 00000002 vs 00000003"]
+        );
+    }
+}
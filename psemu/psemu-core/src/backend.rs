@@ -0,0 +1,237 @@
+use crate::{Cpu, PsemuCoreError};
+
+/// The CPU's registers and pipeline, without memory. Enough to compare two
+/// backends or to hand a running CPU from one to another.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuState {
+    pub pc: u32,
+    pub next_instruction: u32,
+    pub next_instruction_pc: u32,
+    pub registers: [u32; 32],
+    pub cycles: u64,
+}
+
+/// A way of running instructions on a `Cpu`. The machine and any decoded
+/// code live in the `Cpu`, so backends can be swapped between any two calls.
+pub trait ExecutionBackend {
+    fn name(&self) -> &'static str;
+
+    /// Run at least one instruction. Returns how many ran.
+    fn step(&mut self, cpu: &mut Cpu) -> Result<u64, PsemuCoreError>;
+
+    /// Run until `cycles` instructions have executed since reset. Backends
+    /// that run whole blocks can go past it. Returns how many ran.
+    fn run_until(&mut self, cpu: &mut Cpu, cycles: u64) -> Result<u64, PsemuCoreError> {
+        let start = cpu.cycles;
+        while cpu.cycles < cycles {
+            self.step(cpu)?;
+        }
+        Ok(cpu.cycles - start)
+    }
+
+    /// Forget any code decoded from `len` bytes at `addr`, for memory that
+    /// changed without going through the CPU
+    fn invalidate_range(&mut self, cpu: &mut Cpu, addr: u32, len: u32);
+
+    fn state(&self, cpu: &Cpu) -> CpuState {
+        cpu.state()
+    }
+
+    fn set_state(&mut self, cpu: &mut Cpu, state: &CpuState) {
+        cpu.set_state(state);
+    }
+}
+
+/// Fetches and decodes every instruction as it runs. The only backend that
+/// records history and stops on watchpoints mid-block, so debuggers always
+/// single-step with it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Interpreter;
+
+impl ExecutionBackend for Interpreter {
+    fn name(&self) -> &'static str {
+        "interpreter"
+    }
+
+    fn step(&mut self, cpu: &mut Cpu) -> Result<u64, PsemuCoreError> {
+        cpu.run_single_cycle().map(|_| 1)
+    }
+
+    fn invalidate_range(&mut self, _cpu: &mut Cpu, _addr: u32, _len: u32) {}
+}
+
+/// Runs pre-decoded basic blocks from the block cache, see `Cpu::run_block`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CachedInterpreter;
+
+impl ExecutionBackend for CachedInterpreter {
+    fn name(&self) -> &'static str {
+        "cached"
+    }
+
+    fn step(&mut self, cpu: &mut Cpu) -> Result<u64, PsemuCoreError> {
+        cpu.run_block()
+    }
+
+    fn invalidate_range(&mut self, cpu: &mut Cpu, addr: u32, len: u32) {
+        cpu.block_cache.invalidate_range(addr, len);
+    }
+}
+
+/// Runs basic blocks translated to x86-64, see `Cpu::run_recompiled`
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Recompiler;
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+impl ExecutionBackend for Recompiler {
+    fn name(&self) -> &'static str {
+        "recompiler"
+    }
+
+    fn step(&mut self, cpu: &mut Cpu) -> Result<u64, PsemuCoreError> {
+        cpu.run_recompiled()
+    }
+
+    fn invalidate_range(&mut self, cpu: &mut Cpu, addr: u32, len: u32) {
+        cpu.compiled_blocks.invalidate_range(addr, len);
+    }
+}
+
+impl Cpu {
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            pc: self.pc,
+            next_instruction: self.next_instruction.0,
+            next_instruction_pc: self.next_instruction_pc,
+            registers: self.registers,
+            cycles: self.cycles,
+        }
+    }
+
+    /// Memory is left alone, so decoded code stays valid
+    pub fn set_state(&mut self, state: &CpuState) {
+        self.pc = state.pc;
+        self.set_next_instruction(state.next_instruction_pc, state.next_instruction);
+        self.registers = state.registers;
+        self.registers[0] = 0;
+        self.cycles = state.cycles;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, RegisterIndex};
+
+    #[test]
+    fn backends_agree() {
+        for mut backend in backends() {
+            assert_same_as(&mut *backend, &LOOP, 500);
+        }
+    }
+
+    #[test]
+    fn writes_to_code_invalidate_decoded_code() {
+        for mut backend in backends() {
+            let cpu = assert_same_as(&mut *backend, &SELF_MODIFYING, 20);
+            assert_eq!(cpu.load32(PROGRAM + 0x18), Ok(0x34090002));
+            assert_eq!(cpu.get_register(RegisterIndex(9)), 2, "{}", backend.name());
+        }
+    }
+
+    #[test]
+    fn stores_through_every_mirror() {
+        let program = [
+            lui(8, 0xa01f),  // KSEG1 RAM
+            lui(9, 0x1f80),  // Scratchpad
+            lui(10, 0x0010), // KUSEG RAM
+            addiu(11, 0, 7),
+            sw(11, 8, 0),
+            sw(11, 9, 0x3fc),
+            sw(11, 10, 4),
+            addiu(11, 11, 1),
+            j(PROGRAM + 0x10),
+            0,
+        ];
+        for mut backend in backends() {
+            let cpu = assert_same_as(&mut *backend, &program, 200);
+            let val = cpu.get_register(RegisterIndex(11)) - 1;
+            assert_eq!(cpu.load32(0x801f0000), Ok(val));
+            assert_eq!(cpu.load32(0x1f8003fc), Ok(val));
+            assert_eq!(cpu.load32(0x00100004), Ok(val));
+        }
+    }
+
+    #[test]
+    fn store_in_delay_slot_sees_prefetch() {
+        // The delay slot overwrites the jump target, which the interpreter
+        // already fetched
+        let program = [
+            lui(8, 0x8001),
+            j(PROGRAM + 0x10),
+            sw(0, 8, 0x10),
+            0,
+            ori(9, 0, 1),
+        ];
+        for mut backend in backends() {
+            let mut cpu = assert_same_as(&mut *backend, &program, 3);
+            assert_eq!(cpu.next_instruction(), ori(9, 0, 1));
+            backend.step(&mut cpu).unwrap();
+            assert_eq!(cpu.get_register(RegisterIndex(9)), 1, "{}", backend.name());
+        }
+    }
+
+    #[test]
+    fn bus_errors_stop_at_the_failing_instruction() {
+        // The interpreter still panics on them
+        for mut backend in backends().into_iter().skip(1) {
+            let name = backend.name();
+            // The store goes to unmapped memory
+            let mut cpu = cpu_with(&[lui(8, 0x1f00), addiu(9, 0, 1), sw(9, 8, 0), addiu(9, 0, 2)]);
+            cpu.instruction_history.set_capacity(0);
+            let res = backend.step(&mut cpu);
+            assert!(matches!(res, Err(PsemuCoreError::BusError(_))), "{name}");
+            assert_eq!(cpu.cycles, 2, "{name}");
+            assert_eq!(cpu.get_register(RegisterIndex(9)), 1, "{name}");
+            assert_eq!(cpu.next_instruction_pc(), PROGRAM + 8, "{name}");
+            assert_eq!(cpu.next_instruction(), sw(9, 8, 0), "{name}");
+            assert_eq!(cpu.pc, PROGRAM + 12, "{name}");
+
+            // The jump target can't be fetched; the delay slot hasn't run
+            let mut cpu = cpu_with(&[j(0x8f000000), addiu(9, 0, 1)]);
+            cpu.instruction_history.set_capacity(0);
+            let res = backend.step(&mut cpu);
+            assert!(matches!(res, Err(PsemuCoreError::BusError(_))), "{name}");
+            assert_eq!(cpu.cycles, 1, "{name}");
+            assert_eq!(cpu.get_register(RegisterIndex(9)), 0xdeadbeef, "{name}");
+            assert_eq!(cpu.next_instruction_pc(), PROGRAM + 4, "{name}");
+            assert_eq!(cpu.pc, 0x8f000000, "{name}");
+        }
+    }
+
+    #[test]
+    fn backends_can_be_swapped_mid_run() {
+        let mut reference = cpu_with(&LOOP);
+        let mut cpu = cpu_with(&LOOP);
+        cpu.instruction_history.set_capacity(0);
+        let mut backends = backends();
+        let count = backends.len();
+        for round in 0..30 {
+            backends[round % count].step(&mut cpu).unwrap();
+        }
+        Interpreter.run_until(&mut reference, cpu.cycles).unwrap();
+        assert_eq!(cpu.state(), reference.state());
+    }
+
+    #[test]
+    fn invalidate_range_drops_decoded_code() {
+        let mut cpu = cpu_with(&LOOP);
+        cpu.instruction_history.set_capacity(0);
+        let mut backend = CachedInterpreter;
+        backend.run_until(&mut cpu, 50).unwrap();
+        assert!(!cpu.block_cache().is_empty());
+        backend.invalidate_range(&mut cpu, PROGRAM, LOOP.len() as u32 * 4);
+        assert!(cpu.block_cache().is_empty());
+    }
+}
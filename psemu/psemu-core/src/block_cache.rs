@@ -0,0 +1,344 @@
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use crate::{
+    mask_region, Cpu, Instruction, Opcode, PsemuCoreError, RegisterIndex, SecondaryOpcode,
+};
+
+// Blocks are invalidated a page at a time
+pub(crate) const PAGE_SHIFT: u32 = 12;
+// Longest straight run of code decoded as one block
+const MAX_BLOCK_LEN: usize = 64;
+
+/// One instruction with its fields already pulled out, so running it needs
+/// neither a fetch nor a decode
+#[derive(Clone, Copy)]
+pub(crate) enum MicroOp {
+    Lui { rt: u8, val: u32 },
+    Ori { rt: u8, rs: u8, imm: u32 },
+    Addiu { rt: u8, rs: u8, imm: u32 },
+    Sw { rt: u8, base: u8, offset: u32 },
+    Sll { rd: u8, rt: u8, sa: u8 },
+    Or { rd: u8, rs: u8, rt: u8 },
+    J { target: u32 },
+}
+
+/// A run of instructions that's always executed start to finish: it ends
+/// with a jump and its delay slot, just before an instruction that can't be
+/// pre-decoded, or after `MAX_BLOCK_LEN` instructions
+pub(crate) struct Block {
+    // Virtual address, since jump targets depend on the segment
+    pub(crate) pc: u32,
+    pub(crate) raw: Vec<u32>,
+    pub(crate) ops: Vec<MicroOp>,
+    // Where execution continues afterwards, for blocks ending in a jump
+    pub(crate) target: Option<u32>,
+}
+
+/// Pre-decoded blocks, by physical address
+#[derive(Default)]
+pub struct BlockCache {
+    blocks: HashMap<u32, Rc<Block>>,
+    // Pages holding the start of at least one block
+    code_pages: HashSet<u32>,
+}
+
+impl BlockCache {
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.code_pages.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Forget the blocks that might include `addr`. True if there were any.
+    fn invalidate(&mut self, addr: u32) -> bool {
+        let page = mask_region(addr) >> PAGE_SHIFT;
+        // A block can spill over from the page before
+        if !self.code_pages.contains(&page) && !self.code_pages.contains(&page.wrapping_sub(1)) {
+            return false;
+        }
+        self.invalidate_range(addr, 1)
+    }
+
+    /// Forget the blocks overlapping `len` bytes from `addr`. True if there
+    /// were any.
+    pub(crate) fn invalidate_range(&mut self, addr: u32, len: u32) -> bool {
+        let (first, last) = (mask_region(addr), mask_region(addr).saturating_add(len));
+        let before = self.blocks.len();
+        self.blocks.retain(|start, block| {
+            let end = start + block.raw.len() as u32 * 4;
+            end <= first || *start >= last
+        });
+        self.code_pages = self
+            .blocks
+            .keys()
+            .map(|start| start >> PAGE_SHIFT)
+            .collect();
+        self.blocks.len() != before
+    }
+}
+
+fn decode(instr: Instruction, pc: u32) -> Option<MicroOp> {
+    let rs = instr.gpr_rs().0 as u8;
+    let rt = instr.gpr_rt().0 as u8;
+    let rd = instr.gpr_rd().0 as u8;
+    let op = match instr.sop()? {
+        Opcode::Special => match instr.secondary_opcode()? {
+            SecondaryOpcode::ShiftLeftLogical => MicroOp::Sll {
+                rd,
+                rt,
+                sa: instr.sa() as u8,
+            },
+            SecondaryOpcode::Or => MicroOp::Or { rd, rs, rt },
+        },
+        Opcode::LoadUpperImmediate => MicroOp::Lui {
+            rt,
+            val: instr.immediate() << 16,
+        },
+        Opcode::OrImmediate => MicroOp::Ori {
+            rt,
+            rs,
+            imm: instr.immediate(),
+        },
+        Opcode::AddImmediateUnsignedWord => MicroOp::Addiu {
+            rt,
+            rs,
+            imm: instr.immediate_sign_extended(),
+        },
+        Opcode::StoreWord => MicroOp::Sw {
+            rt,
+            base: rs,
+            offset: instr.offset_sign_extended(),
+        },
+        // Same as `op_jump`: the upper bits come from the address after the
+        // delay slot
+        Opcode::Jump => MicroOp::J {
+            target: (pc.wrapping_add(8) & 0xf0000000) | (instr.instr_index() << 2),
+        },
+    };
+    Some(op)
+}
+
+impl Cpu {
+    /// Run the next basic block from the block cache, decoding it first if
+    /// it isn't cached. Returns how many instructions ran; falls back to
+    /// `run_single_cycle` when recording history, since the cache doesn't
+    /// keep the debug details.
+    pub fn run_block(&mut self) -> Result<u64, PsemuCoreError> {
+        if self.instruction_history.is_recording() {
+            return self.run_single_cycle().map(|_| 1);
+        }
+        let pc = self.next_instruction_pc;
+        // Blocks run straight through; a delay slot that's already pending
+        // has to be finished first
+        if self.pc != pc.wrapping_add(4) {
+            return self.run_single_cycle().map(|_| 1);
+        }
+        let paddr = mask_region(pc);
+        let cached = self
+            .block_cache
+            .blocks
+            .get(&paddr)
+            .is_some_and(|b| b.pc == pc);
+        if !cached {
+            let Some(block) = self.decode_block(pc) else {
+                // Not something the cache handles, e.g. an unknown
+                // instruction; let the interpreter report it
+                return self.run_single_cycle().map(|_| 1);
+            };
+            self.block_cache.code_pages.insert(paddr >> PAGE_SHIFT);
+            self.block_cache.blocks.insert(paddr, Rc::new(block));
+        }
+        // Stays usable even if it overwrites itself
+        let block = self.block_cache.blocks[&paddr].clone();
+        self.execute_block(&block)
+    }
+
+    /// Drop every cached and compiled block. Loading a save state does this.
+    /// Cache isolation would have to as well, since writes to the isolated
+    /// cache aren't tracked, but there's no COP0 to isolate it yet.
+    pub fn invalidate_block_cache(&mut self) {
+        self.block_cache.clear();
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        self.compiled_blocks.clear();
+    }
+
+    // Called for every write to memory. True if `addr` held cached or
+    // compiled code.
+    pub(crate) fn invalidate_code_at(&mut self, addr: u32) -> bool {
+        let cached = self.block_cache.invalidate(addr);
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        let cached = self.compiled_blocks.invalidate(addr) || cached;
+        cached
+    }
+
+    pub(crate) fn decode_block(&self, pc: u32) -> Option<Block> {
+        // Must match what the CPU already fetched
+        if self.load32(pc).ok()? != self.next_instruction.0 {
+            return None;
+        }
+        let mut block = Block {
+            pc,
+            raw: vec![],
+            ops: vec![],
+            target: None,
+        };
+        let mut addr = pc;
+        while block.ops.len() < MAX_BLOCK_LEN {
+            let Some(word) = self.load32(addr).ok() else {
+                break;
+            };
+            let Some(op) = decode(Instruction(word), addr) else {
+                break;
+            };
+            block.raw.push(word);
+            block.ops.push(op);
+            addr = addr.wrapping_add(4);
+            if let MicroOp::J { target } = op {
+                // The delay slot belongs to the block too
+                let slot = self.load32(addr).ok();
+                match slot.and_then(|w| decode(Instruction(w), addr).map(|op| (w, op))) {
+                    Some((word, op)) if !matches!(op, MicroOp::J { .. }) => {
+                        block.raw.push(word);
+                        block.ops.push(op);
+                        block.target = Some(target);
+                    }
+                    // Leave the jump for the interpreter
+                    _ => {
+                        block.raw.pop();
+                        block.ops.pop();
+                    }
+                }
+                break;
+            }
+        }
+        (!block.ops.is_empty()).then_some(block)
+    }
+
+    // Returns how many instructions ran. Stops early after writing to cached
+    // code or hitting a watchpoint. A store or fetch outside mapped memory
+    // leaves the CPU about to run the instruction that failed.
+    pub(crate) fn execute_block(&mut self, block: &Block) -> Result<u64, PsemuCoreError> {
+        self.interconnect.accesses.clear();
+        self.interconnect.watchpoints.hit = None;
+        let last = block.ops.len() - 1;
+        for (i, op) in block.ops.iter().enumerate() {
+            let addr = block.pc.wrapping_add(i as u32 * 4);
+            // The interpreter fetches one instruction ahead, before running
+            // the current one
+            let prefetch = if i == last {
+                let next = block.target.unwrap_or(addr.wrapping_add(4));
+                match self.load32(next) {
+                    Ok(word) => Some((next, word)),
+                    Err(e) => {
+                        return Err(self.stop_block_at(block.pc, &block.raw, block.target, i, e))
+                    }
+                }
+            } else {
+                None
+            };
+            let wrote_code = match self.execute_micro_op(*op, addr) {
+                Ok(wrote_code) => wrote_code,
+                Err(e) => return Err(self.stop_block_at(block.pc, &block.raw, block.target, i, e)),
+            };
+            self.cycles += 1;
+
+            let hit = self.interconnect.watchpoints.hit.is_some();
+            if let Some((next, word)) = prefetch {
+                self.set_next_instruction(next, word);
+                self.pc = next.wrapping_add(4);
+                return Ok(i as u64 + 1);
+            }
+            if wrote_code || hit {
+                // Stopping early never happens after the jump, only the
+                // delay slot is after it and that's the last one
+                let next = addr.wrapping_add(4);
+                self.set_next_instruction(next, block.raw[i + 1]);
+                self.pc = next.wrapping_add(4);
+                return Ok(i as u64 + 1);
+            }
+        }
+        unreachable!("Blocks aren't empty")
+    }
+
+    // Leaves the CPU about to run the block's `i`th instruction again
+    pub(crate) fn stop_block_at(
+        &mut self,
+        pc: u32,
+        raw: &[u32],
+        target: Option<u32>,
+        i: usize,
+        error: String,
+    ) -> PsemuCoreError {
+        let addr = pc.wrapping_add(i as u32 * 4);
+        // By the delay slot, the jump has already happened
+        self.pc = match target {
+            Some(target) if i == raw.len() - 1 => target,
+            _ => addr.wrapping_add(4),
+        };
+        self.set_next_instruction(addr, raw[i]);
+        PsemuCoreError::BusError(error)
+    }
+
+    // True if it wrote to memory holding cached code
+    fn execute_micro_op(&mut self, op: MicroOp, addr: u32) -> Result<bool, String> {
+        let reg = |cpu: &Cpu, r: u8| cpu.registers[r as usize];
+        match op {
+            MicroOp::Lui { rt, val } => self.set_register(RegisterIndex(rt as u32), val),
+            MicroOp::Ori { rt, rs, imm } => {
+                self.set_register(RegisterIndex(rt as u32), reg(self, rs) | imm)
+            }
+            MicroOp::Addiu { rt, rs, imm } => {
+                let val = reg(self, rs).wrapping_add(imm);
+                self.set_register(RegisterIndex(rt as u32), val)
+            }
+            MicroOp::Sll { rd, rt, sa } => {
+                self.set_register(RegisterIndex(rd as u32), reg(self, rt) << sa)
+            }
+            MicroOp::Or { rd, rs, rt } => {
+                self.set_register(RegisterIndex(rd as u32), reg(self, rs) | reg(self, rt))
+            }
+            MicroOp::Sw { rt, base, offset } => {
+                self.interconnect.accesses.clear();
+                self.interconnect.watchpoints.hit = None;
+                let target = reg(self, base).wrapping_add(offset);
+                self.interconnect.store32(target, reg(self, rt))?;
+                if let Some(hit) = &mut self.interconnect.watchpoints.hit {
+                    hit.pc = addr;
+                }
+                return Ok(self.invalidate_code_at(target));
+            }
+            // Taken care of by the block's target
+            MicroOp::J { .. } => (),
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util::*, CachedInterpreter};
+
+    #[test]
+    fn loops_are_decoded_once() {
+        let cpu = assert_same_as(&mut CachedInterpreter, &LOOP, 1000);
+        assert_eq!(cpu.block_cache().len(), 2);
+    }
+
+    #[test]
+    fn recording_history_runs_one_instruction_at_a_time() {
+        let mut cpu = cpu_with(&[0x34090001, 0x34090002]);
+        cpu.instruction_history.set_capacity(10);
+        assert_eq!(cpu.run_block().unwrap(), 1);
+        assert!(cpu.block_cache().is_empty());
+    }
+}
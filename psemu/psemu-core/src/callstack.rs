@@ -0,0 +1,301 @@
+use crate::{disassemble, Cpu, Flow, RegisterIndex, SymbolTable};
+
+// Where the CPU goes when an exception is raised, through KSEG0 and KSEG1,
+// and while BEV is set
+const EXCEPTION_VECTORS: [u32; 3] = [0x80000080, 0xa0000080, 0xbfc00180];
+const RFE: u32 = 0x42000010;
+const SP: RegisterIndex = RegisterIndex(29);
+const RA: RegisterIndex = RegisterIndex(31);
+// The heuristic walk gives up after this many frames, or when a prologue
+// can't be found within this many instructions
+const MAX_WALK_FRAMES: usize = 32;
+const MAX_PROLOGUE_SEARCH: u32 = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// Start of the function this frame is running
+    pub function: u32,
+    /// The call, or the instruction an exception interrupted
+    pub call_site: u32,
+    pub return_addr: u32,
+    pub exception: bool,
+}
+
+// What the instruction about to run will do to the stack
+enum Pending {
+    Call { function: u32, call_site: u32 },
+    Return { target: u32 },
+    ReturnFromException,
+}
+
+/// A shadow call stack, kept up to date by watching calls, returns,
+/// exceptions and `rfe` as they execute. Innermost frame last.
+#[derive(Default)]
+pub struct CallStack {
+    pub frames: Vec<Frame>,
+    pending: Option<Pending>,
+    last_pc: u32,
+}
+
+impl CallStack {
+    /// Start over from whatever `walk` finds, for when the CPU state jumps
+    /// somewhere the shadow stack didn't follow, e.g. loading a state
+    pub fn reseed(&mut self, cpu: &Cpu, symbols: &SymbolTable) {
+        self.frames = CallStack::walk(cpu, symbols);
+        self.pending = None;
+    }
+
+    /// Called with the instruction that's about to run
+    pub fn before(&mut self, cpu: &Cpu) {
+        let pc = cpu.next_instruction_pc();
+        let word = cpu.next_instruction();
+        let rs = cpu.get_register(RegisterIndex((word >> 21) & 0x1f));
+        let instr = disassemble(word, pc);
+        self.last_pc = pc;
+        self.pending = match instr.flow {
+            Flow::Call => {
+                // bltzal and bgezal only call when their condition holds
+                let taken = match (word >> 26, (word >> 16) & 0x1f) {
+                    (0x01, 0x10) => (rs as i32) < 0,
+                    (0x01, 0x11) => (rs as i32) >= 0,
+                    _ => true,
+                };
+                taken.then(|| Pending::Call {
+                    function: instr.target.unwrap(),
+                    call_site: pc,
+                })
+            }
+            Flow::IndirectCall => Some(Pending::Call {
+                function: rs,
+                call_site: pc,
+            }),
+            Flow::IndirectJump => Some(Pending::Return { target: rs }),
+            _ if word == RFE => Some(Pending::ReturnFromException),
+            _ => None,
+        };
+    }
+
+    /// Whether the instruction passed to `before` calls or returns. Anything
+    /// else can only push an exception frame.
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Called once the instruction passed to `before` has run
+    pub fn after(&mut self, cpu: &Cpu) {
+        match self.pending.take() {
+            Some(Pending::Call {
+                function,
+                call_site,
+            }) => self.frames.push(Frame {
+                function,
+                call_site,
+                return_addr: call_site.wrapping_add(8),
+                exception: false,
+            }),
+            // Anything else is a jump table or a tail call
+            Some(Pending::Return { target }) => {
+                if let Some(i) = self.frames.iter().rposition(|f| f.return_addr == target) {
+                    self.frames.truncate(i);
+                }
+            }
+            Some(Pending::ReturnFromException) => {
+                if let Some(i) = self.frames.iter().rposition(|f| f.exception) {
+                    self.frames.truncate(i);
+                }
+            }
+            None => (),
+        }
+
+        // Nothing jumps to an exception vector, the CPU just ends up there
+        let pc = cpu.next_instruction_pc();
+        if EXCEPTION_VECTORS.contains(&pc) && !EXCEPTION_VECTORS.contains(&self.last_pc) {
+            self.frames.push(Frame {
+                function: pc,
+                call_site: self.last_pc,
+                return_addr: self.last_pc,
+                exception: true,
+            });
+        }
+    }
+
+    /// Reconstruct the stack from memory, innermost frame last. Finds each
+    /// function's prologue (`addiu $sp, $sp, -<size>` and `sw $ra, <offset>($sp)`)
+    /// to learn where the return address was saved. Symbols mark function
+    /// starts more reliably than prologues when they're available.
+    pub fn walk(cpu: &Cpu, symbols: &SymbolTable) -> Vec<Frame> {
+        let mut frames = vec![];
+        let mut pc = cpu.next_instruction_pc();
+        let mut sp = cpu.get_register(SP);
+        let mut ra = Some(cpu.get_register(RA));
+        while frames.len() < MAX_WALK_FRAMES {
+            let Some(prologue) = find_prologue(cpu, symbols, pc) else {
+                break;
+            };
+            // Only the parts of the prologue that already ran count
+            let mut frame_size = 0;
+            let mut saved_ra = None;
+            let mut addr = prologue;
+            while addr < pc {
+                let Ok(word) = cpu.load32(addr) else {
+                    break;
+                };
+                match word >> 16 {
+                    // addiu $sp, $sp, -<size>
+                    0x27bd if (word as i16) < 0 => frame_size = -(word as i16 as i32) as u32,
+                    // sw $ra, <offset>($sp)
+                    0xafbf => saved_ra = Some(sp.wrapping_add(word as i16 as u32)),
+                    _ => (),
+                }
+                addr = addr.wrapping_add(4);
+            }
+            let return_addr = match saved_ra {
+                Some(slot) => cpu.load32(slot).ok(),
+                // A leaf function, or the return address hasn't been saved
+                // yet; only the innermost frames can rely on $ra
+                None => ra.take(),
+            };
+            let Some(return_addr) = return_addr.filter(|a| *a != 0 && a.is_multiple_of(4)) else {
+                break;
+            };
+            let call_site = return_addr.wrapping_sub(8);
+            frames.push(Frame {
+                function: prologue,
+                call_site,
+                return_addr,
+                exception: false,
+            });
+            if saved_ra.is_some() {
+                ra = None;
+            }
+            pc = call_site;
+            sp = sp.wrapping_add(frame_size);
+        }
+        frames.reverse();
+        frames
+    }
+}
+
+// Start of the function containing `pc`
+fn find_prologue(cpu: &Cpu, symbols: &SymbolTable, pc: u32) -> Option<u32> {
+    if let Some((_, offset)) = symbols.lookup(pc) {
+        return Some(pc - offset);
+    }
+    let mut addr = pc;
+    for _ in 0..MAX_PROLOGUE_SEARCH {
+        let word = cpu.load32(addr).ok()?;
+        if word >> 16 == 0x27bd && (word as i16) < 0 {
+            return Some(addr);
+        }
+        // Went past the end of the previous function: `jr $ra` and its delay
+        // slot, so this function has no stack frame
+        if addr != pc && cpu.load32(addr.wrapping_sub(4)).ok()? == 0x03e00008 {
+            return Some(addr.wrapping_add(4));
+        }
+        addr = addr.wrapping_sub(4);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    const JR_RA: u32 = 0x03e00008;
+
+    fn frame(function: u32, call_site: u32) -> Frame {
+        Frame {
+            function,
+            call_site,
+            return_addr: call_site + 8,
+            exception: false,
+        }
+    }
+
+    #[test]
+    fn walk_finds_prologues_leaf_functions_and_saved_return_addresses() {
+        // a at 0x00 saves $ra and calls b at 0x20, which has no stack frame
+        // and is only found from the `jr $ra` ending the code before it.
+        // main at 0x38 called a from 0x40.
+        let mut program = [0; 13];
+        program[0] = 0x27bdffe8; // addiu $sp, $sp, -24
+        program[1] = 0xafbf0014; // sw $ra, 20($sp)
+        program[2] = 0x0c004008; // jal b
+        program[6] = JR_RA;
+        program[12] = JR_RA;
+        let mut cpu = cpu_with(&program);
+        let sp = 0x801ff000;
+        cpu.set_register(SP, sp);
+        cpu.poke32(sp + 20, PROGRAM + 0x48).unwrap();
+        cpu.set_register(RA, PROGRAM + 0x10);
+        let symbols = SymbolTable::default();
+
+        cpu.set_next_instruction(PROGRAM + 0x24, 0);
+        assert_eq!(
+            CallStack::walk(&cpu, &symbols),
+            [
+                frame(PROGRAM, PROGRAM + 0x40),
+                frame(PROGRAM + 0x20, PROGRAM + 8)
+            ]
+        );
+
+        // Before the `sw` has run, $ra still holds the return address
+        cpu.set_register(RA, PROGRAM + 0x48);
+        cpu.set_next_instruction(PROGRAM + 4, 0);
+        assert_eq!(
+            CallStack::walk(&cpu, &symbols),
+            [frame(PROGRAM, PROGRAM + 0x40)]
+        );
+    }
+
+    // Runs `word` at `pc` through `stack`, with the CPU ending up at `next`
+    fn step(stack: &mut CallStack, cpu: &mut Cpu, pc: u32, word: u32, next: u32) {
+        cpu.set_next_instruction(pc, word);
+        stack.before(cpu);
+        cpu.set_next_instruction(next, 0);
+        stack.after(cpu);
+    }
+
+    #[test]
+    fn shadow_stack_follows_calls_exceptions_and_returns() {
+        let mut cpu = cpu_with(&[]);
+        let mut stack = CallStack::default();
+        // jal 0x80020000
+        step(&mut stack, &mut cpu, PROGRAM, 0x0c008000, PROGRAM + 4);
+        let call = frame(0x80020000, PROGRAM);
+        assert_eq!(stack.frames, [call]);
+
+        // bltzal $t0, with $t0 positive
+        cpu.set_register(RegisterIndex(8), 1);
+        step(&mut stack, &mut cpu, 0x80020000, 0x0510ffff, 0x80020004);
+        assert_eq!(stack.frames, [call]);
+
+        // Nothing jumps to the vector, the CPU just ends up there
+        step(&mut stack, &mut cpu, 0x80020004, 0, 0x80000080);
+        let exception = Frame {
+            function: 0x80000080,
+            call_site: 0x80020004,
+            return_addr: 0x80020004,
+            exception: true,
+        };
+        assert_eq!(stack.frames, [call, exception]);
+        step(&mut stack, &mut cpu, 0x80000080, 0, 0x80000084);
+        assert_eq!(stack.frames, [call, exception]);
+        // A call the handler doesn't return from goes with it
+        step(&mut stack, &mut cpu, 0x80000084, 0x0c00c000, 0x80000088);
+        assert_eq!(stack.frames.len(), 3);
+        step(&mut stack, &mut cpu, 0x80000090, RFE, 0x80020004);
+        assert_eq!(stack.frames, [call]);
+
+        // Taken this time
+        cpu.set_register(RegisterIndex(8), -1i32 as u32);
+        step(&mut stack, &mut cpu, 0x80020004, 0x0510ffff, 0x80020008);
+        assert_eq!(stack.frames, [call, frame(0x80020004, 0x80020004)]);
+
+        // Returning from the outer call drops both
+        cpu.set_register(RA, PROGRAM + 8);
+        step(&mut stack, &mut cpu, 0x80020010, JR_RA, 0x80020014);
+        assert!(stack.frames.is_empty());
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+    ops::Range,
+};
+
+use crate::{symbols::MAX_UNSIZED_OFFSET, Cpu, RegisterIndex, SymbolTable};
+
+#[derive(Clone, Copy, Default)]
+struct Outcomes {
+    taken: bool,
+    not_taken: bool,
+}
+
+/// Which instructions ran, and which ways each conditional branch went.
+/// Fed one instruction at a time, so it needs the interpreter.
+#[derive(Default)]
+pub struct Coverage {
+    executed: HashSet<u32>,
+    branches: HashMap<u32, Outcomes>,
+}
+
+impl Coverage {
+    /// Called with the instruction that's about to run
+    pub fn record(&mut self, cpu: &Cpu) {
+        let pc = cpu.next_instruction_pc();
+        let word = cpu.next_instruction();
+        self.executed.insert(pc);
+        if is_conditional_branch(word) {
+            let outcomes = self.branches.entry(pc).or_default();
+            if branch_taken(cpu, word) {
+                outcomes.taken = true;
+            } else {
+                outcomes.not_taken = true;
+            }
+        }
+    }
+
+    pub fn is_executed(&self, addr: u32) -> bool {
+        self.executed.contains(&addr)
+    }
+
+    /// Executed instructions merged into contiguous ranges, in order. One
+    /// that runs to the top of the address space ends at 0.
+    pub fn ranges(&self) -> Vec<Range<u32>> {
+        let mut addrs: Vec<_> = self.executed.iter().copied().collect();
+        addrs.sort_unstable();
+        let mut ranges: Vec<Range<u32>> = vec![];
+        for addr in addrs {
+            match ranges.last_mut() {
+                Some(range) if range.end == addr => range.end = addr.wrapping_add(4),
+                _ => ranges.push(addr..addr.wrapping_add(4)),
+            }
+        }
+        ranges
+    }
+
+    /// One `<start> <end>` line per executed range, end exclusive. The
+    /// addresses can go straight to `addr2line -e game.elf` to get the
+    /// source lines they came from.
+    pub fn write_ranges(&self, w: &mut impl Write) -> io::Result<()> {
+        for range in self.ranges() {
+            writeln!(w, "{:#010x} {:#010x}", range.start, range.end)?;
+        }
+        Ok(())
+    }
+
+    /// How much of each symbol ran and how many of its branch outcomes
+    /// were seen, then every branch that only ever went one way. The code
+    /// is read from `cpu`'s memory to find branches that never ran.
+    pub fn write_report(
+        &self,
+        cpu: &Cpu,
+        symbols: &SymbolTable,
+        w: &mut impl Write,
+    ) -> io::Result<()> {
+        let mut lines = vec![];
+        let mut total = Counts::default();
+        let mut symbols_iter = symbols.iter().peekable();
+        while let Some((start, name, size)) = symbols_iter.next() {
+            let next = symbols_iter.peek().map(|(addr, ..)| *addr);
+            let size = match (size, next) {
+                (Some(size), _) => size,
+                (None, Some(next)) => (next - start).min(MAX_UNSIZED_OFFSET),
+                // Nothing says where the last one ends, so only as far as it ran
+                (None, None) => (0..MAX_UNSIZED_OFFSET)
+                    .step_by(4)
+                    .rev()
+                    .find(|offset| self.is_executed(start.wrapping_add(*offset)))
+                    .map_or(0, |offset| offset + 4),
+            };
+            // Labels
+            if size < 4 {
+                continue;
+            }
+            let mut counts = Counts::default();
+            for addr in (start..start.saturating_add(size)).step_by(4) {
+                counts.instructions += 1;
+                counts.executed += self.is_executed(addr) as u32;
+                if cpu.load32(addr).is_ok_and(is_conditional_branch) {
+                    let seen = self.branches.get(&addr).copied().unwrap_or_default();
+                    counts.outcomes += 2;
+                    counts.outcomes_seen += seen.taken as u32 + seen.not_taken as u32;
+                }
+            }
+            total.add(&counts);
+            lines.push((name, counts));
+        }
+
+        writeln!(
+            w,
+            "{} of {} instructions ({}) and {} of {} branch outcomes ({}) in named code",
+            total.executed,
+            total.instructions,
+            percent(total.executed, total.instructions),
+            total.outcomes_seen,
+            total.outcomes,
+            percent(total.outcomes_seen, total.outcomes)
+        )?;
+        let unnamed = (self.executed.len() as u32).saturating_sub(total.executed);
+        if unnamed > 0 {
+            writeln!(w, "{unnamed} executed instructions aren't in any symbol")?;
+        }
+        writeln!(w, "\n     instructions         branches  symbol")?;
+        for (name, counts) in lines {
+            let instructions = format!(
+                "{}/{} {}",
+                counts.executed,
+                counts.instructions,
+                percent(counts.executed, counts.instructions)
+            );
+            let branches = match counts.outcomes {
+                0 => String::new(),
+                outcomes => format!(
+                    "{}/{outcomes} {}",
+                    counts.outcomes_seen,
+                    percent(counts.outcomes_seen, outcomes)
+                ),
+            };
+            writeln!(w, "  {instructions:>15} {branches:>16}  {name}")?;
+        }
+
+        let mut partial: Vec<_> = self
+            .branches
+            .iter()
+            .filter(|(_, o)| !(o.taken && o.not_taken))
+            .collect();
+        if !partial.is_empty() {
+            partial.sort_by_key(|(addr, _)| **addr);
+            writeln!(w, "\nBranches that only went one way")?;
+            for (addr, outcomes) in partial {
+                let way = if outcomes.taken { "taken" } else { "not taken" };
+                writeln!(
+                    w,
+                    "  {addr:#010x} {:<24} always {way}",
+                    symbols.symbolize(*addr)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    instructions: u32,
+    executed: u32,
+    // Two per conditional branch
+    outcomes: u32,
+    outcomes_seen: u32,
+}
+
+impl Counts {
+    fn add(&mut self, other: &Counts) {
+        self.instructions += other.instructions;
+        self.executed += other.executed;
+        self.outcomes += other.outcomes;
+        self.outcomes_seen += other.outcomes_seen;
+    }
+}
+
+fn percent(part: u32, whole: u32) -> String {
+    format!("{:.0}%", 100.0 * part as f64 / whole.max(1) as f64)
+}
+
+// Branches that can go either way. `b` and `bal` are spelled as branches
+// comparing registers with themselves, which always go the same way.
+fn is_conditional_branch(word: u32) -> bool {
+    let (rs, rt) = ((word >> 21) & 0x1f, (word >> 16) & 0x1f);
+    match word >> 26 {
+        // bltz, bgez, bltzal, bgezal
+        0x01 => rs != 0 && matches!(rt, 0x00 | 0x01 | 0x10 | 0x11),
+        // beq, bne
+        0x04 | 0x05 => rs != rt,
+        // blez, bgtz
+        0x06 | 0x07 => rs != 0 && rt == 0,
+        _ => false,
+    }
+}
+
+fn branch_taken(cpu: &Cpu, word: u32) -> bool {
+    let rs = cpu.get_register(RegisterIndex((word >> 21) & 0x1f));
+    let rt = cpu.get_register(RegisterIndex((word >> 16) & 0x1f));
+    match word >> 26 {
+        // The low bit of rt picks between less than zero and at least zero
+        0x01 => ((word >> 16) & 1 == 1) == (rs as i32 >= 0),
+        0x04 => rs == rt,
+        0x05 => rs != rt,
+        0x06 => rs as i32 <= 0,
+        _ => rs as i32 > 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{cpu_with, PROGRAM};
+
+    #[test]
+    fn merges_executed_instructions_into_ranges() {
+        let mut cpu = cpu_with(&[
+            0x3c088001, // lui $t0, 0x8001
+            0x08004004, // j 0x80010010
+            0x35080080, // ori $t0, $t0, 0x80
+            0x00000000, // nop, skipped
+            0x25290001, // addiu $t1, $t1, 1
+        ]);
+        let mut coverage = Coverage::default();
+        for _ in 0..4 {
+            coverage.record(&cpu);
+            cpu.run_single_cycle().unwrap();
+        }
+        assert_eq!(
+            coverage.ranges(),
+            [PROGRAM..PROGRAM + 12, PROGRAM + 16..PROGRAM + 20]
+        );
+        let mut out = vec![];
+        coverage.write_ranges(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "0x80010000 0x8001000c\n0x80010010 0x80010014\n"
+        );
+    }
+
+    #[test]
+    fn ranges_at_the_top_of_the_address_space() {
+        let mut coverage = Coverage::default();
+        coverage.executed.extend([0, 0xfffffff8, 0xfffffffc]);
+        let mut out = vec![];
+        coverage.write_ranges(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "0x00000000 0x00000004\n0xfffffff8 0x00000000\n"
+        );
+    }
+
+    #[test]
+    fn records_branch_outcomes() {
+        // bne $t0, $zero, +4
+        const BNE: u32 = 0x15000004;
+        let mut cpu = cpu_with(&[BNE, BNE]);
+        let mut coverage = Coverage::default();
+        // The CPU can't run branches yet, only look at them
+        cpu.set_register(RegisterIndex(8), 1);
+        coverage.record(&cpu);
+        cpu.set_next_instruction(PROGRAM + 4, BNE);
+        cpu.set_register(RegisterIndex(8), 0);
+        coverage.record(&cpu);
+        coverage.record(&cpu);
+
+        let mut symbols = SymbolTable::default();
+        symbols.insert(PROGRAM, "main", Some(8));
+        let mut out = vec![];
+        coverage.write_report(&cpu, &symbols, &mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(
+            report.starts_with("2 of 2 instructions (100%) and 2 of 4 branch outcomes (50%)"),
+            "{report}"
+        );
+        assert!(
+            report.contains("0x80010000 main                     always taken"),
+            "{report}"
+        );
+        assert!(
+            report.contains("0x80010004 main+0x4                 always not taken"),
+            "{report}"
+        );
+    }
+
+    #[test]
+    fn unconditional_branches_have_one_outcome() {
+        assert!(!is_conditional_branch(0x10000004)); // b +4
+        assert!(!is_conditional_branch(0x04110004)); // bal +4
+        assert!(is_conditional_branch(0x11090004)); // beq $t0, $t1, +4
+        assert!(is_conditional_branch(0x05000004)); // bltz $t0, +4
+    }
+}
@@ -0,0 +1,377 @@
+use std::fmt;
+
+use crate::{Instruction, REGISTER_NAMES};
+
+/// One instruction decoded without executing it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodedInstruction {
+    pub raw: u32,
+    pub pc: u32,
+    /// Lowercase, e.g. `addiu`, or the pseudo-op when one applies (`li`)
+    pub mnemonic: &'static str,
+    /// Comma separated, in GNU as order, e.g. `$t0, 4($sp)`
+    pub operands: String,
+    /// Where a branch or jump with a fixed destination goes
+    pub target: Option<u32>,
+    pub flow: Flow,
+}
+
+/// How an instruction affects control flow, for code analysis. Everything
+/// other than `Sequential` has a delay slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    Sequential,
+    /// Conditional, either goes to `target` or falls through
+    Branch,
+    /// Always goes to `target`
+    Jump,
+    /// Goes to `target` and comes back after the delay slot. `bltzal` and
+    /// `bgezal` are conditional, but either way execution continues after.
+    Call,
+    /// `jr`: a return or a jump table, the target isn't known statically
+    IndirectJump,
+    /// `jalr`
+    IndirectCall,
+}
+
+impl fmt::Display for DecodedInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.operands.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{:<7} {}", self.mnemonic, self.operands)
+        }
+    }
+}
+
+/// Decode `word` as if it was fetched from `pc`. Covers the whole R3000A
+/// instruction set, including the ones the CPU can't execute yet; anything
+/// else comes back as a `.word` directive.
+pub fn disassemble(word: u32, pc: u32) -> DecodedInstruction {
+    decode(word, pc, true)
+}
+
+/// Like `disassemble`, but only uses syntax that GNU as turns back into the
+/// exact same word: no pseudo-ops other than `nop`, and `.word` for the
+/// SYSCALL/BREAK codes that don't survive a round trip.
+pub fn disassemble_exact(word: u32, pc: u32) -> DecodedInstruction {
+    decode(word, pc, false)
+}
+
+fn decode(word: u32, pc: u32, pseudo_ops: bool) -> DecodedInstruction {
+    let instr = Instruction(word);
+    let rs = reg(instr.gpr_rs().0);
+    let rt = reg(instr.gpr_rt().0);
+    let rd = reg(instr.gpr_rd().0);
+    let imm = instr.immediate();
+    let simm = imm as i16;
+    let sa = instr.sa();
+    // Branches are relative to the delay slot
+    let branch_target = pc
+        .wrapping_add(4)
+        .wrapping_add(instr.immediate_sign_extended() << 2);
+    let jump_target = (pc.wrapping_add(4) & 0xf0000000) | (instr.instr_index() << 2);
+
+    let decoded = |mnemonic, operands: String| DecodedInstruction {
+        raw: word,
+        pc,
+        mnemonic,
+        operands,
+        target: None,
+        flow: Flow::Sequential,
+    };
+    let branch = |mnemonic, operands: String, target| DecodedInstruction {
+        target: Some(target),
+        flow: Flow::Branch,
+        ..decoded(mnemonic, operands)
+    };
+    let flow = |flow, decoded: DecodedInstruction| DecodedInstruction { flow, ..decoded };
+    let unknown = || decoded(".word", format!("{word:#010x}"));
+    let has_code = (word >> 6) & 0xfffff != 0;
+
+    let op = word >> 26;
+    match op {
+        0x00 => {
+            let funct = word & 0x3f;
+            match funct {
+                0x00 if word == 0 => decoded("nop", String::new()),
+                0x00 => decoded("sll", format!("{rd}, {rt}, {sa}")),
+                0x02 => decoded("srl", format!("{rd}, {rt}, {sa}")),
+                0x03 => decoded("sra", format!("{rd}, {rt}, {sa}")),
+                0x04 => decoded("sllv", format!("{rd}, {rt}, {rs}")),
+                0x06 => decoded("srlv", format!("{rd}, {rt}, {rs}")),
+                0x07 => decoded("srav", format!("{rd}, {rt}, {rs}")),
+                0x08 => flow(Flow::IndirectJump, decoded("jr", rs.to_string())),
+                0x09 if rd == "$ra" => flow(Flow::IndirectCall, decoded("jalr", rs.to_string())),
+                0x09 => flow(Flow::IndirectCall, decoded("jalr", format!("{rd}, {rs}"))),
+                // GNU as splits the code field differently depending on the
+                // operand count
+                0x0c | 0x0d if has_code && !pseudo_ops => unknown(),
+                0x0c => decoded("syscall", code(word)),
+                0x0d => decoded("break", code(word)),
+                0x10 => decoded("mfhi", rd.to_string()),
+                0x11 => decoded("mthi", rs.to_string()),
+                0x12 => decoded("mflo", rd.to_string()),
+                0x13 => decoded("mtlo", rs.to_string()),
+                0x18 => decoded("mult", format!("{rs}, {rt}")),
+                0x19 => decoded("multu", format!("{rs}, {rt}")),
+                // Without the `$zero`, GNU as would expand these into a
+                // macro with divide-by-zero checks
+                0x1a => decoded("div", format!("$zero, {rs}, {rt}")),
+                0x1b => decoded("divu", format!("$zero, {rs}, {rt}")),
+                0x20 => decoded("add", format!("{rd}, {rs}, {rt}")),
+                0x21 | 0x25 if pseudo_ops && rt == "$zero" => {
+                    decoded("move", format!("{rd}, {rs}"))
+                }
+                0x21 | 0x25 if pseudo_ops && rs == "$zero" => {
+                    decoded("move", format!("{rd}, {rt}"))
+                }
+                0x21 => decoded("addu", format!("{rd}, {rs}, {rt}")),
+                0x22 => decoded("sub", format!("{rd}, {rs}, {rt}")),
+                0x23 => decoded("subu", format!("{rd}, {rs}, {rt}")),
+                0x24 => decoded("and", format!("{rd}, {rs}, {rt}")),
+                0x25 => decoded("or", format!("{rd}, {rs}, {rt}")),
+                0x26 => decoded("xor", format!("{rd}, {rs}, {rt}")),
+                0x27 => decoded("nor", format!("{rd}, {rs}, {rt}")),
+                0x2a => decoded("slt", format!("{rd}, {rs}, {rt}")),
+                0x2b => decoded("sltu", format!("{rd}, {rs}, {rt}")),
+                _ => unknown(),
+            }
+        }
+        // REGIMM: the kind of branch is in the rt field
+        0x01 => {
+            let mnemonic = match instr.gpr_rt().0 {
+                0x00 => "bltz",
+                0x01 => "bgez",
+                0x10 => "bltzal",
+                0x11 => "bgezal",
+                _ => return unknown(),
+            };
+            let operands = format!("{rs}, {branch_target:#010x}");
+            match mnemonic {
+                "bgez" if pseudo_ops && rs == "$zero" => {
+                    let operands = format!("{branch_target:#010x}");
+                    flow(Flow::Jump, branch("b", operands, branch_target))
+                }
+                "bgez" if rs == "$zero" => {
+                    flow(Flow::Jump, branch(mnemonic, operands, branch_target))
+                }
+                "bltzal" | "bgezal" => flow(Flow::Call, branch(mnemonic, operands, branch_target)),
+                _ => branch(mnemonic, operands, branch_target),
+            }
+        }
+        0x02 => flow(
+            Flow::Jump,
+            branch("j", format!("{jump_target:#010x}"), jump_target),
+        ),
+        0x03 => flow(
+            Flow::Call,
+            branch("jal", format!("{jump_target:#010x}"), jump_target),
+        ),
+        0x04 if pseudo_ops && rs == "$zero" && rt == "$zero" => flow(
+            Flow::Jump,
+            branch("b", format!("{branch_target:#010x}"), branch_target),
+        ),
+        // Still unconditional without the pseudo-op
+        0x04 if rs == "$zero" && rt == "$zero" => {
+            let operands = format!("{rs}, {rt}, {branch_target:#010x}");
+            flow(Flow::Jump, branch("beq", operands, branch_target))
+        }
+        0x04 => {
+            let operands = format!("{rs}, {rt}, {branch_target:#010x}");
+            branch("beq", operands, branch_target)
+        }
+        0x05 => {
+            let operands = format!("{rs}, {rt}, {branch_target:#010x}");
+            branch("bne", operands, branch_target)
+        }
+        0x06 => branch(
+            "blez",
+            format!("{rs}, {branch_target:#010x}"),
+            branch_target,
+        ),
+        0x07 => branch(
+            "bgtz",
+            format!("{rs}, {branch_target:#010x}"),
+            branch_target,
+        ),
+        0x08 => decoded("addi", format!("{rt}, {rs}, {simm}")),
+        0x09 if pseudo_ops && rs == "$zero" => decoded("li", format!("{rt}, {simm}")),
+        0x09 => decoded("addiu", format!("{rt}, {rs}, {simm}")),
+        0x0a => decoded("slti", format!("{rt}, {rs}, {simm}")),
+        0x0b => decoded("sltiu", format!("{rt}, {rs}, {simm}")),
+        0x0c => decoded("andi", format!("{rt}, {rs}, {imm:#x}")),
+        0x0d if pseudo_ops && rs == "$zero" => decoded("li", format!("{rt}, {imm:#x}")),
+        0x0d => decoded("ori", format!("{rt}, {rs}, {imm:#x}")),
+        0x0e => decoded("xori", format!("{rt}, {rs}, {imm:#x}")),
+        0x0f => decoded("lui", format!("{rt}, {imm:#x}")),
+        0x10 | 0x12 => coprocessor(word, pc, op & 3).unwrap_or_else(unknown),
+        0x20 => decoded("lb", format!("{rt}, {simm}({rs})")),
+        0x21 => decoded("lh", format!("{rt}, {simm}({rs})")),
+        0x22 => decoded("lwl", format!("{rt}, {simm}({rs})")),
+        0x23 => decoded("lw", format!("{rt}, {simm}({rs})")),
+        0x24 => decoded("lbu", format!("{rt}, {simm}({rs})")),
+        0x25 => decoded("lhu", format!("{rt}, {simm}({rs})")),
+        0x26 => decoded("lwr", format!("{rt}, {simm}({rs})")),
+        0x28 => decoded("sb", format!("{rt}, {simm}({rs})")),
+        0x29 => decoded("sh", format!("{rt}, {simm}({rs})")),
+        0x2a => decoded("swl", format!("{rt}, {simm}({rs})")),
+        0x2b => decoded("sw", format!("{rt}, {simm}({rs})")),
+        0x2e => decoded("swr", format!("{rt}, {simm}({rs})")),
+        // The GTE's data registers are numbered, not named
+        0x32 => decoded("lwc2", format!("${}, {simm}({rs})", instr.gpr_rt().0)),
+        0x3a => decoded("swc2", format!("${}, {simm}({rs})", instr.gpr_rt().0)),
+        _ => unknown(),
+    }
+}
+
+fn reg(index: u32) -> &'static str {
+    REGISTER_NAMES[index as usize]
+}
+
+// SYSCALL and BREAK carry a 20 bit code that's only shown when set
+fn code(word: u32) -> String {
+    let code = (word >> 6) & 0xfffff;
+    if code == 0 {
+        String::new()
+    } else {
+        format!("{code:#x}")
+    }
+}
+
+// COP0 (system control) and COP2 (GTE). Coprocessor registers are printed by
+// number, the way GNU as expects them.
+fn coprocessor(word: u32, pc: u32, cop: u32) -> Option<DecodedInstruction> {
+    let instr = Instruction(word);
+    let rt = reg(instr.gpr_rt().0);
+    let rd = instr.gpr_rd().0;
+    let (mnemonic, operands) = match (cop, instr.gpr_rs().0) {
+        // GTE commands put the whole command in the low 25 bits
+        (2, rs) if rs & 0x10 != 0 => ("cop2", format!("{:#x}", word & 0x1ffffff)),
+        (0, 0x10) if word & 0x3f == 0x10 => ("rfe", String::new()),
+        (0, 0x00) => ("mfc0", format!("{rt}, ${rd}")),
+        (0, 0x04) => ("mtc0", format!("{rt}, ${rd}")),
+        (2, 0x00) => ("mfc2", format!("{rt}, ${rd}")),
+        (2, 0x02) => ("cfc2", format!("{rt}, ${rd}")),
+        (2, 0x04) => ("mtc2", format!("{rt}, ${rd}")),
+        (2, 0x06) => ("ctc2", format!("{rt}, ${rd}")),
+        _ => return None,
+    };
+    Some(DecodedInstruction {
+        raw: word,
+        pc,
+        mnemonic,
+        operands,
+        target: None,
+        flow: Flow::Sequential,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PC: u32 = 0x80010000;
+
+    fn asm(word: u32) -> String {
+        disassemble(word, PC).to_string()
+    }
+
+    fn exact(word: u32) -> String {
+        disassemble_exact(word, PC).to_string()
+    }
+
+    #[test]
+    fn pseudo_ops() {
+        assert_eq!(asm(0), "nop");
+        assert_eq!(exact(0), "nop");
+        // addu $t0, $t1, $zero and or $t0, $zero, $t1
+        assert_eq!(asm(0x01204021), "move    $t0, $t1");
+        assert_eq!(exact(0x01204021), "addu    $t0, $t1, $zero");
+        assert_eq!(asm(0x00094025), "move    $t0, $t1");
+        assert_eq!(exact(0x00094025), "or      $t0, $zero, $t1");
+        // addiu sign extends, ori doesn't
+        assert_eq!(asm(0x2408ffff), "li      $t0, -1");
+        assert_eq!(exact(0x2408ffff), "addiu   $t0, $zero, -1");
+        assert_eq!(asm(0x34088000), "li      $t0, 0x8000");
+        assert_eq!(exact(0x34088000), "ori     $t0, $zero, 0x8000");
+        assert_eq!(asm(0x01094021), "addu    $t0, $t0, $t1");
+    }
+
+    #[test]
+    fn unconditional_branches() {
+        // beq $zero, $zero and bgez $zero
+        for (word, target, mnemonic) in [
+            (0x10000003, 0x80010010, "beq     $zero, $zero, "),
+            (0x0401fffe, 0x8000fffc, "bgez    $zero, "),
+        ] {
+            let pseudo = disassemble(word, PC);
+            assert_eq!(pseudo.to_string(), format!("b       {target:#010x}"));
+            assert_eq!((pseudo.target, pseudo.flow), (Some(target), Flow::Jump));
+            let exact = disassemble_exact(word, PC);
+            assert_eq!(exact.to_string(), format!("{mnemonic}{target:#010x}"));
+            assert_eq!((exact.target, exact.flow), (Some(target), Flow::Jump));
+        }
+
+        let beq = disassemble(0x11090002, PC);
+        assert_eq!(beq.to_string(), "beq     $t0, $t1, 0x8001000c");
+        assert_eq!((beq.target, beq.flow), (Some(0x8001000c), Flow::Branch));
+        let bltzal = disassemble(0x0510ffff, PC);
+        assert_eq!(bltzal.to_string(), "bltzal  $t0, 0x80010000");
+        assert_eq!(bltzal.flow, Flow::Call);
+    }
+
+    #[test]
+    fn jump_targets_keep_the_delay_slot_segment() {
+        // j 0x00010000
+        let j = |pc| disassemble(0x08004000, pc);
+        assert_eq!(j(0x80000000).target, Some(0x80010000));
+        assert_eq!(j(0xbfc00010).target, Some(0xb0010000));
+        // The delay slot is in the next segment
+        assert_eq!(j(0x8ffffffc).target, Some(0x90010000));
+        assert_eq!(j(0x80000000).to_string(), "j       0x80010000");
+        assert_eq!(j(0x80000000).flow, Flow::Jump);
+
+        let jal = disassemble(0x0c004000, PC);
+        assert_eq!((jal.target, jal.flow), (Some(0x80010000), Flow::Call));
+        let jr = disassemble(0x03e00008, PC);
+        assert_eq!(jr.to_string(), "jr      $ra");
+        assert_eq!((jr.target, jr.flow), (None, Flow::IndirectJump));
+        let jalr = disassemble(0x0100f809, PC);
+        assert_eq!(jalr.to_string(), "jalr    $t0");
+        assert_eq!(jalr.flow, Flow::IndirectCall);
+    }
+
+    #[test]
+    fn coprocessors() {
+        assert_eq!(asm(0x40086000), "mfc0    $t0, $12");
+        assert_eq!(asm(0x40886000), "mtc0    $t0, $12");
+        assert_eq!(asm(0x42000010), "rfe");
+        assert_eq!(asm(0x48080800), "mfc2    $t0, $1");
+        assert_eq!(asm(0x48c80800), "ctc2    $t0, $1");
+        assert_eq!(asm(0x4a180001), "cop2    0x180001");
+        assert_eq!(asm(0xc9010004), "lwc2    $1, 4($t0)");
+        // COP1 and COP3 don't exist
+        assert_eq!(asm(0x44080800), ".word   0x44080800");
+        assert_eq!(asm(0x4c080800), ".word   0x4c080800");
+    }
+
+    #[test]
+    fn exact_mode_only_uses_nop() {
+        let words = [
+            0, 0x01204021, 0x00094025, 0x2408ffff, 0x34088000, 0x10000003, 0x0401fffe, 0x11090002,
+            0x0000000c, 0x0000000d,
+        ];
+        for word in words {
+            let decoded = disassemble_exact(word, PC);
+            assert!(
+                !["move", "li", "b"].contains(&decoded.mnemonic),
+                "{word:#010x} is {decoded}"
+            );
+        }
+        // Codes that GNU as would re-encode differently
+        assert_eq!(asm(0x0000004c), "syscall 0x1");
+        assert_eq!(exact(0x0000004c), ".word   0x0000004c");
+        assert_eq!(exact(0x0000000c), "syscall");
+    }
+}
@@ -0,0 +1,90 @@
+use crate::PsemuCoreError;
+
+const MAGIC: &[u8] = b"PS-X EXE";
+// The code and data start right after the header
+const HEADER_LEN: usize = 0x800;
+
+/// A PlayStation executable, as found on discs and in homebrew toolchains
+pub struct PsxExe {
+    /// Initial program counter
+    pub pc: u32,
+    /// Initial `$gp`
+    pub gp: u32,
+    /// Where `data` gets copied to in RAM
+    pub load_addr: u32,
+    /// Initial `$sp`, or 0 to keep the one the BIOS set up
+    pub sp: u32,
+    pub data: Vec<u8>,
+}
+
+impl PsxExe {
+    pub fn parse(bytes: &[u8]) -> Result<Self, PsemuCoreError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(PsemuCoreError::InvalidExe);
+        }
+        if bytes.len() < HEADER_LEN {
+            return Err(PsemuCoreError::TruncatedExe);
+        }
+        let word =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let size = word(0x1c) as usize;
+        let data = bytes
+            .get(HEADER_LEN..HEADER_LEN + size)
+            .ok_or(PsemuCoreError::TruncatedExe)?;
+        let load_addr = word(0x18);
+        if load_addr.checked_add(size as u32).is_none() {
+            return Err(PsemuCoreError::ExeLoadAddress);
+        }
+        Ok(PsxExe {
+            pc: word(0x10),
+            gp: word(0x14),
+            load_addr,
+            sp: word(0x30).wrapping_add(word(0x34)),
+            data: data.to_vec(),
+        })
+    }
+
+    pub fn is_exe(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exe(load_addr: u32, data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0; HEADER_LEN];
+        bytes[..MAGIC.len()].copy_from_slice(MAGIC);
+        bytes[0x10..0x14].copy_from_slice(&load_addr.to_le_bytes());
+        bytes[0x18..0x1c].copy_from_slice(&load_addr.to_le_bytes());
+        bytes[0x1c..0x20].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend(data);
+        bytes
+    }
+
+    #[test]
+    fn parses_the_header() {
+        let exe = PsxExe::parse(&exe(0x80010000, &[1, 2, 3, 4])).unwrap();
+        assert_eq!((exe.pc, exe.load_addr), (0x80010000, 0x80010000));
+        assert_eq!(exe.data, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert!(matches!(
+            PsxExe::parse(b"ELF"),
+            Err(PsemuCoreError::InvalidExe)
+        ));
+        let mut truncated = exe(0x80010000, &[0; 8]);
+        truncated.truncate(HEADER_LEN + 4);
+        assert!(matches!(
+            PsxExe::parse(&truncated),
+            Err(PsemuCoreError::TruncatedExe)
+        ));
+        assert!(matches!(
+            PsxExe::parse(&exe(0xfffffffc, &[0; 8])),
+            Err(PsemuCoreError::ExeLoadAddress)
+        ));
+    }
+}
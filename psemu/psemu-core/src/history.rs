@@ -0,0 +1,140 @@
+use std::{
+    collections::{vec_deque, VecDeque},
+    fmt::Write as _,
+    io::{self, Write},
+};
+
+use crate::{disassemble, InstructionForDebugger, REGISTER_NAMES};
+
+/// How many instructions are remembered unless told otherwise
+pub const DEFAULT_HISTORY_CAPACITY: usize = 100_000;
+
+/// A general purpose register changed by an instruction
+#[derive(Clone, Copy, Debug)]
+pub struct RegisterWrite {
+    pub index: u8,
+    pub old: u32,
+    pub new: u32,
+}
+
+/// The last `capacity` instructions the CPU executed, oldest first
+pub struct InstructionHistory {
+    entries: VecDeque<InstructionForDebugger>,
+    capacity: usize,
+}
+
+impl Default for InstructionHistory {
+    fn default() -> Self {
+        InstructionHistory::with_capacity(DEFAULT_HISTORY_CAPACITY)
+    }
+}
+
+impl InstructionHistory {
+    /// A capacity of 0 turns recording off
+    pub fn with_capacity(capacity: usize) -> Self {
+        InstructionHistory {
+            entries: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Drops the oldest entries if there are more than `capacity`
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Whether anything would be kept; lets the CPU skip building entries
+    pub fn is_recording(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn push(&mut self, entry: InstructionForDebugger) {
+        if !self.is_recording() {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn iter(&self) -> vec_deque::Iter<'_, InstructionForDebugger> {
+        self.entries.iter()
+    }
+
+    pub fn last(&self) -> Option<&InstructionForDebugger> {
+        self.entries.back()
+    }
+
+    /// Forget instructions executed at or after `cycle`, e.g. after going back
+    /// in time
+    pub fn truncate_from(&mut self, cycle: u64) {
+        while matches!(self.entries.back(), Some(e) if e.cycle >= cycle) {
+            self.entries.pop_back();
+        }
+    }
+
+    /// One line per instruction: cycle, PC, raw word, disassembly, then the
+    /// registers and memory it changed or read
+    pub fn export(&self, w: &mut impl Write) -> io::Result<()> {
+        let mut line = String::new();
+        for entry in &self.entries {
+            line.clear();
+            let instr = disassemble(entry.raw, entry.pc);
+            write!(
+                line,
+                "{:>10} {:08x} {:08x} {:<32}",
+                entry.cycle,
+                entry.pc,
+                entry.raw,
+                instr.to_string()
+            )
+            .unwrap();
+            for write in &entry.registers {
+                let name = REGISTER_NAMES[write.index as usize];
+                write!(line, " {name}={:08x}(was {:08x})", write.new, write.old).unwrap();
+            }
+            for access in &entry.memory {
+                write!(
+                    line,
+                    " {}{}[{:08x}]={:08x}",
+                    access.kind, access.size, access.addr, access.new
+                )
+                .unwrap();
+                if access.old != access.new {
+                    write!(line, "(was {:08x})", access.old).unwrap();
+                }
+            }
+            writeln!(w, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> IntoIterator for &'a InstructionHistory {
+    type Item = &'a InstructionForDebugger;
+    type IntoIter = vec_deque::Iter<'a, InstructionForDebugger>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
@@ -0,0 +1,608 @@
+use std::{cmp::Reverse, collections::HashMap, mem::offset_of, ptr, rc::Rc};
+
+use crate::{
+    block_cache::{Block, MicroOp, PAGE_SHIFT},
+    mask_region,
+    x86::{
+        Alu, Assembler, Cond, Fixup, Mem, Reg, R10, R12, R13, R14, R15, R8, R9, RAX, RBP, RBX, RCX,
+        RDI, RDX, RSI, RSP,
+    },
+    Cpu, PsemuCoreError, RAM_ADDR_RANGE, SCRATCHPAD_ADDR_RANGE,
+};
+
+// Executable memory for compiled blocks. When it fills up, everything is
+// thrown away and recompiled as it's reached again.
+const CODE_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+// One flag per page of RAM, then one for the whole scratchpad
+const SCRATCHPAD_PAGE: usize = (RAM_ADDR_RANGE.last_addr >> PAGE_SHIFT) as usize;
+const CODE_PAGE_COUNT: usize = SCRATCHPAD_PAGE + 1;
+// Guest registers used most in a block live in these for the whole block.
+// All callee-saved, so calls into Rust don't clobber them.
+const HOST_REGISTERS: [Reg; 5] = [RBP, R12, R13, R14, R15];
+// Passed to `store_word` when the store isn't the last instruction. Never a
+// real fetch address since those are aligned.
+const NO_PREFETCH: u32 = 1;
+
+type BlockFn = unsafe extern "sysv64" fn(*mut Context) -> u64;
+
+// What compiled code gets in rdi. The pointers all come from the same
+// `*mut Cpu`, nothing else touches the CPU while a block runs.
+#[repr(C)]
+struct Context {
+    registers: *mut u32,
+    ram: *mut u8,
+    scratchpad: *mut u8,
+    code_pages: *const u8,
+    cpu: *mut Cpu,
+    // Set when the last instruction is a store, which has to see the word
+    // the interpreter would have prefetched before it ran
+    prefetch: Option<u32>,
+    // Why the block stopped at a store that couldn't be done
+    error: Option<String>,
+}
+
+struct CompiledBlock {
+    pc: u32,
+    raw: Vec<u32>,
+    target: Option<u32>,
+    entry: BlockFn,
+}
+
+// Append-only; compiled code is never freed while a block might be running.
+// Only writable while a block is being copied in, never writable and
+// executable at once.
+struct CodeBuffer {
+    ptr: *mut u8,
+    used: usize,
+    page_size: usize,
+}
+
+impl CodeBuffer {
+    fn new() -> Self {
+        // SAFETY: a fresh anonymous mapping, nothing else refers to it
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                CODE_BUFFER_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            panic!(
+                "Unable to map memory for the recompiler: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        // SAFETY: no preconditions
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        CodeBuffer {
+            ptr: ptr.cast(),
+            used: 0,
+            page_size,
+        }
+    }
+
+    // Changes the protection of the pages holding `len` bytes at `offset`
+    fn protect(&mut self, offset: usize, len: usize, prot: libc::c_int) {
+        let start = offset / self.page_size * self.page_size;
+        let end = (offset + len).next_multiple_of(self.page_size);
+        // SAFETY: the range is page aligned and inside the mapping, since
+        // its size is a multiple of the page size
+        let res = unsafe { libc::mprotect(self.ptr.add(start).cast(), end - start, prot) };
+        if res != 0 {
+            panic!(
+                "Unable to protect memory for the recompiler: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+
+    // None if it doesn't fit
+    fn push(&mut self, code: &[u8]) -> Option<BlockFn> {
+        if CODE_BUFFER_SIZE - self.used < code.len() {
+            return None;
+        }
+        let offset = self.used;
+        self.protect(offset, code.len(), libc::PROT_READ | libc::PROT_WRITE);
+        // SAFETY: in bounds, and past everything handed out so far
+        let dst = unsafe {
+            let dst = self.ptr.add(offset);
+            ptr::copy_nonoverlapping(code.as_ptr(), dst, code.len());
+            dst
+        };
+        self.protect(offset, code.len(), libc::PROT_READ | libc::PROT_EXEC);
+        self.used += code.len();
+        // SAFETY: the code was generated as a `BlockFn`
+        Some(unsafe { std::mem::transmute::<*mut u8, BlockFn>(dst) })
+    }
+}
+
+impl Drop for CodeBuffer {
+    fn drop(&mut self) {
+        // SAFETY: mapped in `new` with this size
+        unsafe {
+            libc::munmap(self.ptr.cast(), CODE_BUFFER_SIZE);
+        }
+    }
+}
+
+/// Blocks translated to x86-64, by physical address
+pub struct CompiledBlocks {
+    // Mapped the first time something is compiled
+    code: Option<CodeBuffer>,
+    blocks: HashMap<u32, Rc<CompiledBlock>>,
+    // Non-zero for pages holding compiled code. Read by compiled code to
+    // decide whether a store can skip invalidation.
+    code_pages: Box<[u8]>,
+}
+
+impl Default for CompiledBlocks {
+    fn default() -> Self {
+        CompiledBlocks {
+            code: None,
+            blocks: HashMap::new(),
+            code_pages: vec![0; CODE_PAGE_COUNT].into_boxed_slice(),
+        }
+    }
+}
+
+impl CompiledBlocks {
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.code_pages.fill(0);
+        if let Some(code) = &mut self.code {
+            code.used = 0;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Forget the blocks that might include `addr`. True if there were any.
+    pub(crate) fn invalidate(&mut self, addr: u32) -> bool {
+        let paddr = mask_region(addr);
+        match code_page(paddr) {
+            Some(page) if self.code_pages[page] != 0 => self.invalidate_range(addr, 1),
+            _ => false,
+        }
+    }
+
+    /// Forget the blocks overlapping `len` bytes from `addr`. True if there
+    /// were any.
+    pub(crate) fn invalidate_range(&mut self, addr: u32, len: u32) -> bool {
+        let (first, last) = (mask_region(addr), mask_region(addr).saturating_add(len));
+        let before = self.blocks.len();
+        self.blocks.retain(|start, block| {
+            let end = start + block.raw.len() as u32 * 4;
+            end <= first || *start >= last
+        });
+        self.code_pages.fill(0);
+        for (start, block) in &self.blocks {
+            mark_code_pages(&mut self.code_pages, *start, block.raw.len());
+        }
+        self.blocks.len() != before
+    }
+}
+
+fn code_page(paddr: u32) -> Option<usize> {
+    if RAM_ADDR_RANGE.contains(paddr) {
+        Some((paddr >> PAGE_SHIFT) as usize)
+    } else if SCRATCHPAD_ADDR_RANGE.contains(paddr) {
+        Some(SCRATCHPAD_PAGE)
+    } else {
+        None
+    }
+}
+
+fn mark_code_pages(code_pages: &mut [u8], start: u32, len: usize) {
+    // Blocks are short enough to span two pages at most
+    let last = start + (len as u32 - 1) * 4;
+    for paddr in [start, last] {
+        if let Some(page) = code_page(paddr) {
+            code_pages[page] = 1;
+        }
+    }
+}
+
+type StoreFn = unsafe extern "sysv64" fn(*mut Context, u32, u32, u32) -> u32;
+
+// Called by compiled code for stores the fast path can't do. Returns
+// non-zero if the block has to stop: it wrote over compiled code, or failed.
+unsafe extern "sysv64" fn store_word(ctx: *mut Context, addr: u32, val: u32, prefetch: u32) -> u32 {
+    // SAFETY: `execute_compiled` passes a live context and nothing else
+    // holds on to the CPU while the block runs
+    let ctx = unsafe { &mut *ctx };
+    let cpu = unsafe { &mut *ctx.cpu };
+    if prefetch != NO_PREFETCH {
+        match cpu.load32(prefetch) {
+            Ok(word) => ctx.prefetch = Some(word),
+            Err(e) => {
+                ctx.error = Some(e);
+                return 1;
+            }
+        }
+    }
+    cpu.interconnect.accesses.clear();
+    match cpu.interconnect.store32(addr, val) {
+        Ok(()) => cpu.invalidate_code_at(addr) as u32,
+        Err(e) => {
+            ctx.error = Some(e);
+            1
+        }
+    }
+}
+
+// (registers read, register written)
+fn operands(op: MicroOp) -> ([Option<u8>; 2], Option<u8>) {
+    match op {
+        MicroOp::Lui { rt, .. } => ([None, None], Some(rt)),
+        MicroOp::Ori { rt, rs, .. } | MicroOp::Addiu { rt, rs, .. } => ([Some(rs), None], Some(rt)),
+        MicroOp::Sw { rt, base, .. } => ([Some(rt), Some(base)], None),
+        MicroOp::Sll { rd, rt, .. } => ([Some(rt), None], Some(rd)),
+        MicroOp::Or { rd, rs, rt } => ([Some(rs), Some(rt)], Some(rd)),
+        MicroOp::J { .. } => ([None, None], None),
+    }
+}
+
+struct Translator {
+    asm: Assembler,
+    // Host register holding each guest register, if any
+    allocated: [Option<Reg>; 32],
+    // Jumps to the epilogue, with the instruction count in eax
+    exits: Vec<Fixup>,
+}
+
+impl Translator {
+    fn new(block: &Block) -> Self {
+        let mut uses = [0u32; 32];
+        for op in &block.ops {
+            let (reads, write) = operands(*op);
+            for r in reads.into_iter().chain([write]).flatten() {
+                uses[r as usize] += 1;
+            }
+        }
+        // $zero is an immediate, and a register used once isn't worth a
+        // load and a write-back
+        let mut candidates: Vec<usize> = (1..32).filter(|r| uses[*r] >= 2).collect();
+        candidates.sort_by_key(|r| Reverse(uses[*r]));
+        let mut allocated = [None; 32];
+        for (guest, host) in candidates.into_iter().zip(HOST_REGISTERS) {
+            allocated[guest] = Some(host);
+        }
+        Translator {
+            asm: Assembler::default(),
+            allocated,
+            exits: vec![],
+        }
+    }
+
+    fn guest(r: u8) -> Mem {
+        Mem::base(RBX, r as i32 * 4)
+    }
+
+    fn read(&mut self, dst: Reg, r: u8) {
+        match (r, self.allocated[r as usize]) {
+            (0, _) => self.asm.mov32_imm(dst, 0),
+            (_, Some(host)) => self.asm.mov32(dst, host),
+            (_, None) => self.asm.load32(dst, Self::guest(r)),
+        }
+    }
+
+    fn write(&mut self, r: u8, src: Reg) {
+        match (r, self.allocated[r as usize]) {
+            (0, _) => (),
+            (_, Some(host)) => self.asm.mov32(host, src),
+            (_, None) => self.asm.store32(Self::guest(r), src),
+        }
+    }
+
+    fn exit(&mut self, count: usize) {
+        self.asm.mov32_imm(RAX, count as u32);
+        let fixup = self.asm.jump();
+        self.exits.push(fixup);
+    }
+
+    fn prologue(&mut self) {
+        for reg in [RBX, RBP, R12, R13, R14, R15] {
+            self.asm.push(reg);
+        }
+        // Keeps the stack 16-byte aligned for calls, and holds the context
+        self.asm.alu64_imm(Alu::Sub, RSP, 8);
+        self.asm.store64(Mem::base(RSP, 0), RDI);
+        self.asm
+            .load64(RBX, Mem::base(RDI, offset_of!(Context, registers) as i32));
+        for (r, host) in self.allocated.into_iter().enumerate() {
+            if let Some(host) = host {
+                self.asm.load32(host, Self::guest(r as u8));
+            }
+        }
+    }
+
+    fn epilogue(&mut self, written: &[bool; 32]) {
+        for fixup in std::mem::take(&mut self.exits) {
+            self.asm.bind(fixup);
+        }
+        for (r, host) in self.allocated.into_iter().enumerate() {
+            if let (Some(host), true) = (host, written[r]) {
+                self.asm.store32(Self::guest(r as u8), host);
+            }
+        }
+        self.asm.alu64_imm(Alu::Add, RSP, 8);
+        for reg in [R15, R14, R13, R12, RBP, RBX] {
+            self.asm.pop(reg);
+        }
+        self.asm.ret();
+    }
+
+    fn op(&mut self, op: MicroOp, index: usize, prefetch: Option<u32>) {
+        match op {
+            MicroOp::Lui { rt, val } => {
+                self.asm.mov32_imm(RAX, val);
+                self.write(rt, RAX);
+            }
+            MicroOp::Ori { rt, rs, imm } => {
+                self.read(RAX, rs);
+                self.asm.alu32_imm(Alu::Or, RAX, imm);
+                self.write(rt, RAX);
+            }
+            MicroOp::Addiu { rt, rs, imm } => {
+                self.read(RAX, rs);
+                self.asm.alu32_imm(Alu::Add, RAX, imm);
+                self.write(rt, RAX);
+            }
+            MicroOp::Sll { rd, rt, sa } => {
+                self.read(RAX, rt);
+                self.asm.shl32(RAX, sa);
+                self.write(rd, RAX);
+            }
+            MicroOp::Or { rd, rs, rt } => {
+                self.read(RAX, rs);
+                self.read(RCX, rt);
+                self.asm.or32(RAX, RCX);
+                self.write(rd, RAX);
+            }
+            MicroOp::Sw { rt, base, offset } => {
+                self.read(RCX, rt);
+                self.read(RAX, base);
+                self.asm.alu32_imm(Alu::Add, RAX, offset);
+                self.store_word(index, prefetch);
+            }
+            // Taken care of by the block's target
+            MicroOp::J { .. } => (),
+        }
+    }
+
+    // Address in eax, value in ecx
+    fn store_word(&mut self, index: usize, prefetch: Option<u32>) {
+        let mut slow = vec![];
+        let mut done = vec![];
+        // The last instruction always goes through Rust to get the prefetch
+        // right
+        if prefetch.is_none() {
+            let asm = &mut self.asm;
+            asm.test32_imm(RAX, 3);
+            slow.push(asm.jump_if(Cond::NotEqual));
+            // Physical address in edx: KUSEG is untouched, KSEG0 and KSEG1
+            // drop the top 3 bits, KSEG2 is never RAM
+            asm.mov32(RDX, RAX);
+            asm.alu32_imm(Alu::Cmp, RAX, 0x80000000);
+            let kuseg = asm.jump_if(Cond::Below);
+            asm.alu32_imm(Alu::Cmp, RAX, 0xc0000000);
+            slow.push(asm.jump_if(Cond::AboveEqual));
+            asm.alu32_imm(Alu::And, RDX, 0x1fffffff);
+            asm.bind(kuseg);
+            asm.load64(R8, Mem::base(RSP, 0));
+            asm.load64(R9, Mem::base(R8, offset_of!(Context, code_pages) as i32));
+
+            asm.alu32_imm(Alu::Cmp, RDX, RAM_ADDR_RANGE.last_addr);
+            let not_ram = asm.jump_if(Cond::AboveEqual);
+            asm.mov32(R10, RDX);
+            asm.shr32(R10, PAGE_SHIFT as u8);
+            asm.cmp8_imm(Mem::indexed(R9, R10, 0), 0);
+            slow.push(asm.jump_if(Cond::NotEqual));
+            asm.load64(R9, Mem::base(R8, offset_of!(Context, ram) as i32));
+            asm.store32(Mem::indexed(R9, RDX, 0), RCX);
+            done.push(asm.jump());
+
+            asm.bind(not_ram);
+            asm.mov32(R10, RDX);
+            asm.alu32_imm(Alu::Sub, R10, SCRATCHPAD_ADDR_RANGE.starting_addr);
+            asm.alu32_imm(
+                Alu::Cmp,
+                R10,
+                SCRATCHPAD_ADDR_RANGE.last_addr - SCRATCHPAD_ADDR_RANGE.starting_addr,
+            );
+            slow.push(asm.jump_if(Cond::AboveEqual));
+            asm.cmp8_imm(Mem::base(R9, SCRATCHPAD_PAGE as i32), 0);
+            slow.push(asm.jump_if(Cond::NotEqual));
+            asm.load64(R9, Mem::base(R8, offset_of!(Context, scratchpad) as i32));
+            asm.store32(Mem::indexed(R9, R10, 0), RCX);
+            done.push(asm.jump());
+        }
+
+        for fixup in slow {
+            self.asm.bind(fixup);
+        }
+        let asm = &mut self.asm;
+        asm.load64(RDI, Mem::base(RSP, 0));
+        asm.mov32(RSI, RAX);
+        asm.mov32(RDX, RCX);
+        asm.mov32_imm(RCX, prefetch.unwrap_or(NO_PREFETCH));
+        asm.mov64_imm(RAX, store_word as StoreFn as usize as u64);
+        asm.call(RAX);
+        asm.test32(RAX, RAX);
+        let ok = asm.jump_if(Cond::Equal);
+        self.exit(index + 1);
+        self.asm.bind(ok);
+        for fixup in done {
+            self.asm.bind(fixup);
+        }
+    }
+}
+
+fn translate(block: &Block) -> Vec<u8> {
+    let mut t = Translator::new(block);
+    let mut written = [false; 32];
+    t.prologue();
+    let last = block.ops.len() - 1;
+    for (i, op) in block.ops.iter().enumerate() {
+        if let (_, Some(r)) = operands(*op) {
+            written[r as usize] = true;
+        }
+        let prefetch = (i == last).then(|| {
+            block
+                .target
+                .unwrap_or(block.pc.wrapping_add(block.ops.len() as u32 * 4))
+        });
+        t.op(*op, i, prefetch);
+    }
+    t.exit(block.ops.len());
+    t.epilogue(&written);
+    t.asm.code
+}
+
+impl Cpu {
+    /// Run the next basic block as native code, compiling it first if
+    /// needed. Returns how many instructions ran. Like `run_block`, falls
+    /// back to `run_single_cycle` when recording history, and also when
+    /// there are watchpoints since compiled code doesn't check them.
+    pub fn run_recompiled(&mut self) -> Result<u64, PsemuCoreError> {
+        if self.instruction_history.is_recording() || !self.interconnect.watchpoints.is_empty() {
+            return self.run_single_cycle().map(|_| 1);
+        }
+        // Compiled stores only keep compiled blocks up to date
+        if !self.block_cache.is_empty() {
+            self.block_cache.clear();
+        }
+        let pc = self.next_instruction_pc;
+        if self.pc != pc.wrapping_add(4) {
+            return self.run_single_cycle().map(|_| 1);
+        }
+        let paddr = mask_region(pc);
+        let compiled = self
+            .compiled_blocks
+            .blocks
+            .get(&paddr)
+            .filter(|b| b.pc == pc);
+        let block = match compiled {
+            Some(block) => block.clone(),
+            None => {
+                // Anything the block cache can't decode, e.g. COP0 and GTE
+                // instructions once they exist, goes to the interpreter
+                let Some(block) = self.decode_block(pc) else {
+                    return self.run_single_cycle().map(|_| 1);
+                };
+                self.compile(paddr, &block)
+            }
+        };
+        // Compiled code can't stop before running the last instruction when
+        // the next one can't be fetched, so those blocks are interpreted
+        let len = block.raw.len() as u32;
+        let next = block.target.unwrap_or(pc.wrapping_add(len * 4));
+        if self.load32(next).is_err() {
+            let block = self
+                .decode_block(pc)
+                .expect("Compiled blocks can be decoded");
+            return self.execute_block(&block);
+        }
+        self.execute_compiled(&block)
+    }
+
+    /// Blocks compiled by `run_recompiled`
+    pub fn compiled_blocks(&self) -> &CompiledBlocks {
+        &self.compiled_blocks
+    }
+
+    fn compile(&mut self, paddr: u32, block: &Block) -> Rc<CompiledBlock> {
+        let code = translate(block);
+        let jit = &mut self.compiled_blocks;
+        let buffer = jit.code.get_or_insert_with(CodeBuffer::new);
+        let entry = match buffer.push(&code) {
+            Some(entry) => entry,
+            None => {
+                // Nothing is running, so it's safe to start over
+                jit.clear();
+                let buffer = jit.code.as_mut().unwrap();
+                buffer
+                    .push(&code)
+                    .expect("Block larger than the code buffer")
+            }
+        };
+        let compiled = Rc::new(CompiledBlock {
+            pc: block.pc,
+            raw: block.raw.clone(),
+            target: block.target,
+            entry,
+        });
+        mark_code_pages(&mut jit.code_pages, paddr, block.raw.len());
+        jit.blocks.insert(paddr, compiled.clone());
+        compiled
+    }
+
+    // Same bookkeeping as `execute_block`, after the fact
+    fn execute_compiled(&mut self, block: &CompiledBlock) -> Result<u64, PsemuCoreError> {
+        let cpu: *mut Cpu = self;
+        // SAFETY: the pointers stay valid for the whole call: RAM and the
+        // scratchpad are never resized, and `code_pages` is boxed
+        let mut ctx = unsafe {
+            Context {
+                registers: ptr::addr_of_mut!((*cpu).registers).cast(),
+                ram: (*cpu).interconnect.ram.data.as_mut_ptr(),
+                scratchpad: (*cpu).interconnect.scratchpad.data.as_mut_ptr(),
+                code_pages: (*cpu).compiled_blocks.code_pages.as_ptr(),
+                cpu,
+                prefetch: None,
+                error: None,
+            }
+        };
+        // SAFETY: `entry` was generated for this context layout, and the
+        // code buffer isn't reset until the next compile
+        let count = unsafe { (block.entry)(&mut ctx) };
+        if let Some(error) = ctx.error {
+            // `count` includes the store that failed
+            let i = count as usize - 1;
+            self.cycles += i as u64;
+            return Err(self.stop_block_at(block.pc, &block.raw, block.target, i, error));
+        }
+        self.cycles += count;
+
+        let len = block.raw.len() as u64;
+        let (next, word) = if count == len {
+            let next = block
+                .target
+                .unwrap_or(block.pc.wrapping_add(len as u32 * 4));
+            let word = match ctx.prefetch {
+                Some(word) => word,
+                None => self.load32(next).expect("Checked by `run_recompiled`"),
+            };
+            (next, word)
+        } else {
+            // Stopped after writing to compiled code, which never happens
+            // after the jump
+            let next = block.pc.wrapping_add(count as u32 * 4);
+            (next, block.raw[count as usize])
+        };
+        self.set_next_instruction(next, word);
+        self.pc = next.wrapping_add(4);
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{backend::Recompiler, test_util::*};
+
+    #[test]
+    fn loops_are_compiled_once() {
+        let cpu = assert_same_as(&mut Recompiler, &LOOP, 1000);
+        assert_eq!(cpu.compiled_blocks().len(), 2);
+    }
+}
@@ -0,0 +1,263 @@
+//! What's known about the BIOS kernel's layout, for annotating disassembly
+//! and traces. Names follow the nocash PSX specs.
+
+use crate::{mask_region, Cpu, RegisterIndex};
+
+/// Fixed addresses the hardware or the kernel jumps to
+pub const BIOS_ENTRY_POINTS: [(u32, &str); 6] = [
+    (0xbfc00000, "reset"),
+    // Used while BEV is set in COP0's status register, i.e. during boot
+    (0xbfc00180, "boot_exception_vector"),
+    (0x80000080, "exception_vector"),
+    // Programs call into the kernel by jumping to one of these with the
+    // function number in $t1
+    (0x000000a0, "kernel_a0_dispatch"),
+    (0x000000b0, "kernel_b0_dispatch"),
+    (0x000000c0, "kernel_c0_dispatch"),
+];
+
+pub fn entry_point_name(addr: u32) -> Option<&'static str> {
+    BIOS_ENTRY_POINTS
+        .iter()
+        .find(|(a, _)| *a == addr)
+        .map(|(_, name)| *name)
+}
+
+/// A call into one of the kernel's function tables
+pub struct KernelCall {
+    /// 0xa0, 0xb0 or 0xc0
+    pub table: u32,
+    pub function: u32,
+    pub name: Option<&'static str>,
+    /// Where the call returns to
+    pub return_addr: u32,
+}
+
+impl KernelCall {
+    /// The call being made, if the next instruction `cpu` runs is the entry
+    /// of a dispatch table
+    pub fn at(cpu: &Cpu) -> Option<Self> {
+        const T1: RegisterIndex = RegisterIndex(9);
+        const RA: RegisterIndex = RegisterIndex(31);
+        let table = mask_region(cpu.next_instruction_pc());
+        if !matches!(table, 0xa0 | 0xb0 | 0xc0) {
+            return None;
+        }
+        let function = cpu.get_register(T1);
+        Some(KernelCall {
+            table,
+            function,
+            name: kernel_call_name(table, function),
+            return_addr: cpu.get_register(RA),
+        })
+    }
+}
+
+impl std::fmt::Display for KernelCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:X}:{:02X} {}",
+            self.table,
+            self.function,
+            self.name.unwrap_or("unknown")
+        )
+    }
+}
+
+/// Name of kernel function `function` in the table reached through `table`
+/// (0xa0, 0xb0 or 0xc0)
+pub fn kernel_call_name(table: u32, function: u32) -> Option<&'static str> {
+    let names: &[(u32, &str)] = match table {
+        0xa0 => &A0_FUNCTIONS,
+        0xb0 => &B0_FUNCTIONS,
+        0xc0 => &C0_FUNCTIONS,
+        _ => return None,
+    };
+    names
+        .iter()
+        .find(|(f, _)| *f == function)
+        .map(|(_, name)| *name)
+}
+
+const A0_FUNCTIONS: [(u32, &str); 79] = [
+    (0x00, "FileOpen"),
+    (0x01, "FileSeek"),
+    (0x02, "FileRead"),
+    (0x03, "FileWrite"),
+    (0x04, "FileClose"),
+    (0x05, "FileIoctl"),
+    (0x06, "exit"),
+    (0x07, "FileGetDeviceFlag"),
+    (0x08, "FileGetc"),
+    (0x09, "FilePutc"),
+    (0x0a, "todigit"),
+    (0x0b, "atof"),
+    (0x0c, "strtoul"),
+    (0x0d, "strtol"),
+    (0x0e, "abs"),
+    (0x0f, "labs"),
+    (0x10, "atoi"),
+    (0x11, "atol"),
+    (0x12, "atob"),
+    (0x13, "SaveState"),
+    (0x14, "RestoreState"),
+    (0x15, "strcat"),
+    (0x16, "strncat"),
+    (0x17, "strcmp"),
+    (0x18, "strncmp"),
+    (0x19, "strcpy"),
+    (0x1a, "strncpy"),
+    (0x1b, "strlen"),
+    (0x1c, "index"),
+    (0x1d, "rindex"),
+    (0x1e, "strchr"),
+    (0x1f, "strrchr"),
+    (0x20, "strpbrk"),
+    (0x21, "strspn"),
+    (0x22, "strcspn"),
+    (0x23, "strtok"),
+    (0x24, "strstr"),
+    (0x25, "toupper"),
+    (0x26, "tolower"),
+    (0x27, "bcopy"),
+    (0x28, "bzero"),
+    (0x29, "bcmp"),
+    (0x2a, "memcpy"),
+    (0x2b, "memset"),
+    (0x2c, "memmove"),
+    (0x2d, "memcmp"),
+    (0x2e, "memchr"),
+    (0x2f, "rand"),
+    (0x30, "srand"),
+    (0x31, "qsort"),
+    (0x32, "strtod"),
+    (0x33, "malloc"),
+    (0x34, "free"),
+    (0x35, "lsearch"),
+    (0x36, "bsearch"),
+    (0x37, "calloc"),
+    (0x38, "realloc"),
+    (0x39, "InitHeap"),
+    (0x3a, "SystemErrorExit"),
+    (0x3b, "std_in_getchar"),
+    (0x3c, "std_out_putchar"),
+    (0x3d, "std_in_gets"),
+    (0x3e, "std_out_puts"),
+    (0x3f, "printf"),
+    (0x40, "SystemErrorUnresolvedException"),
+    (0x41, "LoadExeHeader"),
+    (0x42, "LoadExeFile"),
+    (0x43, "DoExecute"),
+    (0x44, "FlushCache"),
+    (0x45, "init_a0_b0_c0_vectors"),
+    (0x46, "GPU_dw"),
+    (0x47, "gpu_send_dma"),
+    (0x48, "SendGP1Command"),
+    (0x49, "GPU_cw"),
+    (0x4a, "GPU_cwp"),
+    (0x4b, "send_gpu_linked_list"),
+    (0x4c, "gpu_abort_dma"),
+    (0x4d, "GetGPUStatus"),
+    (0x4e, "gpu_sync"),
+];
+
+const B0_FUNCTIONS: [(u32, &str); 66] = [
+    (0x00, "alloc_kernel_memory"),
+    (0x01, "free_kernel_memory"),
+    (0x02, "init_timer"),
+    (0x03, "get_timer"),
+    (0x04, "enable_timer_irq"),
+    (0x05, "disable_timer_irq"),
+    (0x06, "restart_timer"),
+    (0x07, "DeliverEvent"),
+    (0x08, "OpenEvent"),
+    (0x09, "CloseEvent"),
+    (0x0a, "WaitEvent"),
+    (0x0b, "TestEvent"),
+    (0x0c, "EnableEvent"),
+    (0x0d, "DisableEvent"),
+    (0x0e, "OpenThread"),
+    (0x0f, "CloseThread"),
+    (0x10, "ChangeThread"),
+    (0x12, "InitPad"),
+    (0x13, "StartPad"),
+    (0x14, "StopPad"),
+    (0x15, "OutdatedPadInitAndStart"),
+    (0x16, "OutdatedPadGetButtons"),
+    (0x17, "ReturnFromException"),
+    (0x18, "SetDefaultExitFromException"),
+    (0x19, "SetCustomExitFromException"),
+    (0x20, "UnDeliverEvent"),
+    (0x32, "FileOpen"),
+    (0x33, "FileSeek"),
+    (0x34, "FileRead"),
+    (0x35, "FileWrite"),
+    (0x36, "FileClose"),
+    (0x37, "FileIoctl"),
+    (0x38, "exit"),
+    (0x39, "FileGetDeviceFlag"),
+    (0x3a, "FileGetc"),
+    (0x3b, "FilePutc"),
+    (0x3c, "std_in_getchar"),
+    (0x3d, "std_out_putchar"),
+    (0x3e, "std_in_gets"),
+    (0x3f, "std_out_puts"),
+    (0x40, "chdir"),
+    (0x41, "FormatDevice"),
+    (0x42, "firstfile"),
+    (0x43, "nextfile"),
+    (0x44, "FileRename"),
+    (0x45, "FileDelete"),
+    (0x46, "FileUndelete"),
+    (0x47, "AddDevice"),
+    (0x48, "RemoveDevice"),
+    (0x49, "PrintInstalledDevices"),
+    (0x4a, "InitCard"),
+    (0x4b, "StartCard"),
+    (0x4c, "StopCard"),
+    (0x4e, "write_card_sector"),
+    (0x4f, "read_card_sector"),
+    (0x50, "allow_new_card"),
+    (0x51, "Krom2RawAdd"),
+    (0x54, "get_errno"),
+    (0x55, "get_error"),
+    (0x56, "GetC0Table"),
+    (0x57, "GetB0Table"),
+    (0x58, "get_bu_callback_port"),
+    (0x59, "testdevice"),
+    (0x5b, "ChangeClearPad"),
+    (0x5c, "get_card_status"),
+    (0x5d, "wait_card_status"),
+];
+
+const C0_FUNCTIONS: [(u32, &str); 28] = [
+    (0x00, "EnqueueTimerAndVblankIrqs"),
+    (0x01, "EnqueueSyscallHandler"),
+    (0x02, "SysEnqIntRP"),
+    (0x03, "SysDeqIntRP"),
+    (0x04, "get_free_EvCB_slot"),
+    (0x05, "get_free_TCB_slot"),
+    (0x06, "ExceptionHandler"),
+    (0x07, "InstallExceptionHandlers"),
+    (0x08, "SysInitMemory"),
+    (0x09, "SysInitKernelVariables"),
+    (0x0a, "ChangeClearRCnt"),
+    (0x0b, "SystemError"),
+    (0x0c, "InitDefInt"),
+    (0x0d, "SetIrqAutoAck"),
+    (0x0e, "dev_sio_init"),
+    (0x0f, "dev_sio_open"),
+    (0x10, "dev_sio_in_out"),
+    (0x11, "dev_sio_ioctl"),
+    (0x12, "InstallDevices"),
+    (0x13, "FlushStdInOutPut"),
+    (0x14, "SystemError"),
+    (0x15, "tty_cdevinput"),
+    (0x16, "tty_cdevscan"),
+    (0x17, "tty_circgetc"),
+    (0x18, "tty_circputc"),
+    (0x19, "ioabort"),
+    (0x1a, "set_card_find_mode"),
+    (0x1b, "KernelRedirect"),
+];
@@ -7,12 +7,67 @@ use num_traits::FromPrimitive;
 use thiserror::Error;
 use tracing::{error, info, instrument, warn};
 
+mod backend;
+mod block_cache;
+mod callstack;
+mod coverage;
+mod disasm;
+mod exe;
+mod history;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod jit;
+pub mod kernel;
+mod movie;
+mod operands;
+mod perf;
+mod profiler;
+mod savestate;
+mod symbols;
+#[cfg(test)]
+pub(crate) mod test_util;
+mod video;
+mod watchpoint;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod x86;
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub use backend::Recompiler;
+pub use backend::{CachedInterpreter, CpuState, ExecutionBackend, Interpreter};
+pub use block_cache::BlockCache;
+pub use callstack::{CallStack, Frame};
+pub use coverage::Coverage;
+pub use disasm::{disassemble, disassemble_exact, DecodedInstruction, Flow};
+pub use exe::PsxExe;
+pub use history::{InstructionHistory, RegisterWrite, DEFAULT_HISTORY_CAPACITY};
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub use jit::CompiledBlocks;
+pub use movie::{state_hash, Movie, MovieFrame, MoviePlayer, MOVIE_VERSION};
+pub use operands::Operands;
+pub use perf::{FrameLimiter, PerfCounter, Speed, CPU_CLOCK_HZ, SPEED_RANGE};
+pub use profiler::Profiler;
+pub use savestate::{slot_path, SAVE_STATE_VERSION};
+pub use symbols::{parse_number, SymbolTable};
+pub use video::{HorizontalResolution, VideoStandard, VideoTiming};
+pub use watchpoint::{MemoryAccess, WatchKind, Watchpoint, WatchpointHit};
+
+use watchpoint::Watchpoints;
+
 const PROGRAM_COUNTER_RESET_VALUE: u32 = 0xbfc00000;
+
+// Ranges below are physical addresses, see `mask_region`
 const BIOS_ADDR_RANGE: AddressRange = AddressRange {
-    starting_addr: 0xbfc00000,
-    last_addr: 0xbfc00000 + (512 * 1024),
+    starting_addr: 0x1fc00000,
+    last_addr: 0x1fc00000 + (512 * 1024),
     // size: 512 * 1024,
 };
+const RAM_ADDR_RANGE: AddressRange = AddressRange {
+    starting_addr: 0x00000000,
+    last_addr: 2 * 1024 * 1024,
+};
+const SCRATCHPAD_ADDR_RANGE: AddressRange = AddressRange {
+    starting_addr: 0x1f800000,
+    last_addr: 0x1f800000 + 1024,
+};
 
 const MEM_CONTROL_ADDR_RANGE: AddressRange = AddressRange {
     starting_addr: 0x1f801000,
@@ -41,6 +96,44 @@ pub enum PsemuCoreError {
     UnknownInstruction(u32),
     #[error("Unknown secondary-op instruction {0:#010x}")]
     UnknownSecondaryOpInstruction(u32),
+    #[error("Unable to access save state: {0}")]
+    SaveStateIo(#[from] io::Error),
+    #[error("Not a psemu save state")]
+    InvalidSaveState,
+    #[error("Save state is truncated")]
+    TruncatedSaveState,
+    #[error("Incompatible save state version {found} (this build uses {expected})")]
+    IncompatibleSaveStateVersion { expected: u32, found: u32 },
+    #[error("Save state was made with a different BIOS")]
+    SaveStateBiosMismatch,
+    #[error("Save state has {0} bytes of trailing data")]
+    TrailingSaveStateData(usize),
+    #[error("Not a PS-X EXE")]
+    InvalidExe,
+    #[error("PS-X EXE is truncated")]
+    TruncatedExe,
+    #[error("PS-X EXE loads past the end of the address space")]
+    ExeLoadAddress,
+    #[error("Unable to load symbols from {path}: {reason}")]
+    InvalidSymbolFile { path: String, reason: String },
+    #[error("BIOS image is {0} bytes, it can be at most 512KiB")]
+    BiosTooLarge(usize),
+    #[error("RAM image at {addr:#010x} ({len} bytes) doesn't fit in RAM")]
+    RamImageOutOfRange { addr: u32, len: usize },
+    #[error("Unable to start executing at {0:#010x}")]
+    InvalidEntryPoint(u32),
+    #[error("Bus error: {0}")]
+    BusError(String),
+    #[error("Unable to access movie: {0}")]
+    MovieIo(io::Error),
+    #[error("Not a psemu movie")]
+    InvalidMovie,
+    #[error("Movie is truncated")]
+    TruncatedMovie,
+    #[error("Incompatible movie version {found} (this build uses {expected})")]
+    IncompatibleMovieVersion { expected: u32, found: u32 },
+    #[error("Movie desynced at frame {0}")]
+    MovieDesync(usize),
     // #[error("invalid header (expected {expected:?}, found {found:?})")]
     // InvalidHeader {
     //     expected: String,
@@ -56,23 +149,55 @@ pub struct AddressRange {
     // size: u32,
 }
 
+impl AddressRange {
+    pub fn contains(&self, addr: u32) -> bool {
+        addr >= self.starting_addr && addr < self.last_addr
+    }
+}
+
 pub struct HumanReadableInstruction(pub String);
 pub struct HumanReadableEvalInstruction(pub String);
 
 pub struct InstructionForDebugger {
+    /// Address the instruction was fetched from
+    pub pc: u32,
+    /// Value of `Cpu::cycles` when it ran
+    pub cycle: u64,
     pub raw: u32,
-    pub op: String,
-    pub human: HumanReadableInstruction,
-    pub eval: HumanReadableEvalInstruction,
+    pub operands: Operands,
+    pub registers: Vec<RegisterWrite>,
+    pub memory: Vec<MemoryAccess>,
+}
+
+impl InstructionForDebugger {
+    pub fn op(&self) -> &'static str {
+        self.operands.mnemonic()
+    }
+
+    pub fn human(&self) -> HumanReadableInstruction {
+        self.operands.human()
+    }
+
+    pub fn eval(&self) -> HumanReadableEvalInstruction {
+        self.operands.eval()
+    }
 }
 
 pub struct Cpu {
     pub pc: u32,
     // Used to simulate branch-delay slot
     next_instruction: Instruction,
+    // Address `next_instruction` was fetched from. Not always `pc - 4`: after a
+    // jump, `pc` already points at the target while the delay slot is pending.
+    next_instruction_pc: u32,
     registers: [u32; 32],
     interconnect: Interconnect,
-    pub instruction_history: Vec<InstructionForDebugger>,
+    /// Instructions executed since reset
+    pub cycles: u64,
+    pub instruction_history: InstructionHistory,
+    block_cache: BlockCache,
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    compiled_blocks: CompiledBlocks,
 }
 
 impl Default for Cpu {
@@ -81,41 +206,178 @@ impl Default for Cpu {
     }
 }
 
+/// Builds a `Cpu` from images in memory rather than the BIOS file on disk,
+/// e.g. for tests
+#[derive(Default)]
+pub struct CpuBuilder {
+    bios: Option<Vec<u8>>,
+    ram: Vec<(u32, Vec<u8>)>,
+    entry: Option<u32>,
+}
+
+impl CpuBuilder {
+    /// Shorter images are padded with zeroes. Without one, the BIOS is all
+    /// zeroes (NOPs).
+    pub fn bios(mut self, bytes: Vec<u8>) -> Self {
+        self.bios = Some(bytes);
+        self
+    }
+
+    /// Copy `bytes` into RAM starting at `addr`. Can be repeated.
+    pub fn ram(mut self, addr: u32, bytes: &[u8]) -> Self {
+        self.ram.push((addr, bytes.to_vec()));
+        self
+    }
+
+    /// Start executing at `pc` instead of the reset vector
+    pub fn entry(mut self, pc: u32) -> Self {
+        self.entry = Some(pc);
+        self
+    }
+
+    pub fn build(self) -> Result<Cpu, PsemuCoreError> {
+        let bios = Bios::from_bytes(self.bios.unwrap_or_default())?;
+        let mut cpu = Cpu::with_interconnect(Interconnect::new(bios));
+        for (addr, bytes) in &self.ram {
+            cpu.interconnect.load_ram_image(*addr, bytes)?;
+        }
+        if let Some(pc) = self.entry {
+            cpu.jump_to(pc)
+                .map_err(|_| PsemuCoreError::InvalidEntryPoint(pc))?;
+        }
+        Ok(cpu)
+    }
+}
+
 impl Cpu {
+    /// Reads the BIOS from `./data/SCPH1001.BIN`
     pub fn new() -> Self {
+        Cpu::with_interconnect(Interconnect::new(Bios::new()))
+    }
+
+    pub fn builder() -> CpuBuilder {
+        CpuBuilder::default()
+    }
+
+    fn with_interconnect(interconnect: Interconnect) -> Self {
         let mut registers = [0xdeadbeef; 32];
         registers[0] = 0;
         Cpu {
             pc: PROGRAM_COUNTER_RESET_VALUE,
             next_instruction: Instruction(0x00), // NOP
+            // The reset NOP doesn't come from memory, pretend it sits right
+            // before the reset vector
+            next_instruction_pc: PROGRAM_COUNTER_RESET_VALUE.wrapping_sub(4),
             registers,
-            interconnect: Interconnect::new(),
-            instruction_history: vec![],
+            interconnect,
+            cycles: 0,
+            instruction_history: InstructionHistory::default(),
+            block_cache: BlockCache::default(),
+            #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+            compiled_blocks: CompiledBlocks::default(),
         }
     }
 
+    /// The instruction sitting in the branch-delay slot, executed next cycle
+    pub fn next_instruction(&self) -> u32 {
+        self.next_instruction.0
+    }
+
+    /// Address of the instruction that runs on the next cycle; this is what a
+    /// debugger should show as the PC
+    pub fn next_instruction_pc(&self) -> u32 {
+        self.next_instruction_pc
+    }
+
+    pub fn set_next_instruction(&mut self, pc: u32, instr: u32) {
+        self.next_instruction_pc = pc;
+        self.next_instruction = Instruction(instr);
+    }
+
+    /// Redirect execution so that the next cycle runs the instruction at `addr`
+    pub fn jump_to(&mut self, addr: u32) -> Result<(), String> {
+        let instr = self.load32(addr)?;
+        self.set_next_instruction(addr, instr);
+        self.pc = addr.wrapping_add(4);
+        Ok(())
+    }
+
+    /// Side-effect-free read, for instruction fetches and debuggers
     pub fn load32(&self, addr: u32) -> Result<u32, String> {
         self.interconnect.load32(addr)
     }
 
     pub fn store32(&mut self, addr: u32, val: u32) -> Result<(), String> {
-        self.interconnect.store32(addr, val)
+        self.interconnect.store32(addr, val)?;
+        self.invalidate_code_at(addr);
+        Ok(())
+    }
+
+    /// Debugger write. Unlike `store32`, this never triggers watchpoints.
+    pub fn poke32(&mut self, addr: u32, val: u32) -> Result<(), String> {
+        self.interconnect.write32(addr, val)?;
+        self.invalidate_code_at(addr);
+        Ok(())
+    }
+
+    /// Debugger byte write, limited to RAM and the scratchpad so a stray edit
+    /// can't reconfigure the hardware
+    pub fn poke8(&mut self, addr: u32, val: u8) -> Result<(), String> {
+        self.interconnect.poke8(addr, val)?;
+        self.invalidate_code_at(addr);
+        Ok(())
+    }
+
+    /// Pre-decoded blocks used by `run_block`
+    pub fn block_cache(&self) -> &BlockCache {
+        &self.block_cache
+    }
+
+    /// Data loads and stores made by the last instruction
+    pub fn last_memory_accesses(&self) -> &[MemoryAccess] {
+        &self.interconnect.accesses
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.interconnect.watchpoints.add(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        self.interconnect.watchpoints.remove(watchpoint)
+    }
+
+    /// The watchpoint hit by the last instruction, if any
+    pub fn take_watchpoint_hit(&mut self) -> Option<WatchpointHit> {
+        self.interconnect.watchpoints.hit.take()
     }
 
     pub fn run_single_cycle(&mut self) -> Result<(), PsemuCoreError> {
         let pc = self.pc;
         let instr = self.next_instruction;
+        let instr_pc = self.next_instruction_pc;
+        self.interconnect.accesses.clear();
+        self.interconnect.watchpoints.hit = None;
+
         self.next_instruction =
             Instruction(self.load32(pc).expect("Unable to load next instruction"));
+        self.next_instruction_pc = pc;
         self.pc = self.pc.wrapping_add(4);
-        self.execute_instr(instr.0)
+        let res = self.execute_instr(instr_pc, instr.0);
+        self.cycles += 1;
+
+        // The interconnect doesn't know which instruction made the access
+        if let Some(hit) = &mut self.interconnect.watchpoints.hit {
+            hit.pc = instr_pc;
+        }
+        res
     }
 
-    #[instrument(skip(self, instr_), fields(instr=%format!("{instr_:#x}")))]
-    pub fn execute_instr(&mut self, instr_: u32) -> Result<(), PsemuCoreError> {
+    #[instrument(skip(self, instr_pc, instr_), fields(instr=%format!("{instr_:#x}")))]
+    pub fn execute_instr(&mut self, instr_pc: u32, instr_: u32) -> Result<(), PsemuCoreError> {
         let instr = Instruction(instr_);
+        let old_registers = self.registers;
         if let Some(op) = instr.sop() {
-            let (op_s, (h, e)) = match op {
+            let operands = match op {
                 Opcode::Special => {
                     if let Some(res) = self.execute_special_op_instr(instr_) {
                         res
@@ -124,18 +386,33 @@ impl Cpu {
                         return Err(PsemuCoreError::UnknownSecondaryOpInstruction(instr_));
                     }
                 }
-                Opcode::LoadUpperImmediate => ("LUI".to_string(), self.op_lui(instr)),
-                Opcode::OrImmediate => ("ORI".to_string(), self.op_ori(instr)),
-                Opcode::StoreWord => ("SW".to_string(), self.op_sw(instr)),
-                Opcode::AddImmediateUnsignedWord => ("ADDIU".to_string(), self.op_addiu(instr)),
-                Opcode::Jump => ("J".to_string(), self.op_jump(instr)),
+                Opcode::LoadUpperImmediate => self.op_lui(instr),
+                Opcode::OrImmediate => self.op_ori(instr),
+                Opcode::StoreWord => self.op_sw(instr),
+                Opcode::AddImmediateUnsignedWord => self.op_addiu(instr),
+                Opcode::Jump => self.op_jump(instr),
             };
-            self.instruction_history.push(InstructionForDebugger {
-                raw: instr_,
-                op: op_s,
-                human: h,
-                eval: e,
-            });
+            if self.instruction_history.is_recording() {
+                let registers = old_registers
+                    .iter()
+                    .zip(self.registers)
+                    .enumerate()
+                    .filter(|(_, (old, new))| *old != new)
+                    .map(|(i, (old, new))| RegisterWrite {
+                        index: i as u8,
+                        old: *old,
+                        new,
+                    })
+                    .collect();
+                self.instruction_history.push(InstructionForDebugger {
+                    pc: instr_pc,
+                    cycle: self.cycles,
+                    raw: instr_,
+                    operands,
+                    registers,
+                    memory: self.interconnect.accesses.clone(),
+                });
+            }
         } else {
             error!("Unknown instruction");
             return Err(PsemuCoreError::UnknownInstruction(instr_));
@@ -143,19 +420,11 @@ impl Cpu {
         Ok(())
     }
 
-    pub fn execute_special_op_instr(
-        &mut self,
-        instr_: u32,
-    ) -> Option<(
-        String,
-        (HumanReadableInstruction, HumanReadableEvalInstruction),
-    )> {
+    pub fn execute_special_op_instr(&mut self, instr_: u32) -> Option<Operands> {
         let instr = Instruction(instr_);
         match instr.secondary_opcode() {
-            Some(SecondaryOpcode::ShiftLeftLogical) => {
-                Some(("SLL".to_string(), self.op_sll(instr)))
-            }
-            Some(SecondaryOpcode::Or) => Some(("OR".to_string(), self.op_or(instr))),
+            Some(SecondaryOpcode::ShiftLeftLogical) => Some(self.op_sll(instr)),
+            Some(SecondaryOpcode::Or) => Some(self.op_or(instr)),
             None => None,
         }
     }
@@ -176,26 +445,18 @@ impl Cpu {
 
     /// Load Upper Immediate
     // rt = imm << 16
-    fn op_lui(
-        &mut self,
-        instr: Instruction,
-    ) -> (HumanReadableInstruction, HumanReadableEvalInstruction) {
+    fn op_lui(&mut self, instr: Instruction) -> Operands {
         // TODO: newtypes
         let rt = instr.gpr_rt();
         let imm = instr.immediate();
         let val = imm << 16;
         self.set_register(rt, val);
-        let h = HumanReadableInstruction("rt = imm << 16".to_string());
-        let e = HumanReadableEvalInstruction(format!("{rt} = ({imm:#x} << 16) => {val:#x}"));
-        (h, e)
+        Operands::Lui { rt, imm, val }
     }
 
     // Or
     // rd = get(rs) | get(rt)
-    fn op_or(
-        &mut self,
-        instr: Instruction,
-    ) -> (HumanReadableInstruction, HumanReadableEvalInstruction) {
+    fn op_or(&mut self, instr: Instruction) -> Operands {
         let rd = instr.gpr_rd();
         let rs = instr.gpr_rs();
         let rt = instr.gpr_rt();
@@ -203,38 +464,37 @@ impl Cpu {
         let get_rt = self.get_register(rt);
         let val = get_rs | get_rt;
         self.set_register(rd, val);
-        let h = HumanReadableInstruction("rd = get(rs) | get(rt)".to_string());
-        let e = HumanReadableEvalInstruction(format!(
-            "{rd} = (get({rs}) | get({rt}) => ({get_rs:#x} | {get_rt:#x}) => {val:#x}"
-        ));
-        (h, e)
+        Operands::Or {
+            rd,
+            rs,
+            rt,
+            get_rs,
+            get_rt,
+            val,
+        }
     }
 
     /// Or Immediate
     /// rt = get(rs) | imm
-    fn op_ori(
-        &mut self,
-        instr: Instruction,
-    ) -> (HumanReadableInstruction, HumanReadableEvalInstruction) {
+    fn op_ori(&mut self, instr: Instruction) -> Operands {
         let rt = instr.gpr_rt();
         let rs = instr.gpr_rs();
         let imm = instr.immediate();
         let get_rs = self.get_register(rs);
         let val = get_rs | imm;
         self.set_register(rt, val);
-        let h = HumanReadableInstruction("rt = get(rs) | immediate".to_string());
-        let e = HumanReadableEvalInstruction(format!(
-            "{rt} = (get({rs}) | {imm:#x}) => ({get_rs:#x} | {imm:#x}) => {val:#x}"
-        ));
-        (h, e)
+        Operands::Ori {
+            rt,
+            rs,
+            imm,
+            get_rs,
+            val,
+        }
     }
 
     /// Store Word
     /// memory[get(base)+offset] = get(rt)
-    fn op_sw(
-        &mut self,
-        instr: Instruction,
-    ) -> (HumanReadableInstruction, HumanReadableEvalInstruction) {
+    fn op_sw(&mut self, instr: Instruction) -> Operands {
         let rt = instr.gpr_rt();
         // TODO: Is `base` always a register? If so, change the base() method to return RegisterIndex
         let base = instr.base();
@@ -244,19 +504,18 @@ impl Cpu {
         let addr = get_base.wrapping_add(offset);
         let val = self.get_register(rt);
         self.store32(addr, val).unwrap();
-        let h = HumanReadableInstruction("memory[get(base)+offset] = get(rt)".to_string());
-        let e = HumanReadableEvalInstruction(
-            format!("memory[(get(${base})+{offset:#x}) => ({get_base:#x}+{offset:#x}) => {addr:#x}] = {val:#x}"),
-        );
-        (h, e)
+        Operands::Sw {
+            base: RegisterIndex(base),
+            get_base,
+            offset,
+            addr,
+            val,
+        }
     }
 
     /// Shift Left Logical
     /// rd = get(rt) << sa
-    fn op_sll(
-        &mut self,
-        instr: Instruction,
-    ) -> (HumanReadableInstruction, HumanReadableEvalInstruction) {
+    fn op_sll(&mut self, instr: Instruction) -> Operands {
         let rt = instr.gpr_rt();
         let rd = instr.gpr_rd();
         let sa = instr.sa();
@@ -264,9 +523,7 @@ impl Cpu {
         let val = self.get_register(rt) << sa;
 
         self.set_register(rd, val);
-        let h = HumanReadableInstruction("rd = get(rt) << sa".to_string());
-        let e = HumanReadableEvalInstruction(format!("{rd} = {val:#x} << {sa}"));
-        (h, e)
+        Operands::Sll { rd, sa, val }
     }
 
     /// Add Immediate Unsigned Word
@@ -279,10 +536,7 @@ impl Cpu {
     /// This instruction is appropriate for unsigned arithmetic, such as
     /// address arithmetic, or integer arithmetic environments that ignore
     /// overflow, such as C language arithmetic.
-    fn op_addiu(
-        &mut self,
-        instr: Instruction,
-    ) -> (HumanReadableInstruction, HumanReadableEvalInstruction) {
+    fn op_addiu(&mut self, instr: Instruction) -> Operands {
         let rt = instr.gpr_rt();
         let rs = instr.gpr_rs();
         let imm = instr.immediate_sign_extended();
@@ -290,39 +544,57 @@ impl Cpu {
         let get_rs = self.get_register(rs);
         let val = get_rs.wrapping_add(imm);
         self.set_register(rt, val);
-        let h = HumanReadableInstruction("rt = get(rs) + imm".to_string());
-        let e = HumanReadableEvalInstruction(format!(
-            "{rt} = (get({rs}) + {imm:#x}) => ({get_rs:#x} + {imm:#x})"
-        ));
-        (h, e)
+        Operands::Addiu {
+            rt,
+            rs,
+            imm,
+            get_rs,
+            val,
+        }
     }
 
-    fn op_jump(
-        &mut self,
-        instr: Instruction,
-    ) -> (HumanReadableInstruction, HumanReadableEvalInstruction) {
+    fn op_jump(&mut self, instr: Instruction) -> Operands {
         let instr_index = instr.instr_index();
         let instr_index = instr_index << 2;
         let pc_4_msb = 0xF0000000 & self.pc;
         let res = pc_4_msb | instr_index;
         self.pc = res;
-        let h = HumanReadableInstruction("pc = 4MSB(pc) | (instr_index << 2)".to_string());
-        let e = HumanReadableEvalInstruction(format!(
-            "pc = 4MSB(pc) | (instr_index << 2) => {pc_4_msb:#x} | {instr_index:#} => {res:#x}"
-        ));
-        (h, e)
+        Operands::J {
+            pc_4_msb,
+            instr_index,
+            target: res,
+        }
     }
 }
 
 struct Bios {
     data: Vec<u8>,
+    // Fingerprint stored in save states, worked out once since the BIOS is ROM
+    checksum: u32,
 }
 
 impl Bios {
     pub fn new() -> Self {
         // TODO: Move path to config
         let data = std::fs::read("./data/SCPH1001.BIN").expect("unable to load BIOS file!");
-        Bios { data }
+        Bios::with_data(data)
+    }
+
+    fn with_data(data: Vec<u8>) -> Self {
+        // FNV-1a, good enough to tell BIOS dumps apart
+        let checksum = data.iter().fold(0x811c9dc5u32, |hash, byte| {
+            (hash ^ *byte as u32).wrapping_mul(0x01000193)
+        });
+        Bios { data, checksum }
+    }
+
+    pub fn from_bytes(mut data: Vec<u8>) -> Result<Self, PsemuCoreError> {
+        let size = BIOS_ADDR_RANGE.last_addr - BIOS_ADDR_RANGE.starting_addr;
+        if data.len() > size as usize {
+            return Err(PsemuCoreError::BiosTooLarge(data.len()));
+        }
+        data.resize(size as usize, 0);
+        Ok(Bios::with_data(data))
     }
 
     // Little endian (LSB goes first, i.e., the left side)
@@ -338,39 +610,157 @@ impl Bios {
     }
 }
 
+struct Ram {
+    data: Vec<u8>,
+}
+
+impl Ram {
+    pub fn new(size: usize) -> Self {
+        Ram {
+            data: vec![0; size],
+        }
+    }
+
+    // Little endian, same as the BIOS
+    pub fn load32(&self, offset: u32) -> u32 {
+        let offset = offset as usize;
+        u32::from_le_bytes(self.data[offset..offset + 4].try_into().unwrap())
+    }
+
+    pub fn store32(&mut self, offset: u32, val: u32) {
+        let offset = offset as usize;
+        self.data[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn store8(&mut self, offset: u32, val: u8) {
+        self.data[offset as usize] = val;
+    }
+}
+
+// KUSEG, KSEG0 and KSEG1 all mirror the same physical memory, only the
+// caching differs. KSEG2 is left untouched.
+fn mask_region(addr: u32) -> u32 {
+    const REGION_MASK: [u32; 8] = [
+        // KUSEG: 2048MB
+        0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, //
+        // KSEG0: 512MB
+        0x7fffffff, //
+        // KSEG1: 512MB
+        0x1fffffff, //
+        // KSEG2: 1024MB
+        0xffffffff, 0xffffffff,
+    ];
+    addr & REGION_MASK[(addr >> 29) as usize]
+}
+
 struct Interconnect {
     bios: Bios,
+    ram: Ram,
+    scratchpad: Ram,
+    watchpoints: Watchpoints,
+    // Data accesses made by the instruction currently executing
+    accesses: Vec<MemoryAccess>,
 }
 
 impl Interconnect {
-    pub fn new() -> Self {
-        Interconnect { bios: Bios::new() }
+    pub fn new(bios: Bios) -> Self {
+        Interconnect {
+            bios,
+            ram: Ram::new(RAM_ADDR_RANGE.last_addr as usize),
+            scratchpad: Ram::new(1024),
+            watchpoints: Watchpoints::default(),
+            accesses: vec![],
+        }
     }
 
     #[instrument(skip(self, addr), fields(addr=%format!("{addr:#x}")))]
     pub fn load32(&self, addr: u32) -> Result<u32, String> {
         // Word addresses must be aligned by 4
-        if addr % 4 != 0 {
+        if !addr.is_multiple_of(4) {
             return Err(format!("Addr {addr} is not aligned"));
         }
-        if addr >= BIOS_ADDR_RANGE.starting_addr || addr < BIOS_ADDR_RANGE.last_addr {
+        let paddr = mask_region(addr);
+        if BIOS_ADDR_RANGE.contains(paddr) {
             // The addr relative to BIOS' starting address
-            let offset = addr - BIOS_ADDR_RANGE.starting_addr;
+            let offset = paddr - BIOS_ADDR_RANGE.starting_addr;
             return Ok(self.bios.load32(offset));
         }
+        if RAM_ADDR_RANGE.contains(paddr) {
+            return Ok(self.ram.load32(paddr - RAM_ADDR_RANGE.starting_addr));
+        }
+        if SCRATCHPAD_ADDR_RANGE.contains(paddr) {
+            return Ok(self
+                .scratchpad
+                .load32(paddr - SCRATCHPAD_ADDR_RANGE.starting_addr));
+        }
 
         Err(format!("Addr {addr} not in range for any peripheral"))
     }
 
+    /// Store made by the CPU; recorded so watchpoints and debuggers can see it
     #[instrument(skip(self, addr, val), fields(addr=%format!("{addr:#x}"), val=%format!("{val:#x}")))]
     pub fn store32(&mut self, addr: u32, val: u32) -> Result<(), String> {
+        // Write-only registers read back as 0
+        let old = self.load32(addr).unwrap_or(0);
+        self.write32(addr, val)?;
+
+        let access = MemoryAccess {
+            addr,
+            size: 4,
+            kind: WatchKind::Write,
+            old,
+            new: val,
+        };
+        self.watchpoints.check(&access);
+        self.accesses.push(access);
+        Ok(())
+    }
+
+    pub fn load_ram_image(&mut self, addr: u32, bytes: &[u8]) -> Result<(), PsemuCoreError> {
+        let paddr = mask_region(addr);
+        let end = paddr as u64 + bytes.len() as u64;
+        if !RAM_ADDR_RANGE.contains(paddr) || end > RAM_ADDR_RANGE.last_addr as u64 {
+            return Err(PsemuCoreError::RamImageOutOfRange {
+                addr,
+                len: bytes.len(),
+            });
+        }
+        let offset = (paddr - RAM_ADDR_RANGE.starting_addr) as usize;
+        self.ram.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub fn poke8(&mut self, addr: u32, val: u8) -> Result<(), String> {
+        let paddr = mask_region(addr);
+        if RAM_ADDR_RANGE.contains(paddr) {
+            self.ram.store8(paddr - RAM_ADDR_RANGE.starting_addr, val);
+            Ok(())
+        } else if SCRATCHPAD_ADDR_RANGE.contains(paddr) {
+            self.scratchpad
+                .store8(paddr - SCRATCHPAD_ADDR_RANGE.starting_addr, val);
+            Ok(())
+        } else {
+            Err(format!("Addr {addr:#x} is not in RAM or the scratchpad"))
+        }
+    }
+
+    // The write itself, without any of the bookkeeping
+    fn write32(&mut self, addr: u32, val: u32) -> Result<(), String> {
         // Word addresses must be aligned by 4
-        if addr % 4 != 0 {
+        if !addr.is_multiple_of(4) {
             return Err(format!("Addr {addr} is not aligned"));
         }
-        if addr >= MEM_CONTROL_ADDR_RANGE.starting_addr && addr < MEM_CONTROL_ADDR_RANGE.last_addr {
+        let paddr = mask_region(addr);
+        if RAM_ADDR_RANGE.contains(paddr) {
+            self.ram.store32(paddr - RAM_ADDR_RANGE.starting_addr, val);
+            Ok(())
+        } else if SCRATCHPAD_ADDR_RANGE.contains(paddr) {
+            self.scratchpad
+                .store32(paddr - SCRATCHPAD_ADDR_RANGE.starting_addr, val);
+            Ok(())
+        } else if MEM_CONTROL_ADDR_RANGE.contains(paddr) {
             // The addr relative to BIOS' starting address
-            let offset = addr - MEM_CONTROL_ADDR_RANGE.starting_addr;
+            let offset = paddr - MEM_CONTROL_ADDR_RANGE.starting_addr;
 
             // These registers contain the base address of the expansion 1 and 2 register
             // maps, respectively. Should never be changed from these hardcoded values.
@@ -388,19 +778,20 @@ impl Interconnect {
 
             warn!(offset, "Unhandled write to MEM_CONTROL register");
             Ok(())
-        } else if addr >= RAM_SIZE_RANGE.starting_addr && addr < RAM_SIZE_RANGE.last_addr {
+        } else if RAM_SIZE_RANGE.contains(paddr) {
             // The addr relative to RAM_SIZE's starting address
-            let offset = addr - RAM_SIZE_RANGE.starting_addr;
+            let offset = paddr - RAM_SIZE_RANGE.starting_addr;
             info!(offset, "Ignoring write to RAM_SIZE register");
             Ok(())
-        } else if addr >= CACHE_CONTROL_RANGE.starting_addr && addr < CACHE_CONTROL_RANGE.last_addr
-        {
+        } else if CACHE_CONTROL_RANGE.contains(paddr) {
             // The addr relative to CACHE_CONTROL's starting address
-            let offset = addr - CACHE_CONTROL_RANGE.starting_addr;
+            let offset = paddr - CACHE_CONTROL_RANGE.starting_addr;
             info!(offset, "Ignoring write to CACHE_CONTROL register");
             Ok(())
         } else {
-            todo!("Interconnect::store32!!! addr: {addr:#x}, value: {val:#x}");
+            Err(format!(
+                "Addr {addr:#x} not in range for any peripheral (value: {val:#x})"
+            ))
         }
     }
 }
@@ -456,12 +847,6 @@ impl Instruction {
         0xFFFF & self.0
     }
 
-    // Alias; same as above
-    fn offset(&self) -> u32 {
-        // 15..0 (16b)
-        0xFFFF & self.0
-    }
-
     // Force the compiler to sign-extend val
     fn immediate_sign_extended(&self) -> u32 {
         let val = self.immediate() as i16;
@@ -504,13 +889,188 @@ enum SecondaryOpcode {
     Or = 0b0010_0101,
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    const T0: RegisterIndex = RegisterIndex(8);
+    const T1: RegisterIndex = RegisterIndex(9);
+    const T2: RegisterIndex = RegisterIndex(10);
+
+    fn run(cpu: &mut Cpu, instructions: usize) {
+        for _ in 0..instructions {
+            cpu.run_single_cycle().unwrap();
+        }
+    }
+
+    #[test]
+    fn builder_starts_at_entry() {
+        let cpu = cpu_with(&[lui(8, 0x1234)]);
+        assert_eq!(cpu.next_instruction_pc(), PROGRAM);
+        assert_eq!(cpu.next_instruction(), lui(8, 0x1234));
+        assert_eq!(cpu.pc, PROGRAM + 4);
+    }
+
+    #[test]
+    fn builder_boots_from_bios_image() {
+        let bios: Vec<u8> = [lui(8, 0xbeef), ori(8, 8, 0x1)]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+        let mut cpu = Cpu::builder().bios(bios).build().unwrap();
+        // The reset NOP, then the BIOS
+        run(&mut cpu, 3);
+        assert_eq!(cpu.get_register(T0), 0xbeef0001);
+        assert_eq!(cpu.next_instruction_pc(), PROGRAM_COUNTER_RESET_VALUE + 8);
+    }
+
+    #[test]
+    fn builder_pads_the_bios() {
+        let cpu = Cpu::builder().bios(vec![0x12]).build().unwrap();
+        assert_eq!(cpu.load32(0xbfc00000), Ok(0x12));
+        assert_eq!(cpu.load32(0xbfc7fffc), Ok(0));
+    }
+
+    #[test]
+    fn builder_rejects_bad_images() {
+        let res = Cpu::builder().bios(vec![0; 512 * 1024 + 1]).build();
+        assert!(matches!(res, Err(PsemuCoreError::BiosTooLarge(_))));
+        let res = Cpu::builder().ram(0x801ffffc, &[0; 8]).build();
+        assert!(matches!(
+            res,
+            Err(PsemuCoreError::RamImageOutOfRange { len: 8, .. })
+        ));
+        let res = Cpu::builder().entry(0x1f801c00).build();
+        assert!(matches!(res, Err(PsemuCoreError::InvalidEntryPoint(_))));
+    }
+
+    #[test]
+    fn ram_images_are_mirrored_across_segments() {
+        let cpu = Cpu::builder()
+            .ram(0xa0000100, &[1, 2, 3, 4])
+            .build()
+            .unwrap();
+        assert_eq!(cpu.load32(0x00000100), Ok(0x04030201));
+        assert_eq!(cpu.load32(0x80000100), Ok(0x04030201));
+    }
+
+    #[test]
+    fn lui_and_ori() {
+        let mut cpu = cpu_with(&[lui(8, 0x1234), ori(8, 8, 0x5678), ori(9, 0, 0xffff)]);
+        run(&mut cpu, 3);
+        assert_eq!(cpu.get_register(T0), 0x12345678);
+        // The immediate is zero-extended
+        assert_eq!(cpu.get_register(T1), 0x0000ffff);
+    }
+
+    #[test]
+    fn addiu_sign_extends_and_wraps() {
+        let mut cpu = cpu_with(&[
+            addiu(8, 0, 0xffff),
+            addiu(9, 8, 2),
+            lui(10, 0x7fff),
+            ori(10, 10, 0xffff),
+            addiu(10, 10, 1),
+        ]);
+        run(&mut cpu, 5);
+        assert_eq!(cpu.get_register(T0), 0xffffffff);
+        assert_eq!(cpu.get_register(T1), 1);
+        // No overflow trap
+        assert_eq!(cpu.get_register(T2), 0x80000000);
+    }
+
+    #[test]
+    fn sll_and_or() {
+        let mut cpu = cpu_with(&[ori(8, 0, 0x81), sll(9, 8, 31), sll(10, 8, 4), or(10, 10, 9)]);
+        run(&mut cpu, 4);
+        assert_eq!(cpu.get_register(T1), 0x80000000);
+        assert_eq!(cpu.get_register(T2), 0x80000810);
+    }
+
+    #[test]
+    fn writes_to_zero_are_discarded() {
+        let mut cpu = cpu_with(&[ori(0, 0, 0x1234), or(8, 0, 0)]);
+        run(&mut cpu, 2);
+        assert_eq!(cpu.get_register(RegisterIndex(0)), 0);
+        assert_eq!(cpu.get_register(T0), 0);
+    }
+
+    #[test]
+    fn sw_stores_and_records_the_access() {
+        let mut cpu = cpu_with(&[
+            lui(8, 0x8002),
+            ori(9, 0, 0xcafe),
+            sw(9, 8, 0xfffc),
+            sw(9, 8, 0x10),
+        ]);
+        run(&mut cpu, 3);
+        assert_eq!(cpu.load32(0x8001fffc), Ok(0xcafe));
+        let accesses = cpu.last_memory_accesses();
+        assert_eq!(accesses.len(), 1);
+        assert_eq!(accesses[0].addr, 0x8001fffc);
+        assert_eq!((accesses[0].old, accesses[0].new), (0, 0xcafe));
+
+        run(&mut cpu, 1);
+        assert_eq!(cpu.load32(0x80020010), Ok(0xcafe));
+    }
+
+    #[test]
+    fn jump_runs_its_delay_slot() {
+        let mut cpu = cpu_with(&[
+            j(PROGRAM + 16),
+            ori(8, 0, 1),
+            ori(9, 0, 1),
+            ori(9, 0, 2),
+            ori(10, 0, 3),
+        ]);
+        run(&mut cpu, 1);
+        // The delay slot is next, but the target is already being fetched
+        assert_eq!(cpu.next_instruction_pc(), PROGRAM + 4);
+        assert_eq!(cpu.pc, PROGRAM + 16);
+        run(&mut cpu, 2);
+        assert_eq!(cpu.get_register(T0), 1);
+        assert_eq!(cpu.get_register(T1), 0xdeadbeef);
+        assert_eq!(cpu.get_register(T2), 3);
+    }
+
+    #[test]
+    fn unknown_instructions_stop_the_cpu() {
+        // MULT isn't implemented yet
+        let mut cpu = cpu_with(&[0x01090018]);
+        assert!(matches!(
+            cpu.run_single_cycle(),
+            Err(PsemuCoreError::UnknownSecondaryOpInstruction(0x01090018))
+        ));
+        let mut cpu = cpu_with(&[0xfc000000]);
+        assert!(matches!(
+            cpu.run_single_cycle(),
+            Err(PsemuCoreError::UnknownInstruction(0xfc000000))
+        ));
+    }
 
-//     #[test]
-//     fn it_works() {
-//         let result = add(2, 2);
-//         assert_eq!(result, 4);
-//     }
-// }
+    #[test]
+    fn history_records_register_writes() {
+        let mut cpu = cpu_with(&[ori(8, 0, 0x42), addiu(9, 8, 1)]);
+        run(&mut cpu, 2);
+        assert_eq!(cpu.cycles, 2);
+        let entries: Vec<_> = cpu.instruction_history.iter().collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[1].pc, entries[1].cycle), (PROGRAM + 4, 1));
+        let write = entries[1].registers[0];
+        assert_eq!((write.index, write.old, write.new), (9, 0xdeadbeef, 0x43));
+    }
+
+    #[test]
+    fn history_renders_descriptions_on_demand() {
+        let mut cpu = cpu_with(&[ori(8, 0, 0x42)]);
+        run(&mut cpu, 1);
+        let entry = cpu.instruction_history.last().unwrap();
+        assert_eq!(entry.op(), "ORI");
+        assert_eq!(entry.human().0, "rt = get(rs) | immediate");
+        assert_eq!(
+            entry.eval().0,
+            "$8 = (get($0) | 0x42) => (0x0 | 0x42) => 0x42"
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use std::{fs, path::Path};
+
+use crate::{Cpu, PsemuCoreError, VideoStandard};
+
+/// Bumped every time the layout below changes
+pub const MOVIE_VERSION: u32 = 2;
+
+const MOVIE_MAGIC: [u8; 4] = *b"PSMV";
+// FNV-1a, which unlike std's hashers is the same in every build
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MovieFrame {
+    /// `state_hash` when the frame's vblank started
+    pub state_hash: u64,
+}
+
+/// A hash of the machine at every vblank from power-on or a save state, to
+/// catch playback going somewhere else. There's no pad input in it: nothing
+/// polls the controller until SIO0 is emulated. Frames are counted from the
+/// start with the beam at the top of the screen, since save states don't
+/// include it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Movie {
+    pub standard: VideoStandard,
+    /// Save state the movie starts from, power-on if there's none
+    pub start: Option<Vec<u8>>,
+    pub frames: Vec<MovieFrame>,
+}
+
+/// Hash of the CPU registers, RAM and scratchpad, read in place so it's
+/// cheap enough for every frame
+pub fn state_hash(cpu: &Cpu) -> u64 {
+    let registers = [cpu.pc, cpu.next_instruction.0, cpu.next_instruction_pc]
+        .into_iter()
+        .chain(cpu.registers)
+        .map(u64::from);
+    // FNV-1a a word at a time rather than a byte at a time
+    let memory = [
+        &cpu.interconnect.ram.data,
+        &cpu.interconnect.scratchpad.data,
+    ]
+    .into_iter()
+    .flat_map(|data| data.chunks_exact(8))
+    .map(|word| u64::from_le_bytes(word.try_into().unwrap()));
+    registers
+        .chain(memory)
+        .fold(FNV_OFFSET_BASIS, |hash, word| {
+            (hash ^ word).wrapping_mul(FNV_PRIME)
+        })
+}
+
+impl Movie {
+    pub fn new(standard: VideoStandard, start: Option<Vec<u8>>) -> Self {
+        Movie {
+            standard,
+            start,
+            frames: vec![],
+        }
+    }
+
+    /// Put a freshly powered-on `cpu` where the movie starts
+    pub fn load_start(&self, cpu: &mut Cpu) -> Result<(), PsemuCoreError> {
+        match &self.start {
+            Some(state) => cpu.load_state(state),
+            None => Ok(()),
+        }
+    }
+
+    /// Called when a vblank starts
+    pub fn record_frame(&mut self, cpu: &Cpu) {
+        self.frames.push(MovieFrame {
+            state_hash: state_hash(cpu),
+        });
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = MOVIE_MAGIC.to_vec();
+        buf.extend_from_slice(&MOVIE_VERSION.to_le_bytes());
+        let standard: u32 = match self.standard {
+            VideoStandard::Ntsc => 0,
+            VideoStandard::Pal => 1,
+        };
+        buf.extend_from_slice(&standard.to_le_bytes());
+        let start = self.start.as_deref().unwrap_or_default();
+        buf.extend_from_slice(&(start.len() as u32).to_le_bytes());
+        buf.extend_from_slice(start);
+        buf.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        for frame in &self.frames {
+            buf.extend_from_slice(&frame.state_hash.to_le_bytes());
+        }
+        buf
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, PsemuCoreError> {
+        let mut r = MovieReader { data, pos: 0 };
+        if r.read_bytes(MOVIE_MAGIC.len())? != MOVIE_MAGIC {
+            return Err(PsemuCoreError::InvalidMovie);
+        }
+        let version = r.read_u32()?;
+        if version != MOVIE_VERSION {
+            return Err(PsemuCoreError::IncompatibleMovieVersion {
+                expected: MOVIE_VERSION,
+                found: version,
+            });
+        }
+        let standard = match r.read_u32()? {
+            0 => VideoStandard::Ntsc,
+            1 => VideoStandard::Pal,
+            _ => return Err(PsemuCoreError::InvalidMovie),
+        };
+        let start_len = r.read_u32()? as usize;
+        let start = (start_len > 0)
+            .then(|| r.read_bytes(start_len).map(<[u8]>::to_vec))
+            .transpose()?;
+        let count = r.read_u32()? as usize;
+        // Checked up front so a bad count can't allocate much
+        if (data.len() - r.pos) / 8 < count {
+            return Err(PsemuCoreError::TruncatedMovie);
+        }
+        let mut frames = Vec::with_capacity(count);
+        for _ in 0..count {
+            let state_hash = u64::from_le_bytes(r.read_bytes(8)?.try_into().unwrap());
+            frames.push(MovieFrame { state_hash });
+        }
+        Ok(Movie {
+            standard,
+            start,
+            frames,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), PsemuCoreError> {
+        fs::write(path, self.to_bytes()).map_err(PsemuCoreError::MovieIo)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, PsemuCoreError> {
+        Movie::from_bytes(&fs::read(path).map_err(PsemuCoreError::MovieIo)?)
+    }
+}
+
+/// Plays a movie back a frame at a time, checking each vblank against it
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: usize,
+}
+
+impl MoviePlayer {
+    pub fn new(movie: Movie) -> Self {
+        MoviePlayer { movie, frame: 0 }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame == self.movie.frames.len()
+    }
+
+    /// Frames played so far
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    /// Called when a vblank starts. Fails if the machine isn't where it was
+    /// when the movie was recorded.
+    pub fn end_frame(&mut self, cpu: &Cpu) -> Result<(), PsemuCoreError> {
+        let Some(expected) = self.movie.frames.get(self.frame) else {
+            return Ok(());
+        };
+        if state_hash(cpu) != expected.state_hash {
+            return Err(PsemuCoreError::MovieDesync(self.frame));
+        }
+        self.frame += 1;
+        Ok(())
+    }
+}
+
+struct MovieReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> MovieReader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], PsemuCoreError> {
+        if self.data.len() - self.pos < len {
+            return Err(PsemuCoreError::TruncatedMovie);
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, PsemuCoreError> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_util::{cpu_with, LOOP},
+        ExecutionBackend, Interpreter, VideoTiming,
+    };
+
+    // Runs `frames` frames, calling `vblank` as each one starts
+    fn run_frames(cpu: &mut Cpu, frames: usize, mut vblank: impl FnMut(&mut Cpu, usize)) {
+        let mut video = VideoTiming::new(VideoStandard::Ntsc);
+        for frame in 0..frames {
+            let end = cpu.cycles + video.cycles_to_vblank();
+            let before = cpu.cycles;
+            Interpreter.run_until(cpu, end).unwrap();
+            assert_eq!(video.advance(cpu.cycles - before), 1);
+            vblank(cpu, frame);
+        }
+    }
+
+    fn record(frames: usize) -> Movie {
+        let mut cpu = cpu_with(&LOOP);
+        cpu.instruction_history.set_capacity(0);
+        let mut movie = Movie::new(VideoStandard::Ntsc, Some(cpu.save_state()));
+        run_frames(&mut cpu, frames, |cpu, _| movie.record_frame(cpu));
+        movie
+    }
+
+    #[test]
+    fn playback_matches_the_recording() {
+        let movie = record(2);
+        assert_eq!(movie.frames.len(), 2);
+        assert_ne!(movie.frames[0], movie.frames[1]);
+
+        let mut player = MoviePlayer::new(movie);
+        let mut cpu = cpu_with(&[]);
+        cpu.instruction_history.set_capacity(0);
+        player.movie().load_start(&mut cpu).unwrap();
+        run_frames(&mut cpu, 2, |cpu, _| player.end_frame(cpu).unwrap());
+        assert_eq!(player.frame(), 2);
+        assert!(player.is_finished());
+    }
+
+    #[test]
+    fn desyncs_are_caught_on_the_frame_they_happen() {
+        let mut player = MoviePlayer::new(record(2));
+        let mut cpu = cpu_with(&[]);
+        cpu.instruction_history.set_capacity(0);
+        player.movie().load_start(&mut cpu).unwrap();
+        run_frames(&mut cpu, 2, |cpu, frame| {
+            if frame == 1 {
+                cpu.set_register(crate::RegisterIndex(20), 1);
+                assert!(matches!(
+                    player.end_frame(cpu),
+                    Err(PsemuCoreError::MovieDesync(1))
+                ));
+            } else {
+                player.end_frame(cpu).unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn movies_round_trip() {
+        let mut movie = Movie::new(VideoStandard::Pal, None);
+        movie.frames.push(MovieFrame {
+            state_hash: 0x0123456789abcdef,
+        });
+        let bytes = movie.to_bytes();
+        assert_eq!(Movie::from_bytes(&bytes).unwrap(), movie);
+
+        let movie = Movie::new(VideoStandard::Ntsc, Some(vec![1, 2, 3]));
+        assert_eq!(Movie::from_bytes(&movie.to_bytes()).unwrap(), movie);
+
+        for len in 0..bytes.len() {
+            assert!(
+                Movie::from_bytes(&bytes[..len]).is_err(),
+                "{len} bytes parsed"
+            );
+        }
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert!(matches!(
+            Movie::from_bytes(&bad),
+            Err(PsemuCoreError::InvalidMovie)
+        ));
+        let mut bad = bytes.clone();
+        bad[4] = 9;
+        assert!(matches!(
+            Movie::from_bytes(&bad),
+            Err(PsemuCoreError::IncompatibleMovieVersion { found: 9, .. })
+        ));
+        // A frame count far past the end
+        let mut bad = bytes;
+        bad[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            Movie::from_bytes(&bad),
+            Err(PsemuCoreError::TruncatedMovie)
+        ));
+    }
+}
@@ -0,0 +1,129 @@
+use crate::{HumanReadableEvalInstruction, HumanReadableInstruction, RegisterIndex};
+
+/// What an instruction read and produced. Kept in the history instead of
+/// the debugger's descriptions, which are only formatted when shown.
+#[derive(Clone, Copy)]
+pub enum Operands {
+    Lui {
+        rt: RegisterIndex,
+        imm: u32,
+        val: u32,
+    },
+    Or {
+        rd: RegisterIndex,
+        rs: RegisterIndex,
+        rt: RegisterIndex,
+        get_rs: u32,
+        get_rt: u32,
+        val: u32,
+    },
+    Ori {
+        rt: RegisterIndex,
+        rs: RegisterIndex,
+        imm: u32,
+        get_rs: u32,
+        val: u32,
+    },
+    Sw {
+        base: RegisterIndex,
+        get_base: u32,
+        offset: u32,
+        // Effective address
+        addr: u32,
+        val: u32,
+    },
+    Sll {
+        rd: RegisterIndex,
+        sa: u32,
+        val: u32,
+    },
+    Addiu {
+        rt: RegisterIndex,
+        rs: RegisterIndex,
+        imm: u32,
+        get_rs: u32,
+        val: u32,
+    },
+    J {
+        pc_4_msb: u32,
+        instr_index: u32,
+        target: u32,
+    },
+}
+
+impl Operands {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Operands::Lui { .. } => "LUI",
+            Operands::Or { .. } => "OR",
+            Operands::Ori { .. } => "ORI",
+            Operands::Sw { .. } => "SW",
+            Operands::Sll { .. } => "SLL",
+            Operands::Addiu { .. } => "ADDIU",
+            Operands::J { .. } => "J",
+        }
+    }
+
+    /// What the instruction does, in terms of its fields
+    pub fn human(&self) -> HumanReadableInstruction {
+        let s = match self {
+            Operands::Lui { .. } => "rt = imm << 16",
+            Operands::Or { .. } => "rd = get(rs) | get(rt)",
+            Operands::Ori { .. } => "rt = get(rs) | immediate",
+            Operands::Sw { .. } => "memory[get(base)+offset] = get(rt)",
+            Operands::Sll { .. } => "rd = get(rt) << sa",
+            Operands::Addiu { .. } => "rt = get(rs) + imm",
+            Operands::J { .. } => "pc = 4MSB(pc) | (instr_index << 2)",
+        };
+        HumanReadableInstruction(s.to_string())
+    }
+
+    /// `human` with the values it ran with filled in
+    pub fn eval(&self) -> HumanReadableEvalInstruction {
+        let s = match *self {
+            Operands::Lui { rt, imm, val } => format!("{rt} = ({imm:#x} << 16) => {val:#x}"),
+            Operands::Or {
+                rd,
+                rs,
+                rt,
+                get_rs,
+                get_rt,
+                val,
+            } => format!(
+                "{rd} = (get({rs}) | get({rt}) => ({get_rs:#x} | {get_rt:#x}) => {val:#x}"
+            ),
+            Operands::Ori {
+                rt,
+                rs,
+                imm,
+                get_rs,
+                val,
+            } => format!("{rt} = (get({rs}) | {imm:#x}) => ({get_rs:#x} | {imm:#x}) => {val:#x}"),
+            Operands::Sw {
+                base,
+                get_base,
+                offset,
+                addr,
+                val,
+            } => format!(
+                "memory[(get({base})+{offset:#x}) => ({get_base:#x}+{offset:#x}) => {addr:#x}] = {val:#x}"
+            ),
+            Operands::Sll { rd, sa, val } => format!("{rd} = {val:#x} << {sa}"),
+            Operands::Addiu {
+                rt,
+                rs,
+                imm,
+                get_rs,
+                ..
+            } => format!("{rt} = (get({rs}) + {imm:#x}) => ({get_rs:#x} + {imm:#x})"),
+            Operands::J {
+                pc_4_msb,
+                instr_index,
+                target,
+            } => format!(
+                "pc = 4MSB(pc) | (instr_index << 2) => {pc_4_msb:#x} | {instr_index:#} => {target:#x}"
+            ),
+        };
+        HumanReadableEvalInstruction(s)
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    fmt,
+    ops::RangeInclusive,
+    time::{Duration, Instant},
+};
+
+/// The R3000A's clock. `run_single_cycle` executes one instruction, so this
+/// is also how many instructions real hardware runs per second.
+pub const CPU_CLOCK_HZ: u64 = 33_868_800;
+/// Speed multipliers `FrameLimiter` can keep to
+pub const SPEED_RANGE: RangeInclusive<f64> = 0.01..=100.0;
+
+// Shortest stretch `PerfCounter` measures over, so the numbers don't jitter
+const PERF_WINDOW: Duration = Duration::from_secs(1);
+// How far behind `FrameLimiter` can fall before it stops trying to catch up
+const MAX_FRAME_LAG: Duration = Duration::from_millis(100);
+
+/// How fast some number of instructions and frames ran
+#[derive(Clone, Copy, Debug)]
+pub struct Speed {
+    pub instructions: u64,
+    /// Vblanks, from `VideoTiming::advance`
+    pub frames: u64,
+    pub elapsed: Duration,
+}
+
+impl Speed {
+    /// Millions of emulated instructions per second
+    pub fn mips(&self) -> f64 {
+        self.per_second(self.instructions) / 1_000_000.0
+    }
+
+    /// Emulated frames per second
+    pub fn fps(&self) -> f64 {
+        self.per_second(self.frames)
+    }
+
+    /// 1.0 is as fast as the real console
+    pub fn hardware_ratio(&self) -> f64 {
+        self.per_second(self.instructions) / CPU_CLOCK_HZ as f64
+    }
+
+    fn per_second(&self, count: u64) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        count as f64 / secs
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.2} MIPS, {:.1} fps ({:.0}% of hardware)",
+            self.mips(),
+            self.fps(),
+            self.hardware_ratio() * 100.0
+        )
+    }
+}
+
+/// Tracks emulation speed while running, e.g. for a status line. Takes the
+/// CPU's cycle count and how many vblanks there have been.
+pub struct PerfCounter {
+    start: Instant,
+    start_cycles: u64,
+    start_frames: u64,
+    speed: Option<Speed>,
+}
+
+impl PerfCounter {
+    pub fn new(cycles: u64, frames: u64) -> Self {
+        PerfCounter {
+            start: Instant::now(),
+            start_cycles: cycles,
+            start_frames: frames,
+            speed: None,
+        }
+    }
+
+    /// Start measuring from here, e.g. after waiting for input, which
+    /// shouldn't count against the emulator
+    pub fn restart(&mut self, cycles: u64, frames: u64) {
+        self.start = Instant::now();
+        self.start_cycles = cycles;
+        self.start_frames = frames;
+    }
+
+    /// Refreshes `speed` once a full window has passed. Cheap enough to call
+    /// every few thousand instructions.
+    pub fn update(&mut self, cycles: u64, frames: u64) {
+        if self.start.elapsed() >= PERF_WINDOW {
+            self.flush(cycles, frames);
+        }
+    }
+
+    /// Refreshes `speed` from whatever ran since the window started, e.g.
+    /// at the end of a run
+    pub fn flush(&mut self, cycles: u64, frames: u64) {
+        let instructions = cycles.saturating_sub(self.start_cycles);
+        if instructions > 0 {
+            self.speed = Some(Speed {
+                instructions,
+                frames: frames.saturating_sub(self.start_frames),
+                elapsed: self.start.elapsed(),
+            });
+        }
+        self.restart(cycles, frames);
+    }
+
+    /// The last measurement, if anything ran yet
+    pub fn speed(&self) -> Option<Speed> {
+        self.speed
+    }
+}
+
+/// Keeps emulation at the console's speed, or a multiple of it, by waiting
+/// out what's left of each frame
+pub struct FrameLimiter {
+    frame: Duration,
+    deadline: Instant,
+}
+
+impl FrameLimiter {
+    /// `speed` scales the refresh rate, e.g. 2.0 to fast-forward at double
+    /// speed. It's clamped to `SPEED_RANGE`.
+    pub fn new(refresh_rate: f64, speed: f64) -> Self {
+        let speed = speed.clamp(*SPEED_RANGE.start(), *SPEED_RANGE.end());
+        FrameLimiter {
+            frame: Duration::from_secs_f64(1.0 / (refresh_rate * speed)),
+            deadline: Instant::now(),
+        }
+    }
+
+    /// Called once per emulated frame, sleeps until it's due
+    pub fn wait(&mut self) {
+        self.deadline += self.frame;
+        let now = Instant::now();
+        if let Some(early) = self.deadline.checked_duration_since(now) {
+            std::thread::sleep(early);
+        } else if now - self.deadline > MAX_FRAME_LAG {
+            // Too slow to keep up, or stopped for a while; racing through
+            // the backlog wouldn't look like anything
+            self.deadline = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_at_hardware_rate() {
+        let speed = Speed {
+            instructions: CPU_CLOCK_HZ * 2,
+            frames: 100,
+            elapsed: Duration::from_secs(2),
+        };
+        assert!((speed.hardware_ratio() - 1.0).abs() < 1e-9);
+        assert!((speed.fps() - 50.0).abs() < 1e-9);
+        assert_eq!(speed.to_string(), "33.87 MIPS, 50.0 fps (100% of hardware)");
+    }
+
+    #[test]
+    fn frame_limiter_paces_frames() {
+        let start = Instant::now();
+        let mut limiter = FrameLimiter::new(50.0, 2.0);
+        for _ in 0..5 {
+            limiter.wait();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn frame_limiter_clamps_speed() {
+        // Would overflow a `Duration` unclamped
+        FrameLimiter::new(60.0, 1e-300);
+        FrameLimiter::new(60.0, 0.0);
+    }
+
+    #[test]
+    fn nothing_measured_in_no_time() {
+        let speed = Speed {
+            instructions: 100,
+            frames: 1,
+            elapsed: Duration::ZERO,
+        };
+        assert_eq!(speed.mips(), 0.0);
+        assert_eq!(speed.fps(), 0.0);
+        let mut counter = PerfCounter::new(0, 0);
+        counter.flush(0, 0);
+        assert!(counter.speed().is_none());
+    }
+}
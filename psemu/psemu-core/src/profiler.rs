@@ -0,0 +1,420 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{self, Write},
+};
+
+use crate::{CallStack, Cpu, SymbolTable, PROGRAM_COUNTER_RESET_VALUE};
+
+// The call tree's root stands for whatever was running when profiling
+// started, before any call was seen
+const ROOT: usize = 0;
+// How many of the busiest instructions the flat report lists
+const HOT_SPOTS: usize = 20;
+
+struct Node {
+    function: u32,
+    parent: usize,
+    children: HashMap<u32, usize>,
+    // Instructions that ran with exactly this call stack
+    cycles: u64,
+}
+
+/// Counts where the guest spends its time: how many instructions ran at each
+/// PC, and under which call stack. Fed one instruction at a time like the
+/// tracer, so every instruction has to go through the interpreter.
+pub struct Profiler {
+    pcs: HashMap<u32, u64>,
+    nodes: Vec<Node>,
+    current: usize,
+    depth: usize,
+    call_stack: CallStack,
+    // Call targets seen so far, to group PCs into functions when there are
+    // no symbols for them
+    functions: BTreeSet<u32>,
+    pc: u32,
+    total: u64,
+}
+
+impl Profiler {
+    pub fn new(cpu: &Cpu) -> Self {
+        // The reset NOP isn't really code, the BIOS starts at the reset vector
+        let entry = match cpu.next_instruction_pc() {
+            pc if pc == PROGRAM_COUNTER_RESET_VALUE.wrapping_sub(4) => PROGRAM_COUNTER_RESET_VALUE,
+            pc => pc,
+        };
+        Profiler {
+            pcs: HashMap::new(),
+            nodes: vec![Node {
+                function: entry,
+                parent: ROOT,
+                children: HashMap::new(),
+                cycles: 0,
+            }],
+            current: ROOT,
+            depth: 0,
+            call_stack: CallStack::default(),
+            functions: BTreeSet::from([entry]),
+            pc: entry,
+            total: 0,
+        }
+    }
+
+    /// Called with the instruction that's about to run
+    pub fn before(&mut self, cpu: &Cpu) {
+        self.pc = cpu.next_instruction_pc();
+        self.call_stack.before(cpu);
+    }
+
+    /// Called once the instruction passed to `before` has run
+    pub fn after(&mut self, cpu: &Cpu) {
+        *self.pcs.entry(self.pc).or_default() += 1;
+        self.nodes[self.current].cycles += 1;
+        self.total += 1;
+        self.call_stack.after(cpu);
+        self.follow_call_stack();
+    }
+
+    /// Instructions counted so far
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    // Moves `current` to the call tree node for the shadow stack
+    fn follow_call_stack(&mut self) {
+        let frames = &self.call_stack.frames;
+        let top = frames
+            .last()
+            .map_or(self.nodes[ROOT].function, |f| f.function);
+        // Most instructions don't call or return
+        if frames.len() == self.depth && self.nodes[self.current].function == top {
+            return;
+        }
+        let mut node = ROOT;
+        for frame in frames {
+            node = match self.nodes[node].children.get(&frame.function) {
+                Some(&child) => child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(Node {
+                        function: frame.function,
+                        parent: node,
+                        children: HashMap::new(),
+                        cycles: 0,
+                    });
+                    self.nodes[node].children.insert(frame.function, child);
+                    self.functions.insert(frame.function);
+                    child
+                }
+            };
+        }
+        self.current = node;
+        self.depth = frames.len();
+    }
+
+    // The function `pc` is in, by symbol if there is one, or else the
+    // closest call target before it. Anything before all of them goes with
+    // where profiling started.
+    fn function_of(&self, symbols: &SymbolTable, pc: u32) -> String {
+        if let Some((name, _)) = symbols.lookup(pc) {
+            return name.to_string();
+        }
+        let start = self.functions.range(..=pc).next_back();
+        symbols.symbolize(start.copied().unwrap_or(self.nodes[ROOT].function))
+    }
+
+    // Nodes above `node`, innermost first
+    fn ancestors(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        std::iter::successors(Some(node), |&n| (n != ROOT).then(|| self.nodes[n].parent)).skip(1)
+    }
+
+    // Instructions run in each node and everything it called
+    fn inclusive_cycles(&self) -> Vec<u64> {
+        let mut inclusive: Vec<u64> = self.nodes.iter().map(|n| n.cycles).collect();
+        // Children are always created after their parent
+        for i in (1..self.nodes.len()).rev() {
+            inclusive[self.nodes[i].parent] += inclusive[i];
+        }
+        inclusive
+    }
+
+    fn percent(&self, cycles: u64) -> f64 {
+        100.0 * cycles as f64 / self.total.max(1) as f64
+    }
+
+    /// Where the time went per function and per instruction, then the call
+    /// graph with each function's callers and callees
+    pub fn write_report(&self, symbols: &SymbolTable, w: &mut impl Write) -> io::Result<()> {
+        writeln!(w, "{} instructions profiled\n", self.total)?;
+
+        let mut functions: HashMap<String, u64> = HashMap::new();
+        for (&pc, &cycles) in &self.pcs {
+            *functions.entry(self.function_of(symbols, pc)).or_default() += cycles;
+        }
+        let mut functions: Vec<_> = functions.into_iter().collect();
+        functions.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        writeln!(w, "Flat profile\n")?;
+        writeln!(w, "   self%      instructions  function")?;
+        for (name, cycles) in &functions {
+            writeln!(w, "  {:>6.2}%  {cycles:>16}  {name}", self.percent(*cycles))?;
+        }
+
+        let mut pcs: Vec<_> = self.pcs.iter().collect();
+        pcs.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        writeln!(w, "\nHot spots\n")?;
+        writeln!(w, "   self%      instructions  pc")?;
+        for (&pc, &cycles) in pcs.into_iter().take(HOT_SPOTS) {
+            let name = match symbols.lookup(pc) {
+                Some(_) => format!(" {}", symbols.symbolize(pc)),
+                None => String::new(),
+            };
+            writeln!(
+                w,
+                "  {:>6.2}%  {cycles:>16}  {pc:#010x}{name}",
+                self.percent(cycles)
+            )?;
+        }
+
+        self.write_call_graph(symbols, w)
+    }
+
+    fn write_call_graph(&self, symbols: &SymbolTable, w: &mut impl Write) -> io::Result<()> {
+        #[derive(Default)]
+        struct Entry {
+            self_cycles: u64,
+            total_cycles: u64,
+            callers: HashMap<String, u64>,
+            callees: HashMap<String, u64>,
+        }
+
+        let inclusive = self.inclusive_cycles();
+        let names: Vec<String> = self
+            .nodes
+            .iter()
+            .map(|n| symbols.symbolize(n.function))
+            .collect();
+        let mut entries: HashMap<&str, Entry> = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            // Recursive calls are already counted in the outermost one
+            let recursive = self.ancestors(i).any(|a| names[a] == names[i]);
+            let entry = entries.entry(&names[i]).or_default();
+            entry.self_cycles += node.cycles;
+            if !recursive {
+                entry.total_cycles += inclusive[i];
+            }
+            // Likewise a call from the same caller further out already
+            // counts this one
+            let caller = &names[node.parent];
+            let recursive_call = self.ancestors(i).any(|a| {
+                a != ROOT && names[a] == names[i] && names[self.nodes[a].parent] == *caller
+            });
+            if i != ROOT && !recursive_call {
+                *entry.callers.entry(caller.clone()).or_default() += inclusive[i];
+                let caller = entries.entry(caller).or_default();
+                *caller.callees.entry(names[i].clone()).or_default() += inclusive[i];
+            }
+        }
+
+        let mut entries: Vec<_> = entries.into_iter().collect();
+        entries.sort_by(|a, b| {
+            (b.1.total_cycles, b.1.self_cycles)
+                .cmp(&(a.1.total_cycles, a.1.self_cycles))
+                .then_with(|| a.0.cmp(b.0))
+        });
+        writeln!(
+            w,
+            "\nCall graph (total includes everything a function called)\n"
+        )?;
+        writeln!(w, "  total%    self%  function")?;
+        for (name, entry) in entries {
+            writeln!(
+                w,
+                "  {:>6.2}%  {:>6.2}%  {name}",
+                self.percent(entry.total_cycles),
+                self.percent(entry.self_cycles)
+            )?;
+            let mut callers: Vec<_> = entry.callers.into_iter().collect();
+            callers.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            for (caller, cycles) in callers {
+                writeln!(
+                    w,
+                    "                      <- {caller} ({:.2}%)",
+                    self.percent(cycles)
+                )?;
+            }
+            let mut callees: Vec<_> = entry.callees.into_iter().collect();
+            callees.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            for (callee, cycles) in callees {
+                writeln!(
+                    w,
+                    "                      -> {callee} ({:.2}%)",
+                    self.percent(cycles)
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// One line per call stack, `outer;inner <instructions>`, which is what
+    /// flamegraph.pl, inferno and speedscope read
+    pub fn write_folded(&self, symbols: &SymbolTable, w: &mut impl Write) -> io::Result<()> {
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.cycles == 0 {
+                continue;
+            }
+            let mut stack: Vec<_> = std::iter::once(i)
+                .chain(self.ancestors(i))
+                .map(|n| symbols.symbolize(self.nodes[n].function))
+                .collect();
+            stack.reverse();
+            writeln!(w, "{} {}", stack.join(";"), node.cycles)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: u32 = 0x80010000;
+    const FUNCTION: u32 = 0x80010100;
+    const NOP: u32 = 0;
+    // jal FUNCTION
+    const JAL: u32 = 0x0c000000 | ((FUNCTION & 0x0fffffff) >> 2);
+    // jr $ra
+    const JR_RA: u32 = 0x03e00008;
+
+    // The CPU can't execute calls yet, so fake one: `word` at `pc` runs and
+    // the CPU ends up at `next`
+    fn fake_step(profiler: &mut Profiler, cpu: &mut Cpu, pc: u32, word: u32, next: u32) {
+        cpu.set_next_instruction(pc, word);
+        profiler.before(cpu);
+        if word == JAL {
+            cpu.set_register(crate::RegisterIndex(31), pc + 8);
+        }
+        cpu.set_next_instruction(next, NOP);
+        profiler.after(cpu);
+    }
+
+    fn profile() -> Profiler {
+        let mut cpu = Cpu::builder().entry(PROGRAM).build().unwrap();
+        let mut profiler = Profiler::new(&cpu);
+        fake_step(&mut profiler, &mut cpu, PROGRAM, NOP, PROGRAM + 4);
+        fake_step(&mut profiler, &mut cpu, PROGRAM + 4, JAL, FUNCTION);
+        for i in 0..3 {
+            fake_step(
+                &mut profiler,
+                &mut cpu,
+                FUNCTION + i * 4,
+                NOP,
+                FUNCTION + i * 4 + 4,
+            );
+        }
+        fake_step(&mut profiler, &mut cpu, FUNCTION + 12, JR_RA, PROGRAM + 12);
+        fake_step(&mut profiler, &mut cpu, PROGRAM + 12, NOP, PROGRAM + 16);
+        profiler
+    }
+
+    #[test]
+    fn counts_every_instruction() {
+        let mut cpu = Cpu::builder()
+            .ram(PROGRAM, &[0; 16])
+            .entry(PROGRAM)
+            .build()
+            .unwrap();
+        cpu.instruction_history.set_capacity(0);
+        let mut profiler = Profiler::new(&cpu);
+        for _ in 0..4 {
+            profiler.before(&cpu);
+            cpu.run_single_cycle().unwrap();
+            profiler.after(&cpu);
+        }
+        assert_eq!(profiler.total(), 4);
+        assert_eq!(profiler.pcs.len(), 4);
+        assert!(profiler.pcs.values().all(|&n| n == 1));
+    }
+
+    #[test]
+    fn folds_call_stacks() {
+        let mut symbols = SymbolTable::default();
+        symbols.insert(PROGRAM, "main", None);
+        symbols.insert(FUNCTION, "update", None);
+        let mut out = vec![];
+        profile().write_folded(&symbols, &mut out).unwrap();
+        let mut lines: Vec<_> = std::str::from_utf8(&out).unwrap().lines().collect();
+        lines.sort();
+        assert_eq!(lines, ["main 3", "main;update 4"]);
+    }
+
+    #[test]
+    fn counts_recursion_once() {
+        let mut cpu = Cpu::builder().entry(PROGRAM).build().unwrap();
+        let mut profiler = Profiler::new(&cpu);
+        let p = &mut profiler;
+        fake_step(p, &mut cpu, PROGRAM, NOP, PROGRAM + 4);
+        fake_step(p, &mut cpu, PROGRAM + 4, JAL, FUNCTION);
+        // Calls itself twice, returning to FUNCTION + 12 each time
+        for _ in 0..2 {
+            fake_step(p, &mut cpu, FUNCTION, NOP, FUNCTION + 4);
+            fake_step(p, &mut cpu, FUNCTION + 4, JAL, FUNCTION);
+        }
+        fake_step(p, &mut cpu, FUNCTION, NOP, FUNCTION + 12);
+        fake_step(p, &mut cpu, FUNCTION + 12, JR_RA, FUNCTION + 12);
+        fake_step(p, &mut cpu, FUNCTION + 12, JR_RA, FUNCTION + 12);
+        fake_step(p, &mut cpu, FUNCTION + 12, JR_RA, PROGRAM + 12);
+        fake_step(p, &mut cpu, PROGRAM + 12, NOP, PROGRAM + 16);
+
+        let mut symbols = SymbolTable::default();
+        symbols.insert(PROGRAM, "main", None);
+        symbols.insert(FUNCTION, "update", None);
+        let mut out = vec![];
+        profiler.write_folded(&symbols, &mut out).unwrap();
+        let mut lines: Vec<_> = std::str::from_utf8(&out).unwrap().lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                "main 2",
+                "main;update 4",
+                "main;update;update 3",
+                "main;update;update;update 2"
+            ]
+        );
+
+        let mut out = vec![];
+        profiler.write_report(&symbols, &mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        let call_graph = &report[report.find("Call graph").unwrap()..];
+        let expected = "
+  total%    self%  function
+  100.00%   18.18%  main
+                      -> update (81.82%)
+   81.82%   81.82%  update
+                      <- main (81.82%)
+                      <- update (45.45%)
+                      -> update (45.45%)
+";
+        assert!(call_graph.ends_with(expected), "{call_graph}");
+    }
+
+    #[test]
+    fn attributes_to_discovered_functions_without_symbols() {
+        let profiler = profile();
+        let symbols = SymbolTable::default();
+        assert_eq!(profiler.function_of(&symbols, FUNCTION + 8), "0x80010100");
+        assert_eq!(profiler.function_of(&symbols, PROGRAM + 12), "0x80010000");
+
+        let mut out = vec![];
+        profiler.write_report(&symbols, &mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(
+            report.contains("  57.14%                 4  0x80010100"),
+            "{report}"
+        );
+        assert!(
+            report.contains("  100.00%   42.86%  0x80010000"),
+            "{report}"
+        );
+        assert!(report.contains("-> 0x80010100 (57.14%)"), "{report}");
+    }
+}
@@ -0,0 +1,362 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use tracing::info;
+
+use crate::{Cpu, Instruction, Interconnect, PsemuCoreError};
+
+/// Bumped every time the layout below changes. States written by a different
+/// version are rejected instead of being loaded into a half-initialized machine.
+pub const SAVE_STATE_VERSION: u32 = 5;
+
+const SAVE_STATE_MAGIC: [u8; 4] = *b"PSST";
+const SAVE_STATE_DIR: &str = "./data/states";
+
+/// Where the numbered save slots live on disk
+pub fn slot_path(slot: u8) -> PathBuf {
+    Path::new(SAVE_STATE_DIR).join(format!("slot{slot}.state"))
+}
+
+pub(crate) struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    fn new() -> Self {
+        StateWriter { buf: vec![] }
+    }
+
+    pub(crate) fn write_u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub(crate) fn write_u32s(&mut self, vals: &[u32]) {
+        for val in vals {
+            self.write_u32(*val);
+        }
+    }
+
+    pub(crate) fn write_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+}
+
+pub(crate) struct StateReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        StateReader { buf, pos: 0 }
+    }
+
+    pub(crate) fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], PsemuCoreError> {
+        if self.buf.len() - self.pos < len {
+            return Err(PsemuCoreError::TruncatedSaveState);
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub(crate) fn read_u32(&mut self) -> Result<u32, PsemuCoreError> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub(crate) fn read_u32s(&mut self, vals: &mut [u32]) -> Result<(), PsemuCoreError> {
+        for val in vals.iter_mut() {
+            *val = self.read_u32()?;
+        }
+        Ok(())
+    }
+
+    // Anything left over means the state isn't what we think it is
+    fn finish(&self) -> Result<(), PsemuCoreError> {
+        match self.buf.len() - self.pos {
+            0 => Ok(()),
+            extra => Err(PsemuCoreError::TrailingSaveStateData(extra)),
+        }
+    }
+}
+
+impl Cpu {
+    /// Serialize the whole machine into the versioned save state format
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.buf.extend_from_slice(&SAVE_STATE_MAGIC);
+        w.write_u32(SAVE_STATE_VERSION);
+
+        w.write_u32(self.pc);
+        w.write_u32(self.next_instruction.0);
+        w.write_u32(self.next_instruction_pc);
+        w.write_u32s(&self.registers);
+        w.write_u32(self.cycles as u32);
+        w.write_u32((self.cycles >> 32) as u32);
+        self.interconnect.save_state(&mut w);
+
+        w.buf
+    }
+
+    /// Restore the machine from a buffer produced by `save_state`. The
+    /// machine is left untouched if the state can't be loaded.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), PsemuCoreError> {
+        let mut r = StateReader::new(state);
+        if r.read_bytes(SAVE_STATE_MAGIC.len())? != SAVE_STATE_MAGIC {
+            return Err(PsemuCoreError::InvalidSaveState);
+        }
+        let version = r.read_u32()?;
+        if version != SAVE_STATE_VERSION {
+            return Err(PsemuCoreError::IncompatibleSaveStateVersion {
+                expected: SAVE_STATE_VERSION,
+                found: version,
+            });
+        }
+
+        let pc = r.read_u32()?;
+        let next_instruction = Instruction(r.read_u32()?);
+        let next_instruction_pc = r.read_u32()?;
+        let mut registers = [0; 32];
+        r.read_u32s(&mut registers)?;
+        let cycles = r.read_u32()? as u64 | (r.read_u32()? as u64) << 32;
+        let memory = self.interconnect.read_state(&mut r)?;
+        r.finish()?;
+
+        // Nothing can fail from here on
+        self.interconnect.restore_state(memory);
+        self.pc = pc;
+        self.next_instruction = next_instruction;
+        self.next_instruction_pc = next_instruction_pc;
+        self.registers = registers;
+        self.cycles = cycles;
+        // History from before the load doesn't lead to the restored state
+        self.instruction_history.clear();
+        self.invalidate_block_cache();
+        Ok(())
+    }
+
+    pub fn save_state_to_slot(&self, slot: u8) -> Result<(), PsemuCoreError> {
+        let path = slot_path(slot);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, self.save_state())?;
+        info!(slot, path = %path.display(), "Saved state");
+        Ok(())
+    }
+
+    pub fn load_state_from_slot(&mut self, slot: u8) -> Result<(), PsemuCoreError> {
+        let path = slot_path(slot);
+        let state = fs::read(&path)?;
+        self.load_state(&state)?;
+        info!(slot, path = %path.display(), "Loaded state");
+        Ok(())
+    }
+}
+
+impl Interconnect {
+    fn save_state(&self, w: &mut StateWriter) {
+        // The BIOS is ROM, so only its fingerprint is stored
+        w.write_u32(self.bios.checksum);
+        for memory in [&self.ram.data, &self.scratchpad.data] {
+            let packed = compress(memory);
+            w.write_u32(packed.len() as u32);
+            w.write_bytes(&packed);
+        }
+    }
+
+    // Returns the RAM and scratchpad contents, leaving the machine alone
+    fn read_state(&self, r: &mut StateReader) -> Result<[Vec<u8>; 2], PsemuCoreError> {
+        if r.read_u32()? != self.bios.checksum {
+            return Err(PsemuCoreError::SaveStateBiosMismatch);
+        }
+        let mut read_memory = |len| {
+            let packed_len = r.read_u32()? as usize;
+            decompress(r.read_bytes(packed_len)?, len)
+        };
+        Ok([
+            read_memory(self.ram.data.len())?,
+            read_memory(self.scratchpad.data.len())?,
+        ])
+    }
+
+    fn restore_state(&mut self, [ram, scratchpad]: [Vec<u8>; 2]) {
+        self.ram.data = ram;
+        self.scratchpad.data = scratchpad;
+    }
+}
+
+// PackBits-style run-length encoding. Memory is mostly runs of zeroes, and
+// all 2 MB of it would otherwise go into every state.
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        let run = data[i..]
+            .iter()
+            .take(128)
+            .take_while(|b| **b == data[i])
+            .count();
+        if run >= 3 {
+            out.push((257 - run) as u8);
+            out.push(data[i]);
+            i += run;
+        } else {
+            let start = i;
+            while i < data.len()
+                && i - start < 128
+                && !(i + 2 < data.len() && data[i] == data[i + 1] && data[i] == data[i + 2])
+            {
+                i += 1;
+            }
+            out.push((i - start - 1) as u8);
+            out.extend_from_slice(&data[start..i]);
+        }
+    }
+    out
+}
+
+// Undoes `compress`, failing unless `data` unpacks to exactly `len` bytes
+fn decompress(data: &[u8], len: usize) -> Result<Vec<u8>, PsemuCoreError> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < data.len() {
+        let header = data[i] as usize;
+        i += 1;
+        if header < 128 {
+            let literal = data
+                .get(i..i + header + 1)
+                .ok_or(PsemuCoreError::InvalidSaveState)?;
+            out.extend_from_slice(literal);
+            i += literal.len();
+        } else {
+            let byte = *data.get(i).ok_or(PsemuCoreError::InvalidSaveState)?;
+            out.extend(std::iter::repeat_n(byte, 257 - header));
+            i += 1;
+        }
+        // A run can only overshoot by 128 bytes before this catches it
+        if out.len() > len {
+            return Err(PsemuCoreError::InvalidSaveState);
+        }
+    }
+    if out.len() != len {
+        return Err(PsemuCoreError::InvalidSaveState);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, ExecutionBackend, Interpreter};
+
+    // Offset of the BIOS checksum: magic, version, pc, the next
+    // instruction and its pc, registers and cycles
+    const CHECKSUM_OFFSET: usize = 4 + 4 + 3 * 4 + 32 * 4 + 8;
+
+    fn running_cpu() -> Cpu {
+        let mut cpu = cpu_with(&LOOP);
+        cpu.instruction_history.set_capacity(0);
+        Interpreter.run_until(&mut cpu, 40).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn states_round_trip() {
+        let mut cpu = running_cpu();
+        let state = cpu.save_state();
+        let saved = cpu.state();
+        Interpreter.run_until(&mut cpu, 100).unwrap();
+        assert_ne!(cpu.save_state(), state);
+
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.state(), saved);
+        assert_eq!(cpu.save_state(), state);
+        // Memory is compressed rather than stored raw
+        assert!(state.len() < 64 * 1024, "{} bytes", state.len());
+    }
+
+    #[test]
+    fn bad_states_leave_the_machine_untouched() {
+        let state = running_cpu().save_state();
+        let mut wrong_magic = state.clone();
+        wrong_magic[0] = b'X';
+        let mut wrong_version = state.clone();
+        wrong_version[4] = 9;
+        let mut wrong_bios = state.clone();
+        wrong_bios[CHECKSUM_OFFSET] ^= 1;
+        let mut trailing = state.clone();
+        trailing.extend_from_slice(&[0; 3]);
+
+        let mut cpu = cpu_with(&[]);
+        let before = cpu.save_state();
+        let check = |cpu: &mut Cpu, bad: &[u8], expected: fn(&PsemuCoreError) -> bool| {
+            let res = cpu.load_state(bad);
+            assert!(matches!(&res, Err(e) if expected(e)), "{res:?}");
+            assert_eq!(cpu.save_state(), before);
+        };
+        check(&mut cpu, &wrong_magic, |e| {
+            matches!(e, PsemuCoreError::InvalidSaveState)
+        });
+        check(&mut cpu, &wrong_version, |e| {
+            matches!(
+                e,
+                PsemuCoreError::IncompatibleSaveStateVersion {
+                    expected: SAVE_STATE_VERSION,
+                    found: 9
+                }
+            )
+        });
+        check(&mut cpu, &wrong_bios, |e| {
+            matches!(e, PsemuCoreError::SaveStateBiosMismatch)
+        });
+        check(&mut cpu, &trailing, |e| {
+            matches!(e, PsemuCoreError::TrailingSaveStateData(3))
+        });
+        for len in [
+            0,
+            3,
+            7,
+            CHECKSUM_OFFSET,
+            CHECKSUM_OFFSET + 6,
+            state.len() - 1,
+        ] {
+            check(&mut cpu, &state[..len], |e| {
+                matches!(e, PsemuCoreError::TruncatedSaveState)
+            });
+        }
+    }
+
+    #[test]
+    fn states_from_another_bios_are_rejected() {
+        let state = running_cpu().save_state();
+        let mut cpu = Cpu::builder().bios(vec![1, 2, 3, 4]).build().unwrap();
+        assert!(matches!(
+            cpu.load_state(&state),
+            Err(PsemuCoreError::SaveStateBiosMismatch)
+        ));
+    }
+
+    #[test]
+    fn compression_round_trips() {
+        let mut data = vec![0u8; 1000];
+        data.extend(0..=255);
+        data.extend([7, 7, 1, 7, 7, 7, 2]);
+        data.extend(std::iter::repeat_n(0xff, 129));
+        data.push(3);
+        for len in [0, 1, 2, 3, 130, data.len()] {
+            let slice = &data[..len];
+            assert_eq!(decompress(&compress(slice), len).unwrap(), slice);
+        }
+        assert!(compress(&data).len() < data.len() / 2);
+
+        // Unpacking to the wrong size, or a literal past the end
+        let packed = compress(&data);
+        assert!(decompress(&packed, data.len() - 1).is_err());
+        assert!(decompress(&packed, data.len() + 1).is_err());
+        assert!(decompress(&[5, 1, 2], 6).is_err());
+    }
+}
@@ -0,0 +1,465 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use crate::PsemuCoreError;
+
+// How far past a symbol without a known size an address can be and still be
+// shown relative to it
+pub(crate) const MAX_UNSIZED_OFFSET: u32 = 0x1000;
+
+struct Symbol {
+    name: String,
+    size: Option<u32>,
+}
+
+/// Names for addresses, loaded from the files the homebrew toolchain leaves
+/// behind: ELF executables, PsyQ .SYM files and linker .map files
+#[derive(Default)]
+pub struct SymbolTable {
+    by_addr: BTreeMap<u32, Symbol>,
+    by_name: HashMap<String, u32>,
+}
+
+impl SymbolTable {
+    /// Adds the symbols in `path`, guessing the format from its contents.
+    /// Returns how many addresses got a name.
+    pub fn load(&mut self, path: &Path) -> Result<usize, PsemuCoreError> {
+        let invalid = |reason: String| PsemuCoreError::InvalidSymbolFile {
+            path: path.display().to_string(),
+            reason,
+        };
+        let bytes = std::fs::read(path).map_err(|e| invalid(e.to_string()))?;
+        let before = self.by_addr.len();
+        if bytes.starts_with(b"\x7fELF") {
+            self.parse_elf(&bytes).map_err(invalid)?;
+        } else if bytes.starts_with(b"MND") {
+            self.parse_psyq_sym(&bytes).map_err(invalid)?;
+        } else {
+            self.parse_map(&String::from_utf8_lossy(&bytes));
+        }
+        Ok(self.by_addr.len() - before)
+    }
+
+    pub fn insert(&mut self, addr: u32, name: &str, size: Option<u32>) {
+        self.by_name.entry(name.to_string()).or_insert(addr);
+        // Display the first name given to an address; later ones are usually
+        // aliases or local labels, but can still be looked up
+        self.by_addr.entry(addr).or_insert(Symbol {
+            name: name.to_string(),
+            size,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_addr.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_addr.len()
+    }
+
+    /// Every named address in order, with the symbol's size if the file
+    /// gave one
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &str, Option<u32>)> + '_ {
+        self.by_addr
+            .iter()
+            .map(|(addr, symbol)| (*addr, symbol.name.as_str(), symbol.size))
+    }
+
+    /// The symbol at exactly `addr`
+    pub fn name(&self, addr: u32) -> Option<&str> {
+        self.by_addr.get(&addr).map(|s| s.name.as_str())
+    }
+
+    /// The symbol `addr` falls in, and how far into it
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        let (start, symbol) = self.by_addr.range(..=addr).next_back()?;
+        let offset = addr - start;
+        let limit = symbol.size.unwrap_or(MAX_UNSIZED_OFFSET);
+        // Zero-sized symbols are labels, they only match exactly
+        if offset == 0 || offset < limit {
+            Some((&symbol.name, offset))
+        } else {
+            None
+        }
+    }
+
+    /// `name`, `name+0x10`, or the bare address when nothing matches
+    pub fn symbolize(&self, addr: u32) -> String {
+        match self.lookup(addr) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{name}+{offset:#x}"),
+            None => format!("{addr:#010x}"),
+        }
+    }
+
+    /// The address of `name` or `name+<offset>`
+    pub fn resolve(&self, s: &str) -> Option<u32> {
+        let (name, offset) = match s.split_once('+') {
+            Some((name, offset)) => (name.trim(), parse_number(offset.trim())?),
+            None => (s.trim(), 0),
+        };
+        self.by_name.get(name).map(|addr| addr.wrapping_add(offset))
+    }
+
+    // Only 32-bit little endian, which is all the PlayStation toolchains emit
+    fn parse_elf(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let truncated = || "ELF file is truncated".to_string();
+        let u16_at = |offset: usize| -> Result<u32, String> {
+            let b = bytes.get(offset..offset + 2).ok_or_else(truncated)?;
+            Ok(u16::from_le_bytes(b.try_into().unwrap()) as u32)
+        };
+        let u32_at = |offset: usize| -> Result<u32, String> {
+            let b = bytes.get(offset..offset + 4).ok_or_else(truncated)?;
+            Ok(u32::from_le_bytes(b.try_into().unwrap()))
+        };
+        // EI_CLASS and EI_DATA
+        if bytes.get(4..6) != Some(&[1, 1]) {
+            return Err("Only 32-bit little endian ELF files are supported".to_string());
+        }
+
+        let section_headers = u32_at(0x20)? as usize;
+        let section_header_size = u16_at(0x2e)? as usize;
+        let sections = u16_at(0x30)? as usize;
+        for i in 0..sections {
+            let header = section_headers + i * section_header_size;
+            const SHT_SYMTAB: u32 = 2;
+            if u32_at(header + 4)? != SHT_SYMTAB {
+                continue;
+            }
+            let offset = u32_at(header + 16)? as usize;
+            let size = u32_at(header + 20)? as usize;
+            // The symbol names live in the section `sh_link` points at
+            let strtab_header =
+                section_headers + u32_at(header + 24)? as usize * section_header_size;
+            let strtab = u32_at(strtab_header + 16)? as usize;
+
+            const SYMBOL_SIZE: usize = 16;
+            for symbol in (offset..offset + size).step_by(SYMBOL_SIZE) {
+                let name = strtab + u32_at(symbol)? as usize;
+                let value = u32_at(symbol + 4)?;
+                let size = u32_at(symbol + 8)?;
+                let info = *bytes.get(symbol + 12).ok_or_else(truncated)?;
+                let section = u16_at(symbol + 14)?;
+                // NOTYPE, OBJECT and FUNC; sections and file names aren't
+                // interesting. Undefined symbols have no address.
+                if info & 0xf > 2 || section == 0 {
+                    continue;
+                }
+                let name = bytes.get(name..).ok_or_else(truncated)?;
+                let name = &name[..name.iter().position(|b| *b == 0).ok_or_else(truncated)?];
+                let name = String::from_utf8_lossy(name);
+                // Compiler-generated local labels
+                if name.is_empty() || name.starts_with(".L") || name.starts_with('$') {
+                    continue;
+                }
+                self.insert(value, &name, (size != 0).then_some(size));
+            }
+        }
+        Ok(())
+    }
+
+    // The layout comes from PsyQ's DUMPSYM. Every record is an address and a
+    // tag; tags up to 0x7f are plain symbols, the rest are debug info that
+    // only matters here for its length.
+    fn parse_psyq_sym(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let mut reader = SymReader { bytes, pos: 8 };
+        while reader.pos < bytes.len() {
+            let addr = reader.u32()?;
+            let tag = reader.u8()?;
+            match tag {
+                0x00..=0x7f => {
+                    let name = reader.string()?;
+                    self.insert(addr, &name, None);
+                }
+                0x80 | 0x8a => (),
+                0x82 => reader.skip(1)?,
+                0x84 => reader.skip(2)?,
+                0x86 | 0x8e | 0x90 | 0x92 => reader.skip(4)?,
+                0x88 => {
+                    reader.skip(4)?;
+                    reader.string()?;
+                }
+                // Function start: frame info, then source file and name
+                0x8c => {
+                    reader.skip(2 + 4 + 2 + 4 + 4 + 4)?;
+                    reader.string()?;
+                    let name = reader.string()?;
+                    self.insert(addr, &name, None);
+                }
+                // Type and variable definitions
+                0x94 => {
+                    reader.skip(2 + 2 + 4)?;
+                    reader.string()?;
+                }
+                0x96 => {
+                    reader.skip(2 + 2 + 4)?;
+                    let dims = reader.u16()? as usize;
+                    reader.skip(dims * 4)?;
+                    reader.string()?;
+                    reader.string()?;
+                }
+                _ => return Err(format!("Unknown record {tag:#x} at {:#x}", reader.pos - 5)),
+            }
+        }
+        Ok(())
+    }
+
+    // Handles both GNU ld's and psylink's maps by only looking at lines that
+    // are an address followed by a name, e.g. `0x80010000    main`
+    fn parse_map(&mut self, text: &str) {
+        for line in text.lines() {
+            let mut words = line.split_whitespace();
+            let (Some(addr), Some(name), None) = (words.next(), words.next(), words.next()) else {
+                continue;
+            };
+            let addr = addr.strip_prefix("0x").unwrap_or(addr);
+            let Ok(addr) = u64::from_str_radix(addr, 16) else {
+                continue;
+            };
+            // GNU ld prints 64-bit addresses even for 32-bit targets
+            let Ok(addr) = u32::try_from(addr) else {
+                continue;
+            };
+            let is_identifier = name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$');
+            if !is_identifier
+                || name.starts_with('.')
+                || name.starts_with(|c: char| c.is_ascii_digit())
+            {
+                continue;
+            }
+            self.insert(addr, name, None);
+        }
+    }
+}
+
+struct SymReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl SymReader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or("SYM file is truncated")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), String> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    // Length-prefixed
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u8()? as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+}
+
+/// Parse a number the way addresses are usually typed: `0x` for hex, plain
+/// digits for decimal
+pub fn parse_number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ELF_HEADER_LEN: usize = 0x34;
+    const SECTION_HEADER_LEN: usize = 40;
+
+    // (name, value, size, st_info, st_shndx)
+    const ELF_SYMBOLS: [(&str, u32, u32, u8, u16); 6] = [
+        ("", 0, 0, 0, 0),
+        (".L5", 0x80010008, 0, 0, 1),
+        ("printf", 0, 0, 0x12, 0),
+        ("text", 0x80010000, 0, 3, 1),
+        ("counter", 0x80020000, 4, 0x11, 2),
+        ("main", 0x80010000, 0x20, 0x12, 1),
+    ];
+
+    // Header, section headers, then the symbol table and its strings, so
+    // cutting the file short anywhere loses something the parser needs
+    fn elf() -> Vec<u8> {
+        let mut strtab = vec![0];
+        let mut symtab = vec![];
+        for (name, value, size, info, section) in ELF_SYMBOLS {
+            let name_offset = if name.is_empty() { 0 } else { strtab.len() };
+            strtab.extend(name.as_bytes());
+            if !name.is_empty() {
+                strtab.push(0);
+            }
+            symtab.extend((name_offset as u32).to_le_bytes());
+            symtab.extend(value.to_le_bytes());
+            symtab.extend(size.to_le_bytes());
+            symtab.extend([info, 0]);
+            symtab.extend(section.to_le_bytes());
+        }
+        let symtab_offset = ELF_HEADER_LEN + 3 * SECTION_HEADER_LEN;
+        let strtab_offset = symtab_offset + symtab.len();
+
+        let mut elf = vec![0; ELF_HEADER_LEN];
+        elf[..6].copy_from_slice(b"\x7fELF\x01\x01");
+        elf[0x20..0x24].copy_from_slice(&(ELF_HEADER_LEN as u32).to_le_bytes());
+        elf[0x2e..0x30].copy_from_slice(&(SECTION_HEADER_LEN as u16).to_le_bytes());
+        elf[0x30..0x32].copy_from_slice(&3u16.to_le_bytes());
+        // Null section, then SHT_SYMTAB linked to SHT_STRTAB
+        for (ty, offset, size, link) in [
+            (0, 0, 0, 0),
+            (2, symtab_offset, symtab.len(), 2),
+            (3, strtab_offset, strtab.len(), 0),
+        ] {
+            let mut header = [0; SECTION_HEADER_LEN];
+            header[4..8].copy_from_slice(&(ty as u32).to_le_bytes());
+            header[16..20].copy_from_slice(&(offset as u32).to_le_bytes());
+            header[20..24].copy_from_slice(&(size as u32).to_le_bytes());
+            header[24..28].copy_from_slice(&(link as u32).to_le_bytes());
+            elf.extend(header);
+        }
+        elf.extend(symtab);
+        elf.extend(strtab);
+        elf
+    }
+
+    fn sym() -> Vec<u8> {
+        let mut sym = b"MND\x01\0\0\0\0".to_vec();
+        // Plain symbol
+        sym.extend(0x80010000u32.to_le_bytes());
+        sym.push(0x01);
+        sym.extend(b"\x04main");
+        // Source line, no payload
+        sym.extend(0x80010004u32.to_le_bytes());
+        sym.push(0x80);
+        // Function start
+        sym.extend(0x80010100u32.to_le_bytes());
+        sym.push(0x8c);
+        sym.extend([0; 20]);
+        sym.extend(b"\x06main.c\x04loop");
+        sym
+    }
+
+    #[test]
+    fn parses_elf_symbol_tables() {
+        let mut symbols = SymbolTable::default();
+        symbols.parse_elf(&elf()).unwrap();
+        assert_eq!(
+            symbols.iter().collect::<Vec<_>>(),
+            [
+                (0x80010000, "main", Some(0x20)),
+                (0x80020000, "counter", Some(4)),
+            ]
+        );
+        assert_eq!(symbols.symbolize(0x80010010), "main+0x10");
+        assert_eq!(symbols.symbolize(0x80010020), "0x80010020");
+        assert_eq!(symbols.resolve("counter+4"), Some(0x80020004));
+    }
+
+    #[test]
+    fn malformed_elf_files_are_errors() {
+        let elf = elf();
+        for len in 0..elf.len() {
+            assert!(
+                SymbolTable::default().parse_elf(&elf[..len]).is_err(),
+                "{len} bytes"
+            );
+        }
+        // 64-bit
+        let mut bad = elf.clone();
+        bad[4] = 2;
+        assert!(SymbolTable::default().parse_elf(&bad).is_err());
+        // Section headers past the end of the file
+        let mut bad = elf.clone();
+        bad[0x20..0x24].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(SymbolTable::default().parse_elf(&bad).is_err());
+        // Symbol table size past the end of the file
+        let mut bad = elf.clone();
+        let size = ELF_HEADER_LEN + SECTION_HEADER_LEN + 20;
+        bad[size..size + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(SymbolTable::default().parse_elf(&bad).is_err());
+        // Name offset past the end of the string table
+        let mut bad = elf;
+        let name = ELF_HEADER_LEN + 3 * SECTION_HEADER_LEN + 16;
+        bad[name..name + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(SymbolTable::default().parse_elf(&bad).is_err());
+    }
+
+    #[test]
+    fn parses_psyq_sym_files() {
+        let mut symbols = SymbolTable::default();
+        symbols.parse_psyq_sym(&sym()).unwrap();
+        assert_eq!(symbols.name(0x80010000), Some("main"));
+        assert_eq!(symbols.name(0x80010100), Some("loop"));
+        assert_eq!(symbols.len(), 2);
+    }
+
+    #[test]
+    fn malformed_psyq_sym_files_are_errors() {
+        let sym = sym();
+        // Every cut that isn't between two records
+        for len in (9..sym.len()).filter(|len| ![18, 23].contains(len)) {
+            assert!(
+                SymbolTable::default().parse_psyq_sym(&sym[..len]).is_err(),
+                "{len} bytes"
+            );
+        }
+        let mut unknown = sym;
+        unknown.extend(0x80010200u32.to_le_bytes());
+        unknown.push(0xff);
+        assert_eq!(
+            SymbolTable::default().parse_psyq_sym(&unknown),
+            Err("Unknown record 0xff at 0x3c".to_string())
+        );
+    }
+
+    #[test]
+    fn parses_map_files() {
+        let mut symbols = SymbolTable::default();
+        symbols.parse_map(
+            " .text          0x0000000080010000      0x120 main.o\n\
+             \x20               0x0000000080010000                main\n\
+             80010100 loop\n\
+             0x80010200 .hidden\n\
+             0x80010300 1st\n\
+             0x180010400 too_big\n\
+             0x80010500 not(an_identifier)\n\
+             main 0x80010600\n\
+             \u{fffd}\u{fffd}\n",
+        );
+        assert_eq!(
+            symbols.iter().collect::<Vec<_>>(),
+            [(0x80010000, "main", None), (0x80010100, "loop", None)]
+        );
+    }
+
+    #[test]
+    fn parses_numbers() {
+        assert_eq!(parse_number("0x10"), Some(16));
+        assert_eq!(parse_number("0XfF"), Some(255));
+        assert_eq!(parse_number("10"), Some(10));
+        assert_eq!(parse_number("0x100000000"), None);
+        assert_eq!(parse_number("main"), None);
+    }
+}
@@ -0,0 +1,107 @@
+//! Programs and helpers shared by the tests of the CPU and its backends
+
+use crate::{backend::ExecutionBackend, CachedInterpreter, Cpu, Interpreter};
+
+/// Where test programs are loaded and start running
+pub(crate) const PROGRAM: u32 = 0x80010000;
+
+pub(crate) fn lui(rt: u32, imm: u32) -> u32 {
+    0x3c000000 | (rt << 16) | imm
+}
+
+pub(crate) fn ori(rt: u32, rs: u32, imm: u32) -> u32 {
+    0x34000000 | (rs << 21) | (rt << 16) | imm
+}
+
+pub(crate) fn addiu(rt: u32, rs: u32, imm: u32) -> u32 {
+    0x24000000 | (rs << 21) | (rt << 16) | imm
+}
+
+pub(crate) fn sw(rt: u32, base: u32, offset: u32) -> u32 {
+    0xac000000 | (base << 21) | (rt << 16) | offset
+}
+
+pub(crate) fn sll(rd: u32, rt: u32, sa: u32) -> u32 {
+    (rt << 16) | (rd << 11) | (sa << 6)
+}
+
+pub(crate) fn or(rd: u32, rs: u32, rt: u32) -> u32 {
+    (rs << 21) | (rt << 16) | (rd << 11) | 0x25
+}
+
+pub(crate) fn j(target: u32) -> u32 {
+    0x08000000 | ((target >> 2) & 0x03ffffff)
+}
+
+/// Counts up in $t1 and stores it, forever
+pub(crate) const LOOP: [u32; 7] = [
+    0x3c088001, // lui $t0, 0x8001
+    0x35080080, // ori $t0, $t0, 0x80
+    0x25290001, // addiu $t1, $t1, 1
+    0x00095080, // sll $t2, $t1, 2
+    0xad0a0000, // sw $t2, 0($t0)
+    0x08004002, // j 0x80010008
+    0x01495825, // or $t3, $t2, $t1
+];
+
+/// Overwrites the `ori` at 0x80010018 with `ori $t1, $zero, 2` on the
+/// second time around its loop
+pub(crate) const SELF_MODIFYING: [u32; 10] = [
+    0x3c088001, // lui $t0, 0x8001
+    0x3c0a3409, // lui $t2, 0x3409
+    0x354a0002, // ori $t2, $t2, 2
+    0x08004005, // j 0x80010014
+    0x00000000, // nop
+    0x34090001, // ori $t1, $zero, 1
+    0x34090001, // ori $t1, $zero, 1
+    0xad0a0018, // sw $t2, 0x18($t0)
+    0x08004005, // j 0x80010014
+    0x00000000, // nop
+];
+
+/// A CPU about to run `program` from RAM, with no BIOS
+pub(crate) fn cpu_with(program: &[u32]) -> Cpu {
+    let bytes: Vec<u8> = program.iter().flat_map(|w| w.to_le_bytes()).collect();
+    Cpu::builder()
+        .ram(PROGRAM, &bytes)
+        .entry(PROGRAM)
+        .build()
+        .unwrap()
+}
+
+/// Every backend this target has
+pub(crate) fn backends() -> Vec<Box<dyn ExecutionBackend>> {
+    vec![
+        Box::new(Interpreter),
+        Box::new(CachedInterpreter),
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        Box::new(crate::Recompiler),
+    ]
+}
+
+/// Runs `program` on `backend` for at least `instructions`, then checks it
+/// against the interpreter caught up to the same point. History is off, so
+/// block-based backends don't fall back to single steps.
+pub(crate) fn assert_same_as(
+    backend: &mut dyn ExecutionBackend,
+    program: &[u32],
+    instructions: u64,
+) -> Cpu {
+    let mut cpu = cpu_with(program);
+    cpu.instruction_history.set_capacity(0);
+    backend.run_until(&mut cpu, instructions).unwrap();
+    let mut reference = cpu_with(program);
+    reference.instruction_history.set_capacity(0);
+    Interpreter.run_until(&mut reference, cpu.cycles).unwrap();
+
+    let name = backend.name();
+    assert_eq!(backend.state(&cpu), reference.state(), "{name} diverged");
+    for addr in (PROGRAM..PROGRAM + 0x100).step_by(4) {
+        assert_eq!(
+            cpu.load32(addr),
+            reference.load32(addr),
+            "{name} diverged at {addr:#010x}"
+        );
+    }
+    cpu
+}
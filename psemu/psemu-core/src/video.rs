@@ -0,0 +1,296 @@
+use crate::CPU_CLOCK_HZ;
+
+// Horizontal display range the BIOS sets up (GP1(06h)), in video cycles.
+// Everything outside it is hblank.
+const HDISPLAY_START: u32 = 0x260;
+const HDISPLAY_END: u32 = 0xc60;
+
+/// The console's video standard, which sets the video clock and how many
+/// scanlines make up a frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VideoStandard {
+    Ntsc,
+    Pal,
+}
+
+impl VideoStandard {
+    pub fn video_clock_hz(self) -> u64 {
+        match self {
+            VideoStandard::Ntsc => 53_693_175,
+            VideoStandard::Pal => 53_203_425,
+        }
+    }
+
+    pub fn cycles_per_scanline(self) -> u32 {
+        match self {
+            VideoStandard::Ntsc => 3413,
+            VideoStandard::Pal => 3406,
+        }
+    }
+
+    /// Scanlines in a progressive frame. Interlaced fields alternate between
+    /// this and one fewer.
+    pub fn scanlines(self) -> u32 {
+        match self {
+            VideoStandard::Ntsc => 263,
+            VideoStandard::Pal => 314,
+        }
+    }
+
+    /// First scanline of vblank. There's no GPU to move the vertical display
+    /// range yet, so this is where the standard's 240 or 288 lines end.
+    pub fn vblank_start(self) -> u32 {
+        match self {
+            VideoStandard::Ntsc => 240,
+            VideoStandard::Pal => 288,
+        }
+    }
+}
+
+/// Horizontal resolutions the GPU can output, each a different dotclock
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HorizontalResolution {
+    H256,
+    H320,
+    H368,
+    H512,
+    H640,
+}
+
+impl HorizontalResolution {
+    /// Video cycles per dot
+    pub fn dotclock_divider(self) -> u32 {
+        match self {
+            HorizontalResolution::H256 => 10,
+            HorizontalResolution::H320 => 8,
+            HorizontalResolution::H368 => 7,
+            HorizontalResolution::H512 => 5,
+            HorizontalResolution::H640 => 4,
+        }
+    }
+
+    // GPUSTAT bits 16-18
+    fn gpustat_bits(self) -> u32 {
+        match self {
+            HorizontalResolution::H256 => 0,
+            HorizontalResolution::H320 => 1 << 17,
+            HorizontalResolution::H512 => 2 << 17,
+            HorizontalResolution::H640 => 3 << 17,
+            HorizontalResolution::H368 => 1 << 16,
+        }
+    }
+}
+
+/// Where the beam is, advanced by CPU cycles. Until there's a GPU this only
+/// paces the frontend, but it's also where GPUSTAT's timing bits come from.
+#[derive(Clone, Debug)]
+pub struct VideoTiming {
+    standard: VideoStandard,
+    resolution: HorizontalResolution,
+    interlaced: bool,
+    // Video cycles into the current scanline
+    line_cycle: u32,
+    scanline: u32,
+    odd_field: bool,
+    // CPU cycles times the video clock that haven't added up to a whole
+    // video cycle yet, so converting between the clocks never drifts
+    remainder: u64,
+}
+
+impl VideoTiming {
+    /// 320x240, not interlaced, like after the BIOS sets up the display
+    pub fn new(standard: VideoStandard) -> Self {
+        VideoTiming {
+            standard,
+            resolution: HorizontalResolution::H320,
+            interlaced: false,
+            line_cycle: 0,
+            scanline: 0,
+            odd_field: false,
+            remainder: 0,
+        }
+    }
+
+    pub fn standard(&self) -> VideoStandard {
+        self.standard
+    }
+
+    /// What GP1(08h) would set. Interlacing means 480 or 576 lines, drawn
+    /// as alternating odd and even fields.
+    pub fn set_display_mode(&mut self, resolution: HorizontalResolution, interlaced: bool) {
+        self.resolution = resolution;
+        self.interlaced = interlaced;
+        self.odd_field = false;
+    }
+
+    /// Run the beam for `cpu_cycles`. Returns how many vblanks started.
+    pub fn advance(&mut self, cpu_cycles: u64) -> u32 {
+        self.remainder += cpu_cycles * self.standard.video_clock_hz();
+        let mut video_cycles = self.remainder / CPU_CLOCK_HZ;
+        self.remainder %= CPU_CLOCK_HZ;
+
+        let per_line = self.standard.cycles_per_scanline() as u64;
+        let mut vblanks = 0;
+        while self.line_cycle as u64 + video_cycles >= per_line {
+            video_cycles -= per_line - self.line_cycle as u64;
+            self.line_cycle = 0;
+            self.scanline += 1;
+            if self.scanline == self.standard.vblank_start() {
+                vblanks += 1;
+            }
+            if self.scanline == self.scanlines_this_field() {
+                self.scanline = 0;
+                if self.interlaced {
+                    self.odd_field = !self.odd_field;
+                }
+            }
+        }
+        self.line_cycle += video_cycles as u32;
+        vblanks
+    }
+
+    /// CPU cycles until `advance` returns the next vblank
+    pub fn cycles_to_vblank(&self) -> u64 {
+        let vblank_start = self.standard.vblank_start();
+        let lines = if self.scanline < vblank_start {
+            vblank_start - self.scanline
+        } else {
+            self.scanlines_this_field() - self.scanline + vblank_start
+        };
+        let video_cycles =
+            lines as u64 * self.standard.cycles_per_scanline() as u64 - self.line_cycle as u64;
+        (video_cycles * CPU_CLOCK_HZ - self.remainder).div_ceil(self.standard.video_clock_hz())
+    }
+
+    // Alternating the field lengths is what gives interlaced video its
+    // half line per field
+    fn scanlines_this_field(&self) -> u32 {
+        self.standard.scanlines() - (self.interlaced && self.odd_field) as u32
+    }
+
+    pub fn scanline(&self) -> u32 {
+        self.scanline
+    }
+
+    /// Dot within the scanline at the current dotclock
+    pub fn dot(&self) -> u32 {
+        self.line_cycle / self.resolution.dotclock_divider()
+    }
+
+    pub fn dots_per_scanline(&self) -> u32 {
+        self.standard.cycles_per_scanline() / self.resolution.dotclock_divider()
+    }
+
+    pub fn in_hblank(&self) -> bool {
+        !(HDISPLAY_START..HDISPLAY_END).contains(&self.line_cycle)
+    }
+
+    pub fn in_vblank(&self) -> bool {
+        self.scanline >= self.standard.vblank_start()
+    }
+
+    /// Frames (or interlaced fields) per second
+    pub fn refresh_rate(&self) -> f64 {
+        let lines = self.standard.scanlines() as f64 - if self.interlaced { 0.5 } else { 0.0 };
+        self.standard.video_clock_hz() as f64 / (self.standard.cycles_per_scanline() as f64 * lines)
+    }
+
+    /// The video mode and timing bits of GPUSTAT: 13 (interlace field), 16-18
+    /// (horizontal resolution), 19 (vertical resolution), 20 (PAL), 22
+    /// (interlace) and 31 (odd line being drawn)
+    pub fn gpustat_bits(&self) -> u32 {
+        // Always set when not interlacing
+        let field = !self.interlaced || self.odd_field;
+        // Per field when interlacing, per scanline otherwise, and always
+        // clear in vblank
+        let odd_line = !self.in_vblank()
+            && if self.interlaced {
+                self.odd_field
+            } else {
+                self.scanline & 1 == 1
+            };
+        (field as u32) << 13
+            | self.resolution.gpustat_bits()
+            | (self.interlaced as u32) << 19
+            | ((self.standard == VideoStandard::Pal) as u32) << 20
+            | (self.interlaced as u32) << 22
+            | (odd_line as u32) << 31
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // CPU cycles in a field, rounded up so a run of them ends just past it
+    fn field_cycles(timing: &VideoTiming) -> u64 {
+        (CPU_CLOCK_HZ as f64 / timing.refresh_rate()).ceil() as u64
+    }
+
+    #[test]
+    fn refresh_rates() {
+        let mut ntsc = VideoTiming::new(VideoStandard::Ntsc);
+        let mut pal = VideoTiming::new(VideoStandard::Pal);
+        assert!((ntsc.refresh_rate() - 59.82).abs() < 0.01);
+        assert!((pal.refresh_rate() - 49.75).abs() < 0.01);
+        ntsc.set_display_mode(HorizontalResolution::H640, true);
+        pal.set_display_mode(HorizontalResolution::H640, true);
+        assert!((ntsc.refresh_rate() - 59.94).abs() < 0.01);
+        assert!((pal.refresh_rate() - 49.83).abs() < 0.01);
+    }
+
+    #[test]
+    fn one_vblank_per_frame() {
+        for standard in [VideoStandard::Ntsc, VideoStandard::Pal] {
+            let mut timing = VideoTiming::new(standard);
+            let frame = field_cycles(&timing);
+            let mut vblanks: u32 = (0..frame * 10 / 100).map(|_| timing.advance(100)).sum();
+            vblanks += timing.advance(frame * 10 % 100);
+            assert_eq!(vblanks, 10, "{standard:?}");
+            assert!(timing.scanline() < 2, "{standard:?} drifted");
+        }
+    }
+
+    #[test]
+    fn cycles_to_vblank_lands_on_it() {
+        let mut timing = VideoTiming::new(VideoStandard::Pal);
+        timing.set_display_mode(HorizontalResolution::H640, true);
+        for step in [1, 12345, 300_000, 1_000_000] {
+            timing.advance(step);
+            let cycles = timing.cycles_to_vblank();
+            assert_eq!(timing.advance(cycles - 1), 0);
+            assert_eq!(timing.advance(1), 1);
+            assert_eq!(timing.scanline(), VideoStandard::Pal.vblank_start());
+        }
+    }
+
+    #[test]
+    fn interlaced_fields_toggle_gpustat() {
+        let mut timing = VideoTiming::new(VideoStandard::Ntsc);
+        timing.set_display_mode(HorizontalResolution::H640, true);
+        let field = field_cycles(&timing);
+        let mut fields = vec![];
+        for _ in 0..4 {
+            // Halfway down the screen
+            timing.advance(field / 2);
+            fields.push(timing.gpustat_bits() >> 13 & 1);
+            assert_eq!(timing.gpustat_bits() >> 31, timing.gpustat_bits() >> 13 & 1);
+            timing.advance(field - field / 2);
+        }
+        assert_eq!(fields, [0, 1, 0, 1]);
+        assert_eq!(timing.gpustat_bits() & 0x007f_0000, 0x004e_0000);
+    }
+
+    #[test]
+    fn dotclock_follows_resolution() {
+        let mut timing = VideoTiming::new(VideoStandard::Ntsc);
+        assert_eq!(timing.dots_per_scanline(), 426);
+        timing.set_display_mode(HorizontalResolution::H368, false);
+        assert_eq!(timing.dots_per_scanline(), 487);
+        assert!(timing.in_hblank());
+        // 634 video cycles in
+        timing.advance(400);
+        assert!(!timing.in_hblank());
+        assert_eq!(timing.dot(), 634 / 7);
+    }
+}
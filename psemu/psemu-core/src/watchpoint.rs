@@ -0,0 +1,243 @@
+use std::{fmt, str::FromStr};
+
+use crate::{mask_region, symbols::parse_number};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // Either of the above
+    Access,
+}
+
+impl WatchKind {
+    fn matches(&self, access: WatchKind) -> bool {
+        *self == WatchKind::Access || *self == access
+    }
+}
+
+impl fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchKind::Read => write!(f, "r"),
+            WatchKind::Write => write!(f, "w"),
+            WatchKind::Access => write!(f, "rw"),
+        }
+    }
+}
+
+/// A data load or store made by the CPU
+#[derive(Clone, Debug)]
+pub struct MemoryAccess {
+    pub addr: u32,
+    // In bytes
+    pub size: u32,
+    // Either Read or Write
+    pub kind: WatchKind,
+    // Same as `new` for reads
+    pub old: u32,
+    pub new: u32,
+}
+
+/// Halts execution when the CPU touches `len` bytes starting at `addr`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: u32,
+    pub len: u32,
+    pub kind: WatchKind,
+}
+
+/// Parses `<addr>[,<len>][:w]`, e.g. `0x80010000,4:w`. Defaults to watching
+/// a word for writes. There are no load instructions to trigger `r` or `rw`
+/// yet, so those are rejected rather than accepted and never hit.
+impl FromStr for Watchpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (range, kind) = match s.trim().split_once(':') {
+            Some((range, kind)) => (range, kind),
+            None => (s.trim(), "w"),
+        };
+        let kind = match kind {
+            "w" => WatchKind::Write,
+            "r" | "rw" => {
+                return Err(format!(
+                    "`{kind}` watchpoints aren't supported until loads are emulated"
+                ))
+            }
+            _ => return Err(format!("Invalid watchpoint kind `{kind}`, expected w")),
+        };
+        let (addr, len) = match range.split_once(',') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (range, None),
+        };
+        let addr = parse_number(addr.trim()).ok_or(format!("Invalid address `{addr}`"))?;
+        let len = match len {
+            Some(len) => parse_number(len.trim()).ok_or(format!("Invalid length `{len}`"))?,
+            None => 4,
+        };
+        if len == 0 {
+            return Err("Watchpoint length can't be 0".to_string());
+        }
+        Ok(Watchpoint { addr, len, kind })
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#010x},{}:{}", self.addr, self.len, self.kind)
+    }
+}
+
+impl Watchpoint {
+    // Compares physical addresses, so a watchpoint catches accesses through
+    // any mirror. Ends are 64-bit so a range can reach the top of memory.
+    fn overlaps(&self, addr: u32, size: u32) -> bool {
+        let start = mask_region(self.addr) as u64;
+        let addr = mask_region(addr) as u64;
+        addr < start + self.len as u64 && start < addr + size as u64
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WatchpointHit {
+    pub watchpoint: Watchpoint,
+    // Address of the instruction that made the access
+    pub pc: u32,
+    pub access: MemoryAccess,
+}
+
+impl fmt::Display for WatchpointHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = &self.access;
+        let kind = if access.kind == WatchKind::Read {
+            "read"
+        } else {
+            "write"
+        };
+        write!(
+            f,
+            "{} hit at pc={:#010x}: {}-byte {kind} of {:#010x}, {:#x} -> {:#x}",
+            self.watchpoint, self.pc, access.size, access.addr, access.old, access.new
+        )
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Watchpoints {
+    list: Vec<Watchpoint>,
+    pub(crate) hit: Option<WatchpointHit>,
+}
+
+impl Watchpoints {
+    pub(crate) fn add(&mut self, watchpoint: Watchpoint) {
+        if !self.list.contains(&watchpoint) {
+            self.list.push(watchpoint);
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub(crate) fn remove(&mut self, watchpoint: &Watchpoint) -> bool {
+        let len = self.list.len();
+        self.list.retain(|w| w != watchpoint);
+        self.list.len() != len
+    }
+
+    // Only data accesses should go through here, not instruction fetches.
+    // Until loads are emulated that means stores, which is why parsing
+    // only accepts write watchpoints.
+    pub(crate) fn check(&mut self, access: &MemoryAccess) {
+        if self.hit.is_some() {
+            return;
+        }
+        if let Some(watchpoint) = self
+            .list
+            .iter()
+            .find(|w| w.kind.matches(access.kind) && w.overlaps(access.addr, access.size))
+        {
+            self.hit = Some(WatchpointHit {
+                watchpoint: watchpoint.clone(),
+                // Filled in by the CPU once the instruction is done
+                pc: 0,
+                access: access.clone(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, ExecutionBackend, Interpreter};
+
+    #[test]
+    fn parses_write_watchpoints() {
+        let write = |addr, len| Watchpoint {
+            addr,
+            len,
+            kind: WatchKind::Write,
+        };
+        assert_eq!("0x80010000".parse(), Ok(write(0x80010000, 4)));
+        assert_eq!(" 0x80010000, 2 :w".parse(), Ok(write(0x80010000, 2)));
+        assert_eq!("4096,0x10:w".parse(), Ok(write(0x1000, 0x10)));
+        assert!("0x80010000:r".parse::<Watchpoint>().is_err());
+        assert!("0x80010000,4:rw".parse::<Watchpoint>().is_err());
+        assert!("0x80010000:x".parse::<Watchpoint>().is_err());
+        assert!("main:w".parse::<Watchpoint>().is_err());
+        assert!("0x80010000,0:w".parse::<Watchpoint>().is_err());
+    }
+
+    #[test]
+    fn overlaps_through_mirrors_and_at_the_top_of_memory() {
+        let watch = |addr, len| Watchpoint {
+            addr,
+            len,
+            kind: WatchKind::Write,
+        };
+        let w = watch(0x80010000, 4);
+        assert!(w.overlaps(0x80010000, 4));
+        assert!(w.overlaps(0xa0010002, 1));
+        assert!(w.overlaps(0x0000fffe, 4));
+        assert!(!w.overlaps(0x00010004, 4));
+        assert!(!w.overlaps(0x0000fffc, 4));
+
+        let top = watch(0xfffffffc, 4);
+        assert!(top.overlaps(0xffffffff, 1));
+        assert!(!top.overlaps(0, 4));
+    }
+
+    #[test]
+    fn stores_through_any_mirror_hit() {
+        let program = [
+            lui(8, 0xa001), // KSEG1 mirror of PROGRAM
+            addiu(9, 0, 5),
+            sw(9, 8, 0x100),
+            lui(8, 0x0001), // KUSEG mirror
+            addiu(9, 0, 6),
+            sw(9, 8, 0x100),
+            sw(9, 8, 0x104),
+        ];
+        let mut cpu = cpu_with(&program);
+        cpu.instruction_history.set_capacity(0);
+        cpu.add_watchpoint("0x80010100".parse().unwrap());
+        let mut hits = vec![];
+        for _ in 0..program.len() {
+            Interpreter.step(&mut cpu).unwrap();
+            hits.extend(cpu.take_watchpoint_hit());
+        }
+        let hits: Vec<_> = hits
+            .iter()
+            .map(|h| (h.pc, h.access.addr, h.access.old, h.access.new))
+            .collect();
+        assert_eq!(
+            hits,
+            [
+                (PROGRAM + 8, 0xa0010100, 0, 5),
+                (PROGRAM + 20, 0x00010100, 5, 6),
+            ]
+        );
+    }
+}
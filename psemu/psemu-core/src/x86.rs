@@ -0,0 +1,305 @@
+// Just enough of an x86-64 assembler for the recompiler. Every memory operand
+// is [base + index + disp32], which keeps the encoding in one place.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Reg(u8);
+
+pub(crate) const RAX: Reg = Reg(0);
+pub(crate) const RCX: Reg = Reg(1);
+pub(crate) const RDX: Reg = Reg(2);
+pub(crate) const RBX: Reg = Reg(3);
+pub(crate) const RSP: Reg = Reg(4);
+pub(crate) const RBP: Reg = Reg(5);
+pub(crate) const RSI: Reg = Reg(6);
+pub(crate) const RDI: Reg = Reg(7);
+pub(crate) const R8: Reg = Reg(8);
+pub(crate) const R9: Reg = Reg(9);
+pub(crate) const R10: Reg = Reg(10);
+pub(crate) const R12: Reg = Reg(12);
+pub(crate) const R13: Reg = Reg(13);
+pub(crate) const R14: Reg = Reg(14);
+pub(crate) const R15: Reg = Reg(15);
+
+#[derive(Clone, Copy)]
+pub(crate) struct Mem {
+    base: Reg,
+    index: Option<Reg>,
+    disp: i32,
+}
+
+impl Mem {
+    pub(crate) fn base(base: Reg, disp: i32) -> Self {
+        Mem {
+            base,
+            index: None,
+            disp,
+        }
+    }
+
+    // `index` can't be RSP, there's no encoding for it
+    pub(crate) fn indexed(base: Reg, index: Reg, disp: i32) -> Self {
+        Mem {
+            base,
+            index: Some(index),
+            disp,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Alu {
+    Add,
+    Or,
+    And,
+    Sub,
+    Cmp,
+}
+
+impl Alu {
+    // The /digit used with an immediate
+    fn ext(self) -> u8 {
+        match self {
+            Alu::Add => 0,
+            Alu::Or => 1,
+            Alu::And => 4,
+            Alu::Sub => 5,
+            Alu::Cmp => 7,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Cond {
+    Below = 0x2,
+    AboveEqual = 0x3,
+    Equal = 0x4,
+    NotEqual = 0x5,
+}
+
+/// A forward jump waiting for `bind`
+#[must_use]
+pub(crate) struct Fixup(usize);
+
+#[derive(Default)]
+pub(crate) struct Assembler {
+    pub(crate) code: Vec<u8>,
+}
+
+impl Assembler {
+    fn rex(&mut self, wide: bool, reg: Reg, index: Reg, base: Reg) {
+        let rex = 0x40 | (wide as u8) << 3 | (reg.0 >> 3) << 2 | (index.0 >> 3) << 1 | base.0 >> 3;
+        if rex != 0x40 {
+            self.code.push(rex);
+        }
+    }
+
+    fn rex_mem(&mut self, wide: bool, reg: Reg, mem: Mem) {
+        self.rex(wide, reg, mem.index.unwrap_or(RAX), mem.base);
+    }
+
+    fn modrm_reg(&mut self, reg: u8, rm: Reg) {
+        self.code.push(0xc0 | (reg & 7) << 3 | rm.0 & 7);
+    }
+
+    fn modrm_mem(&mut self, reg: u8, mem: Mem) {
+        // RSP and R12 as a base need a SIB byte
+        if mem.index.is_none() && mem.base.0 & 7 != 4 {
+            self.code.push(0x80 | (reg & 7) << 3 | mem.base.0 & 7);
+        } else {
+            // Index 4 without REX.X means no index
+            let index = mem.index.map_or(4, |r| r.0 & 7);
+            self.code.push(0x80 | (reg & 7) << 3 | 4);
+            self.code.push(index << 3 | mem.base.0 & 7);
+        }
+        self.imm32(mem.disp as u32);
+    }
+
+    fn imm32(&mut self, imm: u32) {
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    pub(crate) fn mov32(&mut self, dst: Reg, src: Reg) {
+        self.rex(false, src, RAX, dst);
+        self.code.push(0x89);
+        self.modrm_reg(src.0, dst);
+    }
+
+    pub(crate) fn mov32_imm(&mut self, dst: Reg, imm: u32) {
+        self.rex(false, RAX, RAX, dst);
+        self.code.push(0xb8 | dst.0 & 7);
+        self.imm32(imm);
+    }
+
+    pub(crate) fn mov64_imm(&mut self, dst: Reg, imm: u64) {
+        self.rex(true, RAX, RAX, dst);
+        self.code.push(0xb8 | dst.0 & 7);
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    pub(crate) fn load32(&mut self, dst: Reg, mem: Mem) {
+        self.rex_mem(false, dst, mem);
+        self.code.push(0x8b);
+        self.modrm_mem(dst.0, mem);
+    }
+
+    pub(crate) fn load64(&mut self, dst: Reg, mem: Mem) {
+        self.rex_mem(true, dst, mem);
+        self.code.push(0x8b);
+        self.modrm_mem(dst.0, mem);
+    }
+
+    pub(crate) fn store32(&mut self, mem: Mem, src: Reg) {
+        self.rex_mem(false, src, mem);
+        self.code.push(0x89);
+        self.modrm_mem(src.0, mem);
+    }
+
+    pub(crate) fn store64(&mut self, mem: Mem, src: Reg) {
+        self.rex_mem(true, src, mem);
+        self.code.push(0x89);
+        self.modrm_mem(src.0, mem);
+    }
+
+    pub(crate) fn or32(&mut self, dst: Reg, src: Reg) {
+        self.rex(false, src, RAX, dst);
+        self.code.push(0x09);
+        self.modrm_reg(src.0, dst);
+    }
+
+    pub(crate) fn alu32_imm(&mut self, op: Alu, dst: Reg, imm: u32) {
+        self.rex(false, RAX, RAX, dst);
+        self.code.push(0x81);
+        self.modrm_reg(op.ext(), dst);
+        self.imm32(imm);
+    }
+
+    pub(crate) fn alu64_imm(&mut self, op: Alu, dst: Reg, imm: u32) {
+        self.rex(true, RAX, RAX, dst);
+        self.code.push(0x81);
+        self.modrm_reg(op.ext(), dst);
+        self.imm32(imm);
+    }
+
+    pub(crate) fn shl32(&mut self, dst: Reg, amount: u8) {
+        self.rex(false, RAX, RAX, dst);
+        self.code
+            .extend_from_slice(&[0xc1, 0xe0 | dst.0 & 7, amount]);
+    }
+
+    pub(crate) fn shr32(&mut self, dst: Reg, amount: u8) {
+        self.rex(false, RAX, RAX, dst);
+        self.code
+            .extend_from_slice(&[0xc1, 0xe8 | dst.0 & 7, amount]);
+    }
+
+    pub(crate) fn test32_imm(&mut self, dst: Reg, imm: u32) {
+        self.rex(false, RAX, RAX, dst);
+        self.code.push(0xf7);
+        self.modrm_reg(0, dst);
+        self.imm32(imm);
+    }
+
+    pub(crate) fn test32(&mut self, dst: Reg, src: Reg) {
+        self.rex(false, src, RAX, dst);
+        self.code.push(0x85);
+        self.modrm_reg(src.0, dst);
+    }
+
+    pub(crate) fn cmp8_imm(&mut self, mem: Mem, imm: u8) {
+        self.rex_mem(false, RAX, mem);
+        self.code.push(0x80);
+        self.modrm_mem(7, mem);
+        self.code.push(imm);
+    }
+
+    pub(crate) fn push(&mut self, reg: Reg) {
+        self.rex(false, RAX, RAX, reg);
+        self.code.push(0x50 | reg.0 & 7);
+    }
+
+    pub(crate) fn pop(&mut self, reg: Reg) {
+        self.rex(false, RAX, RAX, reg);
+        self.code.push(0x58 | reg.0 & 7);
+    }
+
+    pub(crate) fn call(&mut self, target: Reg) {
+        self.rex(false, RAX, RAX, target);
+        self.code.push(0xff);
+        self.modrm_reg(2, target);
+    }
+
+    pub(crate) fn ret(&mut self) {
+        self.code.push(0xc3);
+    }
+
+    pub(crate) fn jump(&mut self) -> Fixup {
+        self.code.push(0xe9);
+        self.imm32(0);
+        Fixup(self.code.len())
+    }
+
+    pub(crate) fn jump_if(&mut self, cond: Cond) -> Fixup {
+        self.code.extend_from_slice(&[0x0f, 0x80 | cond as u8]);
+        self.imm32(0);
+        Fixup(self.code.len())
+    }
+
+    /// Point `fixup` at the next instruction emitted
+    pub(crate) fn bind(&mut self, fixup: Fixup) {
+        let rel = (self.code.len() - fixup.0) as u32;
+        self.code[fixup.0 - 4..fixup.0].copy_from_slice(&rel.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble(f: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+        let mut asm = Assembler::default();
+        f(&mut asm);
+        asm.code
+    }
+
+    // Expected bytes checked with `objdump -D -b binary -mi386:x86-64`
+    #[test]
+    fn encodes_extended_registers() {
+        assert_eq!(assemble(|a| a.mov32(R12, RAX)), [0x41, 0x89, 0xc4]);
+        assert_eq!(assemble(|a| a.push(R15)), [0x41, 0x57]);
+        assert_eq!(
+            assemble(|a| a.alu32_imm(Alu::Add, R13, 1)),
+            [0x41, 0x81, 0xc5, 1, 0, 0, 0]
+        );
+        assert_eq!(assemble(|a| a.shl32(R14, 2)), [0x41, 0xc1, 0xe6, 2]);
+    }
+
+    #[test]
+    fn encodes_memory_operands() {
+        assert_eq!(
+            assemble(|a| a.load64(RDI, Mem::base(RSP, 0))),
+            [0x48, 0x8b, 0xbc, 0x24, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            assemble(|a| a.store32(Mem::base(RBX, 8), R12)),
+            [0x44, 0x89, 0xa3, 8, 0, 0, 0]
+        );
+        assert_eq!(
+            assemble(|a| a.store32(Mem::indexed(R9, RDX, 0), RCX)),
+            [0x41, 0x89, 0x8c, 0x11, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            assemble(|a| a.cmp8_imm(Mem::indexed(R9, R10, 0), 0)),
+            [0x43, 0x80, 0xbc, 0x11, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn binds_forward_jumps() {
+        let code = assemble(|a| {
+            let fixup = a.jump_if(Cond::NotEqual);
+            a.ret();
+            a.bind(fixup);
+        });
+        assert_eq!(code, [0x0f, 0x85, 1, 0, 0, 0, 0xc3]);
+    }
+}
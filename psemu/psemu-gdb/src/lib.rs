@@ -0,0 +1,703 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use tracing::{info, warn};
+
+use psemu_core::{Cpu, RegisterIndex, WatchKind, Watchpoint, WatchpointHit};
+
+// Signal numbers GDB expects in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// GDB's MIPS register numbering
+const REG_STATUS: usize = 32;
+const REG_LO: usize = 33;
+const REG_HI: usize = 34;
+const REG_BADVADDR: usize = 35;
+const REG_CAUSE: usize = 36;
+const REG_PC: usize = 37;
+const REG_FIRST_FPU: usize = 38;
+const NUM_REGISTERS: usize = 72;
+
+// How often a running CPU checks for Ctrl-C from GDB
+const INTERRUPT_POLL_INTERVAL: u64 = 4096;
+
+/// A stream GDB can talk to us over
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Wait for GDB on `addr` (`host:port`, or `unix:/path/to/socket`) and serve
+/// a single session, which ends when GDB detaches or kills the target.
+pub fn serve(cpu: &mut Cpu, addr: &str) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(path) = addr.strip_prefix("unix:") {
+        // A stale socket from a previous run would make bind fail
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        info!(path, "Waiting for GDB to connect");
+        let (stream, _) = listener.accept()?;
+        info!("GDB connected");
+        return GdbStub::new(cpu, stream).run();
+    }
+
+    let listener = TcpListener::bind(addr)?;
+    info!(addr, "Waiting for GDB to connect");
+    let (stream, peer) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    info!(%peer, "GDB connected");
+    GdbStub::new(cpu, stream).run()
+}
+
+enum StopReason {
+    Signal(u8),
+    SwBreak,
+    HwBreak,
+    Watch(WatchpointHit),
+}
+
+pub struct GdbStub<'a, C: Connection> {
+    cpu: &'a mut Cpu,
+    conn: C,
+    // Bytes received but not consumed yet
+    pending: VecDeque<u8>,
+    no_ack: bool,
+    sw_breakpoints: HashSet<u32>,
+    hw_breakpoints: HashSet<u32>,
+    last_stop: StopReason,
+}
+
+impl<'a, C: Connection> GdbStub<'a, C> {
+    pub fn new(cpu: &'a mut Cpu, conn: C) -> Self {
+        GdbStub {
+            cpu,
+            conn,
+            pending: VecDeque::new(),
+            no_ack: false,
+            sw_breakpoints: HashSet::new(),
+            hw_breakpoints: HashSet::new(),
+            last_stop: StopReason::Signal(SIGTRAP),
+        }
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            let packet = match self.read_packet() {
+                Ok(packet) => packet,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    info!("GDB disconnected");
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            let packet = String::from_utf8_lossy(&packet).to_string();
+            match self.handle_packet(&packet)? {
+                Some(reply) => self.send_packet(&reply)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // Returns the reply to send, or None when the session is over
+    fn handle_packet(&mut self, packet: &str) -> io::Result<Option<String>> {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => self.stop_reply(),
+            Some(b'g') => self.read_registers(),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => self.read_register(&packet[1..]),
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b'c') => self.resume(&packet[1..], false)?,
+            Some(b's') => self.resume(&packet[1..], true)?,
+            Some(b'Z') => self.update_breakpoint(&packet[1..], true),
+            Some(b'z') => self.update_breakpoint(&packet[1..], false),
+            Some(b'H') | Some(b'T') => "OK".to_string(),
+            Some(b'k') => return Ok(None),
+            Some(b'D') => {
+                self.send_packet("OK")?;
+                return Ok(None);
+            }
+            _ => self.handle_query(packet),
+        };
+        Ok(Some(reply))
+    }
+
+    fn handle_query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+".to_string()
+        } else if packet == "QStartNoAckMode" {
+            // This packet itself was already acked
+            self.no_ack = true;
+            "OK".to_string()
+        } else if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            read_xfer_chunk(&target_xml(), args)
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else {
+            // Empty reply means "not supported"
+            String::new()
+        }
+    }
+
+    fn stop_reply(&self) -> String {
+        match &self.last_stop {
+            StopReason::Signal(signal) => format!("S{signal:02x}"),
+            StopReason::SwBreak => format!("T{SIGTRAP:02x}swbreak:;"),
+            StopReason::HwBreak => format!("T{SIGTRAP:02x}hwbreak:;"),
+            StopReason::Watch(hit) => {
+                let kind = match hit.watchpoint.kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T{SIGTRAP:02x}{kind}:{:x};", hit.access.addr)
+            }
+        }
+    }
+
+    fn get_register(&self, n: usize) -> Option<u32> {
+        match n {
+            0..=31 => Some(self.cpu.get_registers()[n]),
+            REG_PC => Some(self.cpu.next_instruction_pc()),
+            // COP0, HI/LO and the FPU aren't emulated yet
+            _ => None,
+        }
+    }
+
+    fn set_register(&mut self, n: usize, val: u32) -> bool {
+        match n {
+            0..=31 => {
+                self.cpu.set_register(RegisterIndex(n as u32), val);
+                true
+            }
+            // Rewriting the same PC (e.g. from a `G` packet) mustn't drop a
+            // pending jump in the delay slot
+            REG_PC if val == self.cpu.next_instruction_pc() => true,
+            REG_PC => self.cpu.jump_to(val).is_ok(),
+            _ => false,
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        (0..NUM_REGISTERS)
+            .map(|n| encode_register(self.get_register(n)))
+            .collect()
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        for (n, chunk) in args.as_bytes().chunks(8).enumerate() {
+            // Unavailable registers come back as "xxxxxxxx"
+            if let Some(val) = decode_register(chunk) {
+                self.set_register(n, val);
+            }
+        }
+        "OK".to_string()
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        match usize::from_str_radix(args, 16) {
+            Ok(n) if n < NUM_REGISTERS => encode_register(self.get_register(n)),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let Some((n, val)) = args.split_once('=') else {
+            return "E01".to_string();
+        };
+        let n = usize::from_str_radix(n, 16).ok();
+        let val = decode_register(val.as_bytes());
+        match (n, val) {
+            (Some(n), Some(val)) if self.set_register(n, val) => "OK".to_string(),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn peek8(&self, addr: u32) -> Option<u8> {
+        let word = self.cpu.load32(addr & !3).ok()?;
+        Some(word.to_le_bytes()[(addr & 3) as usize])
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let Some((addr, len)) = parse_addr_len(args) else {
+            return "E01".to_string();
+        };
+        let mut reply = String::new();
+        for i in 0..len.min(0x1000) {
+            match self.peek8(addr.wrapping_add(i)) {
+                Some(byte) => reply.push_str(&format!("{byte:02x}")),
+                // GDB is fine with short reads, as long as there's something
+                None => break,
+            }
+        }
+        if reply.is_empty() {
+            "E01".to_string()
+        } else {
+            reply
+        }
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let Some((header, data)) = args.split_once(':') else {
+            return "E01".to_string();
+        };
+        let (Some((addr, len)), Some(bytes)) = (parse_addr_len(header), decode_hex(data)) else {
+            return "E01".to_string();
+        };
+        if bytes.len() != len as usize {
+            return "E01".to_string();
+        }
+
+        // Writes can't wrap around the end of the address space
+        let Some(end) = addr.checked_add(len) else {
+            return "E01".to_string();
+        };
+
+        // The interconnect only does word accesses, so merge partial words
+        // with what's already there. Every word is read first, so nothing is
+        // written unless the whole range is mapped.
+        let mut words = vec![];
+        let mut word_addr = addr & !3;
+        while word_addr < end {
+            let Ok(old) = self.cpu.load32(word_addr) else {
+                return "E01".to_string();
+            };
+            let mut word = old.to_le_bytes();
+            for (i, byte) in word.iter_mut().enumerate() {
+                let byte_addr = word_addr + i as u32;
+                if byte_addr >= addr && byte_addr < end {
+                    *byte = bytes[(byte_addr - addr) as usize];
+                }
+            }
+            words.push((word_addr, u32::from_le_bytes(word)));
+            match word_addr.checked_add(4) {
+                Some(next) => word_addr = next,
+                None => break,
+            }
+        }
+        for (word_addr, word) in words {
+            if let Err(e) = self.cpu.poke32(word_addr, word) {
+                warn!("GDB memory write failed: {e}");
+                return "E01".to_string();
+            }
+        }
+        "OK".to_string()
+    }
+
+    // Z/z packets: type,addr,kind
+    fn update_breakpoint(&mut self, args: &str, insert: bool) -> String {
+        let mut fields = args.split(',');
+        let (Some(ty), Some(addr), Some(kind)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return "E01".to_string();
+        };
+        let (Ok(addr), Ok(len)) = (u32::from_str_radix(addr, 16), u32::from_str_radix(kind, 16))
+        else {
+            return "E01".to_string();
+        };
+
+        let watch_kind = match ty {
+            "0" | "1" => {
+                let breakpoints = if ty == "0" {
+                    &mut self.sw_breakpoints
+                } else {
+                    &mut self.hw_breakpoints
+                };
+                if insert {
+                    breakpoints.insert(addr);
+                } else {
+                    breakpoints.remove(&addr);
+                }
+                return "OK".to_string();
+            }
+            "2" => WatchKind::Write,
+            // Read and access watchpoints (3 and 4) need load instructions,
+            // so they're reported as unsupported like any other type
+            _ => return String::new(),
+        };
+        let watchpoint = Watchpoint {
+            addr,
+            len,
+            kind: watch_kind,
+        };
+        if insert {
+            self.cpu.add_watchpoint(watchpoint);
+        } else {
+            self.cpu.remove_watchpoint(&watchpoint);
+        }
+        "OK".to_string()
+    }
+
+    fn resume(&mut self, args: &str, step: bool) -> io::Result<String> {
+        if !args.is_empty() {
+            let Ok(addr) = u32::from_str_radix(args, 16) else {
+                return Ok("E01".to_string());
+            };
+            if self.cpu.jump_to(addr).is_err() {
+                return Ok("E01".to_string());
+            }
+        }
+        self.last_stop = self.execute(step)?;
+        Ok(self.stop_reply())
+    }
+
+    fn execute(&mut self, step: bool) -> io::Result<StopReason> {
+        let mut executed = 0u64;
+        loop {
+            if let Err(e) = self.cpu.run_single_cycle() {
+                warn!("CPU stopped: {e}");
+                return Ok(StopReason::Signal(SIGILL));
+            }
+            if let Some(hit) = self.cpu.take_watchpoint_hit() {
+                return Ok(StopReason::Watch(hit));
+            }
+            if step {
+                return Ok(StopReason::Signal(SIGTRAP));
+            }
+
+            let pc = self.cpu.next_instruction_pc();
+            if self.sw_breakpoints.contains(&pc) {
+                return Ok(StopReason::SwBreak);
+            }
+            if self.hw_breakpoints.contains(&pc) {
+                return Ok(StopReason::HwBreak);
+            }
+
+            executed += 1;
+            if executed.is_multiple_of(INTERRUPT_POLL_INTERVAL) && self.poll_interrupt()? {
+                return Ok(StopReason::Signal(SIGINT));
+            }
+        }
+    }
+
+    // GDB sends a raw 0x03 byte when the user hits Ctrl-C
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.conn.set_nonblocking(true)?;
+        let mut buf = [0; 256];
+        let res = self.conn.read(&mut buf);
+        self.conn.set_nonblocking(false)?;
+        match res {
+            Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                let interrupted = buf[..n].contains(&0x03);
+                self.pending.extend(buf[..n].iter().filter(|b| **b != 0x03));
+                Ok(interrupted)
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        if self.pending.is_empty() {
+            let mut buf = [0; 4096];
+            let n = self.conn.read(&mut buf)?;
+            if n == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            self.pending.extend(&buf[..n]);
+        }
+        Ok(self.pending.pop_front().unwrap())
+    }
+
+    // Packets look like `$data#cs`, where cs is the modulo 256 sum of data
+    fn read_packet(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            // Skip acks and stray interrupts until the start of a packet
+            while self.read_byte()? != b'$' {}
+
+            // The checksum covers the escapes as they were sent
+            let mut data = vec![];
+            let mut sum = 0u8;
+            loop {
+                let byte = self.read_byte()?;
+                sum = sum.wrapping_add(byte);
+                match byte {
+                    b'#' => {
+                        sum = sum.wrapping_sub(byte);
+                        break;
+                    }
+                    b'}' => {
+                        let escaped = self.read_byte()?;
+                        sum = sum.wrapping_add(escaped);
+                        data.push(escaped ^ 0x20);
+                    }
+                    byte => data.push(byte),
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|cs| u8::from_str_radix(cs, 16).ok());
+            let valid = expected == Some(sum);
+
+            if !self.no_ack {
+                self.conn.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(data);
+            }
+            warn!("Dropping GDB packet with bad checksum");
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${data}#{:02x}", checksum_of(data.as_bytes()));
+        self.conn.write_all(packet.as_bytes())?;
+        self.conn.flush()
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+// Registers go over the wire in target (little-endian) byte order
+fn encode_register(val: Option<u32>) -> String {
+    match val {
+        Some(val) => val
+            .to_le_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect(),
+        None => "xxxxxxxx".to_string(),
+    }
+}
+
+fn decode_register(hex: &[u8]) -> Option<u32> {
+    let bytes = decode_hex(std::str::from_utf8(hex).ok()?)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_addr_len(args: &str) -> Option<(u32, u32)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((
+        u32::from_str_radix(addr, 16).ok()?,
+        u32::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+// qXfer reads are `offset,length` into the document; `l` marks the last chunk
+fn read_xfer_chunk(doc: &str, args: &str) -> String {
+    let Some((offset, len)) = parse_addr_len(args) else {
+        return "E01".to_string();
+    };
+    let start = (offset as usize).min(doc.len());
+    let end = start.saturating_add(len as usize).min(doc.len());
+    let marker = if end == doc.len() { 'l' } else { 'm' };
+    format!("{marker}{}", &doc[start..end])
+}
+
+// Describes the R3000A in the layout GDB's MIPS support expects
+fn target_xml() -> String {
+    let mut cpu = String::new();
+    for n in 0..32 {
+        cpu.push_str(&format!(r#"<reg name="r{n}" bitsize="32" regnum="{n}"/>"#));
+    }
+    cpu.push_str(&format!(
+        r#"<reg name="lo" bitsize="32" regnum="{REG_LO}"/><reg name="hi" bitsize="32" regnum="{REG_HI}"/><reg name="pc" bitsize="32" regnum="{REG_PC}"/>"#
+    ));
+    let cp0 = format!(
+        r#"<reg name="status" bitsize="32" regnum="{REG_STATUS}"/><reg name="badvaddr" bitsize="32" regnum="{REG_BADVADDR}"/><reg name="cause" bitsize="32" regnum="{REG_CAUSE}"/>"#
+    );
+    let mut fpu = String::new();
+    for n in 0..32 {
+        let regnum = REG_FIRST_FPU + n;
+        fpu.push_str(&format!(
+            r#"<reg name="f{n}" bitsize="32" type="ieee_single" regnum="{regnum}"/>"#
+        ));
+    }
+    fpu.push_str(&format!(
+        r#"<reg name="fcsr" bitsize="32" group="float" regnum="{}"/><reg name="fir" bitsize="32" group="float" regnum="{}"/>"#,
+        REG_FIRST_FPU + 32,
+        REG_FIRST_FPU + 33
+    ));
+
+    format!(
+        r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd"><target version="1.0"><architecture>mips:3000</architecture><feature name="org.gnu.gdb.mips.cpu">{cpu}</feature><feature name="org.gnu.gdb.mips.cp0">{cp0}</feature><feature name="org.gnu.gdb.mips.fpu">{fpu}</feature></target>"#
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAM: u32 = 0x80000000;
+
+    // GDB's side of the connection: everything it sends is queued up front
+    struct FakeGdb {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for FakeGdb {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for FakeGdb {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for FakeGdb {
+        fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn stub<'a>(cpu: &'a mut Cpu, input: &[u8]) -> GdbStub<'a, FakeGdb> {
+        let conn = FakeGdb {
+            input: io::Cursor::new(input.to_vec()),
+            output: vec![],
+        };
+        GdbStub::new(cpu, conn)
+    }
+
+    fn cpu() -> Cpu {
+        Cpu::builder().entry(RAM + 0x100).build().unwrap()
+    }
+
+    fn handle(stub: &mut GdbStub<FakeGdb>, packet: &str) -> String {
+        stub.handle_packet(packet).unwrap().unwrap()
+    }
+
+    #[test]
+    fn hex_helpers() {
+        assert_eq!(checksum_of(b"qSupported"), 0x37);
+        assert_eq!(encode_register(Some(0x12345678)), "78563412");
+        assert_eq!(encode_register(None), "xxxxxxxx");
+        assert_eq!(decode_register(b"78563412"), Some(0x12345678));
+        assert_eq!(decode_register(b"xxxxxxxx"), None);
+        assert_eq!(decode_register(b"785634"), None);
+        assert_eq!(decode_hex("00ff7f"), Some(vec![0, 0xff, 0x7f]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(parse_addr_len("80010000,10"), Some((0x80010000, 0x10)));
+        assert_eq!(parse_addr_len("80010000"), None);
+        assert_eq!(parse_addr_len("100000000,1"), None);
+    }
+
+    #[test]
+    fn reads_packets_and_acks_them() {
+        let mut cpu = cpu();
+        // A bad checksum, then an escaped `}` (0x7d) in a good packet
+        let mut stub = stub(&mut cpu, b"+$g#00$X}]#32");
+        assert_eq!(stub.read_packet().unwrap(), b"X}");
+        assert_eq!(stub.conn.output, b"-+");
+        assert!(stub.read_packet().is_err());
+
+        stub.conn.output.clear();
+        stub.send_packet("OK").unwrap();
+        assert_eq!(stub.conn.output, b"$OK#9a");
+    }
+
+    #[test]
+    fn register_packets_use_gdbs_layout() {
+        let mut cpu = cpu();
+        let mut stub = stub(&mut cpu, b"");
+        let regs = handle(&mut stub, "g");
+        assert_eq!(regs.len(), NUM_REGISTERS * 8);
+        assert_eq!(&regs[..8], "00000000");
+        assert_eq!(&regs[REG_PC * 8..REG_PC * 8 + 8], "00010080");
+        assert_eq!(&regs[REG_LO * 8..REG_LO * 8 + 8], "xxxxxxxx");
+
+        // $t0 = 0x11223344 through G, everything else sent back unchanged
+        let mut regs = regs.into_bytes();
+        regs[8 * 8..9 * 8].copy_from_slice(b"44332211");
+        assert_eq!(
+            handle(&mut stub, &format!("G{}", String::from_utf8(regs).unwrap())),
+            "OK"
+        );
+        assert_eq!(handle(&mut stub, "p8"), "44332211");
+        assert_eq!(handle(&mut stub, "P9=01000000"), "OK");
+        assert_eq!(stub.cpu.get_register(RegisterIndex(9)), 1);
+        assert_eq!(handle(&mut stub, "p25"), "00010080");
+        assert_eq!(handle(&mut stub, "p100"), "E01");
+    }
+
+    #[test]
+    fn memory_packets_merge_partial_words() {
+        let mut cpu = cpu();
+        let mut stub = stub(&mut cpu, b"");
+        assert_eq!(handle(&mut stub, "M80000000,4:11223344"), "OK");
+        assert_eq!(handle(&mut stub, "M80000001,2:aabb"), "OK");
+        assert_eq!(handle(&mut stub, "m80000000,4"), "11aabb44");
+        assert_eq!(stub.cpu.load32(RAM).unwrap(), 0x44bbaa11);
+        // Wrong length, and unmapped memory
+        assert_eq!(handle(&mut stub, "M80000000,4:11"), "E01");
+        assert_eq!(handle(&mut stub, "m00800000,4"), "E01");
+    }
+
+    #[test]
+    fn memory_writes_past_the_end_of_the_address_space_fail() {
+        let mut cpu = cpu();
+        let mut stub = stub(&mut cpu, b"");
+        // The length wraps the end round to mapped RAM at 0
+        assert_eq!(handle(&mut stub, "Mfffffffe,4:aabbccdd"), "E01");
+        assert_eq!(handle(&mut stub, "Mfffffffc,8:aabbccdd11223344"), "E01");
+        assert_eq!(handle(&mut stub, "m0,4"), "00000000");
+    }
+
+    #[test]
+    fn memory_writes_running_off_the_end_of_ram_write_nothing() {
+        let mut cpu = cpu();
+        let mut stub = stub(&mut cpu, b"");
+        assert_eq!(handle(&mut stub, "M801ffffe,4:aabbccdd"), "E01");
+        assert_eq!(handle(&mut stub, "M801ffffc,8:aabbccdd11223344"), "E01");
+        assert_eq!(handle(&mut stub, "m801ffffc,4"), "00000000");
+    }
+
+    #[test]
+    fn only_write_watchpoints_are_supported() {
+        let mut cpu = cpu();
+        let mut stub = stub(&mut cpu, b"");
+        assert_eq!(handle(&mut stub, "Z2,80000000,4"), "OK");
+        assert_eq!(handle(&mut stub, "Z3,80000000,4"), "");
+        assert_eq!(handle(&mut stub, "Z4,80000000,4"), "");
+        assert_eq!(handle(&mut stub, "z2,80000000,4"), "OK");
+    }
+}
@@ -0,0 +1,84 @@
+use psemu_core::{parse_number, Cpu, SymbolTable};
+use tracing::{info, warn};
+
+use crate::expr::Expr;
+
+pub struct Breakpoint {
+    pub addr: u32,
+    // Only stop when this evaluates to non-zero
+    pub condition: Option<Expr>,
+    pub enabled: bool,
+    pub hit_count: u64,
+}
+
+impl Breakpoint {
+    /// Parse `<addr> [if <condition>]`, e.g. `0xbfc00010 if $a0 == 0x80010000`.
+    /// The address can also be a symbol, e.g. `main` or `main+0x10`.
+    pub fn parse(input: &str, symbols: &SymbolTable) -> Result<Self, String> {
+        let (addr, condition) = match input.split_once(" if ") {
+            Some((addr, condition)) => (addr, Some(Expr::parse(condition, symbols)?)),
+            None => (input, None),
+        };
+        let addr = addr.trim();
+        let addr = parse_number(addr)
+            .or_else(|| symbols.resolve(addr))
+            .ok_or(format!("Invalid address or unknown symbol `{addr}`"))?;
+        Ok(Breakpoint {
+            addr,
+            condition,
+            enabled: true,
+            hit_count: 0,
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct Breakpoints {
+    pub list: Vec<Breakpoint>,
+}
+
+impl Breakpoints {
+    pub fn add(&mut self, breakpoint: Breakpoint) {
+        info!(addr = %format!("{:#010x}", breakpoint.addr), "Added breakpoint");
+        self.list.push(breakpoint);
+    }
+
+    pub fn remove(&mut self, idx: usize) {
+        if idx < self.list.len() {
+            self.list.remove(idx);
+        }
+    }
+
+    pub fn toggle(&mut self, idx: usize) {
+        if let Some(breakpoint) = self.list.get_mut(idx) {
+            breakpoint.enabled = !breakpoint.enabled;
+        }
+    }
+
+    /// Called before every instruction; returns the index of the breakpoint
+    /// that should stop execution, if any
+    pub fn check(&mut self, cpu: &Cpu) -> Option<usize> {
+        let pc = cpu.next_instruction_pc();
+        for (i, breakpoint) in self.list.iter_mut().enumerate() {
+            if !breakpoint.enabled || breakpoint.addr != pc {
+                continue;
+            }
+            let hit = match &breakpoint.condition {
+                None => true,
+                Some(condition) => match condition.eval(cpu) {
+                    Ok(val) => val != 0,
+                    // Better to stop than to silently run past it
+                    Err(e) => {
+                        warn!(%condition, "Unable to evaluate breakpoint condition: {e}");
+                        true
+                    }
+                },
+            };
+            if hit {
+                breakpoint.hit_count += 1;
+                return Some(i);
+            }
+        }
+        None
+    }
+}
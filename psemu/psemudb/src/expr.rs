@@ -0,0 +1,313 @@
+use std::fmt;
+
+use psemu_core::{parse_number, Cpu, SymbolTable, REGISTER_NAMES};
+
+/// A small expression language over the machine state, e.g.
+/// `$a0 == 0x80010000 && [$sp+4] != 0`. Comparisons evaluate to 1 or 0 and
+/// `[...]` reads a word from memory. Symbol names stand for their address.
+pub struct Expr {
+    source: String,
+    node: Node,
+}
+
+enum Node {
+    Const(u32),
+    Register(usize),
+    Pc,
+    Memory(Box<Node>),
+    Binary(BinOp, Box<Node>, Box<Node>),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum BinOp {
+    Add,
+    Sub,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+impl Expr {
+    pub fn parse(source: &str, symbols: &SymbolTable) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+            symbols,
+        };
+        let node = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("Unexpected `{token}`"));
+        }
+        Ok(Expr {
+            source: source.trim().to_string(),
+            node,
+        })
+    }
+
+    pub fn eval(&self, cpu: &Cpu) -> Result<u32, String> {
+        eval(&self.node, cpu)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn eval(node: &Node, cpu: &Cpu) -> Result<u32, String> {
+    Ok(match node {
+        Node::Const(val) => *val,
+        Node::Register(i) => cpu.get_registers()[*i],
+        Node::Pc => cpu.next_instruction_pc(),
+        Node::Memory(addr) => cpu.load32(eval(addr, cpu)?)?,
+        Node::Binary(op, lhs, rhs) => {
+            let lhs = eval(lhs, cpu)?;
+            // Short-circuit so `$a0 != 0 && [$a0] == 1` doesn't read address 0
+            match op {
+                BinOp::And if lhs == 0 => return Ok(0),
+                BinOp::Or if lhs != 0 => return Ok(1),
+                _ => (),
+            }
+            let rhs = eval(rhs, cpu)?;
+            match op {
+                BinOp::Add => lhs.wrapping_add(rhs),
+                BinOp::Sub => lhs.wrapping_sub(rhs),
+                BinOp::Eq => (lhs == rhs) as u32,
+                BinOp::Ne => (lhs != rhs) as u32,
+                BinOp::Lt => (lhs < rhs) as u32,
+                BinOp::Le => (lhs <= rhs) as u32,
+                BinOp::Gt => (lhs > rhs) as u32,
+                BinOp::Ge => (lhs >= rhs) as u32,
+                BinOp::And | BinOp::Or => (rhs != 0) as u32,
+            }
+        }
+    })
+}
+
+fn tokenize(source: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' => (),
+            '$' | '0'..='9' | 'a'..='z' | 'A'..='Z' | '_' | '.' => {
+                let mut token = c.to_string();
+                while let Some(c) =
+                    chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '.')
+                {
+                    token.push(c);
+                }
+                tokens.push(token);
+            }
+            '+' | '-' | '[' | ']' | '(' | ')' => tokens.push(c.to_string()),
+            '=' | '!' | '<' | '>' | '&' | '|' => {
+                let mut token = c.to_string();
+                if let Some(next) = chars.next_if(|n| matches!(n, '=' | '&' | '|')) {
+                    token.push(next);
+                }
+                tokens.push(token);
+            }
+            _ => return Err(format!("Unexpected character `{c}`")),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<String>,
+    pos: usize,
+    symbols: &'a SymbolTable,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|t| t.as_str())
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or("Unexpected end of expression")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        let token = self.next()?;
+        if token != expected {
+            return Err(format!("Expected `{expected}`, found `{token}`"));
+        }
+        Ok(())
+    }
+
+    fn binary(
+        &mut self,
+        ops: &[(&str, BinOp)],
+        operand: fn(&mut Self) -> Result<Node, String>,
+    ) -> Result<Node, String> {
+        let mut lhs = operand(self)?;
+        while let Some(op) = self
+            .peek()
+            .and_then(|t| ops.iter().find(|(s, _)| *s == t).map(|(_, op)| *op))
+        {
+            self.pos += 1;
+            let rhs = operand(self)?;
+            lhs = Node::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn or(&mut self) -> Result<Node, String> {
+        self.binary(&[("||", BinOp::Or)], Self::and)
+    }
+
+    fn and(&mut self) -> Result<Node, String> {
+        self.binary(&[("&&", BinOp::And)], Self::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<Node, String> {
+        self.binary(
+            &[
+                ("==", BinOp::Eq),
+                ("!=", BinOp::Ne),
+                ("<=", BinOp::Le),
+                (">=", BinOp::Ge),
+                ("<", BinOp::Lt),
+                (">", BinOp::Gt),
+            ],
+            Self::sum,
+        )
+    }
+
+    fn sum(&mut self) -> Result<Node, String> {
+        self.binary(&[("+", BinOp::Add), ("-", BinOp::Sub)], Self::atom)
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let token = self.next()?;
+        match token.as_str() {
+            "[" => {
+                let addr = self.sum()?;
+                self.expect("]")?;
+                Ok(Node::Memory(Box::new(addr)))
+            }
+            "(" => {
+                let node = self.or()?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            "$pc" => Ok(Node::Pc),
+            reg if reg.starts_with('$') => parse_register(reg)
+                .map(Node::Register)
+                .ok_or(format!("Unknown register `{reg}`")),
+            // Symbols are resolved now, they can't move while debugging
+            word => parse_number(word)
+                .or_else(|| self.symbols.resolve(word))
+                .map(Node::Const)
+                .ok_or(format!("Unknown symbol or invalid number `{word}`")),
+        }
+    }
+}
+
+/// Accepts both `$a0` and `$4`
+pub fn parse_register(name: &str) -> Option<usize> {
+    REGISTER_NAMES.iter().position(|r| *r == name).or_else(|| {
+        name.strip_prefix('$')?
+            .parse()
+            .ok()
+            .filter(|i: &usize| *i < 32)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use psemu_core::RegisterIndex;
+
+    use super::*;
+
+    const DATA: u32 = 0x80010000;
+
+    fn cpu() -> Cpu {
+        let words = [0x11111111u32, DATA + 8, 0x33333333];
+        let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        let mut cpu = Cpu::builder()
+            .ram(DATA, &bytes)
+            .entry(DATA + 0x100)
+            .build()
+            .unwrap();
+        cpu.set_register(RegisterIndex(4), 5); // $a0
+        cpu.set_register(RegisterIndex(29), DATA); // $sp
+        cpu
+    }
+
+    fn eval_str(source: &str) -> Result<u32, String> {
+        let mut symbols = SymbolTable::default();
+        symbols.insert(DATA, "buffer", Some(12));
+        Expr::parse(source, &symbols)?.eval(&cpu())
+    }
+
+    fn parse_error(source: &str) -> String {
+        Expr::parse(source, &SymbolTable::default()).err().unwrap()
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval_str("10 - 3 - 2"), Ok(5));
+        assert_eq!(eval_str("3 == 1 + 2"), Ok(1));
+        assert_eq!(eval_str("1 < 2 == 1"), Ok(1));
+        assert_eq!(eval_str("1 || 0 && 0"), Ok(1));
+        assert_eq!(eval_str("(1 || 0) && 0"), Ok(0));
+        assert_eq!(eval_str("0 - 1 > 0"), Ok(1));
+    }
+
+    #[test]
+    fn registers() {
+        assert_eq!(eval_str("$a0"), Ok(5));
+        assert_eq!(eval_str("$4 + 1"), Ok(6));
+        assert_eq!(eval_str("$zero"), Ok(0));
+        assert_eq!(eval_str("$pc"), Ok(DATA + 0x100));
+        assert_eq!(parse_register("$ra"), Some(31));
+        assert_eq!(parse_register("$31"), Some(31));
+        assert_eq!(parse_register("$32"), None);
+    }
+
+    #[test]
+    fn memory_reads() {
+        assert_eq!(eval_str("[0x80010000]"), Ok(0x11111111));
+        assert_eq!(eval_str("[$sp+8] == 0x33333333"), Ok(1));
+        assert_eq!(eval_str("[buffer + 4]"), Ok(DATA + 8));
+        assert_eq!(eval_str("[[buffer+4]]"), Ok(0x33333333));
+        assert!(eval_str("[0x1f000000]").is_err());
+        // The right hand side is never read
+        assert_eq!(eval_str("0 && [0x1f000000]"), Ok(0));
+        assert_eq!(eval_str("1 || [0x1f000000]"), Ok(1));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse_error("$foo"), "Unknown register `$foo`");
+        assert_eq!(parse_error("1 +"), "Unexpected end of expression");
+        assert_eq!(parse_error("(1"), "Unexpected end of expression");
+        assert_eq!(parse_error("[1)"), "Expected `]`, found `)`");
+        assert_eq!(parse_error("1 2"), "Unexpected `2`");
+        assert_eq!(parse_error("1 * 2"), "Unexpected character `*`");
+        assert_eq!(
+            parse_error("main"),
+            "Unknown symbol or invalid number `main`"
+        );
+        assert_eq!(
+            Expr::parse("  $a0 == 1 ", &SymbolTable::default())
+                .unwrap()
+                .to_string(),
+            "$a0 == 1"
+        );
+    }
+}
@@ -11,21 +11,67 @@ use ratatui::{
     layout::{Constraint, Direction, Layout},
     style::{Color, Modifier, Style},
     text::{Span, Spans},
-    widgets::{Block, Borders, List, ListItem, ListState, Row, Table, TableState, Tabs},
+    widgets::{
+        Block, Borders, Cell, List, ListItem, ListState, Paragraph, Row, Table, TableState, Tabs,
+    },
     Terminal,
 };
 use std::{
-    io::{self, Stdout},
+    fs::File,
+    io::{self, BufWriter, Stdout, Write},
+    path::Path,
     sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{error, info, warn};
+
+use psemu_core::{
+    disassemble, kernel::KernelCall, parse_number, CallStack, Cpu, Flow, Frame, PerfCounter,
+    PsemuCoreError, RegisterIndex, SymbolTable, VideoStandard, Watchpoint, REGISTER_NAMES,
 };
-use tracing::error;
 
-use psemu_core::{Cpu, REGISTER_NAMES};
+mod breakpoints;
+mod expr;
+mod memory;
+mod rewind;
+mod watchpoints;
 
+use breakpoints::{Breakpoint, Breakpoints};
+use expr::Expr;
+use memory::{MemoryView, BYTES_PER_ROW};
+use rewind::RewindBuffer;
+use watchpoints::Watchpoints;
+
+// How many instructions run between checks for a key press while continuing
+const INTERRUPT_POLL_INTERVAL: u64 = 10_000;
+// Instructions shown before and after the PC in the disassembly panel
+const DISASSEMBLY_BEFORE: u32 = 3;
+const DISASSEMBLY_AFTER: u32 = 28;
+// Most recent instructions shown in the history panel
+const HISTORY_ROWS: usize = 256;
+// Where the memory panel starts out: the beginning of RAM, through KSEG0
+const MEMORY_VIEW_START: u32 = 0x80000000;
+
+#[allow(dead_code)]
 #[derive(Copy, Clone, Debug)]
 enum MenuItem {
     Home,
     NextInstruction,
+    Continue,
+    StepOver,
+    StepOut,
+    RunTo,
+    RunCount,
+    RunToVblank,
+    History,
+    Back,
+    Rewind,
+    AddBreakpoint,
+    Watch,
+    Goto,
+    Edit,
+    SaveState,
+    LoadState,
     Quit,
 }
 
@@ -34,7 +80,22 @@ impl From<MenuItem> for usize {
         match input {
             MenuItem::Home => 0,
             MenuItem::NextInstruction => 1,
-            MenuItem::Quit => 2,
+            MenuItem::Continue => 2,
+            MenuItem::StepOver => 3,
+            MenuItem::StepOut => 4,
+            MenuItem::RunTo => 5,
+            MenuItem::RunCount => 6,
+            MenuItem::RunToVblank => 7,
+            MenuItem::History => 8,
+            MenuItem::Back => 9,
+            MenuItem::Rewind => 10,
+            MenuItem::AddBreakpoint => 11,
+            MenuItem::Watch => 12,
+            MenuItem::Goto => 13,
+            MenuItem::Edit => 14,
+            MenuItem::SaveState => 15,
+            MenuItem::LoadState => 16,
+            MenuItem::Quit => 17,
         }
     }
 }
@@ -44,18 +105,445 @@ pub struct Debugger {
     prev_registers: [u32; 32],
     logs: Arc<Mutex<Vec<String>>>,
     auto: bool,
+    // Save state slot used by the save/load keys; picked with 0-9
+    save_slot: u8,
+    rewind: RewindBuffer,
+    breakpoints: Breakpoints,
+    selected_breakpoint: usize,
+    watchpoints: Watchpoints,
+    selected_watchpoint: usize,
+    memory: MemoryView,
+    // Which panel the selection keys act on
+    focus: Panel,
+    // Text being typed in the menu bar, if any
+    prompt: Option<Prompt>,
+    symbols: SymbolTable,
+    // Speed of the last long run, shown in the menu bar
+    perf: PerfCounter,
+    // Vblanks run into, for `perf`. Only ever goes up, rewinding included.
+    frames: u64,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Panel {
+    Breakpoints,
+    Watchpoints,
+    Memory,
+}
+
+#[derive(Clone, Copy)]
+enum PromptKind {
+    AddBreakpoint,
+    AddWatchpoint,
+    GotoMemory,
+    RunTo,
+    RunCount,
+    ExportHistory,
+}
+
+/// Where a run started from the menu stops, besides breakpoints, watchpoints,
+/// errors and key presses
+#[derive(Clone, Copy)]
+enum RunTarget {
+    Breakpoint,
+    // Like a single step, except calls run until they return
+    StepOver,
+    // Until the current function returns
+    StepOut,
+    Address(u32),
+    Count(u64),
+    // Until the next vblank starts
+    Vblank,
+}
+
+struct Prompt {
+    kind: PromptKind,
+    input: String,
+}
+
+impl Prompt {
+    fn title(&self) -> &'static str {
+        match self.kind {
+            PromptKind::AddBreakpoint => "Add breakpoint (<addr|symbol> [if <condition>])",
+            PromptKind::AddWatchpoint => "Add watchpoint (<addr>[,<len>][:w])",
+            PromptKind::GotoMemory => "Go to address (e.g. 0x80010000, $sp, [$a0]+4)",
+            PromptKind::RunTo => "Run to address (empty for the memory cursor)",
+            PromptKind::RunCount => "Run this many instructions",
+            PromptKind::ExportHistory => "Export instruction history to file",
+        }
+    }
 }
 
 impl Debugger {
-    pub fn new(logs: Arc<Mutex<Vec<String>>>, auto: bool) -> Self {
-        let cpu = Cpu::new();
+    pub fn new(logs: Arc<Mutex<Vec<String>>>, auto: bool, video: VideoStandard) -> Self {
+        Debugger::with_cpu(Cpu::new(), logs, auto, video)
+    }
+
+    fn with_cpu(cpu: Cpu, logs: Arc<Mutex<Vec<String>>>, auto: bool, video: VideoStandard) -> Self {
         let prev_registers: [u32; 32] = cpu.get_registers().try_into().unwrap();
+        let mut memory = MemoryView::new(MEMORY_VIEW_START);
+        memory.snapshot(&cpu);
+        let perf = PerfCounter::new(cpu.cycles, 0);
 
         Debugger {
             cpu,
             prev_registers,
             logs,
             auto,
+            save_slot: 0,
+            rewind: RewindBuffer::new(video),
+            breakpoints: Breakpoints::default(),
+            selected_breakpoint: 0,
+            watchpoints: Watchpoints::default(),
+            selected_watchpoint: 0,
+            memory,
+            focus: Panel::Breakpoints,
+            prompt: None,
+            symbols: SymbolTable::default(),
+            perf,
+            frames: 0,
+        }
+    }
+
+    pub fn set_history_capacity(&mut self, capacity: usize) {
+        self.cpu.instruction_history.set_capacity(capacity);
+    }
+
+    pub fn export_history(&self, path: &Path) {
+        let res = File::create(path).and_then(|file| {
+            let mut w = BufWriter::new(file);
+            self.cpu.instruction_history.export(&mut w)?;
+            w.flush()
+        });
+        match res {
+            Ok(()) => info!(
+                path = %path.display(),
+                "Exported {} instructions",
+                self.cpu.instruction_history.len()
+            ),
+            Err(e) => error!(path = %path.display(), "Unable to export history: {e}"),
+        }
+    }
+
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    // Logs the kernel call the CPU is about to make, if any
+    fn trace_kernel_call(&self) {
+        if let Some(call) = KernelCall::at(&self.cpu) {
+            info!(
+                "Kernel call {call} from {}",
+                self.symbols.symbolize(call.return_addr.wrapping_sub(8))
+            );
+        }
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.add(&mut self.cpu, watchpoint);
+    }
+
+    // Returns true if the last instruction hit a watchpoint
+    fn check_watchpoint(&mut self) -> bool {
+        let Some(hit) = self.cpu.take_watchpoint_hit() else {
+            return false;
+        };
+        warn!("Watchpoint {hit}");
+        if let Some(i) = self.watchpoints.record_hit(&hit) {
+            self.selected_watchpoint = i;
+            self.focus = Panel::Watchpoints;
+        }
+        true
+    }
+
+    fn toggle_selected(&mut self) {
+        match self.focus {
+            Panel::Breakpoints => self.breakpoints.toggle(self.selected_breakpoint),
+            Panel::Watchpoints => self
+                .watchpoints
+                .toggle(&mut self.cpu, self.selected_watchpoint),
+            Panel::Memory => (),
+        }
+    }
+
+    fn delete_selected(&mut self) {
+        match self.focus {
+            Panel::Breakpoints => {
+                self.breakpoints.remove(self.selected_breakpoint);
+                self.selected_breakpoint = self.selected_breakpoint.saturating_sub(1);
+            }
+            Panel::Watchpoints => {
+                self.watchpoints
+                    .remove(&mut self.cpu, self.selected_watchpoint);
+                self.selected_watchpoint = self.selected_watchpoint.saturating_sub(1);
+            }
+            Panel::Memory => (),
+        }
+    }
+
+    fn move_selection(&mut self, down: bool) {
+        let (selected, len) = match self.focus {
+            Panel::Breakpoints => (&mut self.selected_breakpoint, self.breakpoints.list.len()),
+            Panel::Watchpoints => (&mut self.selected_watchpoint, self.watchpoints.list.len()),
+            Panel::Memory => {
+                let row = BYTES_PER_ROW as i32;
+                self.memory.move_cursor(if down { row } else { -row });
+                return;
+            }
+        };
+        if down {
+            if *selected + 1 < len {
+                *selected += 1;
+            }
+        } else {
+            *selected = selected.saturating_sub(1);
+        }
+    }
+
+    fn handle_edit_key(&mut self, code: KeyCode) {
+        match code {
+            KeyCode::Char(c) if c.is_ascii_hexdigit() => {
+                let digit = c.to_digit(16).unwrap() as u8;
+                if let Err(e) = self.memory.edit(&mut self.cpu, digit) {
+                    error!("Unable to edit memory: {e}");
+                    self.memory.editing = false;
+                }
+            }
+            KeyCode::Left => self.memory.move_cursor(-1),
+            KeyCode::Right => self.memory.move_cursor(1),
+            KeyCode::Up => self.memory.move_cursor(-(BYTES_PER_ROW as i32)),
+            KeyCode::Down => self.memory.move_cursor(BYTES_PER_ROW as i32),
+            KeyCode::Esc | KeyCode::Enter => self.memory.editing = false,
+            _ => (),
+        }
+    }
+
+    /// Run until `target` is reached, a breakpoint or watchpoint is hit, the
+    /// CPU errors out or a key is pressed
+    fn run_until(&mut self, target: RunTarget) -> Result<(), PsemuCoreError> {
+        self.run_until_with(target, Debugger::execute)
+    }
+
+    // `run_until`, running each instruction with `step`, which returns how
+    // many vblanks started like `execute` does
+    fn run_until_with(
+        &mut self,
+        target: RunTarget,
+        mut step: impl FnMut(&mut Self) -> Result<u32, PsemuCoreError>,
+    ) -> Result<(), PsemuCoreError> {
+        const RA: RegisterIndex = RegisterIndex(31);
+        const JR_RA: u32 = 0x03e00008;
+
+        let target = match target {
+            RunTarget::StepOver => {
+                let pc = self.cpu.next_instruction_pc();
+                match disassemble(self.cpu.next_instruction(), pc).flow {
+                    // Skip the delay slot too
+                    Flow::Call | Flow::IndirectCall => RunTarget::Address(pc.wrapping_add(8)),
+                    _ => RunTarget::Count(1),
+                }
+            }
+            target => target,
+        };
+        let tmp: [u32; 32] = self.cpu.get_registers().try_into().unwrap();
+        self.memory.snapshot(&self.cpu);
+        // Calls made while stepping out that haven't returned yet
+        let mut depth = 0u32;
+        let mut return_addr = None;
+        let mut executed = 0u64;
+        self.perf.restart(self.cpu.cycles, self.frames);
+        let res = loop {
+            if let RunTarget::StepOut = target {
+                let pc = self.cpu.next_instruction_pc();
+                let instr = disassemble(self.cpu.next_instruction(), pc);
+                if matches!(instr.flow, Flow::Call | Flow::IndirectCall) {
+                    depth += 1;
+                } else if instr.raw == JR_RA {
+                    match depth {
+                        0 => return_addr = Some(self.cpu.get_register(RA)),
+                        _ => depth -= 1,
+                    }
+                }
+            }
+            let vblanks = match step(self) {
+                Ok(vblanks) => vblanks,
+                Err(e) => break Err(e),
+            };
+            executed += 1;
+            if self.check_watchpoint() {
+                break Ok(());
+            }
+
+            let pc = self.cpu.next_instruction_pc();
+            let reached = match target {
+                RunTarget::Breakpoint | RunTarget::StepOver => false,
+                // Only once the delay slot has run too
+                RunTarget::StepOut => return_addr == Some(pc),
+                RunTarget::Address(addr) => pc == addr,
+                RunTarget::Count(count) => executed == count,
+                RunTarget::Vblank => vblanks > 0,
+            };
+            if reached {
+                if executed > 1 {
+                    info!(pc = %self.symbols.symbolize(pc), executed, "Stopped");
+                }
+                break Ok(());
+            }
+            if let Some(i) = self.breakpoints.check(&self.cpu) {
+                self.selected_breakpoint = i;
+                info!(pc = %self.symbols.symbolize(pc), "Hit breakpoint {i}");
+                break Ok(());
+            }
+            if executed.is_multiple_of(INTERRUPT_POLL_INTERVAL) {
+                self.perf.update(self.cpu.cycles, self.frames);
+                if key_pressed() {
+                    info!(executed, "Interrupted");
+                    break Ok(());
+                }
+            }
+        };
+        // Short runs are mostly overhead, they'd only make the number jump
+        if executed >= INTERRUPT_POLL_INTERVAL {
+            self.perf.flush(self.cpu.cycles, self.frames);
+        }
+        self.prev_registers = tmp;
+        res
+    }
+
+    // Returns where to run to if the prompt asked for a run
+    fn handle_prompt_key(&mut self, code: KeyCode) -> Option<RunTarget> {
+        let Some(prompt) = &mut self.prompt else {
+            return None;
+        };
+        match code {
+            KeyCode::Char(c) => prompt.input.push(c),
+            KeyCode::Backspace => {
+                prompt.input.pop();
+            }
+            KeyCode::Esc => self.prompt = None,
+            KeyCode::Enter => {
+                let prompt = self.prompt.take().unwrap();
+                match prompt.kind {
+                    PromptKind::AddBreakpoint => {
+                        match Breakpoint::parse(&prompt.input, &self.symbols) {
+                            Ok(breakpoint) => {
+                                self.breakpoints.add(breakpoint);
+                                self.selected_breakpoint = self.breakpoints.list.len() - 1;
+                            }
+                            Err(e) => error!("Invalid breakpoint: {e}"),
+                        }
+                    }
+                    PromptKind::AddWatchpoint => match prompt.input.parse() {
+                        Ok(watchpoint) => {
+                            self.watchpoints.add(&mut self.cpu, watchpoint);
+                            self.selected_watchpoint = self.watchpoints.list.len() - 1;
+                            self.focus = Panel::Watchpoints;
+                        }
+                        Err(e) => error!("Invalid watchpoint: {e}"),
+                    },
+                    PromptKind::GotoMemory => {
+                        match Expr::parse(&prompt.input, &self.symbols)
+                            .and_then(|expr| expr.eval(&self.cpu))
+                        {
+                            Ok(addr) => {
+                                self.memory.goto(addr);
+                                self.focus = Panel::Memory;
+                            }
+                            Err(e) => error!("Invalid address: {e}"),
+                        }
+                    }
+                    PromptKind::RunTo => {
+                        let addr = if prompt.input.trim().is_empty() {
+                            Ok(self.memory.cursor)
+                        } else {
+                            Expr::parse(&prompt.input, &self.symbols)
+                                .and_then(|expr| expr.eval(&self.cpu))
+                        };
+                        match addr {
+                            Ok(addr) => return Some(RunTarget::Address(addr)),
+                            Err(e) => error!("Invalid address: {e}"),
+                        }
+                    }
+                    PromptKind::ExportHistory => {
+                        self.export_history(Path::new(prompt.input.trim()))
+                    }
+                    PromptKind::RunCount => match parse_number(prompt.input.trim()) {
+                        Some(0) | None => error!("Invalid count `{}`", prompt.input.trim()),
+                        Some(count) => return Some(RunTarget::Count(count as u64)),
+                    },
+                }
+            }
+            _ => (),
+        }
+        None
+    }
+
+    // Runs one instruction, keeping everything that follows execution in
+    // sync. Returns how many vblanks started.
+    fn execute(&mut self) -> Result<u32, PsemuCoreError> {
+        let res = self.rewind.step(&mut self.cpu);
+        if let Ok(vblanks) = res {
+            self.frames += vblanks as u64;
+        }
+        self.trace_kernel_call();
+        if let Err(e) = &res {
+            error!("CPU stopped: {e}");
+            for line in self.backtrace().iter().rev() {
+                error!("  {line}");
+            }
+        }
+        res
+    }
+
+    fn step(&mut self) -> Result<(), PsemuCoreError> {
+        let tmp: [u32; 32] = self.cpu.get_registers().try_into().unwrap();
+        self.memory.snapshot(&self.cpu);
+        let res = self.execute().map(|_| ());
+        self.check_watchpoint();
+        self.prev_registers = tmp;
+        res
+    }
+
+    fn step_back(&mut self) {
+        let tmp: [u32; 32] = self.cpu.get_registers().try_into().unwrap();
+        self.memory.snapshot(&self.cpu);
+        if self.rewind.step_back(&mut self.cpu) {
+            self.prev_registers = tmp;
+        } else {
+            warn!("Nothing to step back to");
+        }
+    }
+
+    fn rewind_frame(&mut self) {
+        let tmp: [u32; 32] = self.cpu.get_registers().try_into().unwrap();
+        self.memory.snapshot(&self.cpu);
+        if self.rewind.rewind_frame(&mut self.cpu) {
+            self.prev_registers = tmp;
+        } else {
+            warn!("Nothing to rewind to");
+        }
+    }
+
+    pub fn save_state_slot(&mut self, slot: u8) {
+        self.save_slot = slot;
+        if let Err(e) = self.cpu.save_state_to_slot(slot) {
+            error!(slot, "Unable to save state: {e}");
+        }
+    }
+
+    pub fn load_state_slot(&mut self, slot: u8) {
+        self.save_slot = slot;
+        match self.cpu.load_state_from_slot(slot) {
+            // Nothing "changed" relative to the restored state
+            Ok(()) => {
+                self.prev_registers = self.cpu.get_registers().try_into().unwrap();
+                self.memory.snapshot(&self.cpu);
+                self.rewind.reset();
+                self.rewind
+                    .call_stack_mut()
+                    .reseed(&self.cpu, &self.symbols);
+            }
+            Err(e) => error!(slot, "Unable to load state: {e}"),
         }
     }
 
@@ -66,10 +554,10 @@ impl Debugger {
         // thread::sleep(Duration::from_millis(5000));
 
         if self.auto {
+            self.perf.restart(self.cpu.cycles, self.frames);
             loop {
-                let tmp: [u32; 32] = self.cpu.get_registers().try_into().unwrap();
-                let res = self.cpu.run_single_cycle();
-                self.prev_registers = tmp;
+                let res = self.step();
+                self.perf.update(self.cpu.cycles, self.frames);
                 self.display(&mut term).unwrap();
                 if res.is_err() {
                     break;
@@ -77,20 +565,131 @@ impl Debugger {
             }
         } else {
             loop {
-                match listen_to_events() {
+                if self.prompt.is_some() {
+                    let target = read_key().and_then(|code| self.handle_prompt_key(code));
+                    let res = target.map(|target| self.run_until(target));
+                    self.display(&mut term).unwrap();
+                    if let Some(Err(_)) = res {
+                        break;
+                    }
+                    continue;
+                }
+                if self.memory.editing {
+                    if let Some(code) = read_key() {
+                        self.handle_edit_key(code);
+                    }
+                    self.display(&mut term).unwrap();
+                    continue;
+                }
+                let event = listen_to_events();
+                match event {
                     TermEvent::Quit => {
                         restore_terminal(&mut term).unwrap();
                         break;
                     }
                     TermEvent::Next => {
-                        let tmp: [u32; 32] = self.cpu.get_registers().try_into().unwrap();
-                        let res = self.cpu.run_single_cycle();
-                        self.prev_registers = tmp;
+                        let res = self.step();
                         self.display(&mut term).unwrap();
                         if res.is_err() {
                             break;
                         }
                     }
+                    TermEvent::Run(target) => {
+                        let res = self.run_until(target);
+                        self.display(&mut term).unwrap();
+                        if res.is_err() {
+                            break;
+                        }
+                    }
+                    TermEvent::RunTo | TermEvent::RunCount | TermEvent::ExportHistory => {
+                        let kind = match event {
+                            TermEvent::RunTo => PromptKind::RunTo,
+                            TermEvent::RunCount => PromptKind::RunCount,
+                            _ => PromptKind::ExportHistory,
+                        };
+                        self.prompt = Some(Prompt {
+                            kind,
+                            input: String::new(),
+                        });
+                        self.display(&mut term).unwrap();
+                    }
+                    TermEvent::AddBreakpoint => {
+                        self.prompt = Some(Prompt {
+                            kind: PromptKind::AddBreakpoint,
+                            input: String::new(),
+                        });
+                        self.display(&mut term).unwrap();
+                    }
+                    TermEvent::AddWatchpoint => {
+                        self.prompt = Some(Prompt {
+                            kind: PromptKind::AddWatchpoint,
+                            input: String::new(),
+                        });
+                        self.display(&mut term).unwrap();
+                    }
+                    TermEvent::ToggleSelected => {
+                        self.toggle_selected();
+                        self.display(&mut term).unwrap();
+                    }
+                    TermEvent::DeleteSelected => {
+                        self.delete_selected();
+                        self.display(&mut term).unwrap();
+                    }
+                    TermEvent::SelectPrevious => {
+                        self.move_selection(false);
+                        self.display(&mut term).unwrap();
+                    }
+                    TermEvent::SelectNext => {
+                        self.move_selection(true);
+                        self.display(&mut term).unwrap();
+                    }
+                    TermEvent::SwitchPanel => {
+                        self.focus = match self.focus {
+                            Panel::Breakpoints => Panel::Watchpoints,
+                            Panel::Watchpoints => Panel::Memory,
+                            Panel::Memory => Panel::Breakpoints,
+                        };
+                        self.display(&mut term).unwrap();
+                    }
+                    TermEvent::MoveLeft | TermEvent::MoveRight => {
+                        if self.focus == Panel::Memory {
+                            let left = matches!(event, TermEvent::MoveLeft);
+                            self.memory.move_cursor(if left { -1 } else { 1 });
+                            self.display(&mut term).unwrap();
+                        }
+                    }
+                    TermEvent::Goto => {
+                        self.prompt = Some(Prompt {
+                            kind: PromptKind::GotoMemory,
+                            input: String::new(),
+                        });
+                        self.display(&mut term).unwrap();
+                    }
+                    TermEvent::Edit => {
+                        self.focus = Panel::Memory;
+                        self.memory.editing = true;
+                        self.display(&mut term).unwrap();
+                    }
+                    TermEvent::Back => {
+                        self.step_back();
+                        self.display(&mut term).unwrap();
+                    }
+                    TermEvent::Rewind => {
+                        self.rewind_frame();
+                        self.display(&mut term).unwrap();
+                    }
+                    TermEvent::SaveState => {
+                        self.save_state_slot(self.save_slot);
+                        self.display(&mut term).unwrap();
+                    }
+                    TermEvent::LoadState => {
+                        self.load_state_slot(self.save_slot);
+                        self.display(&mut term).unwrap();
+                    }
+                    TermEvent::SelectSlot(slot) => {
+                        self.save_slot = slot;
+                        self.display(&mut term).unwrap();
+                    }
                     TermEvent::Resize => {
                         self.display(&mut term).unwrap();
                     }
@@ -114,7 +713,7 @@ impl Debugger {
         std::process::exit(0);
     }
 
-    fn get_registers_table(&self) -> Table {
+    fn get_registers_table(&self) -> Table<'_> {
         let mut rows = Vec::new();
         let pc_row = Row::new(vec![
             format!(""),
@@ -159,14 +758,21 @@ impl Debugger {
             .highlight_symbol(">>")
     }
 
-    fn get_asm_instructions_table(&self) -> (Table, TableState) {
+    fn get_asm_instructions_table(&self) -> (Table<'_>, TableState) {
         let mut rows = Vec::new();
-        for instr in &self.cpu.instruction_history {
+        let history = &self.cpu.instruction_history;
+        // Older rows would scroll out of view anyway
+        for instr in history
+            .iter()
+            .skip(history.len().saturating_sub(HISTORY_ROWS))
+        {
             let row = Row::new(vec![
+                format!("{}", instr.cycle),
+                self.symbols.symbolize(instr.pc),
                 format!("{:#010x}", instr.raw),
-                instr.op.to_owned(),
-                instr.human.0.to_owned(),
-                instr.eval.0.to_owned(),
+                instr.op().to_owned(),
+                instr.human().0,
+                instr.eval().0,
             ]);
             rows.push(row);
         }
@@ -176,7 +782,7 @@ impl Debugger {
             .style(Style::default().fg(Color::White))
             // It has an optional header, which is simply a Row always visible at the top.
             .header(
-                Row::new(vec!["raw", "op", "human", "evaluated"])
+                Row::new(vec!["cycle", "location", "raw", "op", "human", "evaluated"])
                     .style(Style::default().fg(Color::Yellow)), // If you want some space between the header and the rest of the rows, you can always
                                                                 // specify some margin at the bottom.
                                                                 // .bottom_margin(1),
@@ -184,11 +790,16 @@ impl Debugger {
             // As any other widget, a Table can be wrapped in a Block.
             .block(
                 Block::default()
-                    .title("asm instructions")
+                    .title(format!(
+                        "asm instructions (step {})",
+                        self.rewind.position()
+                    ))
                     .borders(Borders::ALL),
             )
             // Columns widths are constrained in the same way as Layout...
             .widths(&[
+                Constraint::Length(9),
+                Constraint::Length(20),
                 Constraint::Length(10),
                 Constraint::Length(5),
                 Constraint::Percentage(30),
@@ -202,17 +813,319 @@ impl Debugger {
             .highlight_symbol(">>");
 
         let mut state = TableState::default();
-        let n = if self.cpu.instruction_history.is_empty() {
-            None
+        state.select(history.len().min(HISTORY_ROWS).checked_sub(1));
+        (table, state)
+    }
+
+    fn get_breakpoints_table(&self) -> (Table<'_>, TableState) {
+        let mut rows = Vec::new();
+        for (i, breakpoint) in self.breakpoints.list.iter().enumerate() {
+            let mut row = Row::new(vec![
+                format!("{i}"),
+                if breakpoint.enabled { "on" } else { "off" }.to_string(),
+                self.symbols.symbolize(breakpoint.addr),
+                format!("{}", breakpoint.hit_count),
+                breakpoint
+                    .condition
+                    .as_ref()
+                    .map(|c| c.to_string())
+                    .unwrap_or_default(),
+            ]);
+            if !breakpoint.enabled {
+                row = row.style(Style::default().fg(Color::DarkGray));
+            }
+            rows.push(row);
+        }
+
+        let table = Table::new(rows)
+            .style(Style::default().fg(Color::White))
+            .header(
+                Row::new(vec!["#", "on", "addr", "hits", "condition"])
+                    .style(Style::default().fg(Color::Yellow)),
+            )
+            .block(panel_block(
+                "breakpoints (t)oggle (d)elete",
+                self.focus == Panel::Breakpoints,
+            ))
+            .widths(&[
+                Constraint::Length(2),
+                Constraint::Length(3),
+                Constraint::Length(16),
+                Constraint::Length(5),
+                Constraint::Percentage(100),
+            ])
+            .column_spacing(1)
+            .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+            .highlight_symbol(">>");
+
+        let mut state = TableState::default();
+        if !self.breakpoints.list.is_empty() {
+            state.select(Some(self.selected_breakpoint));
+        }
+        (table, state)
+    }
+
+    // The shadow stack, or a stack walk when it's empty, e.g. before anything
+    // has been called. Innermost frame last.
+    fn call_stack_frames(&self) -> (Vec<Frame>, bool) {
+        let frames = &self.rewind.call_stack().frames;
+        if frames.is_empty() {
+            (CallStack::walk(&self.cpu, &self.symbols), false)
+        } else {
+            (frames.clone(), true)
+        }
+    }
+
+    // One line per frame, outermost first, ending with where the CPU is now
+    fn backtrace(&self) -> Vec<String> {
+        let (frames, _) = self.call_stack_frames();
+        let mut lines = vec![];
+        let mut location = self.cpu.next_instruction_pc();
+        for frame in frames.iter().rev() {
+            lines.push(format!(
+                "{} {} from {}",
+                self.symbols.symbolize(location),
+                if frame.exception { "raised" } else { "called" },
+                self.symbols.symbolize(frame.call_site)
+            ));
+            location = frame.call_site;
+        }
+        lines.push(self.symbols.symbolize(location));
+        lines.reverse();
+        lines
+    }
+
+    fn get_call_stack_table(&self) -> Table<'_> {
+        let (frames, shadow) = self.call_stack_frames();
+        let mut rows = vec![Row::new(vec![
+            "0".to_string(),
+            self.symbols.symbolize(self.cpu.next_instruction_pc()),
+            String::new(),
+        ])];
+        for (i, frame) in frames.iter().rev().enumerate() {
+            let mut row = Row::new(vec![
+                format!("{}", i + 1),
+                self.symbols.symbolize(frame.call_site),
+                if frame.exception {
+                    "exception".to_string()
+                } else {
+                    self.symbols.symbolize(frame.function)
+                },
+            ]);
+            if frame.exception {
+                row = row.style(Style::default().fg(Color::LightRed));
+            }
+            rows.push(row);
+        }
+
+        Table::new(rows)
+            .style(Style::default().fg(Color::White))
+            .header(
+                Row::new(vec!["#", "location", "called"]).style(Style::default().fg(Color::Yellow)),
+            )
+            .block(
+                Block::default()
+                    .title(if shadow {
+                        "call stack"
+                    } else {
+                        "call stack (stack walk)"
+                    })
+                    .borders(Borders::ALL),
+            )
+            .widths(&[
+                Constraint::Length(2),
+                Constraint::Percentage(50),
+                Constraint::Percentage(50),
+            ])
+            .column_spacing(1)
+    }
+
+    fn get_watchpoints_table(&self) -> (Table<'_>, TableState) {
+        let mut rows = Vec::new();
+        for (i, entry) in self.watchpoints.list.iter().enumerate() {
+            let watchpoint = &entry.watchpoint;
+            let mut row = Row::new(vec![
+                format!("{i}"),
+                if entry.enabled { "on" } else { "off" }.to_string(),
+                format!("{:#010x}", watchpoint.addr),
+                format!("{}", watchpoint.len),
+                format!("{}", watchpoint.kind),
+                format!("{}", entry.hit_count),
+            ]);
+            if !entry.enabled {
+                row = row.style(Style::default().fg(Color::DarkGray));
+            }
+            rows.push(row);
+        }
+
+        let table = Table::new(rows)
+            .style(Style::default().fg(Color::White))
+            .header(
+                Row::new(vec!["#", "on", "addr", "len", "kind", "hits"])
+                    .style(Style::default().fg(Color::Yellow)),
+            )
+            .block(panel_block(
+                "watchpoints (tab to select)",
+                self.focus == Panel::Watchpoints,
+            ))
+            .widths(&[
+                Constraint::Length(2),
+                Constraint::Length(3),
+                Constraint::Length(10),
+                Constraint::Length(4),
+                Constraint::Length(4),
+                Constraint::Length(5),
+            ])
+            .column_spacing(1)
+            .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+            .highlight_symbol(">>");
+
+        let mut state = TableState::default();
+        if !self.watchpoints.list.is_empty() {
+            state.select(Some(self.selected_watchpoint));
+        }
+        (table, state)
+    }
+
+    fn get_disassembly_table(&self) -> (Table<'_>, TableState) {
+        let pc = self.cpu.next_instruction_pc();
+        let first = pc.wrapping_sub(DISASSEMBLY_BEFORE * 4);
+        let mut rows = Vec::new();
+        let mut selected = 0;
+        for i in 0..DISASSEMBLY_BEFORE + 1 + DISASSEMBLY_AFTER {
+            let addr = first.wrapping_add(i * 4);
+            if let Some(name) = self.symbols.name(addr) {
+                rows.push(
+                    Row::new(vec![
+                        String::new(),
+                        String::new(),
+                        String::new(),
+                        format!("{name}:"),
+                    ])
+                    .style(Style::default().fg(Color::Cyan)),
+                );
+            }
+            if addr == pc {
+                selected = rows.len();
+            }
+            // The fetched word is what runs next, even if memory has changed
+            // since (or, after reset, it never came from memory at all)
+            let word = if addr == pc {
+                Ok(self.cpu.next_instruction())
+            } else {
+                self.cpu.load32(addr)
+            };
+            let breakpoint = self
+                .breakpoints
+                .list
+                .iter()
+                .any(|b| b.enabled && b.addr == addr);
+            let (raw, text) = match word {
+                Ok(word) => {
+                    let instr = disassemble(word, addr);
+                    let mut text = instr.to_string();
+                    if let Some(target) = instr.target {
+                        if self.symbols.lookup(target).is_some() {
+                            text = text.replace(
+                                &format!("{target:#010x}"),
+                                &self.symbols.symbolize(target),
+                            );
+                        }
+                    }
+                    (format!("{word:08x}"), text)
+                }
+                Err(_) => ("????????".to_string(), String::new()),
+            };
+            let mut row = Row::new(vec![
+                if breakpoint { "*" } else { "" }.to_string(),
+                format!("{addr:#010x}"),
+                raw,
+                text,
+            ]);
+            if breakpoint {
+                row = row.style(Style::default().fg(Color::LightRed));
+            }
+            rows.push(row);
+        }
+
+        let table = Table::new(rows)
+            .style(Style::default().fg(Color::White))
+            .block(Block::default().title("disassembly").borders(Borders::ALL))
+            .widths(&[
+                Constraint::Length(1),
+                Constraint::Length(10),
+                Constraint::Length(8),
+                Constraint::Percentage(100),
+            ])
+            .column_spacing(1)
+            .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+            .highlight_symbol(">>");
+
+        let mut state = TableState::default();
+        state.select(Some(selected));
+        (table, state)
+    }
+
+    fn get_memory_table(&self) -> (Table<'_>, TableState) {
+        let mut rows = Vec::new();
+        for row in 0..memory::ROWS {
+            let addr = self.memory.start().wrapping_add(row * BYTES_PER_ROW);
+            let bytes = MemoryView::read_row(&self.cpu, addr);
+            let mut hex = Vec::new();
+            let mut ascii = String::new();
+            for (i, byte) in bytes.iter().enumerate() {
+                let byte_addr = addr.wrapping_add(i as u32);
+                let mut style = Style::default();
+                if self.memory.changed(byte_addr, *byte) {
+                    style = style.fg(Color::LightRed);
+                }
+                if byte_addr == self.memory.cursor && self.focus == Panel::Memory {
+                    style = style.add_modifier(Modifier::REVERSED);
+                    if self.memory.editing {
+                        style = style.fg(Color::Yellow);
+                    }
+                }
+                let text = match byte {
+                    Some(byte) => format!("{byte:02x}"),
+                    None => "??".to_string(),
+                };
+                hex.push(Span::styled(text, style));
+                hex.push(Span::raw(" "));
+                ascii.push(match byte {
+                    Some(byte) if byte.is_ascii_graphic() || *byte == b' ' => *byte as char,
+                    _ => '.',
+                });
+            }
+            rows.push(Row::new(vec![
+                Cell::from(format!("{addr:#010x}")),
+                Cell::from(Spans::from(hex)),
+                Cell::from(ascii),
+            ]));
+        }
+
+        let title = if self.memory.editing {
+            "memory (editing: hex digits, arrows, esc to stop)"
         } else {
-            Some(self.cpu.instruction_history.len() - 1)
+            "memory (g)oto (e)dit"
         };
-        state.select(n);
+        let table = Table::new(rows)
+            .style(Style::default().fg(Color::White))
+            .block(panel_block(title, self.focus == Panel::Memory))
+            .widths(&[
+                Constraint::Length(10),
+                Constraint::Length(BYTES_PER_ROW as u16 * 3),
+                Constraint::Length(BYTES_PER_ROW as u16),
+            ])
+            .column_spacing(1);
+
+        let mut state = TableState::default();
+        let cursor_row = self.memory.cursor.wrapping_sub(self.memory.start()) / BYTES_PER_ROW;
+        state.select(Some(cursor_row as usize));
         (table, state)
     }
 
     // TODO: Extract this + ChannelLogger into separate crate and publish on crates.io
-    fn get_logs_table(&self) -> (List, ListState) {
+    fn get_logs_table(&self) -> (List<'_>, ListState) {
         let mut items = Vec::new();
         let mut state = ListState::default();
         let mut n = None;
@@ -250,8 +1163,41 @@ impl Debugger {
         let (asm_instructions_table, mut asm_instructions_table_state) =
             self.get_asm_instructions_table();
         let (logs_table, mut logs_table_state) = self.get_logs_table();
+        let (breakpoints_table, mut breakpoints_table_state) = self.get_breakpoints_table();
+        let call_stack_table = self.get_call_stack_table();
+        let (watchpoints_table, mut watchpoints_table_state) = self.get_watchpoints_table();
+        let (memory_table, mut memory_table_state) = self.get_memory_table();
+        let (disassembly_table, mut disassembly_table_state) = self.get_disassembly_table();
+        let prompt = self.prompt.as_ref().map(|prompt| {
+            Paragraph::new(format!("{}_", prompt.input))
+                .block(Block::default().title(prompt.title()).borders(Borders::ALL))
+                .style(Style::default().fg(Color::Yellow))
+        });
 
-        let menu_titles = vec!["Home", "Next Instruction", "Quit"];
+        let menu_titles = [
+            "Home",
+            "Next Instruction",
+            "Continue",
+            "Over",
+            "Finish",
+            "Until",
+            "X times",
+            "Vblank",
+            "History",
+            "Back",
+            "Rewind",
+            "Add Breakpoint",
+            "Watch",
+            "Goto",
+            "Edit",
+            "Save State",
+            "Load State",
+            "Quit",
+        ];
+        let menu_block_title = match self.perf.speed() {
+            Some(speed) => format!("Menu (slot {}) | {speed}", self.save_slot),
+            None => format!("Menu (slot {})", self.save_slot),
+        };
         let active_menu_item = MenuItem::Home;
 
         terminal.draw(|f| {
@@ -287,7 +1233,11 @@ impl Debugger {
 
             let tabs = Tabs::new(menu)
                 .select(active_menu_item.into())
-                .block(Block::default().title("Menu").borders(Borders::ALL))
+                .block(
+                    Block::default()
+                        .title(menu_block_title)
+                        .borders(Borders::ALL),
+                )
                 .style(Style::default().fg(Color::White))
                 .highlight_style(Style::default().fg(Color::Yellow))
                 .divider(Span::raw("|"));
@@ -295,7 +1245,14 @@ impl Debugger {
             let main_view_chunks = Layout::default()
                 .direction(Direction::Horizontal)
                 // .margin(1)
-                .constraints([Constraint::Percentage(15), Constraint::Percentage(85)].as_ref())
+                .constraints(
+                    [
+                        Constraint::Percentage(15),
+                        Constraint::Percentage(60),
+                        Constraint::Percentage(25),
+                    ]
+                    .as_ref(),
+                )
                 .split(outer_view_chunks[1]);
 
             // let right_subview_chunks = Layout::default()
@@ -305,16 +1262,62 @@ impl Debugger {
             //     .split(main_view_chunks[1]);
             // f.render_widget(asm_instructions_table, right_subview_chunks[0]);
 
-            f.render_widget(tabs, outer_view_chunks[0]);
+            match prompt {
+                Some(prompt) => f.render_widget(prompt, outer_view_chunks[0]),
+                None => f.render_widget(tabs, outer_view_chunks[0]),
+            }
 
             f.render_widget(registers_table, main_view_chunks[0]);
             // f.render_widget(asm_instructions_table, main_view_chunks[1]);
 
+            let middle_subview_chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints(
+                    [
+                        Constraint::Percentage(35),
+                        Constraint::Percentage(30),
+                        Constraint::Percentage(35),
+                    ]
+                    .as_ref(),
+                )
+                .split(main_view_chunks[1]);
             f.render_stateful_widget(
                 asm_instructions_table,
-                main_view_chunks[1],
+                middle_subview_chunks[0],
                 &mut asm_instructions_table_state,
             );
+            f.render_stateful_widget(
+                disassembly_table,
+                middle_subview_chunks[1],
+                &mut disassembly_table_state,
+            );
+            f.render_stateful_widget(
+                memory_table,
+                middle_subview_chunks[2],
+                &mut memory_table_state,
+            );
+            let right_subview_chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints(
+                    [
+                        Constraint::Percentage(34),
+                        Constraint::Percentage(33),
+                        Constraint::Percentage(33),
+                    ]
+                    .as_ref(),
+                )
+                .split(main_view_chunks[2]);
+            f.render_widget(call_stack_table, right_subview_chunks[0]);
+            f.render_stateful_widget(
+                breakpoints_table,
+                right_subview_chunks[1],
+                &mut breakpoints_table_state,
+            );
+            f.render_stateful_widget(
+                watchpoints_table,
+                right_subview_chunks[2],
+                &mut watchpoints_table_state,
+            );
 
             f.render_stateful_widget(logs_table, outer_view_chunks[2], &mut logs_table_state);
         })?;
@@ -323,6 +1326,19 @@ impl Debugger {
     }
 }
 
+// Panels whose selection can be moved get a highlighted border when focused
+fn panel_block(title: &str, focused: bool) -> Block<'_> {
+    let border_style = if focused {
+        Style::default().fg(Color::Yellow)
+    } else {
+        Style::default()
+    };
+    Block::default()
+        .title(title)
+        .borders(Borders::ALL)
+        .border_style(border_style)
+}
+
 pub fn setup_terminal() -> Result<Terminal<CrosstermBackend<Stdout>>, io::Error> {
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -349,24 +1365,211 @@ pub fn restore_terminal(
 enum TermEvent {
     Quit,
     Next,
+    Run(RunTarget),
+    RunTo,
+    RunCount,
+    ExportHistory,
+    Back,
+    Rewind,
+    AddBreakpoint,
+    AddWatchpoint,
+    ToggleSelected,
+    DeleteSelected,
+    SelectPrevious,
+    SelectNext,
+    SwitchPanel,
+    MoveLeft,
+    MoveRight,
+    Goto,
+    Edit,
+    SaveState,
+    LoadState,
+    SelectSlot(u8),
     Resize,
 }
 
 fn listen_to_events() -> TermEvent {
     loop {
-        match event::read() {
-            Ok(Event::Key(KeyEvent { code, kind, .. })) => {
-                if code == KeyCode::Char('q') && kind == KeyEventKind::Press {
-                    return TermEvent::Quit;
-                } else if code == KeyCode::Char('n') && kind == KeyEventKind::Press {
-                    return TermEvent::Next;
-                }
-            }
-            Ok(Event::Resize(..)) => return TermEvent::Resize,
+        let Some(code) = read_key() else {
+            return TermEvent::Resize;
+        };
+        match code {
+            KeyCode::Char('q') => return TermEvent::Quit,
+            KeyCode::Char('n') => return TermEvent::Next,
+            KeyCode::Char('c') => return TermEvent::Run(RunTarget::Breakpoint),
+            KeyCode::Char('o') => return TermEvent::Run(RunTarget::StepOver),
+            KeyCode::Char('f') => return TermEvent::Run(RunTarget::StepOut),
+            KeyCode::Char('u') => return TermEvent::RunTo,
+            KeyCode::Char('x') => return TermEvent::RunCount,
+            KeyCode::Char('h') => return TermEvent::ExportHistory,
+            KeyCode::Char('v') => return TermEvent::Run(RunTarget::Vblank),
+            KeyCode::Char('b') => return TermEvent::Back,
+            KeyCode::Char('r') => return TermEvent::Rewind,
+            KeyCode::Char('a') => return TermEvent::AddBreakpoint,
+            KeyCode::Char('w') => return TermEvent::AddWatchpoint,
+            KeyCode::Char('t') => return TermEvent::ToggleSelected,
+            KeyCode::Char('d') => return TermEvent::DeleteSelected,
+            KeyCode::Up => return TermEvent::SelectPrevious,
+            KeyCode::Down => return TermEvent::SelectNext,
+            KeyCode::Tab => return TermEvent::SwitchPanel,
+            KeyCode::Left => return TermEvent::MoveLeft,
+            KeyCode::Right => return TermEvent::MoveRight,
+            KeyCode::Char('g') => return TermEvent::Goto,
+            KeyCode::Char('e') => return TermEvent::Edit,
+            KeyCode::Char('s') => return TermEvent::SaveState,
+            KeyCode::Char('l') => return TermEvent::LoadState,
+            KeyCode::Char(c @ '0'..='9') => return TermEvent::SelectSlot(c as u8 - b'0'),
             _ => (),
+        }
+    }
+}
+
+// Blocks until a key is pressed; None means the terminal was resized instead
+fn read_key() -> Option<KeyCode> {
+    loop {
+        match event::read() {
+            Ok(Event::Key(KeyEvent {
+                code,
+                kind: KeyEventKind::Press,
+                ..
+            })) => return Some(code),
+            Ok(Event::Resize(..)) => return None,
             Err(e) => {
                 error!(?e, "Error reading event")
             }
+            _ => (),
         }
     }
 }
+
+// Non-blocking; used to interrupt long-running commands
+fn key_pressed() -> bool {
+    let mut pressed = false;
+    while let Ok(true) = event::poll(Duration::ZERO) {
+        if let Ok(Event::Key(KeyEvent {
+            kind: KeyEventKind::Press,
+            ..
+        })) = event::read()
+        {
+            pressed = true;
+        }
+    }
+    pressed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use psemu_core::VideoTiming;
+
+    const PROGRAM: u32 = 0x80010000;
+    const JR_RA: u32 = 0x03e00008;
+    const NOP: u32 = 0;
+
+    fn debugger(program: &[u32]) -> Debugger {
+        let bytes: Vec<u8> = program.iter().flat_map(|w| w.to_le_bytes()).collect();
+        let cpu = Cpu::builder()
+            .ram(PROGRAM, &bytes)
+            .entry(PROGRAM)
+            .build()
+            .unwrap();
+        let mut debugger = Debugger::with_cpu(
+            cpu,
+            Arc::new(Mutex::new(vec![])),
+            false,
+            VideoStandard::Ntsc,
+        );
+        debugger.set_history_capacity(0);
+        debugger
+    }
+
+    // The CPU can't run calls yet, so these tests fake each step by moving
+    // the pipeline on to the next (pc, instruction) of `trace`
+    fn run_trace(trace: &[(u32, u32)], target: RunTarget) -> (Debugger, usize) {
+        let mut debugger = debugger(&[]);
+        debugger.cpu.set_register(RegisterIndex(31), PROGRAM + 8);
+        debugger.cpu.set_next_instruction(trace[0].0, trace[0].1);
+        let mut next = trace[1..].iter();
+        let mut steps = 0;
+        debugger
+            .run_until_with(target, |debugger| {
+                let &(pc, word) = next.next().expect("ran off the end of the trace");
+                debugger.cpu.set_next_instruction(pc, word);
+                steps += 1;
+                Ok(0)
+            })
+            .unwrap();
+        (debugger, steps)
+    }
+
+    #[test]
+    fn step_over_runs_the_call_and_its_delay_slot() {
+        let trace = [
+            (PROGRAM, 0x0c008000), // jal 0x80020000
+            (PROGRAM + 4, NOP),
+            (0x80020000, NOP),
+            (0x80020004, JR_RA),
+            (0x80020008, NOP),
+            (PROGRAM + 8, NOP),
+        ];
+        let (debugger, steps) = run_trace(&trace, RunTarget::StepOver);
+        assert_eq!(steps, 5);
+        assert_eq!(debugger.cpu.next_instruction_pc(), PROGRAM + 8);
+
+        // Anything else is a single step
+        let (debugger, steps) = run_trace(&trace[2..], RunTarget::StepOver);
+        assert_eq!(steps, 1);
+        assert_eq!(debugger.cpu.next_instruction_pc(), 0x80020004);
+    }
+
+    #[test]
+    fn step_out_stops_after_the_return_and_its_delay_slot() {
+        // $ra is PROGRAM + 8, so only the outer `jr $ra` returns there
+        let trace = [
+            (0x80020000, NOP),
+            (0x80020004, 0x0c00c000), // jal 0x80030000
+            (0x80020008, NOP),
+            (0x80030000, JR_RA),
+            (0x80030004, NOP),
+            (0x8002000c, JR_RA),
+            (0x80020010, NOP),
+            (PROGRAM + 8, NOP),
+        ];
+        let (debugger, steps) = run_trace(&trace, RunTarget::StepOut);
+        assert_eq!(steps, 7);
+        assert_eq!(debugger.cpu.next_instruction_pc(), PROGRAM + 8);
+    }
+
+    #[test]
+    fn count_stops_after_exactly_that_many() {
+        let trace: Vec<_> = (0..10).map(|i| (PROGRAM + i * 4, NOP)).collect();
+        let (debugger, steps) = run_trace(&trace, RunTarget::Count(3));
+        assert_eq!(steps, 3);
+        assert_eq!(debugger.cpu.next_instruction_pc(), PROGRAM + 12);
+    }
+
+    #[test]
+    fn vblank_stops_on_the_next_one_after_rewinding() {
+        // j PROGRAM; nop
+        let mut debugger = debugger(&[0x08004000, NOP]);
+        let mut video = VideoTiming::new(VideoStandard::Ntsc);
+        let first = video.cycles_to_vblank();
+        video.advance(first);
+        let second = first + video.cycles_to_vblank();
+
+        debugger.run_until(RunTarget::Vblank).unwrap();
+        assert_eq!(debugger.cpu.cycles, first);
+        for _ in 0..3 {
+            debugger.step_back();
+        }
+        debugger.run_until(RunTarget::Vblank).unwrap();
+        assert_eq!(debugger.cpu.cycles, first);
+
+        debugger.run_until(RunTarget::Vblank).unwrap();
+        assert_eq!(debugger.cpu.cycles, second);
+        debugger.rewind_frame();
+        assert_eq!(debugger.cpu.cycles, first);
+        debugger.run_until(RunTarget::Vblank).unwrap();
+        assert_eq!(debugger.cpu.cycles, second);
+    }
+}
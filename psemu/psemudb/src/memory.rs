@@ -0,0 +1,103 @@
+use psemu_core::Cpu;
+
+pub const BYTES_PER_ROW: u32 = 16;
+// Rows handed to the table; whatever doesn't fit on screen is scrolled to
+pub const ROWS: u32 = 64;
+const WINDOW_LEN: u32 = ROWS * BYTES_PER_ROW;
+
+/// State of the hex viewer: where it's looking and what the memory looked
+/// like before the last step, so changed bytes can be highlighted
+pub struct MemoryView {
+    // First address shown, always row aligned
+    start: u32,
+    pub cursor: u32,
+    // Typing hex digits overwrites the byte under the cursor
+    pub editing: bool,
+    // Set once the high nibble of the byte under the cursor has been typed
+    low_nibble_next: bool,
+    prev_start: u32,
+    prev: Vec<Option<u8>>,
+}
+
+impl MemoryView {
+    pub fn new(addr: u32) -> Self {
+        let mut view = MemoryView {
+            start: 0,
+            cursor: 0,
+            editing: false,
+            low_nibble_next: false,
+            prev_start: 0,
+            prev: vec![],
+        };
+        view.goto(addr);
+        view
+    }
+
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    pub fn goto(&mut self, addr: u32) {
+        self.start = addr - addr % BYTES_PER_ROW;
+        self.cursor = addr;
+        self.low_nibble_next = false;
+    }
+
+    /// Moves the cursor by `delta` bytes, scrolling when it leaves the window
+    pub fn move_cursor(&mut self, delta: i32) {
+        self.cursor = self.cursor.wrapping_add_signed(delta);
+        self.low_nibble_next = false;
+        let row = self.cursor - self.cursor % BYTES_PER_ROW;
+        if row.wrapping_sub(self.start) >= WINDOW_LEN {
+            self.start = if delta < 0 {
+                row
+            } else {
+                row.wrapping_sub(WINDOW_LEN - BYTES_PER_ROW)
+            };
+        }
+    }
+
+    /// One row of bytes; `None` where nothing is mapped
+    pub fn read_row(cpu: &Cpu, addr: u32) -> Vec<Option<u8>> {
+        let mut bytes = Vec::with_capacity(BYTES_PER_ROW as usize);
+        for word_addr in (0..BYTES_PER_ROW).step_by(4) {
+            match cpu.load32(addr.wrapping_add(word_addr)) {
+                Ok(word) => bytes.extend(word.to_le_bytes().map(Some)),
+                Err(_) => bytes.extend([None; 4]),
+            }
+        }
+        bytes
+    }
+
+    /// Remember the visible window; called before anything that runs the CPU
+    pub fn snapshot(&mut self, cpu: &Cpu) {
+        self.prev_start = self.start;
+        self.prev = (0..ROWS)
+            .flat_map(|row| Self::read_row(cpu, self.start.wrapping_add(row * BYTES_PER_ROW)))
+            .collect();
+    }
+
+    pub fn changed(&self, addr: u32, byte: Option<u8>) -> bool {
+        let offset = addr.wrapping_sub(self.prev_start) as usize;
+        self.prev.get(offset).is_some_and(|prev| *prev != byte)
+    }
+
+    /// Types one hex digit over the byte under the cursor. The cursor moves on
+    /// to the next byte once both nibbles have been typed.
+    pub fn edit(&mut self, cpu: &mut Cpu, digit: u8) -> Result<(), String> {
+        let word = cpu.load32(self.cursor & !3)?;
+        let old = (word >> ((self.cursor % 4) * 8)) as u8;
+        let new = if self.low_nibble_next {
+            (old & 0xf0) | digit
+        } else {
+            (digit << 4) | (old & 0x0f)
+        };
+        cpu.poke8(self.cursor, new)?;
+        if self.low_nibble_next {
+            self.move_cursor(1);
+        } else {
+            self.low_nibble_next = true;
+        }
+        Ok(())
+    }
+}
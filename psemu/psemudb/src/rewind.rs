@@ -0,0 +1,351 @@
+use std::collections::VecDeque;
+
+use psemu_core::{
+    CallStack, Cpu, ExecutionBackend, Frame, Interpreter, PsemuCoreError, RegisterIndex,
+    VideoStandard, VideoTiming, WatchKind,
+};
+
+// How many frames worth of snapshots to keep around
+const SNAPSHOT_CAPACITY: usize = 60;
+// How many instructions can be undone without replaying from a snapshot
+const DELTA_CAPACITY: usize = 100_000;
+
+/// Everything needed to undo a single instruction
+struct InstructionDelta {
+    pc: u32,
+    next_instruction: u32,
+    next_instruction_pc: u32,
+    // (register index, old value) for every register the instruction changed
+    registers: Vec<(u8, u32)>,
+    // (address, old value) for every word the instruction stored to
+    memory: Vec<(u32, u32)>,
+    cycles: u64,
+    video: VideoTiming,
+    // Shadow call stack depth, plus the frames themselves when the
+    // instruction called or returned and could have rewritten them
+    frame_count: usize,
+    frames: Option<Vec<Frame>>,
+}
+
+struct Snapshot {
+    // Number of instructions executed when the snapshot was taken
+    position: u64,
+    state: Vec<u8>,
+    // Save states don't have the beam or the shadow call stack in them
+    video: VideoTiming,
+    frames: Vec<Frame>,
+}
+
+/// Lets the debugger go back in time: a ring of save states taken
+/// once per frame, plus per-instruction deltas for fine-grained stepping.
+/// Owns the shadow call stack so going back restores it too.
+pub struct RewindBuffer {
+    position: u64,
+    video: VideoTiming,
+    call_stack: CallStack,
+    // Set when the last instruction started a frame, or nothing has run yet
+    snapshot_due: bool,
+    snapshots: VecDeque<Snapshot>,
+    deltas: VecDeque<InstructionDelta>,
+}
+
+impl Default for RewindBuffer {
+    fn default() -> Self {
+        RewindBuffer::new(VideoStandard::Ntsc)
+    }
+}
+
+impl RewindBuffer {
+    pub fn new(standard: VideoStandard) -> Self {
+        RewindBuffer {
+            position: 0,
+            video: VideoTiming::new(standard),
+            call_stack: CallStack::default(),
+            snapshot_due: true,
+            snapshots: VecDeque::new(),
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Forget everything, e.g. after loading a save state. The beam and the
+    /// call stack carry on from where they were, since save states don't
+    /// include them.
+    pub fn reset(&mut self) {
+        self.position = 0;
+        self.snapshot_due = true;
+        self.snapshots.clear();
+        self.deltas.clear();
+    }
+
+    /// Run one instruction, recording what's needed to undo it. Returns how
+    /// many vblanks started.
+    pub fn step(&mut self, cpu: &mut Cpu) -> Result<u32, PsemuCoreError> {
+        if self.snapshot_due {
+            self.take_snapshot(cpu);
+        }
+
+        let pc = cpu.pc;
+        let next_instruction = cpu.next_instruction();
+        let next_instruction_pc = cpu.next_instruction_pc();
+        let cycles = cpu.cycles;
+        let old_registers: [u32; 32] = cpu.get_registers().try_into().unwrap();
+
+        // Whatever backend the CPU ran on before, stepping is always done by
+        // the interpreter: deltas are per instruction and need the memory
+        // accesses it records
+        let video = self.video.clone();
+        self.call_stack.before(cpu);
+        let frame_count = self.call_stack.frames.len();
+        let frames = self
+            .call_stack
+            .is_pending()
+            .then(|| self.call_stack.frames.clone());
+        let res = Interpreter.step(cpu);
+        self.call_stack.after(cpu);
+        // The cycle counts even when the instruction fails
+        let vblanks = self.video.advance(1);
+        self.snapshot_due = vblanks > 0;
+
+        let registers = old_registers
+            .iter()
+            .zip(cpu.get_registers())
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(i, (old, _))| (i as u8, *old))
+            .collect();
+        let memory = cpu
+            .last_memory_accesses()
+            .iter()
+            .filter(|a| a.kind == WatchKind::Write)
+            .map(|a| (a.addr, a.old))
+            .collect();
+        if self.deltas.len() == DELTA_CAPACITY {
+            self.deltas.pop_front();
+        }
+        self.deltas.push_back(InstructionDelta {
+            pc,
+            next_instruction,
+            next_instruction_pc,
+            registers,
+            memory,
+            cycles,
+            video,
+            frame_count,
+            frames,
+        });
+        self.position += 1;
+        res.map(|_| vblanks)
+    }
+
+    /// Undo the last instruction. Returns false if there's nothing to go back to.
+    pub fn step_back(&mut self, cpu: &mut Cpu) -> bool {
+        if self.position == 0 {
+            return false;
+        }
+        if self.deltas.is_empty() && !self.replay_to(cpu, self.position - 1) {
+            return false;
+        }
+        if let Some(delta) = self.deltas.pop_back() {
+            cpu.pc = delta.pc;
+            cpu.set_next_instruction(delta.next_instruction_pc, delta.next_instruction);
+            for (i, val) in delta.registers {
+                cpu.set_register(RegisterIndex(i as u32), val);
+            }
+            for (addr, val) in delta.memory.into_iter().rev() {
+                // Registers that can't be read back can't be restored either
+                if cpu.load32(addr).is_ok() {
+                    let _ = cpu.poke32(addr, val);
+                }
+            }
+            cpu.cycles = delta.cycles;
+            cpu.instruction_history.truncate_from(delta.cycles);
+            self.video = delta.video;
+            match delta.frames {
+                Some(frames) => self.call_stack.frames = frames,
+                None => self.call_stack.frames.truncate(delta.frame_count),
+            }
+            // Anywhere stepping back can reach already has its snapshot
+            self.snapshot_due = false;
+            self.position -= 1;
+        }
+        // Snapshots from the future are no longer reachable
+        while matches!(self.snapshots.back(), Some(s) if s.position > self.position) {
+            self.snapshots.pop_back();
+        }
+        true
+    }
+
+    /// Go back to the closest frame boundary before the current instruction.
+    /// Returns false if there's no snapshot old enough.
+    pub fn rewind_frame(&mut self, cpu: &mut Cpu) -> bool {
+        let Some(idx) = self
+            .snapshots
+            .iter()
+            .rposition(|s| s.position < self.position)
+        else {
+            return false;
+        };
+        self.restore(cpu, idx);
+        true
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn call_stack(&self) -> &CallStack {
+        &self.call_stack
+    }
+
+    /// For when the CPU jumps somewhere stepping didn't follow, e.g. loading
+    /// a save state
+    pub fn call_stack_mut(&mut self) -> &mut CallStack {
+        &mut self.call_stack
+    }
+
+    fn take_snapshot(&mut self, cpu: &Cpu) {
+        // Stepping back onto a frame boundary keeps the snapshot there, and
+        // a second copy would push real history out of the ring
+        if matches!(self.snapshots.back(), Some(s) if s.position == self.position) {
+            return;
+        }
+        if self.snapshots.len() == SNAPSHOT_CAPACITY {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(Snapshot {
+            position: self.position,
+            state: cpu.save_state(),
+            video: self.video.clone(),
+            frames: self.call_stack.frames.clone(),
+        });
+    }
+
+    fn restore(&mut self, cpu: &mut Cpu, idx: usize) {
+        let snapshot = &self.snapshots[idx];
+        // `load_state` drops the history; keep the part leading up to the snapshot
+        let mut history = std::mem::take(&mut cpu.instruction_history);
+        cpu.load_state(&snapshot.state)
+            .expect("Rewind snapshot should always be loadable");
+        history.truncate_from(cpu.cycles);
+        cpu.instruction_history = history;
+
+        // Deltas for instructions before the snapshot are still valid
+        let undone = (self.position - snapshot.position) as usize;
+        let keep = self.deltas.len().saturating_sub(undone);
+        self.deltas.truncate(keep);
+        self.position = snapshot.position;
+        self.video = snapshot.video.clone();
+        self.call_stack.frames = snapshot.frames.clone();
+        self.snapshot_due = false;
+        self.snapshots.truncate(idx + 1);
+    }
+
+    // Restore the closest snapshot before `target` and re-execute up to it.
+    // The CPU is deterministic, so this lands on exactly the same state.
+    fn replay_to(&mut self, cpu: &mut Cpu, target: u64) -> bool {
+        let Some(idx) = self.snapshots.iter().rposition(|s| s.position <= target) else {
+            return false;
+        };
+        self.restore(cpu, idx);
+        while self.position < target + 1 {
+            if self.step(cpu).is_err() {
+                break;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use psemu_core::CPU_CLOCK_HZ;
+
+    #[test]
+    fn going_back_restores_the_shadow_call_stack() {
+        // j 0x80000080; nop, which lands on the exception vector
+        let program: Vec<u8> = [0x08000020u32, 0]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+        let mut cpu = Cpu::builder()
+            .ram(0x80010000, &program)
+            .entry(0x80010000)
+            .build()
+            .unwrap();
+        let mut rewind = RewindBuffer::default();
+        // Found by a stack walk before anything was recorded
+        let outer = Frame {
+            function: 0x80010000,
+            call_site: 0x80020000,
+            return_addr: 0x80020008,
+            exception: false,
+        };
+        let exception = Frame {
+            function: 0x80000080,
+            call_site: 0x80010004,
+            return_addr: 0x80010004,
+            exception: true,
+        };
+        rewind.call_stack_mut().frames.push(outer);
+        for _ in 0..3 {
+            rewind.step(&mut cpu).unwrap();
+        }
+        assert_eq!(rewind.call_stack().frames, [outer, exception]);
+
+        assert!(rewind.step_back(&mut cpu));
+        assert_eq!(rewind.call_stack().frames, [outer, exception]);
+        assert!(rewind.step_back(&mut cpu));
+        assert_eq!(rewind.call_stack().frames, [outer]);
+        rewind.step(&mut cpu).unwrap();
+        assert_eq!(rewind.call_stack().frames, [outer, exception]);
+        assert!(rewind.rewind_frame(&mut cpu));
+        assert_eq!(rewind.position(), 0);
+        assert_eq!(rewind.call_stack().frames, [outer]);
+    }
+
+    #[test]
+    fn stepping_back_onto_a_frame_boundary_keeps_one_snapshot() {
+        let mut cpu = Cpu::builder()
+            .ram(0x80010000, &[0; 64])
+            .entry(0x80010000)
+            .build()
+            .unwrap();
+        let mut rewind = RewindBuffer::default();
+        for _ in 0..5 {
+            rewind.step(&mut cpu).unwrap();
+            assert!(rewind.step_back(&mut cpu));
+        }
+        rewind.step(&mut cpu).unwrap();
+        assert_eq!(rewind.snapshots.len(), 1);
+    }
+
+    #[test]
+    fn stepping_back_over_a_vblank_rewinds_the_beam() {
+        // j 0x80010000; nop
+        let program: Vec<u8> = [0x08004000u32, 0]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+        let mut cpu = Cpu::builder()
+            .ram(0x80010000, &program)
+            .entry(0x80010000)
+            .build()
+            .unwrap();
+        cpu.instruction_history.set_capacity(0);
+        let mut rewind = RewindBuffer::default();
+        let mut steps = 1;
+        while rewind.step(&mut cpu).unwrap() == 0 {
+            steps += 1;
+        }
+        // Up to the first line of vblank, in CPU cycles
+        let standard = VideoStandard::Ntsc;
+        let video_cycles = (standard.vblank_start() * standard.cycles_per_scanline()) as u64;
+        assert_eq!(
+            steps,
+            (video_cycles * CPU_CLOCK_HZ).div_ceil(standard.video_clock_hz())
+        );
+        assert!(rewind.step_back(&mut cpu));
+        assert_eq!(rewind.step(&mut cpu).unwrap(), 1);
+    }
+}
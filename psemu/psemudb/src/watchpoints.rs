@@ -0,0 +1,55 @@
+use psemu_core::{Cpu, Watchpoint, WatchpointHit};
+use tracing::info;
+
+pub struct WatchEntry {
+    pub watchpoint: Watchpoint,
+    pub enabled: bool,
+    pub hit_count: u64,
+}
+
+/// The debugger's view of the watchpoints; only enabled ones are installed
+/// in the CPU
+#[derive(Default)]
+pub struct Watchpoints {
+    pub list: Vec<WatchEntry>,
+}
+
+impl Watchpoints {
+    pub fn add(&mut self, cpu: &mut Cpu, watchpoint: Watchpoint) {
+        info!(%watchpoint, "Added watchpoint");
+        cpu.add_watchpoint(watchpoint.clone());
+        self.list.push(WatchEntry {
+            watchpoint,
+            enabled: true,
+            hit_count: 0,
+        });
+    }
+
+    pub fn remove(&mut self, cpu: &mut Cpu, idx: usize) {
+        if idx < self.list.len() {
+            let entry = self.list.remove(idx);
+            cpu.remove_watchpoint(&entry.watchpoint);
+        }
+    }
+
+    pub fn toggle(&mut self, cpu: &mut Cpu, idx: usize) {
+        if let Some(entry) = self.list.get_mut(idx) {
+            entry.enabled = !entry.enabled;
+            if entry.enabled {
+                cpu.add_watchpoint(entry.watchpoint.clone());
+            } else {
+                cpu.remove_watchpoint(&entry.watchpoint);
+            }
+        }
+    }
+
+    /// Returns the index of the watchpoint that was hit
+    pub fn record_hit(&mut self, hit: &WatchpointHit) -> Option<usize> {
+        let idx = self
+            .list
+            .iter()
+            .position(|e| e.enabled && e.watchpoint == hit.watchpoint)?;
+        self.list[idx].hit_count += 1;
+        Some(idx)
+    }
+}